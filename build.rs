@@ -0,0 +1,47 @@
+fn main() {
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+
+    // Emit `axcpu_arch` for the supported architectures, so that the code can
+    // use `#[cfg(axcpu_arch = "...")]` regardless of how the target is named.
+    println!(
+        "cargo::rustc-check-cfg=cfg(axcpu_arch, values(\"x86_64\", \"aarch64\", \"riscv32\", \"riscv64\", \"loongarch64\"))"
+    );
+    if matches!(
+        arch.as_str(),
+        "x86_64" | "aarch64" | "riscv32" | "riscv64" | "loongarch64"
+    ) {
+        println!("cargo::rustc-cfg=axcpu_arch=\"{arch}\"");
+    }
+
+    // Architecture-specific features (e.g., "cet", "kprobe" and "arm-el2") are
+    // no-ops on other architectures, so that `--all-features` works on every
+    // target. Only combinations that can never work are rejected here.
+    let feature = |name: &str| {
+        std::env::var_os(format!(
+            "CARGO_FEATURE_{}",
+            name.to_uppercase().replace('-', "_")
+        ))
+        .is_some()
+    };
+    for (name, requires) in FEATURE_DEPS {
+        for dep in *requires {
+            if feature(name) && !feature(dep) {
+                println!("cargo::error=feature \"{name}\" requires feature \"{dep}\"");
+            }
+        }
+    }
+
+    println!("cargo::rerun-if-changed=build.rs");
+}
+
+/// Features that require other features to be enabled.
+///
+/// They are also declared in `Cargo.toml`, and checked here so that the code
+/// gated by them can rely on the requirements.
+const FEATURE_DEPS: &[(&str, &[&str])] = &[
+    ("fp-lazy", &["fp-simd"]),
+    ("sve", &["fp-simd"]),
+    ("pcid", &["uspace"]),
+    ("sysenter", &["uspace"]),
+    ("ipi", &["smp"]),
+];
@@ -0,0 +1,136 @@
+//! Address space identifiers (ASIDs), to tag the TLB entries of different
+//! address spaces and avoid TLB flushes on address space switches.
+//!
+//! ASIDs are allocated to task contexts with a generation number. When all
+//! ASIDs are exhausted, the generation is incremented and the TLBs of all CPUs
+//! are invalidated, then the contexts with ASIDs of old generations are
+//! reallocated on their next [`switch_to`]. A context also gets a new ASID
+//! when its page table root is changed.
+//!
+//! The ASID is held in the upper 16 bits of `TTBR0_EL1`, which requires
+//! `TCR_EL1.A1 = 0`.
+//!
+//! [`switch_to`]: super::TaskContext::switch_to
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use aarch64_cpu::registers::{Readable, TCR_EL1};
+
+use super::TaskContext;
+
+/// The bit offset of the generation in the packed ASID of a task context.
+const GEN_SHIFT: u32 = 16;
+
+/// The bit offset of the ASID in `TTBR0_EL1`.
+const TTBR_ASID_SHIFT: u32 = 48;
+
+/// The current ASID generation, starting from 1 so that a zeroed packed ASID
+/// is always invalid.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Protects [`NEXT_ASID`] and the rollover of [`GENERATION`].
+static ASID_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// The next ASID to allocate in the current generation. ASID 0 is reserved
+/// for contexts without an ASID.
+static mut NEXT_ASID: usize = 1;
+
+/// Returns the number of ASIDs: 65536 if 16-bit ASIDs are enabled
+/// (`TCR_EL1.AS = 1`), otherwise 256.
+pub fn asid_count() -> usize {
+    if TCR_EL1.is_set(TCR_EL1::AS) {
+        1 << 16
+    } else {
+        1 << 8
+    }
+}
+
+/// Returns the ASID of the context if it is allocated in the current
+/// generation.
+pub fn current_asid(ctx: &TaskContext) -> Option<u16> {
+    let packed = ctx.asid.load(Ordering::Relaxed);
+    if packed >> GEN_SHIFT == GENERATION.load(Ordering::Relaxed) {
+        Some(packed as u16)
+    } else {
+        None
+    }
+}
+
+/// Drops the ASID of the context, so that a new one is allocated on its next
+/// [`switch_to`].
+///
+/// It is called when the page table root of the context changes, as the TLB
+/// entries tagged by the old ASID belong to the old page table. The old ASID
+/// is not reused in the current generation, and the TLB entries tagged by it
+/// are invalidated on the rollover to the next generation.
+///
+/// [`switch_to`]: super::TaskContext::switch_to
+pub fn reset(ctx: &TaskContext) {
+    ctx.asid.store(0, Ordering::Relaxed);
+}
+
+/// Allocates an ASID for the context, or returns its current one if it is
+/// still valid in the current generation.
+///
+/// If all ASIDs are exhausted, a new generation is started and the TLBs of
+/// all CPUs are invalidated (`TLBI VMALLE1IS`).
+pub fn allocate(ctx: &TaskContext) -> u16 {
+    if let Some(asid) = current_asid(ctx) {
+        return asid;
+    }
+    while ASID_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    // SAFETY: `NEXT_ASID` is protected by `ASID_LOCK`.
+    let asid = unsafe {
+        let mut gen = GENERATION.load(Ordering::Relaxed);
+        let packed = ctx.asid.load(Ordering::Relaxed);
+        if packed >> GEN_SHIFT == gen {
+            // allocated by another CPU in the meantime
+            packed as u16
+        } else {
+            if NEXT_ASID >= asid_count() {
+                gen += 1;
+                NEXT_ASID = 1;
+                flush_all_cpus();
+                GENERATION.store(gen, Ordering::Relaxed);
+            }
+            let asid = NEXT_ASID as u16;
+            NEXT_ASID += 1;
+            ctx.asid
+                .store((gen << GEN_SHIFT) | asid as u64, Ordering::Relaxed);
+            asid
+        }
+    };
+    ASID_LOCK.store(false, Ordering::Release);
+    asid
+}
+
+/// Invalidates the TLB entries tagged by the given ASID on the current CPU.
+pub fn flush_local(asid: u16) {
+    let operand = (asid as u64) << TTBR_ASID_SHIFT;
+    unsafe { asm!("dsb nshst; tlbi aside1, {}; dsb nsh; isb", in(reg) operand) };
+}
+
+/// Invalidates all stage 1 TLB entries of all CPUs in the inner shareable
+/// domain.
+fn flush_all_cpus() {
+    unsafe { asm!("dsb ishst; tlbi vmalle1is; dsb ish; isb") };
+}
+
+/// Writes the page table root with the ASID to `TTBR0_EL1`.
+///
+/// Both are updated by a single write, so no break-before-make sequence is
+/// needed.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+pub unsafe fn write_user_page_table(root_paddr: memory_addr::PhysAddr, asid: u16) {
+    let value = ((asid as u64) << TTBR_ASID_SHIFT) | root_paddr.as_usize() as u64;
+    unsafe { asm!("msr ttbr0_el1, {}; isb", in(reg) value) };
+}
@@ -10,7 +10,7 @@ use memory_addr::{PhysAddr, VirtAddr};
 /// In AArch64, it unmasks IRQs by clearing the I bit in the `DAIF` register.
 #[inline]
 pub fn enable_irqs() {
-    unsafe { asm!("msr daifclr, #2") };
+    super::daif::enable_irq();
 }
 
 /// Makes the current CPU to ignore interrupts.
@@ -18,7 +18,7 @@ pub fn enable_irqs() {
 /// In AArch64, it masks IRQs by setting the I bit in the `DAIF` register.
 #[inline]
 pub fn disable_irqs() {
-    unsafe { asm!("msr daifset, #2") };
+    super::daif::disable_irq();
 }
 
 /// Returns whether the current CPU is allowed to respond to interrupts.
@@ -26,7 +26,23 @@ pub fn disable_irqs() {
 /// In AArch64, it checks the I bit in the `DAIF` register.
 #[inline]
 pub fn irqs_enabled() -> bool {
-    !DAIF.matches_all(DAIF::I::Masked)
+    super::daif::read() & super::daif::I == 0
+}
+
+/// Masks SErrors (asynchronous aborts) on the current CPU.
+///
+/// In AArch64, it sets the A bit in the `DAIF` register.
+#[inline]
+pub fn mask_serror() {
+    super::daif::disable_serror();
+}
+
+/// Unmasks SErrors (asynchronous aborts) on the current CPU.
+///
+/// In AArch64, it clears the A bit in the `DAIF` register.
+#[inline]
+pub fn unmask_serror() {
+    super::daif::enable_serror();
 }
 
 /// Relaxes the current CPU and waits for interrupts.
@@ -44,6 +60,52 @@ pub fn halt() {
     aarch64_cpu::asm::wfi(); // should never return
 }
 
+/// Waits for an interrupt (`WFI`).
+///
+/// The CPU enters a low-power state until an interrupt or other wakeup event
+/// occurs.
+#[inline]
+pub fn wfi() {
+    aarch64_cpu::asm::wfi();
+}
+
+/// Waits for an event (`WFE`).
+///
+/// The CPU enters a low-power state until an event is signaled, e.g., by
+/// [`sev`] on another CPU, or an interrupt occurs.
+#[inline]
+pub fn wfe() {
+    aarch64_cpu::asm::wfe();
+}
+
+/// Sends an event to all CPUs in the system (`SEV`).
+#[inline]
+pub fn sev() {
+    aarch64_cpu::asm::sev();
+}
+
+/// Sends an event to the current CPU only (`SEVL`).
+///
+/// It is usually used before a `WFE` loop so that the first `WFE` does not
+/// block.
+#[inline]
+pub fn sevl() {
+    aarch64_cpu::asm::sevl();
+}
+
+/// Executes a no-operation instruction (`NOP`).
+#[inline]
+pub fn nop() {
+    aarch64_cpu::asm::nop();
+}
+
+/// Hints the CPU that the current thread is performing a spin-wait loop
+/// (`YIELD`).
+#[inline]
+pub fn yield_cpu() {
+    unsafe { asm!("yield", options(nomem, nostack, preserves_flags)) };
+}
+
 /// Reads the current page table root register for kernel space (`TTBR1_EL1`).
 ///
 /// When the "arm-el2" feature is enabled,
@@ -69,7 +131,8 @@ pub fn read_kernel_page_table() -> PhysAddr {
 /// Returns the physical address of the page table root.
 #[inline]
 pub fn read_user_page_table() -> PhysAddr {
-    let root = TTBR0_EL1.get();
+    // bits 63:48 are the ASID
+    let root = TTBR0_EL1.get() & ((1 << 48) - 1);
     pa!(root as usize)
 }
 
@@ -175,6 +238,78 @@ pub unsafe fn write_exception_vector_base(vbar: usize) {
     VBAR_EL2.set(vbar as _);
 }
 
+/// Writes the exception vector base address register of EL1 (`VBAR_EL1`).
+///
+/// Unlike [`write_exception_vector_base`], it always writes `VBAR_EL1`
+/// regardless of the "arm-el2" feature.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the exception handling behavior of the
+/// current CPU.
+#[inline]
+pub unsafe fn write_vbar_el1(addr: usize) {
+    VBAR_EL1.set(addr as _);
+}
+
+/// Reads the fault address register (`FAR_EL1`).
+///
+/// Returns the faulting virtual address of the last synchronous instruction or
+/// data abort, alignment fault, or watchpoint exception taken to EL1.
+#[inline]
+pub fn read_far_el1() -> VirtAddr {
+    va!(FAR_EL1.get() as usize)
+}
+
+/// Reads the exception syndrome register (`ESR_EL1`).
+///
+/// Returns the raw syndrome information of the last exception taken to EL1.
+#[inline]
+pub fn read_esr_el1() -> u64 {
+    ESR_EL1.get()
+}
+
+/// Reads the floating-point status register (`FPSR`).
+#[inline]
+pub fn read_fpsr() -> u32 {
+    let value: u64;
+    // `FPSR` is written by its encoding, as the name is not accepted by the
+    // assembler without the `fp-armv8` target feature (e.g., on softfloat
+    // targets)
+    unsafe { asm!("mrs {}, S3_3_C4_C4_1", out(reg) value, options(nomem, nostack)) };
+    value as u32
+}
+
+/// Reads the multiprocessor affinity register (`MPIDR_EL1`).
+#[inline]
+pub fn read_mpidr_el1() -> u64 {
+    MPIDR_EL1.get()
+}
+
+/// Reads the AArch64 processor feature register 0 (`ID_AA64PFR0_EL1`).
+#[inline]
+pub fn read_id_aa64pfr0_el1() -> u64 {
+    ID_AA64PFR0_EL1.get()
+}
+
+/// Reads the monitor debug system control register (`MDSCR_EL1`).
+#[inline]
+pub fn read_mdscr_el1() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, mdscr_el1", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+/// Writes the monitor debug system control register (`MDSCR_EL1`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the debug states of the current CPU.
+#[inline]
+pub unsafe fn write_mdscr_el1(value: u64) {
+    unsafe { asm!("msr mdscr_el1, {}", "isb", in(reg) value, options(nostack)) };
+}
+
 /// Reads the thread pointer of the current CPU (`TPIDR_EL0`).
 ///
 /// It is used to implement TLS (Thread Local Storage).
@@ -218,3 +353,38 @@ unsafe extern "C" {
     /// while a value > 0 indicates failure.
     pub fn user_copy(dst: *mut u8, src: *const u8, size: usize) -> usize;
 }
+
+/// Reads a byte from `src`, where the address may be in user space.
+///
+/// The load is annotated in the exception table, so a page fault on it is
+/// recovered by the trap handler, and `Err(())` is returned instead of
+/// panicking.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads from a raw pointer. `src` must
+/// not point to memory-mapped I/O with side effects on reads.
+#[cfg(feature = "uspace")]
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_read_u8(src: *const u8) -> Result<u8, ()> {
+    let val: u32;
+    let err: u32;
+    unsafe {
+        asm!(
+            "mov {err:w}, #1",
+            "2: ldrb {val:w}, [{src}]",
+            "mov {err:w}, wzr",
+            "3:",
+            crate::ex_table_entry!("2b", "3b"),
+            src = in(reg) src,
+            val = out(reg) val,
+            err = out(reg) err,
+            options(nostack, readonly, preserves_flags),
+        )
+    }
+    if err == 0 {
+        Ok(val as u8)
+    } else {
+        Err(())
+    }
+}
@@ -0,0 +1,54 @@
+//! Branch Target Identification (`FEAT_BTI`) support.
+//!
+//! The assembly functions of this crate that may be called from Rust begin
+//! with a `BTI C` landing pad (encoded as `HINT #34`, a `NOP` on CPUs without
+//! `FEAT_BTI`). The exception vectors do not need landing pads, as exception
+//! entries are not branches.
+
+use aarch64_cpu::{asm::barrier, registers::*};
+
+/// `SCTLR_EL1.BT0`: enables BTI for EL0 guarded pages.
+const SCTLR_EL1_BT0: u64 = 1 << 35;
+/// `SCTLR_EL1.BT1`: enables BTI for EL1 guarded pages.
+#[cfg(feature = "bti")]
+const SCTLR_EL1_BT1: u64 = 1 << 36;
+
+/// Enables the BTI enforcement for EL0 by setting `SCTLR_EL1.BT0`.
+///
+/// After that, an indirect branch from EL0 to an instruction in a guarded page
+/// (mapped with the `GP` bit set) that is not a valid landing pad raises a
+/// Branch Target Exception (`ESR_EL1.EC == 0x0d`), which is reported as
+/// `ExceptionKind::BranchTargetFault` by `UserContext::run` (when the
+/// "uspace" feature is enabled).
+///
+/// It has no effect if the CPU does not implement `FEAT_BTI`.
+#[inline]
+pub fn enable_el0_bti() {
+    SCTLR_EL1.set(SCTLR_EL1.get() | SCTLR_EL1_BT0);
+    barrier::isb(barrier::SY);
+}
+
+/// Enables the BTI enforcement for both EL0 and EL1 by setting
+/// `SCTLR_EL1.BT0` and `SCTLR_EL1.BT1`.
+///
+/// The kernel must be built with BTI landing pads (e.g., with
+/// `-Zbranch-protection=bti`) and its code must be mapped as guarded pages,
+/// otherwise its indirect branches will fault. All assembly entry points of
+/// this crate that may be reached by indirect branches begin with `BTI C`.
+///
+/// It has no effect if the CPU does not implement `FEAT_BTI`.
+#[cfg(feature = "bti")]
+#[inline]
+pub fn enable() {
+    SCTLR_EL1.set(SCTLR_EL1.get() | SCTLR_EL1_BT0 | SCTLR_EL1_BT1);
+    barrier::isb(barrier::SY);
+}
+
+/// Returns whether the BTI enforcement is enabled for both EL0 and EL1 by
+/// [`enable`].
+#[cfg(feature = "bti")]
+#[inline]
+pub fn is_enabled() -> bool {
+    let mask = SCTLR_EL1_BT0 | SCTLR_EL1_BT1;
+    SCTLR_EL1.get() & mask == mask
+}
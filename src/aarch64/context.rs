@@ -2,6 +2,10 @@ use core::arch::naked_asm;
 use core::fmt;
 use memory_addr::VirtAddr;
 
+use crate::trap::PrivilegeLevel;
+#[cfg(feature = "fp-lazy")]
+use crate::FpCell;
+
 /// Saved registers when a trap (exception) occurs.
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
@@ -17,6 +21,9 @@ pub struct TrapFrame {
     pub __pad: u64,
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<TrapFrame>(), 34 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(TrapFrame, elr), 31 * 8);
+
 impl fmt::Debug for TrapFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "TrapFrame: {{")?;
@@ -31,6 +38,11 @@ impl fmt::Debug for TrapFrame {
     }
 }
 
+/// The software step bit (`SS`) in `SPSR_EL1`.
+const SPSR_SS: u64 = 1 << 21;
+/// The software step enable bit (`SS`) in `MDSCR_EL1`.
+pub(super) const MDSCR_EL1_SS: u64 = 1 << 0;
+
 impl TrapFrame {
     /// Gets the 0th syscall argument.
     pub const fn arg0(&self) -> usize {
@@ -127,10 +139,208 @@ impl TrapFrame {
         self.x[30] = lr as _;
     }
 
+    /// Sets the condition flags (`N`, `Z`, `C`, `V`) in the saved `SPSR`.
+    pub const fn set_spsr_nzcv(&mut self, n: bool, z: bool, c: bool, v: bool) {
+        const NZCV_MASK: u64 = 0b1111 << 28;
+        let nzcv = (n as u64) << 31 | (z as u64) << 30 | (c as u64) << 29 | (v as u64) << 28;
+        self.spsr = (self.spsr & !NZCV_MASK) | nzcv;
+    }
+
+    /// Sets the exception mask bits (`D`, `A`, `I`, `F`) in the saved `SPSR`.
+    ///
+    /// A `true` value means the corresponding exception is **masked**.
+    pub const fn set_spsr_daif(&mut self, d: bool, a: bool, i: bool, f: bool) {
+        const DAIF_MASK: u64 = 0b1111 << 6;
+        let daif = (d as u64) << 9 | (a as u64) << 8 | (i as u64) << 7 | (f as u64) << 6;
+        self.spsr = (self.spsr & !DAIF_MASK) | daif;
+    }
+
+    /// Sets the exception level and the stack pointer selection (`M[3:0]`)
+    /// in the saved `SPSR`, i.e., the mode to return to on `ERET`.
+    ///
+    /// If `sp_sel` is `true`, the stack pointer of the target exception level
+    /// (`SP_ELx`, the `h` modes) is used, otherwise `SP_EL0` (the `t` modes)
+    /// is used. For EL0, `sp_sel` must be `false`.
+    ///
+    /// Note that a zeroed `SPSR` stands for EL0t with all exceptions
+    /// (`D`, `A`, `I`, `F`) unmasked.
+    pub const fn set_spsr_el(&mut self, el: u8, sp_sel: bool) {
+        const M_MASK: u64 = 0b1111;
+        assert!(el <= 3, "invalid exception level");
+        let m = ((el as u64) << 2) | sp_sel as u64;
+        self.spsr = (self.spsr & !M_MASK) | m;
+    }
+
+    /// Enables single-stepping by setting `MDSCR_EL1.SS` and the `SS` bit in
+    /// the saved `SPSR`.
+    ///
+    /// A software step exception is raised after the next instruction is
+    /// executed after returning to this trap frame. Note that `MDSCR_EL1.SS`
+    /// is a CPU state rather than a part of the trap frame: it must be called
+    /// on the trap frame of the current task, and `MDSCR_EL1.SS` is then
+    /// switched with the task by [`TaskContext::switch_to`].
+    pub fn enable_single_step(&mut self) {
+        unsafe { crate::asm::write_mdscr_el1(crate::asm::read_mdscr_el1() | MDSCR_EL1_SS) };
+        self.spsr |= SPSR_SS;
+    }
+
+    /// Disables single-stepping by clearing the `SS` bit in the saved `SPSR`
+    /// and `MDSCR_EL1.SS`.
+    pub fn disable_single_step(&mut self) {
+        self.spsr &= !SPSR_SS;
+        unsafe { crate::asm::write_mdscr_el1(crate::asm::read_mdscr_el1() & !MDSCR_EL1_SS) };
+    }
+
+    /// Returns whether single-stepping is enabled in this trap frame.
+    pub const fn is_single_step(&self) -> bool {
+        self.spsr & SPSR_SS != 0
+    }
+
+    /// Returns whether the instruction pointer (`ELR`) points into the
+    /// kernel text section, i.e., the trap interrupted kernel code.
+    ///
+    /// The kernel text bounds are given by the `_stext` and `_etext` linker
+    /// symbols. The kernel must define them in its linker script when this
+    /// method is used, e.g.:
+    ///
+    /// ```ld
+    /// .text : {
+    ///     _stext = .;
+    ///     *(.text .text.*)
+    ///     _etext = .;
+    /// }
+    /// ```
+    pub fn elr_in_kernel(&self) -> bool {
+        crate::trap::is_kernel_text(self.elr as _)
+    }
+
+    /// Gets the register by its DWARF register number (see [`dwarf_reg`]).
+    ///
+    /// Returns `None` if the register is not saved in the trap frame, e.g.,
+    /// the stack pointer.
+    ///
+    /// [`dwarf_reg`]: super::dwarf_reg
+    pub const fn get_reg(&self, dwarf_num: u16) -> Option<u64> {
+        use super::dwarf_reg::*;
+        match dwarf_num {
+            X0..=LR => Some(self.x[dwarf_num as usize]),
+            PC => Some(self.elr),
+            PSTATE => Some(self.spsr),
+            _ => None,
+        }
+    }
+
+    /// Sets the register by its DWARF register number (see [`dwarf_reg`]).
+    ///
+    /// Returns `false` if the register is not saved in the trap frame, e.g.,
+    /// the stack pointer.
+    ///
+    /// [`dwarf_reg`]: super::dwarf_reg
+    pub const fn set_reg(&mut self, dwarf_num: u16, val: u64) -> bool {
+        use super::dwarf_reg::*;
+        match dwarf_num {
+            X0..=LR => self.x[dwarf_num as usize] = val,
+            PC => self.elr = val,
+            PSTATE => self.spsr = val,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Zeros the registers that are neither syscall arguments nor return
+    /// values (`x9`-`x17`, `x29` and `x30`), so that no stale values are leaked
+    /// to the user space.
+    ///
+    /// It discards the user states in these registers, so it must only be used
+    /// for a trap frame that does not resume interrupted user code, e.g., the
+    /// initial frame of a new program.
+    pub fn scrub_for_user_return(&mut self) {
+        self.x[9..=17].fill(0);
+        self.x[29] = 0;
+        self.x[30] = 0;
+    }
+
+    /// Whether the trap is from the user space, i.e., the saved `SPSR.M[3:0]`
+    /// is EL0.
+    pub const fn is_from_user(&self) -> bool {
+        self.spsr & 0b1111 == 0
+    }
+
+    /// Returns the privilege level that the trap is taken from.
+    ///
+    /// It is determined by the state saved on trap entry, so it does not
+    /// change during the trap handling.
+    pub const fn privilege_level(&self) -> PrivilegeLevel {
+        if self.is_from_user() {
+            PrivilegeLevel::User
+        } else {
+            PrivilegeLevel::Kernel
+        }
+    }
+
+    /// Whether the trap is from the kernel, i.e., the negation of
+    /// [`is_from_user`](Self::is_from_user).
+    pub const fn is_kernel(&self) -> bool {
+        !self.is_from_user()
+    }
+
     /// Unwind the stack and get the backtrace.
     pub fn backtrace(&self) -> axbacktrace::Backtrace {
         axbacktrace::Backtrace::capture_trap(self.x[29] as _, self.elr as _, self.x[30] as _)
     }
+
+    /// Writes all registers to `w` in a compact columnar format, e.g.,
+    /// `x0=0x0000000000000000  x1=0x...`, four registers per line.
+    pub fn dump<W: core::fmt::Write>(&self, w: &mut W) -> core::fmt::Result {
+        const NAMES: [&str; 31] = [
+            "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+            "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+            "x26", "x27", "x28", "x29", "x30",
+        ];
+        let mut regs = [("", 0); 33];
+        for (i, (&name, &value)) in NAMES.iter().zip(&self.x).enumerate() {
+            regs[i] = (name, value as usize);
+        }
+        regs[31] = ("elr", self.elr as _);
+        regs[32] = ("spsr", self.spsr as _);
+        crate::trap::dump_regs(w, &regs)
+    }
+
+    /// Dumps all registers to a newly allocated string, in the same format as
+    /// [`TrapFrame::dump`].
+    #[cfg(feature = "alloc")]
+    pub fn dump_to_string(&self) -> alloc::string::String {
+        let mut s = alloc::string::String::new();
+        self.dump(&mut s).unwrap();
+        s
+    }
+}
+
+/// The callee-saved registers in a [`TrapFrame`], i.e., the registers that
+/// must be preserved across a function call in the AAPCS64.
+///
+/// The stack pointer is not included since it is not saved in the trap frame.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CalleeSavedRegs {
+    /// Registers X19..X29 (X29 is the frame pointer).
+    pub x: [u64; 11],
+}
+
+impl TrapFrame {
+    /// Copies out the callee-saved registers (`x19`-`x29`).
+    pub fn callee_saved_copy(&self) -> CalleeSavedRegs {
+        let mut x = [0; 11];
+        x.copy_from_slice(&self.x[19..30]);
+        CalleeSavedRegs { x }
+    }
+
+    /// Checks whether the callee-saved registers of two trap frames are
+    /// equal.
+    ///
+    /// `elr`, `spsr` and the scratch registers are not compared.
+    pub fn callee_saved_eq(&self, other: &TrapFrame) -> bool {
+        self.callee_saved_copy() == other.callee_saved_copy()
+    }
 }
 
 /// FP & SIMD registers.
@@ -145,6 +355,9 @@ pub struct FpState {
     pub fpsr: u32,
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<FpState>(), 33 * 16);
+static_assertions::const_assert_eq!(core::mem::align_of::<FpState>(), 16);
+
 #[cfg(feature = "fp-simd")]
 impl FpState {
     /// Saves the current FP/SIMD states from CPU to this structure.
@@ -188,13 +401,88 @@ pub struct TaskContext {
     pub lr: u64, // r30
     /// Thread Pointer
     pub tpidr_el0: u64,
+    /// The CPU affinity mask of the task, where bit N means the task is
+    /// allowed to run on logical CPU N.
+    pub cpu_mask: u64,
     /// The `ttbr0_el1` register value, i.e., the page table root.
     #[cfg(feature = "uspace")]
     pub ttbr0_el1: memory_addr::PhysAddr,
-    #[cfg(feature = "fp-simd")]
+    /// The address space identifier (bits 15:0) and its generation (bits
+    /// 63:16), allocated by [`asid::allocate`](super::asid::allocate).
+    #[cfg(feature = "uspace")]
+    pub asid: core::sync::atomic::AtomicU64,
+    #[cfg(all(feature = "fp-simd", not(feature = "fp-lazy")))]
     pub fp_state: FpState,
+    /// FP/SIMD states, which are switched lazily by the FP/SIMD access trap.
+    #[cfg(feature = "fp-lazy")]
+    pub fp_state: FpCell<FpState>,
+    /// Whether the FPU of the current CPU holds the latest FP/SIMD states of
+    /// the task, which have not been saved to [`fp_state`].
+    ///
+    /// [`fp_state`]: TaskContext::fp_state
+    #[cfg(feature = "fp-lazy")]
+    pub fp_owner_flag: FpCell<bool>,
+    /// The address of the FPU owner slot of the CPU whose FPU holds the states
+    /// of the task, valid when the task may still be the owner there.
+    #[cfg(feature = "fp-lazy")]
+    pub(super) fpu_owner_slot: FpCell<usize>,
+    /// SVE states, switched lazily.
+    #[cfg(feature = "sve")]
+    pub sve_state: super::sve::SveState,
+    /// Pointer authentication keys.
+    #[cfg(feature = "pac")]
+    pub pac_keys: super::pac::PacKeys,
+    /// MTE registers, switched if [`mte_active`] is set.
+    ///
+    /// [`mte_active`]: TaskContext::mte_active
+    #[cfg(feature = "mte")]
+    pub mte_state: super::mte::MteState,
+    /// Whether the task uses MTE.
+    #[cfg(feature = "mte")]
+    pub mte_active: bool,
+    /// Whether single-stepping is enabled in `MDSCR_EL1.SS` for the task (see
+    /// [`TrapFrame::enable_single_step`]), saved when it is switched out.
+    pub single_step: bool,
+    /// Debug registers (hardware breakpoints and watchpoints).
+    #[cfg(feature = "debug-regs")]
+    pub debug_regs: super::debug::DebugRegs,
+    /// Whether the debug registers are used by the task, i.e., need to be
+    /// switched.
+    #[cfg(feature = "debug-regs")]
+    pub debug_active: bool,
+    /// PMU registers, switched if [`pmu_active`] is set.
+    ///
+    /// [`pmu_active`]: TaskContext::pmu_active
+    #[cfg(feature = "pmu")]
+    pub pmu_state: super::pmu::PmuState,
+    /// Whether the task uses the PMU.
+    #[cfg(feature = "pmu")]
+    pub pmu_active: bool,
+    /// The bottom (lowest address) of the kernel stack, where the canary is
+    /// placed, or 0 if the canary is not set.
+    #[cfg(feature = "stack-canary")]
+    pub kstack_bottom: VirtAddr,
+    /// The expected value of the canary at [`kstack_bottom`].
+    ///
+    /// [`kstack_bottom`]: TaskContext::kstack_bottom
+    #[cfg(feature = "stack-canary")]
+    pub stack_canary: u64,
+    /// The kernel stack top of the task, set by [`init`].
+    ///
+    /// [`init`]: TaskContext::init
+    #[cfg(feature = "stack-paint")]
+    pub kstack_top: VirtAddr,
+    /// The size of the kernel stack in bytes, which is painted by [`init`] if
+    /// not 0.
+    ///
+    /// [`init`]: TaskContext::init
+    #[cfg(feature = "stack-paint")]
+    pub kstack_size: usize,
 }
 
+static_assertions::const_assert_eq!(core::mem::offset_of!(TaskContext, r19), 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(TaskContext, lr), 12 * 8);
+
 impl TaskContext {
     /// Creates a dummy context for a new task.
     ///
@@ -203,48 +491,382 @@ impl TaskContext {
     ///
     /// [`init`]: TaskContext::init
     /// [`switch_to`]: TaskContext::switch_to
+    #[allow(clippy::field_reassign_with_default)]
     pub fn new() -> Self {
-        Self::default()
+        // not a struct update, as the fields cannot be moved out of a
+        // `TaskContext` if it implements `Drop` (with `fp-lazy`)
+        let mut ctx = Self::default();
+        ctx.cpu_mask = u64::MAX;
+        ctx
     }
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// With the "stack-paint" feature, the kernel stack is also painted by
+    /// [`paint_stack`] if [`kstack_size`] is set.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
         self.sp = kstack_top.as_usize() as u64;
         self.lr = entry as u64;
         self.tpidr_el0 = tls_area.as_usize() as u64;
+        #[cfg(feature = "stack-paint")]
+        {
+            self.kstack_top = kstack_top;
+            self.paint_stack();
+        }
+    }
+
+    /// Resets the context for a new program of the task (e.g., `execve`), with
+    /// the given entry point, kernel stack and TLS area.
+    ///
+    /// Unlike [`init`], all other states in the context are also reset to
+    /// those of [`new`], so that nothing of the previous program remains:
+    /// the callee-saved registers and the FP/SIMD, SVE, MTE and pointer
+    /// authentication states are cleared, and the user page table root is
+    /// reset. The CPU affinity mask and the allocated ASID are kept, and a new
+    /// ASID is allocated when a different page table root is set by
+    /// `set_page_table_root`.
+    ///
+    /// Only the saved states are reset. If it is called on the running task,
+    /// the FPU still holds the FP/SIMD states of the previous program: with
+    /// the "fp-lazy" feature, the ownership of the FPU is released and the
+    /// next FP/SIMD instruction loads the initial states; otherwise, the
+    /// caller must load them with `fp_state.restore()`.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`new`]: TaskContext::new
+    pub fn reset(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
+        let cpu_mask = self.cpu_mask;
+        #[cfg(feature = "uspace")]
+        let asid = *self.asid.get_mut();
+        *self = Self::new();
+        self.cpu_mask = cpu_mask;
+        #[cfg(feature = "uspace")]
+        {
+            *self.asid.get_mut() = asid;
+        }
+        self.init(entry, kstack_top, tls_area);
+        // Dropping the old states has released the ownership of the FPU.
+        #[cfg(feature = "fp-lazy")]
+        if super::fpu::CURRENT_CTX.read_current() == self as *mut Self as usize {
+            super::fpu::disable_el0_access();
+        }
     }
 
     /// Changes the page table root in this context.
     ///
     /// The hardware register for user page table root (`ttbr0_el1` for aarch64 in EL1)
-    /// will be updated to the next task's after [`Self::switch_to`].
+    /// will be updated to the next task's after [`Self::switch_to`]. If the
+    /// root is changed, a new ASID is also allocated then.
     #[cfg(feature = "uspace")]
     pub fn set_page_table_root(&mut self, ttbr0_el1: memory_addr::PhysAddr) {
+        if self.ttbr0_el1 != ttbr0_el1 {
+            super::asid::reset(self);
+        }
         self.ttbr0_el1 = ttbr0_el1;
     }
 
+    /// Unwind the kernel stack of the task and get the backtrace.
+    ///
+    /// It starts from the frame pointer (`x29`) and the link register (`x30`)
+    /// saved by [`switch_to`](Self::switch_to), so it is only meaningful when
+    /// the task is not running.
+    pub fn backtrace(&self) -> axbacktrace::Backtrace {
+        axbacktrace::Backtrace::capture_trap(self.r29 as _, self.lr as _, 0)
+    }
+
+    /// Places a canary at the bottom of the kernel stack, which is checked on
+    /// every [`switch_to`] from the task to detect kernel stack overflows.
+    ///
+    /// It must be called after [`init`] and before the task runs, with the
+    /// bottom (lowest address) of the kernel stack.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    #[cfg(feature = "stack-canary")]
+    pub fn init_stack_canary(&mut self, kstack_bottom: VirtAddr) {
+        const CANARY_MAGIC: u64 = 0xdead_beef_cafe_babe;
+        self.kstack_bottom = kstack_bottom;
+        // `sp` is still the kernel stack top before the task runs
+        self.stack_canary = CANARY_MAGIC ^ self.sp;
+        unsafe { (kstack_bottom.as_mut_ptr() as *mut u64).write_volatile(self.stack_canary) };
+    }
+
+    /// Checks whether the canary at the bottom of the kernel stack is intact.
+    ///
+    /// Always returns `true` if the canary is not set by
+    /// [`init_stack_canary`](TaskContext::init_stack_canary).
+    #[cfg(feature = "stack-canary")]
+    pub fn check_stack_canary(&self) -> bool {
+        if self.kstack_bottom.as_usize() == 0 {
+            return true;
+        }
+        let value = unsafe { (self.kstack_bottom.as_ptr() as *const u64).read_volatile() };
+        value == self.stack_canary
+    }
+
+    /// Fills the unused part of the kernel stack with [`STACK_PAINT_PATTERN`],
+    /// to find out the maximum stack usage later.
+    ///
+    /// It is called by [`init`] before the task runs, and does nothing if
+    /// [`kstack_size`] is 0. The paint is only reliable if the task is started
+    /// from [`init`], as a running task may have already used the stack.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn paint_stack(&self) {
+        let top = self.kstack_top.as_usize();
+        unsafe { crate::stack_paint::paint(top - self.kstack_size, top) };
+    }
+
+    /// Returns the lowest address of the kernel stack that has been written
+    /// since it was painted by [`paint_stack`], i.e., the deepest point the
+    /// stack has ever reached.
+    ///
+    /// Returns [`kstack_top`] if the stack is not painted.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_top`]: TaskContext::kstack_top
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_high_water(&self) -> VirtAddr {
+        self.first_unpainted(self.kstack_size)
+    }
+
+    /// Returns the worst-case kernel stack usage in bytes, by scanning the
+    /// painted kernel stack of `stack_size` bytes upwards from its bottom until
+    /// the first word that differs from [`STACK_PAINT_PATTERN`].
+    ///
+    /// The result is only meaningful if the stack is painted by
+    /// [`paint_stack`]. `stack_size` is limited to [`kstack_size`].
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_usage(&self, stack_size: usize) -> usize {
+        self.kstack_top.as_usize() - self.first_unpainted(stack_size).as_usize()
+    }
+
+    #[cfg(feature = "stack-paint")]
+    fn first_unpainted(&self, stack_size: usize) -> VirtAddr {
+        let top = self.kstack_top.as_usize();
+        let start = top - stack_size.min(self.kstack_size);
+        // Skip the canary at the bottom of the stack.
+        #[cfg(feature = "stack-canary")]
+        let start = if self.kstack_bottom.as_usize() == start {
+            start + 8
+        } else {
+            start
+        };
+        VirtAddr::from(unsafe { crate::stack_paint::first_unpainted(start, top) })
+    }
+
+    /// Enables SVE for the current task.
+    ///
+    /// It should be called when the task traps on its first SVE instruction
+    /// (`ESR_EL1.EC == 0x19`). After that, its SVE states are saved and
+    /// restored on context switches.
+    #[cfg(feature = "sve")]
+    pub fn enable_sve(&mut self) {
+        self.sve_state.activate();
+    }
+
+    /// Enables MTE for the task, with the initial MTE registers.
+    ///
+    /// After that, its MTE registers are saved and restored on context
+    /// switches. The CPU must implement `FEAT_MTE2`.
+    #[cfg(feature = "mte")]
+    pub fn enable_mte(&mut self, state: super::mte::MteState) {
+        self.mte_state = state;
+        self.mte_active = true;
+    }
+
+    /// Generates new pointer authentication keys for the task with the
+    /// entropy source.
+    ///
+    /// The keys take effect when the task is switched in. If this is the
+    /// context of the current task, call [`PacKeys::restore`] on [`pac_keys`]
+    /// to apply them immediately.
+    ///
+    /// [`PacKeys::restore`]: super::pac::PacKeys::restore
+    /// [`pac_keys`]: TaskContext::pac_keys
+    #[cfg(feature = "pac")]
+    pub fn randomize_pac_keys(&mut self, entropy: fn() -> u64) {
+        self.pac_keys = super::pac::PacKeys::random(entropy);
+    }
+
+    /// Returns the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N. It is not
+    /// enforced by [`switch_to`], but by the scheduler.
+    ///
+    /// [`switch_to`]: TaskContext::switch_to
+    pub const fn affinity(&self) -> u64 {
+        self.cpu_mask
+    }
+
+    /// Sets the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N.
+    pub const fn set_affinity(&mut self, mask: u64) {
+        self.cpu_mask = mask;
+    }
+
+    /// Checks whether the task is allowed to run on the given logical CPU.
+    ///
+    /// Always returns `false` for CPUs beyond the range of the mask.
+    pub const fn is_allowed_on(&self, cpu: usize) -> bool {
+        cpu < u64::BITS as usize && self.cpu_mask & (1 << cpu) != 0
+    }
+
     /// Switches to another task.
     ///
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
     pub fn switch_to(&mut self, next_ctx: &Self) {
+        #[cfg(feature = "stack-canary")]
+        if !self.check_stack_canary() {
+            panic!(
+                "Kernel stack overflow detected: kstack_bottom={:#x}",
+                self.kstack_bottom
+            );
+        }
+        {
+            // `MDSCR_EL1.SS` is set by `TrapFrame::enable_single_step` for the
+            // current task only
+            let mdscr = crate::asm::read_mdscr_el1();
+            self.single_step = mdscr & MDSCR_EL1_SS != 0;
+            if self.single_step != next_ctx.single_step {
+                let ss = if next_ctx.single_step {
+                    MDSCR_EL1_SS
+                } else {
+                    0
+                };
+                let mdscr = (mdscr & !MDSCR_EL1_SS) | ss;
+                unsafe { crate::asm::write_mdscr_el1(mdscr) };
+            }
+        }
         #[cfg(feature = "tls")]
         {
             self.tpidr_el0 = crate::asm::read_thread_pointer() as _;
             unsafe { crate::asm::write_thread_pointer(next_ctx.tpidr_el0 as _) };
         }
-        #[cfg(feature = "fp-simd")]
+        #[cfg(feature = "sve")]
+        if super::sve::access_enabled() {
+            // SVE has been used by the current task
+            self.sve_state.save();
+        }
+        #[cfg(all(feature = "fp-simd", not(feature = "fp-lazy")))]
         {
             self.fp_state.save();
             next_ctx.fp_state.restore();
         }
+        #[cfg(feature = "fp-lazy")]
+        {
+            use super::fpu;
+            // The FP/SIMD states are switched on the first FP/SIMD instruction
+            // of the next task, unless the FPU still holds its states.
+            let next_ptr = next_ctx as *const Self as usize;
+            fpu::CURRENT_CTX.write_current(next_ptr);
+            #[cfg(feature = "sve")]
+            let eager = next_ctx.sve_state.active;
+            #[cfg(not(feature = "sve"))]
+            let eager = false;
+            if eager {
+                // the SVE registers overlap the FP/SIMD ones, so they are
+                // switched eagerly
+                fpu::switch_owner(next_ctx);
+            } else if fpu::owner_slot().load(core::sync::atomic::Ordering::Acquire) == next_ptr {
+                fpu::enable_el0_access();
+            } else {
+                fpu::disable_el0_access();
+            }
+        }
+        #[cfg(feature = "sve")]
+        if next_ctx.sve_state.active {
+            // restored after FP/SIMD states, which zeroes the upper bits of `Z`
+            super::sve::enable_access();
+            next_ctx.sve_state.restore();
+        } else {
+            super::sve::disable_access();
+        }
+        #[cfg(feature = "mte")]
+        {
+            if self.mte_active {
+                self.mte_state.save();
+            }
+            if next_ctx.mte_active {
+                next_ctx.mte_state.restore();
+            } else if self.mte_active {
+                super::mte::MteState::clear_faults();
+            }
+        }
+        #[cfg(feature = "debug-regs")]
+        {
+            if self.debug_active {
+                self.debug_regs.save();
+            }
+            // clear the registers even if the current task does not use
+            // them, as they may have been set on the CPU directly
+            if next_ctx.debug_active {
+                next_ctx.debug_regs.restore();
+            } else {
+                super::debug::DebugRegs::disable_all();
+            }
+        }
+        #[cfg(feature = "pmu")]
+        {
+            if self.pmu_active {
+                self.pmu_state.save();
+            }
+            if next_ctx.pmu_active {
+                next_ctx.pmu_state.restore();
+            } else if self.pmu_active {
+                super::pmu::PmuState::disable_all();
+            }
+        }
+        // the key registers are only written from the task contexts, so the
+        // current keys need not be saved
+        #[cfg(feature = "pac")]
+        next_ctx.pac_keys.restore();
         #[cfg(feature = "uspace")]
-        if self.ttbr0_el1 != next_ctx.ttbr0_el1 {
-            unsafe { crate::asm::write_user_page_table(next_ctx.ttbr0_el1) };
-            crate::asm::flush_tlb(None); // currently flush the entire TLB
+        {
+            use super::asid;
+            let prev_asid = asid::current_asid(self);
+            let next_asid = asid::allocate(next_ctx);
+            if prev_asid.is_none() {
+                // The ASID of the current task has been reallocated to
+                // another context, so its TLB entries must not be left.
+                let stale = self.asid.load(core::sync::atomic::Ordering::Relaxed) as u16;
+                if stale != 0 {
+                    asid::flush_local(stale);
+                }
+            }
+            if self.ttbr0_el1 != next_ctx.ttbr0_el1 || prev_asid != Some(next_asid) {
+                unsafe { asid::write_user_page_table(next_ctx.ttbr0_el1, next_asid) };
+            }
         }
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch(self, next_ctx);
+        #[cfg(feature = "ctx-stats")]
+        crate::ctx_switch_stats::switch_begin();
         unsafe { context_switch(self, next_ctx) }
+        #[cfg(feature = "ctx-stats")]
+        crate::ctx_switch_stats::switch_end();
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch_back(self);
+    }
+}
+
+#[cfg(feature = "fp-lazy")]
+impl Drop for TaskContext {
+    fn drop(&mut self) {
+        super::fpu::release_owner(self);
     }
 }
 
@@ -252,6 +874,7 @@ impl TaskContext {
 unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task: &TaskContext) {
     naked_asm!(
         "
+        hint    #34     // bti c
         // save old context (callee-saved registers)
         stp     x29, x30, [x0, 11 * 8]
         stp     x27, x28, [x0, 9 * 8]
@@ -281,6 +904,7 @@ unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task:
 unsafe extern "C" fn fpstate_save(state: &mut FpState) {
     naked_asm!(
         ".arch armv8
+        hint    #34     // bti c
         // save fp/neon context
         mrs     x9, fpcr
         mrs     x10, fpsr
@@ -313,6 +937,7 @@ unsafe extern "C" fn fpstate_save(state: &mut FpState) {
 unsafe extern "C" fn fpstate_restore(state: &FpState) {
     naked_asm!(
         ".arch armv8
+        hint    #34     // bti c
         // restore fp/neon context
         ldp     q0, q1, [x0, 0 * 16]
         ldp     q2, q3, [x0, 2 * 16]
@@ -0,0 +1,104 @@
+//! CPU feature detection via the AArch64 ID registers.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+bitflags::bitflags! {
+    /// CPU features relevant to this crate, detected via the ID registers.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        /// Scalable Vector Extension (`ID_AA64PFR0_EL1.SVE`).
+        const SVE = 1 << 0;
+        /// Memory Tagging Extension with tag checks, i.e., `FEAT_MTE2`
+        /// (`ID_AA64PFR1_EL1.MTE >= 2`).
+        const MTE = 1 << 1;
+        /// Pointer authentication with the address keys
+        /// (`ID_AA64ISAR1_EL1.{APA, API}` or `ID_AA64ISAR2_EL1.APA3`).
+        const PAC = 1 << 2;
+        /// Branch Target Identification (`ID_AA64PFR1_EL1.BT`).
+        const BTI = 1 << 3;
+        /// Large System Extensions, i.e., atomic instructions
+        /// (`ID_AA64ISAR0_EL1.Atomic >= 2`).
+        const LSE = 1 << 4;
+        /// Random number instructions `RNDR`/`RNDRRS` (`ID_AA64ISAR0_EL1.RNDR`).
+        const RNG = 1 << 5;
+    }
+}
+
+/// Set in [`FEATURES_CACHE`] once the features are detected.
+const DETECTED: u64 = 1 << 63;
+
+/// The cached features detected by [`CpuFeatures::detect`].
+static FEATURES_CACHE: AtomicU64 = AtomicU64::new(0);
+
+/// Extracts the 4-bit ID register field at bit `shift`.
+const fn id_field(reg: u64, shift: u32) -> u64 {
+    (reg >> shift) & 0xf
+}
+
+impl CpuFeatures {
+    /// Detects the features of the current CPU via the ID registers, and
+    /// caches the result for [`CpuFeatures::current`].
+    pub fn detect() -> Self {
+        let (isar0, isar1, isar2, pfr1): (u64, u64, u64, u64);
+        unsafe {
+            asm!(
+                "mrs {isar0}, ID_AA64ISAR0_EL1",
+                "mrs {isar1}, ID_AA64ISAR1_EL1",
+                "mrs {isar2}, S3_0_C0_C6_2", // ID_AA64ISAR2_EL1
+                "mrs {pfr1}, ID_AA64PFR1_EL1",
+                isar0 = out(reg) isar0,
+                isar1 = out(reg) isar1,
+                isar2 = out(reg) isar2,
+                pfr1 = out(reg) pfr1,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        let pfr0 = crate::asm::read_id_aa64pfr0_el1();
+
+        let features = Self::decode(pfr0, pfr1, isar0, isar1, isar2);
+        FEATURES_CACHE.store(features.bits() | DETECTED, Ordering::Relaxed);
+        features
+    }
+
+    /// Decodes the features from the values of `ID_AA64PFR{0,1}_EL1` and
+    /// `ID_AA64ISAR{0,1,2}_EL1`.
+    fn decode(pfr0: u64, pfr1: u64, isar0: u64, isar1: u64, isar2: u64) -> Self {
+        let mut features = Self::empty();
+        features.set(Self::SVE, id_field(pfr0, 32) != 0);
+        features.set(Self::BTI, id_field(pfr1, 0) != 0);
+        features.set(Self::MTE, id_field(pfr1, 8) >= 2);
+        features.set(
+            Self::PAC,
+            id_field(isar1, 4) != 0 || id_field(isar1, 8) != 0 || id_field(isar2, 12) != 0,
+        );
+        features.set(Self::LSE, id_field(isar0, 20) >= 2);
+        features.set(Self::RNG, id_field(isar0, 60) != 0);
+        features
+    }
+
+    /// Returns the cached features, which are detected on the first call.
+    pub fn current() -> Self {
+        let cached = FEATURES_CACHE.load(Ordering::Relaxed);
+        if cached & DETECTED != 0 {
+            Self::from_bits_truncate(cached)
+        } else {
+            Self::detect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuFeatures;
+
+    #[test]
+    fn decode_id_fields() {
+        // SVE; BTI, MTE2; LSE (Atomic = 2), RNDR; APA
+        let features = CpuFeatures::decode(1 << 32, (2 << 8) | 1, (2 << 20) | (1 << 60), 1 << 4, 0);
+        assert_eq!(features, CpuFeatures::all());
+        // FEAT_MTE without tag checks, APA3 only
+        let features = CpuFeatures::decode(0, 1 << 8, 0, 0, 1 << 12);
+        assert_eq!(features, CpuFeatures::PAC);
+    }
+}
@@ -0,0 +1,107 @@
+//! Manipulation of the interrupt mask bits (`DAIF`) of the current CPU.
+//!
+//! A set bit masks the corresponding exceptions: `D` (debug, bit 9), `A`
+//! (SError, bit 8), `I` (IRQ, bit 7) and `F` (FIQ, bit 6).
+
+use core::arch::asm;
+
+/// The debug exception mask bit.
+pub const D: u64 = 1 << 9;
+/// The SError (asynchronous abort) mask bit.
+pub const A: u64 = 1 << 8;
+/// The IRQ mask bit.
+pub const I: u64 = 1 << 7;
+/// The FIQ mask bit.
+pub const F: u64 = 1 << 6;
+
+/// Reads the `DAIF` register.
+#[inline]
+pub fn read() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, daif", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+/// Writes the `DAIF` register.
+///
+/// Only the `D`, `A`, `I` and `F` bits are meaningful, the others are
+/// ignored.
+#[inline]
+pub fn write(value: u64) {
+    unsafe { asm!("msr daif, {}", in(reg) value, options(nostack)) };
+}
+
+/// Masks IRQs by setting the `I` bit.
+#[inline]
+pub fn disable_irq() {
+    unsafe { asm!("msr daifset, #2", options(nostack)) };
+}
+
+/// Unmasks IRQs by clearing the `I` bit.
+#[inline]
+pub fn enable_irq() {
+    unsafe { asm!("msr daifclr, #2", options(nostack)) };
+}
+
+/// Masks FIQs by setting the `F` bit.
+#[inline]
+pub fn disable_fiq() {
+    unsafe { asm!("msr daifset, #1", options(nostack)) };
+}
+
+/// Unmasks FIQs by clearing the `F` bit.
+#[inline]
+pub fn enable_fiq() {
+    unsafe { asm!("msr daifclr, #1", options(nostack)) };
+}
+
+/// Masks SErrors by setting the `A` bit.
+#[inline]
+pub fn disable_serror() {
+    unsafe { asm!("msr daifset, #4", options(nostack)) };
+}
+
+/// Unmasks SErrors by clearing the `A` bit.
+#[inline]
+pub fn enable_serror() {
+    unsafe { asm!("msr daifclr, #4", options(nostack)) };
+}
+
+/// Masks IRQs and returns the previous value of `DAIF`, which can be passed
+/// to [`restore`].
+#[inline]
+pub fn save_and_disable_irq() -> u64 {
+    let saved = read();
+    disable_irq();
+    saved
+}
+
+/// Restores `DAIF` to the value saved by [`save_and_disable_irq`].
+#[inline]
+pub fn restore(saved: u64) {
+    write(saved);
+}
+
+/// A guard that masks IRQs while it is alive, created by
+/// [`irq_disable_scope`].
+///
+/// On drop, `DAIF` is restored to the value before the guard was created, so
+/// IRQs are only unmasked if they were unmasked before. Guards can be nested.
+#[must_use = "IRQs are unmasked again once the guard is dropped"]
+pub struct DaifGuard {
+    saved: u64,
+}
+
+impl Drop for DaifGuard {
+    fn drop(&mut self) {
+        restore(self.saved);
+    }
+}
+
+/// Masks IRQs until the returned guard is dropped.
+#[inline]
+pub fn irq_disable_scope() -> DaifGuard {
+    DaifGuard {
+        saved: save_and_disable_irq(),
+    }
+}
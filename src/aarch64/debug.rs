@@ -0,0 +1,203 @@
+//! Hardware breakpoints and watchpoints with the debug registers
+//! (`DBGBVR<n>_EL1`/`DBGBCR<n>_EL1` and `DBGWVR<n>_EL1`/`DBGWCR<n>_EL1`).
+//!
+//! The debug registers are per-task states, switched by
+//! [`TaskContext::switch_to`] for the tasks with [`debug_active`] set.
+//! Breakpoint and watchpoint exceptions are routed to the [`DEBUG_HANDLER`]
+//! slice.
+//!
+//! Note that the debug exceptions are only generated when `MDSCR_EL1.MDE` (and
+//! `MDSCR_EL1.KDE` for EL1) is set, and the OS lock is cleared.
+//!
+//! [`TaskContext::switch_to`]: super::TaskContext::switch_to
+//! [`debug_active`]: super::TaskContext::debug_active
+
+use core::arch::asm;
+
+use super::{
+    asm::{read_mdscr_el1, write_mdscr_el1},
+    context::MDSCR_EL1_SS,
+    TrapFrame,
+};
+use crate::trap::def_trap_handler;
+
+/// The maximum number of breakpoint or watchpoint register pairs.
+pub const MAX_DEBUG_REGS: usize = 16;
+
+/// `MDSCR_EL1.KDE`: enables debug exceptions at EL1.
+pub(super) const MDSCR_KDE: u64 = 1 << 13;
+/// `MDSCR_EL1.MDE`: enables breakpoint and watchpoint exceptions.
+pub(super) const MDSCR_MDE: u64 = 1 << 15;
+
+/// `ESR_EL1.EC` of a breakpoint exception from a lower EL.
+#[cfg(feature = "uspace")]
+pub(super) const EC_BREAKPOINT_LOWER_EL: u64 = 0b11_0000;
+/// `ESR_EL1.EC` of a breakpoint exception from the current EL.
+pub(super) const EC_BREAKPOINT_CURRENT_EL: u64 = 0b11_0001;
+/// `ESR_EL1.EC` of a watchpoint exception from a lower EL.
+#[cfg(feature = "uspace")]
+pub(super) const EC_WATCHPOINT_LOWER_EL: u64 = 0b11_0100;
+/// `ESR_EL1.EC` of a watchpoint exception from the current EL.
+pub(super) const EC_WATCHPOINT_CURRENT_EL: u64 = 0b11_0101;
+
+macro_rules! dbg_reg_accessors {
+    ($read:ident, $write:ident, $reg:literal, [$($n:literal),*]) => {
+        pub(super) fn $read(n: usize) -> u64 {
+            let value: u64;
+            match n {
+                $($n => unsafe {
+                    asm!(
+                        concat!("mrs {}, ", $reg, stringify!($n), "_el1"),
+                        out(reg) value,
+                        options(nomem, nostack)
+                    )
+                },)*
+                _ => unreachable!(),
+            }
+            value
+        }
+
+        pub(super) unsafe fn $write(n: usize, value: u64) {
+            match n {
+                $($n => unsafe {
+                    asm!(
+                        concat!("msr ", $reg, stringify!($n), "_el1, {}"),
+                        in(reg) value,
+                        options(nostack)
+                    )
+                },)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+dbg_reg_accessors!(
+    read_dbgbvr,
+    write_dbgbvr,
+    "dbgbvr",
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+);
+dbg_reg_accessors!(
+    read_dbgbcr,
+    write_dbgbcr,
+    "dbgbcr",
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+);
+dbg_reg_accessors!(
+    read_dbgwvr,
+    write_dbgwvr,
+    "dbgwvr",
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+);
+dbg_reg_accessors!(
+    read_dbgwcr,
+    write_dbgwcr,
+    "dbgwcr",
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+);
+
+fn read_id_aa64dfr0() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, id_aa64dfr0_el1", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+/// Returns the number of breakpoint register pairs implemented by the CPU
+/// (`ID_AA64DFR0_EL1.BRPs + 1`).
+pub fn num_breakpoints() -> usize {
+    ((read_id_aa64dfr0() >> 12) & 0xf) as usize + 1
+}
+
+/// Returns the number of watchpoint register pairs implemented by the CPU
+/// (`ID_AA64DFR0_EL1.WRPs + 1`).
+pub fn num_watchpoints() -> usize {
+    ((read_id_aa64dfr0() >> 20) & 0xf) as usize + 1
+}
+
+/// Debug registers of a task.
+///
+/// Only the first [`num_breakpoints`] and [`num_watchpoints`] register pairs
+/// are saved and restored.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DebugRegs {
+    /// Monitor debug system control register.
+    pub mdscr_el1: u64,
+    /// Breakpoint value registers.
+    pub dbgbvr: [u64; MAX_DEBUG_REGS],
+    /// Breakpoint control registers.
+    pub dbgbcr: [u64; MAX_DEBUG_REGS],
+    /// Watchpoint value registers.
+    pub dbgwvr: [u64; MAX_DEBUG_REGS],
+    /// Watchpoint control registers.
+    pub dbgwcr: [u64; MAX_DEBUG_REGS],
+}
+
+impl DebugRegs {
+    /// Saves the debug registers from CPU to this structure.
+    pub fn save(&mut self) {
+        self.mdscr_el1 = read_mdscr_el1();
+        let bps = self.dbgbvr.iter_mut().zip(&mut self.dbgbcr);
+        for (i, (bvr, bcr)) in bps.enumerate().take(num_breakpoints()) {
+            *bvr = read_dbgbvr(i);
+            *bcr = read_dbgbcr(i);
+        }
+        let wps = self.dbgwvr.iter_mut().zip(&mut self.dbgwcr);
+        for (i, (wvr, wcr)) in wps.enumerate().take(num_watchpoints()) {
+            *wvr = read_dbgwvr(i);
+            *wcr = read_dbgwcr(i);
+        }
+    }
+
+    /// Restores the debug registers from this structure to CPU.
+    pub fn restore(&self) {
+        unsafe {
+            let bps = self.dbgbvr.iter().zip(&self.dbgbcr);
+            for (i, (&bvr, &bcr)) in bps.enumerate().take(num_breakpoints()) {
+                write_dbgbvr(i, bvr);
+                write_dbgbcr(i, bcr);
+            }
+            let wps = self.dbgwvr.iter().zip(&self.dbgwcr);
+            for (i, (&wvr, &wcr)) in wps.enumerate().take(num_watchpoints()) {
+                write_dbgwvr(i, wvr);
+                write_dbgwcr(i, wcr);
+            }
+            // `MDSCR_EL1.SS` is switched with `TaskContext::single_step`
+            let ss = read_mdscr_el1() & MDSCR_EL1_SS;
+            write_mdscr_el1((self.mdscr_el1 & !MDSCR_EL1_SS) | ss);
+            asm!("isb", options(nostack));
+        }
+    }
+
+    /// Disables all hardware breakpoints and watchpoints on the current CPU,
+    /// by clearing the control registers and `MDSCR_EL1.{MDE, KDE}`.
+    pub fn disable_all() {
+        unsafe {
+            for i in 0..num_breakpoints() {
+                write_dbgbcr(i, 0);
+            }
+            for i in 0..num_watchpoints() {
+                write_dbgwcr(i, 0);
+            }
+            write_mdscr_el1(read_mdscr_el1() & !(MDSCR_MDE | MDSCR_KDE));
+            asm!("isb", options(nostack));
+        }
+    }
+}
+
+/// A slice of hardware breakpoint and watchpoint exception handler functions.
+///
+/// The handlers are called in order with the trap frame and the syndrome
+/// (`ESR_EL1`) until one of them returns `true`. For watchpoints, the accessed
+/// address is in `FAR_EL1`.
+#[def_trap_handler]
+pub static DEBUG_HANDLER: [fn(&mut TrapFrame, u64) -> bool];
+
+/// Handles the breakpoint or watchpoint exception with the given `ESR_EL1`
+/// value.
+///
+/// It calls the registered [`DEBUG_HANDLER`]s until one of them returns
+/// `true`. Returns whether the exception is handled.
+pub(super) fn handle_debug(tf: &mut TrapFrame, esr: u64) -> bool {
+    DEBUG_HANDLER.iter().any(|handler| handler(tf, esr))
+}
@@ -0,0 +1,20 @@
+//! DWARF register numbers of AArch64, used by [`TrapFrame::get_reg`] and
+//! [`TrapFrame::set_reg`].
+//!
+//! `X0`..`X30` are numbered 0..30.
+//!
+//! [`TrapFrame::get_reg`]: crate::TrapFrame::get_reg
+//! [`TrapFrame::set_reg`]: crate::TrapFrame::set_reg
+
+/// The first general-purpose register (`X0`).
+pub const X0: u16 = 0;
+/// The frame pointer (`X29`).
+pub const FP: u16 = 29;
+/// The link register (`X30`).
+pub const LR: u16 = 30;
+/// The stack pointer.
+pub const SP: u16 = 31;
+/// The program counter, i.e., `ELR_EL1` in the trap frame.
+pub const PC: u16 = 32;
+/// The process state, i.e., `SPSR_EL1` in the trap frame.
+pub const PSTATE: u16 = 33;
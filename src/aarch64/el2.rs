@@ -0,0 +1,161 @@
+//! Helpers for kernels running at EL2, e.g., Type-1 hypervisors, or kernels
+//! that boot at EL2 and install a guest OS at EL1.
+//!
+//! Traps from EL1 are routed to EL2 by the bits of `HCR_EL2` (see
+//! [`trap_wfi`], [`trap_smc`] and [`trap_msr`]), and handled by the
+//! [`EL2_TRAP_HANDLER`] slice after the EL2 exception vector is installed by
+//! [`init_trap`].
+//!
+//! [`EL2_TRAP_HANDLER`]: crate::trap::EL2_TRAP_HANDLER
+
+use aarch64_cpu::{
+    asm::barrier,
+    registers::{ESR_EL2, FAR_EL2, HCR_EL2, VBAR_EL2},
+};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+
+use super::trap::TrapKind;
+use super::TrapFrame;
+
+/// `HCR_EL2.TWI`: traps `WFI` at EL0 and EL1 to EL2.
+const HCR_TWI: u64 = 1 << 13;
+/// `HCR_EL2.TSC`: traps `SMC` at EL1 to EL2.
+const HCR_TSC: u64 = 1 << 19;
+/// `HCR_EL2.TVM`: traps writes to the EL1 virtual memory control registers.
+const HCR_TVM: u64 = 1 << 26;
+/// `HCR_EL2.TRVM`: traps reads of the EL1 virtual memory control registers.
+const HCR_TRVM: u64 = 1 << 30;
+
+core::arch::global_asm!(
+    include_str!("el2.S"),
+    trapframe_size = const core::mem::size_of::<TrapFrame>(),
+    TRAP_KIND_SYNC = const TrapKind::Synchronous as u8,
+    TRAP_KIND_IRQ = const TrapKind::Irq as u8,
+    TRAP_KIND_FIQ = const TrapKind::Fiq as u8,
+    TRAP_KIND_SERROR = const TrapKind::SError as u8,
+);
+
+/// Reads the hypervisor configuration register (`HCR_EL2`).
+#[inline]
+pub fn read_hcr() -> u64 {
+    HCR_EL2.get()
+}
+
+/// Writes the hypervisor configuration register (`HCR_EL2`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the execution and trapping behavior
+/// of EL1 and EL0.
+#[inline]
+pub unsafe fn write_hcr(val: u64) {
+    HCR_EL2.set(val);
+    barrier::isb(barrier::SY);
+}
+
+unsafe fn set_hcr_bits(bits: u64, enable: bool) {
+    let hcr = read_hcr();
+    unsafe { write_hcr(if enable { hcr | bits } else { hcr & !bits }) };
+}
+
+/// Enables or disables trapping `WFI` at EL1 and EL0 to EL2 (`HCR_EL2.TWI`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the trapping behavior of EL1 and EL0.
+pub unsafe fn trap_wfi(enable: bool) {
+    unsafe { set_hcr_bits(HCR_TWI, enable) };
+}
+
+/// Enables or disables trapping `SMC` at EL1 to EL2 (`HCR_EL2.TSC`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the trapping behavior of EL1.
+pub unsafe fn trap_smc(enable: bool) {
+    unsafe { set_hcr_bits(HCR_TSC, enable) };
+}
+
+/// Enables or disables trapping `MSR`/`MRS` accesses to the EL1 virtual memory
+/// control registers (e.g., `SCTLR_EL1`, `TTBR0_EL1`, `TCR_EL1`) to EL2
+/// (`HCR_EL2.TVM` and `HCR_EL2.TRVM`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the trapping behavior of EL1.
+pub unsafe fn trap_msr(enable: bool) {
+    unsafe { set_hcr_bits(HCR_TVM | HCR_TRVM, enable) };
+}
+
+/// Installs the EL2 exception vector (`VBAR_EL2`) on the current CPU.
+///
+/// Synchronous exceptions from lower ELs are dispatched to the
+/// [`EL2_TRAP_HANDLER`] slice with the syndrome (`ESR_EL2`), and IRQs to the
+/// [`IRQ`] slice.
+///
+/// [`EL2_TRAP_HANDLER`]: crate::trap::EL2_TRAP_HANDLER
+/// [`IRQ`]: crate::trap::IRQ
+pub fn init_trap() {
+    unsafe extern "C" {
+        fn el2_exception_vector_base();
+    }
+    VBAR_EL2.set(el2_exception_vector_base as *const () as u64);
+    barrier::isb(barrier::SY);
+}
+
+/// Drops from EL2 to EL1 at `el1_entry` with the stack top `sp_el1`, and the
+/// address of the device tree blob `dtb` in `x0` (as the Linux boot protocol).
+///
+/// EL1 runs in AArch64 state, using `SP_EL1` with all exceptions masked. The
+/// EL2 states are set up as [`switch_to_el1`] does, except that the trap
+/// controls in `HCR_EL2` are kept.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the exception level. It must be
+/// called at EL2, `el1_entry` must be a valid EL1 entry point, and `sp_el1`
+/// must be the top of a valid stack for EL1.
+///
+/// [`switch_to_el1`]: super::init::switch_to_el1
+pub unsafe fn enter_el1(el1_entry: usize, sp_el1: usize, dtb: usize) -> ! {
+    // Set EL1 to 64bit, keeping the other trap controls.
+    HCR_EL2.modify(HCR_EL2::RW::EL1IsAarch64);
+    super::init::prepare_el2_eret(el1_entry as u64, sp_el1 as u64);
+    unsafe { core::arch::asm!("eret", in("x0") dtb, options(noreturn)) }
+}
+
+#[unsafe(no_mangle)]
+fn aarch64_el2_trap_handler(tf: &mut TrapFrame, kind: TrapKind, from_lower: bool) {
+    match kind {
+        TrapKind::Irq => {
+            handle_trap!(IRQ, 0);
+        }
+        // SErrors from all four vector groups
+        TrapKind::SError => super::trap::handle_serror_or_panic(tf, ESR_EL2.get()),
+        TrapKind::Synchronous if from_lower => {
+            let esr = ESR_EL2.get();
+            if !crate::trap::EL2_TRAP_HANDLER
+                .iter()
+                .any(|handler| handler(tf, esr))
+            {
+                panic!(
+                    "Unhandled EL2 trap from lower EL @ {:#x}: ESR_EL2={:#x}, FAR_EL2={:#x}:\n{}",
+                    tf.elr,
+                    esr,
+                    FAR_EL2.get(),
+                    crate::trap::TrapFrameDump(tf)
+                );
+            }
+        }
+        _ => {
+            panic!(
+                "Unhandled EL2 exception {:?} @ {:#x}: ESR_EL2={:#x}, FAR_EL2={:#x}:\n{}",
+                kind,
+                tf.elr,
+                ESR_EL2.get(),
+                FAR_EL2.get(),
+                crate::trap::TrapFrameDump(tf)
+            );
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Lazy FP/SIMD state switching.
+//!
+//! When the "fp-lazy" feature is enabled, [`TaskContext::switch_to`] does not
+//! restore the FP/SIMD states of the next task. Instead, the FP/SIMD accesses
+//! are trapped by clearing `CPACR_EL1.FPEN`, unless the FPU of the current CPU
+//! still holds the states of the next task. The first FP/SIMD instruction of
+//! the task then traps (`ESR_EL1.EC == 0x07`), and the states are switched by
+//! the trap handler. This mirrors the `CR0.TS` mechanism of x86_64.
+//!
+//! [`TaskContext::switch_to`]: crate::TaskContext::switch_to
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use aarch64_cpu::{asm::barrier, registers::CPACR_EL1};
+use tock_registers::interfaces::{ReadWriteable, Readable};
+
+use super::TaskContext;
+
+/// The context of the task whose FP/SIMD states are in the FPU of the current
+/// CPU, or 0 if there is none.
+///
+/// It may be cleared by another CPU when the owner is dropped there, and is
+/// [`FPU_OWNER_BUSY`] while the states are saved to the owner.
+#[percpu::def_percpu]
+pub(super) static FPU_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// The value of [`FPU_OWNER`] while its context is being accessed by the
+/// CPU that owns the slot, which must not be freed until then.
+const FPU_OWNER_BUSY: usize = 1;
+
+/// Returns the FPU owner slot of the current CPU.
+#[inline]
+pub(super) fn owner_slot() -> &'static AtomicUsize {
+    // SAFETY: the slot is only accessed atomically.
+    unsafe { FPU_OWNER.current_ref_raw() }
+}
+
+/// The context of the task running on the current CPU, set by
+/// [`TaskContext::switch_to`].
+#[percpu::def_percpu]
+pub(super) static CURRENT_CTX: usize = 0;
+
+/// Returns whether the FP/SIMD instructions are not trapped
+/// (`CPACR_EL1.FPEN`).
+#[inline]
+pub fn access_enabled() -> bool {
+    CPACR_EL1.matches_all(CPACR_EL1::FPEN::TrapNothing)
+}
+
+/// Allows the FP/SIMD instructions at EL0 and EL1 by setting
+/// `CPACR_EL1.FPEN` to `0b11`.
+#[inline]
+pub fn enable_el0_access() {
+    CPACR_EL1.modify(CPACR_EL1::FPEN::TrapNothing);
+    barrier::isb(barrier::SY);
+}
+
+/// Traps the FP/SIMD instructions by setting `CPACR_EL1.FPEN` to `0b00`.
+///
+/// Note that the FP/SIMD instructions at EL1 are trapped as well, so the
+/// kernel must not use them until [`enable_el0_access`] is called.
+#[inline]
+pub fn disable_el0_access() {
+    CPACR_EL1.modify(CPACR_EL1::FPEN::TrapEl0El1);
+    barrier::isb(barrier::SY);
+}
+
+/// Makes the task the owner of the FPU of the current CPU.
+///
+/// It enables the FP/SIMD instructions, saves the states of the previous
+/// owner, and restores the states of the task.
+pub(super) fn switch_owner(next: *const TaskContext) {
+    enable_el0_access();
+    let slot = owner_slot();
+    if slot.load(Ordering::Acquire) == next as usize {
+        return;
+    }
+    // Mark the slot busy, so that the owner is not freed by another CPU until
+    // its states are saved.
+    let owner = slot.swap(FPU_OWNER_BUSY, Ordering::Acquire) as *const TaskContext;
+    // SAFETY: the task contexts are valid as long as the tasks exist, and the
+    // owner is reset when its context is dropped. The lazily switched states
+    // are in `FpCell`s, which may be written through the shared references.
+    unsafe {
+        if let Some(owner) = owner.as_ref() {
+            (*owner.fp_state.get()).save();
+            *owner.fp_owner_flag.get() = false;
+        }
+        if let Some(next) = next.as_ref() {
+            next.fp_state.restore();
+            *next.fp_owner_flag.get() = true;
+            *next.fpu_owner_slot.get() = slot as *const AtomicUsize as usize;
+        }
+    }
+    slot.store(next as usize, Ordering::Release);
+}
+
+/// Handles the FP/SIMD access trap (`ESR_EL1.EC == 0x07`) caused by the first
+/// FP/SIMD instruction of a task after it is switched in.
+pub(super) fn handle_fp_access() {
+    switch_owner(CURRENT_CTX.read_current() as *const TaskContext);
+}
+
+/// Gives up the ownership of the FPU held by the task, without saving its
+/// states.
+///
+/// The owner slot of the CPU whose FPU holds the states is cleared, even if it
+/// is not the current CPU (e.g., the task is dropped on another CPU), so that
+/// it never points to a freed context.
+pub(super) fn release_owner(ctx: &mut TaskContext) {
+    if *ctx.fpu_owner_slot == 0 {
+        return;
+    }
+    // SAFETY: the per-CPU data areas are never freed, and the slot is only
+    // accessed atomically.
+    let slot = unsafe { &*(*ctx.fpu_owner_slot as *const AtomicUsize) };
+    let this = ctx as *mut TaskContext as usize;
+    // Wait until the owner CPU has saved the states to this context, if it is
+    // doing so.
+    while let Err(owner) = slot.compare_exchange(this, 0, Ordering::AcqRel, Ordering::Acquire) {
+        if owner != FPU_OWNER_BUSY {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    *ctx.fpu_owner_slot = 0;
+    *ctx.fp_owner_flag = false;
+}
@@ -0,0 +1,48 @@
+//! Power management helpers for idle CPUs.
+//!
+//! Unlike the plain [`wfi`](super::asm::wfi) and [`wfe`](super::asm::wfe)
+//! wrappers, these helpers also issue the barriers that the idle loops
+//! usually need.
+
+use aarch64_cpu::asm::barrier;
+
+/// Waits for an interrupt (`WFI`) after all previous memory accesses are
+/// completed.
+///
+/// The `DSB SY` before `WFI` ensures that all pending stores are visible to
+/// other observers before the CPU enters the low-power state.
+#[inline]
+pub fn wait_for_interrupt() {
+    barrier::dsb(barrier::SY);
+    aarch64_cpu::asm::wfi();
+}
+
+/// Waits for an event (`WFE`).
+///
+/// The CPU enters a low-power state until an event is signaled by
+/// [`send_event`] on another CPU, or an interrupt occurs.
+#[inline]
+pub fn wait_for_event() {
+    aarch64_cpu::asm::wfe();
+}
+
+/// Sends an event to all CPUs in the system (`SEV`), waking up those waiting
+/// in [`wait_for_event`].
+#[inline]
+pub fn send_event() {
+    barrier::dsb(barrier::ISH);
+    aarch64_cpu::asm::sev();
+}
+
+/// Waits for an interrupt or other wakeup event (`WFI`), with an `ISB`
+/// afterwards as a fence.
+///
+/// `WFI` may return on any wakeup event, so the caller must check for the
+/// condition it waits for. The `ISB` ensures that the instructions after the
+/// wakeup are not executed before it.
+#[inline]
+pub fn wait_for_interrupt_or_event() {
+    barrier::dsb(barrier::SY);
+    aarch64_cpu::asm::wfi();
+    barrier::isb(barrier::SY);
+}
@@ -32,25 +32,35 @@ pub unsafe fn switch_to_el1() {
             );
             ELR_EL3.set(LR.get());
         }
-        // Disable EL1 timer traps and the timer offset.
-        CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
-        CNTVOFF_EL2.set(0);
         // Set EL1 to 64bit.
         HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
-        // Set the return address and exception level.
-        SPSR_EL2.write(
-            SPSR_EL2::M::EL1h
-                + SPSR_EL2::D::Masked
-                + SPSR_EL2::A::Masked
-                + SPSR_EL2::I::Masked
-                + SPSR_EL2::F::Masked,
-        );
-        SP_EL1.set(SP.get());
-        ELR_EL2.set(LR.get());
+        prepare_el2_eret(LR.get(), SP.get());
         aarch64_cpu::asm::eret();
     }
 }
 
+/// Prepares the EL2 states for an `eret` to EL1, which returns to `elr` using
+/// `SP_EL1` = `sp`, with all exceptions masked.
+///
+/// It also disables EL1 timer traps and the timer offset. EL1 must be set to
+/// AArch64 state in `HCR_EL2` by the caller.
+#[inline(always)]
+pub(super) fn prepare_el2_eret(elr: u64, sp: u64) {
+    // Disable EL1 timer traps and the timer offset.
+    CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+    CNTVOFF_EL2.set(0);
+    // Set the return address and exception level.
+    SPSR_EL2.write(
+        SPSR_EL2::M::EL1h
+            + SPSR_EL2::D::Masked
+            + SPSR_EL2::A::Masked
+            + SPSR_EL2::I::Masked
+            + SPSR_EL2::F::Masked,
+    );
+    SP_EL1.set(sp);
+    ELR_EL2.set(elr);
+}
+
 /// Configures and enables the MMU on the current CPU.
 ///
 /// It first sets `MAIR_EL1`, `TCR_EL1`, `TTBR0_EL1`, `TTBR1_EL1` registers to
@@ -99,7 +109,8 @@ pub unsafe fn init_mmu(root_paddr: PhysAddr) {
 /// Initializes trap handling on the current CPU.
 ///
 /// In detail, it initializes the exception vector, and sets `TTBR0_EL1` to 0 to
-/// block low address access.
+/// block low address access. When the "pac" feature is enabled, the pointer
+/// authentication keys are also reset to those of a new task context.
 pub fn init_trap() {
     #[cfg(feature = "uspace")]
     crate::uspace_common::init_exception_table();
@@ -110,4 +121,28 @@ pub fn init_trap() {
         crate::asm::write_exception_vector_base(exception_vector_base as usize);
         crate::asm::write_user_page_table(0.into());
     }
+    #[cfg(feature = "pac")]
+    super::pac::PacKeys::default().restore();
+}
+
+/// Initializes a secondary CPU for SMP bringup, and returns the context of the
+/// boot task running on it.
+///
+/// It sets the per-CPU data register to `cpu_id`, installs the exception
+/// vector (see [`init_trap`]), and decodes the CPU topology. The per-CPU data
+/// areas must have been initialized by the bootstrap CPU, and the MMU of this
+/// CPU must have been enabled (see [`init_mmu`]).
+///
+/// The kernel stack of the boot task is the stack that the CPU is running on,
+/// so it is not recorded in the context. Other CPU features (e.g., FP/SIMD by
+/// [`enable_fp`], and the interrupt controller) must be enabled in the same way
+/// as on the bootstrap CPU.
+///
+/// [`enable_fp`]: crate::asm::enable_fp
+#[cfg(feature = "smp")]
+pub fn init_secondary(cpu_id: usize) -> crate::TaskContext {
+    percpu::init_percpu_reg(cpu_id);
+    init_trap();
+    super::topology::init();
+    crate::TaskContext::new()
 }
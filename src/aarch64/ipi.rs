@@ -0,0 +1,78 @@
+//! Inter-processor interrupts (IPIs) via GICv3 software generated interrupts
+//! (SGIs).
+//!
+//! The IPIs are sent as the SGI [`IPI_SGI`], and the kernel should call
+//! [`handle_ipi`] when it is acknowledged by the interrupt controller.
+//!
+//! The CPU IDs of [`IpiTarget::Cpu`] are the indices of the per-CPU data
+//! areas, which are translated to the affinities cached by
+//! [`topology::init`](super::topology::init), so it must have been called on
+//! the target CPUs.
+
+use core::arch::asm;
+
+pub use crate::ipi_common::{handle_ipi, send, IpiKind, IpiTarget};
+
+/// The interrupt ID of the SGI used for IPIs.
+pub const IPI_SGI: u8 = 1;
+
+/// `ICC_SGI1R_EL1.IRM`: routes the SGI to all CPUs excluding self.
+const SGI1R_IRM: u64 = 1 << 40;
+
+/// Writes `ICC_SGI1R_EL1` to generate the SGI.
+fn write_sgi1r(value: u64) {
+    unsafe { asm!("msr icc_sgi1r_el1, {}; isb", in(reg) value) };
+}
+
+/// Returns the `ICC_SGI1R_EL1` value targeting the CPU with the affinity
+/// fields of `MPIDR_EL1` (see [`CpuTopology::cpu_id`]).
+///
+/// `Aff0` is split into the range selector (`RS`, bits 47:44) and the bit in
+/// the target list (bits 15:0), as the target list covers 16 CPUs.
+///
+/// [`CpuTopology::cpu_id`]: super::topology::CpuTopology::cpu_id
+const fn sgi1r_of_affinity(affinity: u64) -> u64 {
+    let aff0 = affinity & 0xff;
+    let aff1 = (affinity >> 8) & 0xff;
+    let aff2 = (affinity >> 16) & 0xff;
+    let aff3 = (affinity >> 32) & 0xff;
+    ((IPI_SGI as u64) << 24)
+        | (aff3 << 48)
+        | ((aff0 / 16) << 44)
+        | (aff2 << 32)
+        | (aff1 << 16)
+        | (1 << (aff0 % 16))
+}
+
+/// Raises the IPI on the target CPUs by writing `ICC_SGI1R_EL1`.
+pub(crate) fn send_raw(target: IpiTarget) {
+    // make the posted requests visible before the SGI
+    unsafe { asm!("dsb ishst") };
+    match target {
+        IpiTarget::Cpu(cpu_id) => write_sgi1r(sgi1r_of_affinity(
+            super::topology::affinity_of(cpu_id) as u64,
+        )),
+        IpiTarget::AllExcludingSelf => write_sgi1r(((IPI_SGI as u64) << 24) | SGI1R_IRM),
+        IpiTarget::All => {
+            write_sgi1r(((IPI_SGI as u64) << 24) | SGI1R_IRM);
+            write_sgi1r(sgi1r_of_affinity(crate::asm::read_mpidr_el1()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sgi1r_of_affinity;
+
+    #[test]
+    fn sgi1r_affinity() {
+        // Aff1 = 2, Aff0 = 3
+        assert_eq!(sgi1r_of_affinity(0x0203), (1 << 24) | (2 << 16) | (1 << 3));
+        // Aff3 = 4, Aff2 = 1, Aff1 = 0, Aff0 = 0x13 (RS = 1, bit 3), with MT
+        // and RES1 bits ignored
+        assert_eq!(
+            sgi1r_of_affinity((4 << 32) | (1 << 31) | (1 << 24) | 0x01_00_13),
+            (4 << 48) | (1 << 44) | (1 << 32) | (1 << 24) | (1 << 3)
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! IRQ helpers for the GICv3 CPU interface.
+
+use core::arch::asm;
+
+/// Sends the End-Of-Interrupt (EOI) for the interrupt ID `intid` by writing
+/// `ICC_EOIR1_EL1`.
+///
+/// `intid` must be the interrupt ID acknowledged from `ICC_IAR1_EL1`. The
+/// IRQ dispatcher does not know it, so the IRQ handlers must send the EOI by
+/// themselves (see [`IrqResult`](crate::trap::IrqResult)).
+pub fn send_eoi(intid: u32) {
+    unsafe { asm!("msr icc_eoir1_el1, {}; isb", in(reg) intid as u64) };
+}
@@ -1,12 +1,52 @@
 mod context;
 
 pub mod asm;
+pub mod bti;
+pub mod cpu_features;
+pub mod daif;
+pub mod dwarf_reg;
+pub mod idle;
 pub mod init;
+pub mod irq;
+pub mod timer;
 
 #[cfg(target_os = "none")]
 mod trap;
 
+#[cfg(feature = "uspace")]
+pub mod asid;
+
+#[cfg(feature = "debug-regs")]
+pub mod debug;
+
+#[cfg(all(feature = "arm-el2", target_os = "none"))]
+pub mod el2;
+
+#[cfg(feature = "fp-lazy")]
+pub mod fpu;
+
+#[cfg(feature = "ipi")]
+pub mod ipi;
+
+#[cfg(feature = "mte")]
+pub mod mte;
+
+#[cfg(feature = "pac")]
+pub mod pac;
+
+#[cfg(feature = "pmu")]
+pub mod pmu;
+
+#[cfg(feature = "smp")]
+pub mod topology;
+
+#[cfg(feature = "sve")]
+pub mod sve;
+
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{FpState, TaskContext, TrapFrame};
+#[cfg(feature = "debug-regs")]
+pub mod watchpoint;
+
+pub use self::context::{CalleeSavedRegs, FpState, TaskContext, TrapFrame};
@@ -0,0 +1,71 @@
+//! Memory Tagging Extension (`FEAT_MTE2`) register context.
+//!
+//! The tag generation control and the accumulated asynchronous tag check
+//! faults are per-task states, switched by [`TaskContext::switch_to`] for the
+//! tasks with MTE enabled (see [`TaskContext::enable_mte`]).
+//!
+//! [`TaskContext::switch_to`]: super::TaskContext::switch_to
+//! [`TaskContext::enable_mte`]: super::TaskContext::enable_mte
+
+use core::arch::asm;
+
+/// MTE registers of a task.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MteState {
+    /// Tag Control Register (`GCR_EL1`), e.g., the tag exclusion mask.
+    pub gcr_el1: u64,
+    /// Tag Fault Status Register for EL1 (`TFSR_EL1`).
+    pub tfsr_el1: u64,
+    /// Tag Fault Status Register for EL0 (`TFSRE0_EL1`).
+    pub tfsre0_el1: u64,
+}
+
+impl MteState {
+    /// Saves the current MTE registers to this structure.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!(
+                // make the pending asynchronous tag check faults visible
+                "dsb nsh",
+                "isb",
+                "mrs {0}, S3_0_C1_C0_6", // GCR_EL1
+                "mrs {1}, S3_0_C5_C6_0", // TFSR_EL1
+                "mrs {2}, S3_0_C5_C6_1", // TFSRE0_EL1
+                out(reg) self.gcr_el1,
+                out(reg) self.tfsr_el1,
+                out(reg) self.tfsre0_el1,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Restores the MTE registers from this structure.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                "msr S3_0_C1_C0_6, {0}",
+                "msr S3_0_C5_C6_0, {1}",
+                "msr S3_0_C5_C6_1, {2}",
+                "isb",
+                in(reg) self.gcr_el1,
+                in(reg) self.tfsr_el1,
+                in(reg) self.tfsre0_el1,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Clears the accumulated tag check faults of the current CPU, so that
+    /// they are not reported to the next task that does not use MTE.
+    pub fn clear_faults() {
+        unsafe {
+            asm!(
+                "msr S3_0_C5_C6_0, xzr",
+                "msr S3_0_C5_C6_1, xzr",
+                "isb",
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Pointer authentication (`FEAT_PAuth`) key management.
+//!
+//! Each task has its own [`PacKeys`], restored on every context switch, so
+//! that the pointers signed by one task cannot be authenticated by another.
+//! The keys of each CPU are initialized by [`init_trap`] to those of a new
+//! task context.
+//!
+//! [`init_trap`]: crate::init::init_trap
+//!
+//! The kernel itself must not be built with pointer authentication, as the
+//! keys are changed on context switches (rather than on entry to and exit from
+//! user space).
+
+use core::arch::asm;
+
+/// Pointer authentication keys of a task.
+#[allow(missing_docs)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacKeys {
+    pub apiakey_lo: u64,
+    pub apiakey_hi: u64,
+    pub apibkey_lo: u64,
+    pub apibkey_hi: u64,
+    pub apdakey_lo: u64,
+    pub apdakey_hi: u64,
+    pub apdbkey_lo: u64,
+    pub apdbkey_hi: u64,
+    pub apgakey_lo: u64,
+    pub apgakey_hi: u64,
+}
+
+impl PacKeys {
+    /// Creates keys filled with the values from the entropy source.
+    pub fn random(entropy: fn() -> u64) -> Self {
+        Self {
+            apiakey_lo: entropy(),
+            apiakey_hi: entropy(),
+            apibkey_lo: entropy(),
+            apibkey_hi: entropy(),
+            apdakey_lo: entropy(),
+            apdakey_hi: entropy(),
+            apdbkey_lo: entropy(),
+            apdbkey_hi: entropy(),
+            apgakey_lo: entropy(),
+            apgakey_hi: entropy(),
+        }
+    }
+
+    /// Saves the current key registers to this structure.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!(
+                "mrs {0}, S3_0_C2_C1_0", // APIAKeyLo_EL1
+                "mrs {1}, S3_0_C2_C1_1", // APIAKeyHi_EL1
+                "mrs {2}, S3_0_C2_C1_2", // APIBKeyLo_EL1
+                "mrs {3}, S3_0_C2_C1_3", // APIBKeyHi_EL1
+                "mrs {4}, S3_0_C2_C2_0", // APDAKeyLo_EL1
+                "mrs {5}, S3_0_C2_C2_1", // APDAKeyHi_EL1
+                "mrs {6}, S3_0_C2_C2_2", // APDBKeyLo_EL1
+                "mrs {7}, S3_0_C2_C2_3", // APDBKeyHi_EL1
+                "mrs {8}, S3_0_C2_C3_0", // APGAKeyLo_EL1
+                "mrs {9}, S3_0_C2_C3_1", // APGAKeyHi_EL1
+                out(reg) self.apiakey_lo,
+                out(reg) self.apiakey_hi,
+                out(reg) self.apibkey_lo,
+                out(reg) self.apibkey_hi,
+                out(reg) self.apdakey_lo,
+                out(reg) self.apdakey_hi,
+                out(reg) self.apdbkey_lo,
+                out(reg) self.apdbkey_hi,
+                out(reg) self.apgakey_lo,
+                out(reg) self.apgakey_hi,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Restores the key registers from this structure.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                "msr S3_0_C2_C1_0, {0}",
+                "msr S3_0_C2_C1_1, {1}",
+                "msr S3_0_C2_C1_2, {2}",
+                "msr S3_0_C2_C1_3, {3}",
+                "msr S3_0_C2_C2_0, {4}",
+                "msr S3_0_C2_C2_1, {5}",
+                "msr S3_0_C2_C2_2, {6}",
+                "msr S3_0_C2_C2_3, {7}",
+                "msr S3_0_C2_C3_0, {8}",
+                "msr S3_0_C2_C3_1, {9}",
+                "isb",
+                in(reg) self.apiakey_lo,
+                in(reg) self.apiakey_hi,
+                in(reg) self.apibkey_lo,
+                in(reg) self.apibkey_hi,
+                in(reg) self.apdakey_lo,
+                in(reg) self.apdakey_hi,
+                in(reg) self.apdbkey_lo,
+                in(reg) self.apdbkey_hi,
+                in(reg) self.apgakey_lo,
+                in(reg) self.apgakey_hi,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+}
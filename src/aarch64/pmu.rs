@@ -0,0 +1,154 @@
+//! Performance Monitors Extension (PMU) register context.
+//!
+//! The counters and their configuration are per-task states, switched by
+//! [`TaskContext::switch_to`] for the tasks with [`pmu_active`] set, so that
+//! the counter values of different tasks do not bleed into each other.
+//!
+//! [`TaskContext::switch_to`]: super::TaskContext::switch_to
+//! [`pmu_active`]: super::TaskContext::pmu_active
+
+use core::arch::asm;
+
+/// The maximum number of event counters.
+pub const MAX_EVENT_COUNTERS: usize = 31;
+
+/// `PMCR_EL0.E`: enables all counters.
+const PMCR_E: u64 = 1 << 0;
+/// The bit of the cycle counter in `PMCNTENSET_EL0` and `PMINTENSET_EL1`.
+const PMCNTEN_C: u64 = 1 << 31;
+
+macro_rules! pmu_reg {
+    (read $reg:expr) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack)) };
+        value
+    }};
+    (write $reg:expr, $value:expr) => {
+        unsafe { asm!(concat!("msr ", $reg, ", {}"), in(reg) $value, options(nostack)) }
+    };
+}
+
+macro_rules! pmu_evt_accessors {
+    ($read:ident, $write:ident, $reg:literal, [$($n:literal),*]) => {
+        fn $read(n: usize) -> u64 {
+            match n {
+                $($n => pmu_reg!(read concat!($reg, stringify!($n), "_el0")),)*
+                _ => unreachable!(),
+            }
+        }
+
+        fn $write(n: usize, value: u64) {
+            match n {
+                $($n => pmu_reg!(write concat!($reg, stringify!($n), "_el0"), value),)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+pmu_evt_accessors!(
+    read_pmevcntr,
+    write_pmevcntr,
+    "pmevcntr",
+    [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30
+    ]
+);
+pmu_evt_accessors!(
+    read_pmevtyper,
+    write_pmevtyper,
+    "pmevtyper",
+    [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30
+    ]
+);
+
+/// Returns the number of event counters implemented by the CPU
+/// (`PMCR_EL0.N`).
+pub fn num_event_counters() -> usize {
+    let pmcr: u64 = pmu_reg!(read "pmcr_el0");
+    (((pmcr >> 11) & 0x1f) as usize).min(MAX_EVENT_COUNTERS)
+}
+
+/// PMU registers of a task.
+///
+/// Only the first [`num_event_counters`] event counters are saved and
+/// restored.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PmuState {
+    /// Performance monitors control register (`PMCR_EL0`).
+    pub pmcr: u64,
+    /// Enabled counters (`PMCNTENSET_EL0`).
+    pub pmcntenset: u64,
+    /// Counters with overflow interrupts enabled (`PMINTENSET_EL1`).
+    pub pmintenset: u64,
+    /// Counters that have overflowed (`PMOVSSET_EL0`).
+    pub pmovsset: u64,
+    /// Cycle counter (`PMCCNTR_EL0`).
+    pub pmccntr: u64,
+    /// Event counters (`PMEVCNTR<n>_EL0`).
+    pub pmevcntr: [u64; MAX_EVENT_COUNTERS],
+    /// Event type registers (`PMEVTYPER<n>_EL0`).
+    pub pmevtyper: [u64; MAX_EVENT_COUNTERS],
+}
+
+impl PmuState {
+    /// Saves the PMU registers from CPU to this structure.
+    pub fn save(&mut self) {
+        self.pmcntenset = pmu_reg!(read "pmcntenset_el0");
+        self.pmintenset = pmu_reg!(read "pmintenset_el1");
+        self.pmovsset = pmu_reg!(read "pmovsset_el0");
+        self.pmcr = pmu_reg!(read "pmcr_el0");
+        self.pmccntr = pmu_reg!(read "pmccntr_el0");
+        let counters = self.pmevcntr.iter_mut().zip(&mut self.pmevtyper);
+        for (i, (cntr, typer)) in counters.enumerate().take(num_event_counters()) {
+            *cntr = read_pmevcntr(i);
+            *typer = read_pmevtyper(i);
+        }
+    }
+
+    /// Restores the PMU registers from this structure to CPU.
+    ///
+    /// The counters are stopped while they are written, and those enabled in
+    /// this structure are started at last.
+    pub fn restore(&self) {
+        Self::disable_all();
+        pmu_reg!(write "pmintenclr_el1", u64::MAX);
+        pmu_reg!(write "pmccntr_el0", self.pmccntr);
+        let counters = self.pmevcntr.iter().zip(&self.pmevtyper);
+        for (i, (&cntr, &typer)) in counters.enumerate().take(num_event_counters()) {
+            write_pmevtyper(i, typer);
+            write_pmevcntr(i, cntr);
+        }
+        pmu_reg!(write "pmcr_el0", self.pmcr);
+        pmu_reg!(write "pmovsset_el0", self.pmovsset);
+        pmu_reg!(write "pmintenset_el1", self.pmintenset);
+        pmu_reg!(write "pmcntenset_el0", self.pmcntenset);
+        unsafe { asm!("isb", options(nostack)) };
+    }
+
+    /// Stops all counters on the current CPU (`PMCNTENCLR_EL0`), and clears
+    /// their overflow flags (`PMOVSCLR_EL0`).
+    pub fn disable_all() {
+        pmu_reg!(write "pmcntenclr_el0", u64::MAX);
+        pmu_reg!(write "pmovsclr_el0", u64::MAX);
+        unsafe { asm!("isb", options(nostack)) };
+    }
+
+    /// Enables the cycle counter on the current CPU, by setting `PMCR_EL0.E`
+    /// and the cycle counter bit of `PMCNTENSET_EL0`.
+    pub fn enable_cycle_counter() {
+        let pmcr: u64 = pmu_reg!(read "pmcr_el0");
+        pmu_reg!(write "pmcr_el0", pmcr | PMCR_E);
+        pmu_reg!(write "pmcntenset_el0", PMCNTEN_C);
+        unsafe { asm!("isb", options(nostack)) };
+    }
+
+    /// Reads the cycle counter of the current CPU (`PMCCNTR_EL0`).
+    #[inline]
+    pub fn read_cycle_counter() -> u64 {
+        pmu_reg!(read "pmccntr_el0")
+    }
+}
@@ -0,0 +1,190 @@
+//! Scalable Vector Extension (SVE) support.
+//!
+//! SVE states are switched lazily: the access to SVE is trapped (via
+//! `CPACR_EL1.ZEN`) for tasks that have never used SVE, and the kernel should
+//! call [`TaskContext::enable_sve`] on the first SVE access trap
+//! (`ESR_EL1.EC == 0x19`) of such a task.
+//!
+//! [`TaskContext::enable_sve`]: crate::TaskContext::enable_sve
+
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use aarch64_cpu::{asm::barrier, registers::*};
+
+/// The maximum vector length in bytes supported by [`SveState`] (512 bits).
+///
+/// Longer hardware vector lengths are constrained to it by `ZCR_EL1.LEN`.
+pub const SVE_VL_MAX: usize = 64;
+
+/// `CPACR_EL1.ZEN`: traps SVE instructions at EL0 and EL1 unless set to 0b11.
+const CPACR_EL1_ZEN_MASK: u64 = 0b11 << 16;
+
+/// The vector length in bytes (`VL`), set by [`init`].
+static SVE_VL: AtomicUsize = AtomicUsize::new(0);
+
+/// Initializes SVE on the current CPU.
+///
+/// It constrains the vector length to [`SVE_VL_MAX`] via `ZCR_EL1.LEN`,
+/// records the effective vector length, and then disables the access to SVE
+/// so that it is trapped until [`enable_access`] is called.
+///
+/// It should be called on all CPUs before any task is created.
+pub fn init() {
+    enable_access();
+    let vl: usize;
+    unsafe {
+        core::arch::asm!(
+            ".arch_extension sve",
+            "msr S3_0_C1_C2_0, {len}", // ZCR_EL1
+            "isb",
+            "rdvl {vl}, #1",
+            len = in(reg) (SVE_VL_MAX / 16 - 1) as u64,
+            vl = out(reg) vl,
+        )
+    };
+    SVE_VL.store(vl, Ordering::Relaxed);
+    disable_access();
+}
+
+/// Returns the effective SVE vector length in bytes, or 0 if [`init`] has
+/// not been called.
+pub fn vector_length() -> usize {
+    SVE_VL.load(Ordering::Relaxed)
+}
+
+/// Returns whether the SVE instructions are not trapped (`CPACR_EL1.ZEN`).
+#[inline]
+pub fn access_enabled() -> bool {
+    CPACR_EL1.get() & CPACR_EL1_ZEN_MASK == CPACR_EL1_ZEN_MASK
+}
+
+/// Allows the SVE instructions at EL0 and EL1 by setting `CPACR_EL1.ZEN`.
+#[inline]
+pub fn enable_access() {
+    CPACR_EL1.set(CPACR_EL1.get() | CPACR_EL1_ZEN_MASK);
+    barrier::isb(barrier::SY);
+}
+
+/// Traps the SVE instructions at EL0 and EL1 by clearing `CPACR_EL1.ZEN`.
+#[inline]
+pub fn disable_access() {
+    CPACR_EL1.set(CPACR_EL1.get() & !CPACR_EL1_ZEN_MASK);
+    barrier::isb(barrier::SY);
+}
+
+/// SVE registers: `Z0..Z31`, `P0..P15` and `FFR`.
+///
+/// Each register is stored with the effective vector length (see
+/// [`vector_length`]), so only the first part of each array is used if the
+/// vector length is shorter than [`SVE_VL_MAX`].
+#[repr(C, align(16))]
+#[derive(Debug)]
+pub struct SveState {
+    /// Scalable vector registers (`Z0..Z31`), each of `VL` bytes.
+    pub z: [u8; 32 * SVE_VL_MAX],
+    /// Predicate registers (`P0..P15`), each of `VL / 8` bytes.
+    pub p: [u8; 16 * SVE_VL_MAX / 8],
+    /// First Fault Register (`FFR`), of `VL / 8` bytes.
+    pub ffr: [u8; SVE_VL_MAX / 8],
+    /// Whether the task has used SVE, i.e., the saved states are valid.
+    pub active: bool,
+}
+
+impl Default for SveState {
+    fn default() -> Self {
+        Self {
+            z: [0; 32 * SVE_VL_MAX],
+            p: [0; 16 * SVE_VL_MAX / 8],
+            ffr: [0; SVE_VL_MAX / 8],
+            active: false,
+        }
+    }
+}
+
+impl SveState {
+    /// Marks the SVE states as used by the current task, and allows the access
+    /// to SVE.
+    ///
+    /// The `Z` registers keep the values of the FP/SIMD registers (their upper
+    /// bits are zeroed when the FP/SIMD states are restored), and the predicate
+    /// registers are cleared.
+    pub fn activate(&mut self) {
+        enable_access();
+        clear_predicates();
+        self.active = true;
+    }
+
+    /// Saves the current SVE states from CPU to this structure.
+    ///
+    /// The access to SVE must be enabled.
+    pub fn save(&mut self) {
+        unsafe { sve_save(self) }
+    }
+
+    /// Restores the SVE states from this structure to CPU.
+    ///
+    /// The access to SVE must be enabled.
+    pub fn restore(&self) {
+        unsafe { sve_restore(self) }
+    }
+}
+
+/// Clears the predicate registers and sets all bits of `FFR`, so that no
+/// states of other tasks are leaked.
+fn clear_predicates() {
+    unsafe {
+        core::arch::asm!(
+            ".arch_extension sve",
+            ".irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15",
+            "pfalse p\\i.b",
+            ".endr",
+            "setffr",
+        )
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn sve_save(state: &mut SveState) {
+    naked_asm!(
+        ".arch_extension sve
+        hint    #34     // bti c
+        .irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+        str     z\\i, [x0, #\\i, mul vl]
+        .endr
+        add     x1, x0, {p_offset}
+        .irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15
+        str     p\\i, [x1, #\\i, mul vl]
+        .endr
+        // FFR is saved via P0, which has been saved above
+        add     x2, x0, {ffr_offset}
+        rdffr   p0.b
+        str     p0, [x2]
+        ldr     p0, [x1]
+        ret",
+        p_offset = const core::mem::offset_of!(SveState, p),
+        ffr_offset = const core::mem::offset_of!(SveState, ffr),
+    )
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn sve_restore(state: &SveState) {
+    naked_asm!(
+        ".arch_extension sve
+        hint    #34     // bti c
+        .irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+        ldr     z\\i, [x0, #\\i, mul vl]
+        .endr
+        // FFR is restored via P0, which will be restored below
+        add     x1, x0, {ffr_offset}
+        ldr     p0, [x1]
+        wrffr   p0.b
+        add     x1, x0, {p_offset}
+        .irp i, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15
+        ldr     p\\i, [x1, #\\i, mul vl]
+        .endr
+        ret",
+        p_offset = const core::mem::offset_of!(SveState, p),
+        ffr_offset = const core::mem::offset_of!(SveState, ffr),
+    )
+}
@@ -0,0 +1,63 @@
+//! Generic timer of the current CPU.
+//!
+//! The EL1 physical timer fires when the physical count (`CNTPCT_EL0`) reaches
+//! the compare value set by [`set_cval`]. The virtual count read by
+//! [`counter`] is the physical count minus the virtual offset
+//! (`CNTVOFF_EL2`), which is 0 unless a hypervisor sets it, so the two counts
+//! are interchangeable when the kernel runs without one (or at EL2).
+//!
+//! Both counts increase at the [`frequency`] of the system counter, so a
+//! deadline `d` ticks from now is `counter() + d`. The minimum programmable
+//! interval is one tick; a compare value that is not greater than the current
+//! count fires the timer immediately.
+
+use aarch64_cpu::registers::{
+    Readable, Writeable, CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0, CNTVCT_EL0,
+};
+
+/// Reads the cycle counter of the current CPU, i.e., the virtual count of the
+/// generic timer (`CNTVCT_EL0`) on AArch64.
+#[inline]
+pub fn cycles() -> u64 {
+    counter()
+}
+
+/// Reads the virtual count of the generic timer (`CNTVCT_EL0`).
+#[inline]
+pub fn counter() -> u64 {
+    CNTVCT_EL0.get()
+}
+
+/// Returns the frequency of the system counter in Hz (`CNTFRQ_EL0`).
+#[inline]
+pub fn frequency() -> u32 {
+    CNTFRQ_EL0.get() as u32
+}
+
+/// Sets the compare value of the EL1 physical timer (`CNTP_CVAL_EL0`).
+///
+/// The timer condition is met when the physical count reaches `val`.
+#[inline]
+pub fn set_cval(val: u64) {
+    CNTP_CVAL_EL0.set(val);
+}
+
+/// Enables the EL1 physical timer, with its interrupt unmasked
+/// (`CNTP_CTL_EL0.ENABLE = 1`, `CNTP_CTL_EL0.IMASK = 0`).
+#[inline]
+pub fn enable() {
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
+/// Disables the EL1 physical timer (`CNTP_CTL_EL0.ENABLE = 0`).
+#[inline]
+pub fn disable() {
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR);
+}
+
+/// Returns whether the condition of the EL1 physical timer is met
+/// (`CNTP_CTL_EL0.ISTATUS`).
+#[inline]
+pub fn is_expired() -> bool {
+    CNTP_CTL_EL0.is_set(CNTP_CTL_EL0::ISTATUS)
+}
@@ -0,0 +1,131 @@
+//! CPU topology (affinity, core and cluster) of the current CPU.
+//!
+//! The topology is decoded from `MPIDR_EL1` and cached in per-CPU data by
+//! [`init`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// `MPIDR_EL1.MT`: the lowest affinity level consists of logical processors
+/// that are implemented using multithreading.
+const MPIDR_MT: u64 = 1 << 24;
+/// The affinity fields of `MPIDR_EL1`: `Aff3` (bits 39:32) and `Aff2`-`Aff0`
+/// (bits 23:0).
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// The topology of a CPU, decoded from its `MPIDR_EL1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// The affinity of the processing element, i.e., the affinity fields of
+    /// `MPIDR_EL1` (`Aff3` in bits 39:32, `Aff2`-`Aff0` in bits 23:0).
+    pub cpu_id: usize,
+    /// The ID of the core in the cluster.
+    pub core_id: usize,
+    /// The ID of the cluster (socket).
+    pub socket_id: usize,
+}
+
+/// The value of [`CPU_ID`] before [`init`] is called.
+const CPU_ID_UNKNOWN: usize = usize::MAX;
+
+/// The affinity of each CPU, which is also read by other CPUs to send IPIs.
+#[percpu::def_percpu]
+static CPU_ID: AtomicUsize = AtomicUsize::new(CPU_ID_UNKNOWN);
+
+#[percpu::def_percpu]
+static CORE_ID: usize = 0;
+
+#[percpu::def_percpu]
+static SOCKET_ID: usize = 0;
+
+impl CpuTopology {
+    /// Decodes the topology of the current CPU from `MPIDR_EL1`.
+    ///
+    /// If `MPIDR_EL1.MT` is set, affinity level 0 identifies the thread,
+    /// level 1 the core, and level 2 the cluster. Otherwise, level 0
+    /// identifies the core, and level 1 the cluster.
+    pub fn detect() -> Self {
+        Self::decode(crate::asm::read_mpidr_el1())
+    }
+
+    /// Decodes the topology from the value of `MPIDR_EL1`.
+    fn decode(mpidr: u64) -> Self {
+        let aff = |level: u32| ((mpidr >> (level * 8)) & 0xff) as usize;
+        let (core_id, socket_id) = if mpidr & MPIDR_MT != 0 {
+            (aff(1), aff(2))
+        } else {
+            (aff(0), aff(1))
+        };
+        Self {
+            cpu_id: (mpidr & MPIDR_AFFINITY_MASK) as usize,
+            core_id,
+            socket_id,
+        }
+    }
+}
+
+/// Decodes the topology of the current CPU, and caches it in per-CPU data.
+///
+/// It should be called on each CPU after the per-CPU data is initialized, and
+/// before the query functions of this module are used.
+pub fn init() {
+    let topo = CpuTopology::detect();
+    CPU_ID.with_current(|id| id.store(topo.cpu_id, Ordering::Release));
+    CORE_ID.write_current(topo.core_id);
+    SOCKET_ID.write_current(topo.socket_id);
+}
+
+/// Returns the affinity (see [`CpuTopology::cpu_id`]) of the current CPU,
+/// cached by [`init`].
+pub fn cpu_id() -> usize {
+    CPU_ID.with_current(|id| id.load(Ordering::Relaxed))
+}
+
+/// Returns the affinity (see [`CpuTopology::cpu_id`]) of the CPU with the
+/// given index (i.e., the index of its per-CPU data area), cached by [`init`]
+/// on that CPU.
+///
+/// # Panics
+///
+/// Panics if [`init`] has not been called on that CPU.
+pub fn affinity_of(cpu_index: usize) -> usize {
+    // SAFETY: the per-CPU data of other CPUs are only accessed atomically.
+    let affinity = unsafe { CPU_ID.remote_ref_raw(cpu_index) }.load(Ordering::Acquire);
+    assert_ne!(
+        affinity, CPU_ID_UNKNOWN,
+        "topology of CPU {cpu_index} is not initialized"
+    );
+    affinity
+}
+
+/// Returns the ID of the core of the current CPU in its cluster, cached by
+/// [`init`].
+pub fn core_id() -> usize {
+    CORE_ID.read_current()
+}
+
+/// Returns the ID of the cluster (socket) of the current CPU, cached by
+/// [`init`].
+pub fn socket_id() -> usize {
+    SOCKET_ID.read_current()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuTopology;
+
+    #[test]
+    fn decode_mpidr() {
+        // RES1 (bit 31), Aff2 = 1, Aff1 = 2, Aff0 = 3, without MT
+        let topo = CpuTopology::decode((1 << 31) | 0x01_02_03);
+        assert_eq!(
+            (topo.cpu_id, topo.core_id, topo.socket_id),
+            (0x01_02_03, 3, 2)
+        );
+        // with MT, and Aff3 = 4
+        let topo = CpuTopology::decode((4 << 32) | (1 << 31) | (1 << 24) | 0x01_02_03);
+        assert_eq!(
+            (topo.cpu_id, topo.core_id, topo.socket_id),
+            (0x04_0001_0203, 2, 1)
+        );
+    }
+}
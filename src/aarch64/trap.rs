@@ -1,4 +1,4 @@
-use aarch64_cpu::registers::{ESR_EL1, FAR_EL1};
+use aarch64_cpu::registers::ESR_EL1;
 use tock_registers::interfaces::Readable;
 
 use super::TrapFrame;
@@ -35,6 +35,15 @@ core::arch::global_asm!(
     TRAP_SRC_LOWER_AARCH32 = const TrapSource::LowerAArch32 as u8,
 );
 
+/// Exception class of the software step exception taken without a change in
+/// exception level.
+const EC_SOFTWARE_STEP_CURRENT_EL: u64 = 0b11_0011;
+
+/// Exception class of the software step exception taken from a lower exception
+/// level.
+#[cfg(feature = "uspace")]
+pub(super) const EC_SOFTWARE_STEP_LOWER_EL: u64 = 0b11_0010;
+
 #[inline(always)]
 pub(super) fn is_valid_page_fault(iss: u64) -> bool {
     // Only handle Translation fault and Permission fault
@@ -42,7 +51,7 @@ pub(super) fn is_valid_page_fault(iss: u64) -> bool {
 }
 
 fn handle_page_fault(tf: &mut TrapFrame, access_flags: PageFaultFlags) {
-    let vaddr = va!(FAR_EL1.get() as usize);
+    let vaddr = super::asm::read_far_el1();
     if handle_trap!(PAGE_FAULT, vaddr, access_flags) {
         return;
     }
@@ -52,16 +61,45 @@ fn handle_page_fault(tf: &mut TrapFrame, access_flags: PageFaultFlags) {
     }
     core::hint::cold_path();
     panic!(
-        "Unhandled EL1 Page Fault @ {:#x}, fault_vaddr={:#x}, ESR={:#x} ({:?}):\n{:#x?}\n{}",
+        "Unhandled EL1 Page Fault @ {:#x}, fault_vaddr={:#x}, ESR={:#x} ({:?}):\n{}\n{}",
         tf.elr,
         vaddr,
-        ESR_EL1.get(),
+        super::asm::read_esr_el1(),
         access_flags,
-        tf,
+        crate::trap::TrapFrameDump(tf),
+        tf.backtrace()
+    );
+}
+
+/// Dispatches an SError to [`SERROR_HANDLER`](crate::trap::SERROR_HANDLER),
+/// returns whether it is handled.
+pub(super) fn handle_serror(tf: &TrapFrame, esr: u64) -> bool {
+    crate::trap::SERROR_HANDLER
+        .iter()
+        .any(|handler| handler(tf, esr))
+}
+
+/// Dispatches an SError with the syndrome `esr` by [`handle_serror`], and
+/// panics if it is not handled.
+pub(super) fn handle_serror_or_panic(tf: &TrapFrame, esr: u64) {
+    if handle_serror(tf, esr) {
+        return;
+    }
+    core::hint::cold_path();
+    panic!(
+        "Unhandled SError @ {:#x}: ESR={:#x}\n{}\n{}",
+        tf.elr,
+        esr,
+        crate::trap::TrapFrameDump(tf),
         tf.backtrace()
     );
 }
 
+#[unsafe(no_mangle)]
+fn aarch64_serror_handler(tf: &mut TrapFrame) {
+    handle_serror_or_panic(tf, super::asm::read_esr_el1());
+}
+
 #[unsafe(no_mangle)]
 fn aarch64_trap_handler(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) {
     if matches!(
@@ -69,16 +107,25 @@ fn aarch64_trap_handler(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource)
         TrapSource::CurrentSpEl0 | TrapSource::LowerAArch64 | TrapSource::LowerAArch32
     ) {
         panic!(
-            "Invalid exception {:?} from {:?}:\n{:#x?}",
-            kind, source, tf
+            "Invalid exception {:?} from {:?}:\n{}",
+            kind,
+            source,
+            crate::trap::TrapFrameDump(tf)
         );
     }
     match kind {
+        // SErrors are dispatched by `aarch64_serror_handler`
         TrapKind::Fiq | TrapKind::SError => {
-            panic!("Unhandled exception {:?}:\n{:#x?}", kind, tf);
+            panic!(
+                "Unhandled exception {:?}:\n{}",
+                kind,
+                crate::trap::TrapFrameDump(tf)
+            );
         }
         TrapKind::Irq => {
             handle_trap!(IRQ, 0);
+            #[cfg(feature = "softirq")]
+            crate::softirq::run_pending();
         }
         TrapKind::Synchronous => {
             let esr = ESR_EL1.extract();
@@ -99,12 +146,21 @@ fn aarch64_trap_handler(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource)
                         },
                     );
                 }
+                #[cfg(feature = "fp-lazy")]
+                Some(ESR_EL1::EC::Value::TrappedFP) => super::fpu::handle_fp_access(),
                 Some(ESR_EL1::EC::Value::Brk64) => {
                     debug!("BRK #{:#x} @ {:#x} ", iss, tf.elr);
                     tf.elr += 4;
                 }
+                #[cfg(feature = "debug-regs")]
+                _ if matches!(
+                    esr.read(ESR_EL1::EC),
+                    super::debug::EC_BREAKPOINT_CURRENT_EL | super::debug::EC_WATCHPOINT_CURRENT_EL
+                ) && super::debug::handle_debug(tf, esr.get()) => {}
+                _ if esr.read(ESR_EL1::EC) == EC_SOFTWARE_STEP_CURRENT_EL
+                    && crate::trap::STEP_HANDLER.iter().any(|handler| handler(tf)) => {}
                 e => {
-                    let vaddr = va!(FAR_EL1.get() as usize);
+                    let vaddr = super::asm::read_far_el1();
                     panic!(
                         "Unhandled synchronous exception {:?} @ {:#x}: ESR={:#x} (EC {:#08b}, FAR: {:#x} ISS {:#x})\n{}",
                         e,
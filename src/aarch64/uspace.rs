@@ -2,14 +2,20 @@
 
 use core::ops::{Deref, DerefMut};
 
-use aarch64_cpu::registers::{Readable, ESR_EL1, FAR_EL1};
+use aarch64_cpu::registers::{Readable, ESR_EL1};
 use memory_addr::VirtAddr;
 use tock_registers::LocalRegisterCopy;
 
-use super::trap::{is_valid_page_fault, TrapKind};
-use crate::{trap::PageFaultFlags, TrapFrame};
+use super::trap::{handle_serror, is_valid_page_fault, TrapKind};
+use crate::{
+    trap::PageFaultFlags,
+    uaccess::{copy_from_user, copy_to_user},
+    TrapFrame,
+};
 
-pub use crate::uspace_common::{ExceptionKind, ReturnReason};
+pub use crate::uspace_common::{
+    probe_read, probe_write, ExceptionKind, ReturnReason, SigStack, SignalFrameError,
+};
 
 /// Context to enter user space.
 #[repr(C, align(16))]
@@ -22,10 +28,16 @@ pub struct UserContext {
     pub tpidr: u64,
 }
 
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserContext, tf), 0);
+static_assertions::const_assert_eq!(core::mem::size_of::<UserContext>(), 36 * 8);
+
 impl UserContext {
     const PAD_MAGIC: u64 = 0x1234_5678_9abc_def0;
     /// Creates a new context with the given entry point, user stack pointer,
     /// and the argument.
+    ///
+    /// The saved `SPSR` is set to EL0t, with IRQs unmasked and other
+    /// exceptions (`D`, `A`, `F`) masked.
     pub fn new(entry: usize, ustack_top: VirtAddr, arg0: usize) -> Self {
         use aarch64_cpu::registers::SPSR_EL1;
         let mut regs = [0; 31];
@@ -67,6 +79,20 @@ impl UserContext {
         self.tpidr = tls as _;
     }
 
+    /// Prepares the saved `SPSR.BTYPE` for returning to a BTI guarded page.
+    ///
+    /// If `guarded` is true, `BTYPE` is cleared to `0b00`, so the first
+    /// instruction executed after `ERET` does not need to be a landing pad
+    /// (e.g., when the entry point is changed by a signal handler or `execve`).
+    /// Otherwise, the saved `BTYPE` is left unchanged.
+    #[cfg(feature = "bti")]
+    pub const fn set_bti_guarded(&mut self, guarded: bool) {
+        const SPSR_BTYPE_MASK: u64 = 0b11 << 10;
+        if guarded {
+            self.tf.spsr &= !SPSR_BTYPE_MASK;
+        }
+    }
+
     /// Enters user space.
     ///
     /// It restores the user registers and jumps to the user entry point
@@ -78,44 +104,104 @@ impl UserContext {
             fn enter_user(uctx: &mut UserContext) -> TrapKind;
         }
 
+        // `enter_user` saves the callee-saved registers on the current kernel
+        // stack, and restores `SP_EL1` to it on traps, so it must be 16-byte
+        // aligned
+        let sp: usize;
+        unsafe { core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack)) };
+        debug_assert!(
+            sp.is_multiple_of(16),
+            "misaligned kernel stack pointer {sp:#x}"
+        );
+        // the context is also used as the kernel stack when saving the trap
+        // frame
+        debug_assert!(
+            (self as *const Self as usize).is_multiple_of(16),
+            "misaligned user context {:p}",
+            self
+        );
         crate::asm::disable_irqs();
-        let kind = unsafe { enter_user(self) };
 
-        let ret = match kind {
-            TrapKind::Irq => {
-                handle_trap!(IRQ, 0);
-                ReturnReason::Interrupt
-            }
-            TrapKind::Fiq | TrapKind::SError => ReturnReason::Unknown,
-            TrapKind::Synchronous => {
-                let esr = ESR_EL1.extract();
-                let far = FAR_EL1.get() as usize;
-
-                let iss = esr.read(ESR_EL1::ISS);
-
-                match esr.read_as_enum(ESR_EL1::EC) {
-                    Some(ESR_EL1::EC::Value::SVC64) => ReturnReason::Syscall,
-                    Some(ESR_EL1::EC::Value::InstrAbortLowerEL) if is_valid_page_fault(iss) => {
-                        ReturnReason::PageFault(
-                            va!(far),
-                            PageFaultFlags::EXECUTE | PageFaultFlags::USER,
-                        )
+        let ret = loop {
+            let kind = unsafe { enter_user(self) };
+
+            break match kind {
+                TrapKind::Irq => {
+                    handle_trap!(IRQ, 0);
+                    #[cfg(feature = "softirq")]
+                    crate::softirq::run_pending();
+                    ReturnReason::Interrupt
+                }
+                TrapKind::SError => {
+                    let esr = ESR_EL1.extract();
+                    if handle_serror(self, esr.get()) {
+                        continue;
                     }
-                    Some(ESR_EL1::EC::Value::DataAbortLowerEL) if is_valid_page_fault(iss) => {
-                        let wnr = (iss & (1 << 6)) != 0; // WnR: Write not Read
-                        let cm = (iss & (1 << 8)) != 0; // CM: Cache maintenance
-                        ReturnReason::PageFault(
-                            va!(far),
-                            if wnr & !cm {
-                                PageFaultFlags::WRITE
-                            } else {
-                                PageFaultFlags::READ
-                            } | PageFaultFlags::USER,
-                        )
+                    ReturnReason::Exception(ExceptionInfo { esr, far: 0 })
+                }
+                TrapKind::Fiq => ReturnReason::Unknown,
+                TrapKind::Synchronous => {
+                    let esr = ESR_EL1.extract();
+                    let far = crate::asm::read_far_el1().as_usize();
+
+                    let iss = esr.read(ESR_EL1::ISS);
+
+                    match esr.read_as_enum(ESR_EL1::EC) {
+                        // switch the FP/SIMD states lazily, and return to user space
+                        #[cfg(feature = "fp-lazy")]
+                        Some(ESR_EL1::EC::Value::TrappedFP) => {
+                            super::fpu::handle_fp_access();
+                            continue;
+                        }
+                        Some(ESR_EL1::EC::Value::SVC64) => ReturnReason::Syscall,
+                        // floating-point exceptions handled by the kernel
+                        Some(ESR_EL1::EC::Value::TrappedFP64)
+                            if crate::trap::SIMD_FP_HANDLER
+                                .iter()
+                                .any(|handler| handler(self, crate::asm::read_fpsr())) =>
+                        {
+                            continue;
+                        }
+                        // single steps handled by the kernel
+                        _ if esr.read(ESR_EL1::EC) == super::trap::EC_SOFTWARE_STEP_LOWER_EL
+                            && crate::trap::STEP_HANDLER
+                                .iter()
+                                .any(|handler| handler(self)) =>
+                        {
+                            continue;
+                        }
+                        // breakpoints and watchpoints handled by the kernel
+                        #[cfg(feature = "debug-regs")]
+                        _ if matches!(
+                            esr.read(ESR_EL1::EC),
+                            super::debug::EC_BREAKPOINT_LOWER_EL
+                                | super::debug::EC_WATCHPOINT_LOWER_EL
+                        ) && super::debug::handle_debug(self, esr.get()) =>
+                        {
+                            continue;
+                        }
+                        Some(ESR_EL1::EC::Value::InstrAbortLowerEL) if is_valid_page_fault(iss) => {
+                            ReturnReason::PageFault(
+                                va!(far),
+                                PageFaultFlags::EXECUTE | PageFaultFlags::USER,
+                            )
+                        }
+                        Some(ESR_EL1::EC::Value::DataAbortLowerEL) if is_valid_page_fault(iss) => {
+                            let wnr = (iss & (1 << 6)) != 0; // WnR: Write not Read
+                            let cm = (iss & (1 << 8)) != 0; // CM: Cache maintenance
+                            ReturnReason::PageFault(
+                                va!(far),
+                                if wnr & !cm {
+                                    PageFaultFlags::WRITE
+                                } else {
+                                    PageFaultFlags::READ
+                                } | PageFaultFlags::USER,
+                            )
+                        }
+                        _ => ReturnReason::Exception(ExceptionInfo { esr, far }),
                     }
-                    _ => ReturnReason::Exception(ExceptionInfo { esr, far }),
                 }
-            }
+            };
         };
 
         crate::asm::enable_irqs();
@@ -137,6 +223,256 @@ impl DerefMut for UserContext {
     }
 }
 
+/// General registers in the layout of Linux's `struct user_pt_regs`, used by
+/// `ptrace(PTRACE_GETREGSET)` with `NT_PRSTATUS`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserPtRegs {
+    /// General-purpose registers (X0..X30).
+    pub regs: [u64; 31],
+    /// Stack Pointer (SP_EL0).
+    pub sp: u64,
+    /// Program Counter.
+    pub pc: u64,
+    /// Process State (SPSR).
+    pub pstate: u64,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<UserPtRegs>(), 34 * 8);
+
+impl UserContext {
+    /// Converts the user context to [`UserPtRegs`].
+    pub fn to_user_regs(&self) -> UserPtRegs {
+        UserPtRegs {
+            regs: self.tf.x,
+            sp: self.sp,
+            pc: self.tf.elr,
+            pstate: self.tf.spsr,
+        }
+    }
+
+    /// Updates the user context from [`UserPtRegs`].
+    ///
+    /// The caller must validate the `pstate` value from the user space (e.g.,
+    /// it must return to EL0) before returning to it.
+    pub fn set_user_regs(&mut self, regs: &UserPtRegs) {
+        self.tf.x = regs.regs;
+        self.sp = regs.sp;
+        self.tf.elr = regs.pc;
+        self.tf.spsr = regs.pstate;
+    }
+}
+
+/// Machine context in the layout of Linux's `struct sigcontext`, i.e., the
+/// `uc_mcontext` of [`UContext`].
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct MContext {
+    /// The faulting address of the exception (`FAR_EL1`), if applicable.
+    pub fault_address: u64,
+    /// General-purpose registers (X0..X30).
+    pub regs: [u64; 31],
+    /// Stack Pointer (SP_EL0).
+    pub sp: u64,
+    /// Program Counter.
+    pub pc: u64,
+    /// Process State (SPSR).
+    pub pstate: u64,
+    __pad: u64,
+    /// Space for the extension records (e.g., `fpsimd_context`), which is
+    /// 16-byte aligned.
+    pub reserved: [u8; 4096],
+}
+
+/// User context in the layout of Linux's `struct ucontext`, which is saved in
+/// the signal frame and restored by `rt_sigreturn`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UContext {
+    /// The flags of the context.
+    pub uc_flags: usize,
+    /// The user address of the context to resume, or 0 if none.
+    pub uc_link: usize,
+    /// The signal stack used by the context.
+    pub uc_stack: SigStack,
+    /// The signal mask to restore.
+    pub uc_sigmask: u64,
+    __unused: [u8; 128 - 8],
+    /// The saved registers.
+    pub uc_mcontext: MContext,
+}
+
+static_assertions::const_assert_eq!(core::mem::offset_of!(MContext, reserved), 36 * 8);
+static_assertions::const_assert_eq!(core::mem::size_of::<MContext>(), 36 * 8 + 4096);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UContext, uc_sigmask), 40);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UContext, uc_mcontext), 176);
+
+impl TrapFrame {
+    /// Converts the trap frame to [`UContext`].
+    ///
+    /// `SP_EL0` is not saved in the trap frame, so `sp` is set to 0. Use
+    /// [`UserContext::to_ucontext`] to get the stack pointer. Other fields
+    /// that are not in the trap frame (e.g., the signal stack and mask) are
+    /// set to 0, which can be filled by the caller.
+    pub fn to_ucontext(&self) -> UContext {
+        UContext {
+            uc_flags: 0,
+            uc_link: 0,
+            uc_stack: SigStack::default(),
+            uc_sigmask: 0,
+            __unused: [0; 128 - 8],
+            uc_mcontext: MContext {
+                fault_address: 0,
+                regs: self.x,
+                sp: 0,
+                pc: self.elr,
+                pstate: self.spsr,
+                __pad: 0,
+                reserved: [0; 4096],
+            },
+        }
+    }
+
+    /// Creates a trap frame from the `uc_mcontext` of [`UContext`].
+    ///
+    /// `sp` is ignored. The caller must validate the `pstate` value from the
+    /// user space (e.g., it must return to EL0) before returning to it.
+    pub fn from_ucontext(uc: &UContext) -> Self {
+        Self {
+            x: uc.uc_mcontext.regs,
+            elr: uc.uc_mcontext.pc,
+            spsr: uc.uc_mcontext.pstate,
+            __pad: 0,
+        }
+    }
+}
+
+impl UserContext {
+    /// Converts the user context to [`UContext`], including the stack
+    /// pointer.
+    pub fn to_ucontext(&self) -> UContext {
+        let mut uc = self.tf.to_ucontext();
+        uc.uc_mcontext.sp = self.sp;
+        uc
+    }
+
+    /// Updates the user context from the `uc_mcontext` of [`UContext`].
+    ///
+    /// The caller must validate the `pstate` value from the user space (e.g.,
+    /// it must return to EL0) before returning to it.
+    pub fn set_ucontext(&mut self, uc: &UContext) {
+        self.tf = TrapFrame {
+            __pad: self.tf.__pad,
+            ..TrapFrame::from_ucontext(uc)
+        };
+        self.sp = uc.uc_mcontext.sp;
+    }
+}
+
+/// The end of the user address space (`TTBR0_EL1` with 48-bit VA).
+const USER_SPACE_END: usize = 1 << 48;
+
+/// The frame saved on the user stack by [`UserContext::push_signal_frame`].
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    tf: TrapFrame,
+    sp: u64,
+    __pad: u64,
+}
+
+impl UserContext {
+    /// Pushes a signal frame onto the user stack, and sets up the context to
+    /// run the signal handler.
+    ///
+    /// The current trap frame and stack pointer are saved on the user stack,
+    /// and the link register is set to `restorer`. The handler is called with
+    /// `signum`, `siginfo` (if any) and the address of the saved frame as its
+    /// arguments.
+    pub fn push_signal_frame(
+        &mut self,
+        signum: u32,
+        handler: usize,
+        restorer: usize,
+        siginfo: Option<usize>,
+    ) -> Result<(), SignalFrameError> {
+        const FRAME_SIZE: usize = core::mem::size_of::<SignalFrame>();
+        let sp = self.sp as usize;
+        if !sp.is_multiple_of(16) || sp > USER_SPACE_END {
+            return Err(SignalFrameError::BadStack);
+        }
+        let frame = sp
+            .checked_sub(FRAME_SIZE)
+            .ok_or(SignalFrameError::BadStack)?;
+        let saved = SignalFrame {
+            tf: self.tf,
+            sp: self.sp,
+            __pad: 0,
+        };
+        unsafe {
+            let src = &saved as *const SignalFrame as *const u8;
+            if copy_to_user(frame as *mut u8, src, FRAME_SIZE).is_err() {
+                return Err(SignalFrameError::BadStack);
+            }
+        }
+        self.sp = frame as _;
+        self.tf.elr = handler as _;
+        self.tf.x[0] = signum as _;
+        if let Some(siginfo) = siginfo {
+            self.tf.x[1] = siginfo as _;
+        }
+        self.tf.x[2] = frame as _;
+        self.tf.x[30] = restorer as _;
+        Ok(())
+    }
+
+    /// Pops the signal frame pushed by [`push_signal_frame`] from the user
+    /// stack, i.e., `sigreturn`.
+    ///
+    /// It should be called on the syscall from `restorer`, when the user stack
+    /// pointer points to the saved frame. As Linux's `valid_user_regs`, the
+    /// saved `SPSR` must return to EL0 in AArch64 state with no exceptions
+    /// masked (`DAIF`), and the `SS`, `IL` and `RES0` bits are cleared.
+    ///
+    /// [`push_signal_frame`]: UserContext::push_signal_frame
+    pub fn pop_signal_frame(&mut self) -> Result<(), SignalFrameError> {
+        /// `SPSR_EL1.M[4:0]`, which is 0 for EL0t in AArch64 state.
+        const SPSR_MODE_MASK: u64 = 0x1f;
+        /// `SPSR_EL1.{D,A,I,F}`.
+        const SPSR_DAIF_MASK: u64 = 0xf << 6;
+        /// The bits that can be restored from the user space: `NZCV`, `TCO`,
+        /// `DIT`, `SSBS` and `BTYPE`, besides `M` and `DAIF` checked above.
+        /// `SS` (bit 21), `IL` (bit 20) and the `RES0` bits are cleared.
+        const SPSR_USER_MASK: u64 = (0xf << 28) | (0x3 << 24) | (1 << 12) | (0x3 << 10);
+        const FRAME_SIZE: usize = core::mem::size_of::<SignalFrame>();
+        let frame = self.sp as usize;
+        if !frame.is_multiple_of(16) || frame > USER_SPACE_END - FRAME_SIZE {
+            return Err(SignalFrameError::BadStack);
+        }
+        let mut saved = SignalFrame {
+            tf: TrapFrame::default(),
+            sp: 0,
+            __pad: 0,
+        };
+        unsafe {
+            let dst = &mut saved as *mut SignalFrame as *mut u8;
+            if copy_from_user(dst, frame as *const u8, FRAME_SIZE).is_err() {
+                return Err(SignalFrameError::BadStack);
+            }
+        }
+        if saved.tf.spsr & (SPSR_MODE_MASK | SPSR_DAIF_MASK) != 0 {
+            return Err(SignalFrameError::BadFrame);
+        }
+        saved.tf.spsr &= SPSR_USER_MASK;
+        self.tf = TrapFrame {
+            __pad: self.tf.__pad,
+            ..saved.tf
+        };
+        self.sp = saved.sp;
+        Ok(())
+    }
+}
+
 /// Information about an exception that occurred in user space.
 #[derive(Debug, Clone, Copy)]
 pub struct ExceptionInfo {
@@ -149,9 +485,25 @@ pub struct ExceptionInfo {
 impl ExceptionInfo {
     /// Returns a generalized kind of this exception.
     pub fn kind(&self) -> ExceptionKind {
+        /// Exception class of the Branch Target Exception (`FEAT_BTI`).
+        const EC_BRANCH_TARGET: u64 = 0x0d;
+        /// Exception class of the SError interrupt.
+        const EC_SERROR: u64 = 0x2f;
+        match self.esr.read(ESR_EL1::EC) {
+            EC_BRANCH_TARGET => return ExceptionKind::BranchTargetFault,
+            EC_SERROR => return ExceptionKind::SystemError,
+            _ => {}
+        }
         match self.esr.read_as_enum(ESR_EL1::EC) {
-            Some(ESR_EL1::EC::Value::BreakpointLowerEL) => ExceptionKind::Breakpoint,
-            Some(ESR_EL1::EC::Value::IllegalExecutionState) => ExceptionKind::IllegalInstruction,
+            Some(ESR_EL1::EC::Value::BreakpointLowerEL) | Some(ESR_EL1::EC::Value::Brk64) => {
+                ExceptionKind::Breakpoint
+            }
+            // undefined instructions, or FP/SIMD accesses while they are
+            // disabled
+            Some(ESR_EL1::EC::Value::Unknown)
+            | Some(ESR_EL1::EC::Value::TrappedFP)
+            | Some(ESR_EL1::EC::Value::IllegalExecutionState) => ExceptionKind::IllegalInstruction,
+            Some(ESR_EL1::EC::Value::TrappedFP64) => ExceptionKind::FloatingPoint,
             Some(ESR_EL1::EC::Value::PCAlignmentFault)
             | Some(ESR_EL1::EC::Value::SPAlignmentFault) => ExceptionKind::Misaligned,
             _ => ExceptionKind::Other,
@@ -0,0 +1,134 @@
+//! Hardware watchpoints on the current CPU with the `DBGWVR<n>_EL1` and
+//! `DBGWCR<n>_EL1` registers.
+//!
+//! The watchpoints set here are CPU states. To make them per-task states,
+//! they should be saved into the [`DebugRegs`] of the task.
+//!
+//! Watchpoint exceptions (`ESR_EL1.EC` = `0x34` or `0x35`) are routed to the
+//! [`DEBUG_HANDLER`] slice, with the accessed address in `FAR_EL1`.
+//!
+//! [`DebugRegs`]: super::debug::DebugRegs
+//! [`DEBUG_HANDLER`]: super::debug::DEBUG_HANDLER
+
+use core::arch::asm;
+
+use memory_addr::{MemoryAddr, VirtAddr};
+
+use super::{
+    asm::{read_mdscr_el1, write_mdscr_el1},
+    debug::{num_watchpoints, read_dbgwcr, write_dbgwcr, write_dbgwvr, MDSCR_KDE, MDSCR_MDE},
+};
+
+/// `DBGWCR<n>_EL1.E`: enables the watchpoint.
+const DBGWCR_E: u64 = 1 << 0;
+/// `DBGWCR<n>_EL1.PAC`: matches accesses at both EL0 and EL1.
+const DBGWCR_PAC_EL0_EL1: u64 = 0b11 << 1;
+
+/// The length of the memory region watched by a hardware watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointLen {
+    /// 1 byte.
+    Byte,
+    /// 2 bytes.
+    HalfWord,
+    /// 4 bytes.
+    Word,
+    /// 8 bytes.
+    DoubleWord,
+}
+
+impl WatchpointLen {
+    /// Returns the length in bytes.
+    pub const fn bytes(self) -> usize {
+        match self {
+            Self::Byte => 1,
+            Self::HalfWord => 2,
+            Self::Word => 4,
+            Self::DoubleWord => 8,
+        }
+    }
+}
+
+/// The kind of accesses that trigger a hardware watchpoint
+/// (`DBGWCR<n>_EL1.LSC`).
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointKind {
+    /// Trigger on data reads.
+    Read = 0b01,
+    /// Trigger on data writes.
+    Write = 0b10,
+    /// Trigger on data reads or writes.
+    ReadWrite = 0b11,
+}
+
+/// Errors of setting a hardware watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// The watchpoint index is not less than [`num_watchpoints`].
+    InvalidIndex,
+    /// The address is not aligned to the length.
+    MisalignedAddress,
+}
+
+/// Sets and enables the hardware watchpoint `index` on the current CPU, which
+/// watches `len` bytes at `addr` for accesses of `kind` from EL0 and EL1.
+///
+/// The watchpoint exceptions are only generated after [`enable`].
+pub fn set(
+    index: usize,
+    addr: VirtAddr,
+    len: WatchpointLen,
+    kind: WatchpointKind,
+) -> Result<(), WatchpointError> {
+    if index >= num_watchpoints() {
+        return Err(WatchpointError::InvalidIndex);
+    }
+    if !addr.is_aligned(len.bytes()) {
+        return Err(WatchpointError::MisalignedAddress);
+    }
+    // The value register holds a doubleword-aligned address, and the byte
+    // address select field (`BAS`) selects the watched bytes in it.
+    let offset = addr.as_usize() & 7;
+    let bas = ((1u64 << len.bytes()) - 1) << offset;
+    let wcr = DBGWCR_E | DBGWCR_PAC_EL0_EL1 | (kind as u64) << 3 | bas << 5;
+    unsafe {
+        write_dbgwcr(index, 0);
+        write_dbgwvr(index, addr.align_down(8usize).as_usize() as u64);
+        write_dbgwcr(index, wcr);
+        asm!("isb", options(nostack));
+    }
+    Ok(())
+}
+
+/// Disables the hardware watchpoint `index` on the current CPU, by clearing
+/// `DBGWCR<n>_EL1.E`.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`num_watchpoints`].
+pub fn clear(index: usize) {
+    assert!(index < num_watchpoints());
+    unsafe {
+        write_dbgwcr(index, read_dbgwcr(index) & !DBGWCR_E);
+        asm!("isb", options(nostack));
+    }
+}
+
+/// Enables the watchpoint exceptions on the current CPU.
+///
+/// It clears the OS lock and sets `MDSCR_EL1.MDE`, as well as `MDSCR_EL1.KDE`
+/// so that the accesses at EL1 are also watched (which also requires
+/// `PSTATE.D` to be cleared).
+pub fn enable() {
+    unsafe {
+        asm!("msr oslar_el1, xzr", options(nostack));
+        write_mdscr_el1(read_mdscr_el1() | MDSCR_MDE | MDSCR_KDE);
+    }
+}
+
+/// Disables the breakpoint and watchpoint exceptions on the current CPU, by
+/// clearing `MDSCR_EL1.MDE` and `MDSCR_EL1.KDE`.
+pub fn disable() {
+    unsafe { write_mdscr_el1(read_mdscr_el1() & !(MDSCR_MDE | MDSCR_KDE)) };
+}
@@ -0,0 +1,93 @@
+//! Hooks to observe context switches, e.g., for tracing and profiling.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::TaskContext;
+
+/// An observer of context switches, called by [`TaskContext::switch_to`].
+///
+/// The methods are called with the IRQs disabled, so they must not block or
+/// switch tasks. [`on_switch_out`] and [`on_switch_in`] are called right
+/// before the CPU switches to the next task, and [`after_switch`] right after
+/// the CPU switches back, so they bracket the time the task is switched out.
+///
+/// [`on_switch_out`]: ContextSwitchObserver::on_switch_out
+/// [`on_switch_in`]: ContextSwitchObserver::on_switch_in
+/// [`after_switch`]: ContextSwitchObserver::after_switch
+pub trait ContextSwitchObserver: Send + Sync {
+    /// Called when the task with the context `prev` is being switched out.
+    fn on_switch_out(&self, prev: &TaskContext);
+    /// Called when the task with the context `next` is being switched in.
+    fn on_switch_in(&self, next: &TaskContext);
+    /// Called when the task with the context `curr`, which was switched out by
+    /// [`TaskContext::switch_to`], has been switched back and is running.
+    ///
+    /// It is not called when a new task starts from the entry point given to
+    /// [`TaskContext::init`].
+    fn after_switch(&self, curr: &TaskContext) {
+        let _ = curr;
+    }
+}
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+struct Observer {
+    state: AtomicU8,
+    inner: UnsafeCell<Option<&'static dyn ContextSwitchObserver>>,
+}
+
+// SAFETY: `inner` is written only once before `state` becomes `SET`.
+unsafe impl Sync for Observer {}
+
+static OBSERVER: Observer = Observer {
+    state: AtomicU8::new(UNSET),
+    inner: UnsafeCell::new(None),
+};
+
+/// Sets the global context switch observer.
+///
+/// The observer can only be set once. Returns `false` if it has already been
+/// set.
+pub fn set_context_switch_observer(obs: &'static dyn ContextSwitchObserver) -> bool {
+    if OBSERVER
+        .state
+        .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+    // SAFETY: only the winner of the state transition writes `inner`.
+    unsafe { *OBSERVER.inner.get() = Some(obs) };
+    OBSERVER.state.store(SET, Ordering::Release);
+    true
+}
+
+/// Returns the observer if it has been set.
+#[inline]
+fn observer() -> Option<&'static dyn ContextSwitchObserver> {
+    if OBSERVER.state.load(Ordering::Acquire) != SET {
+        return None;
+    }
+    // SAFETY: `inner` is never written after `state` becomes `SET`.
+    unsafe { *OBSERVER.inner.get() }
+}
+
+/// Notifies the observer (if any) of a context switch from `prev` to `next`.
+#[inline]
+pub(crate) fn notify_switch(prev: &TaskContext, next: &TaskContext) {
+    if let Some(obs) = observer() {
+        obs.on_switch_out(prev);
+        obs.on_switch_in(next);
+    }
+}
+
+/// Notifies the observer (if any) that `curr` has been switched back.
+#[inline]
+pub(crate) fn notify_switch_back(curr: &TaskContext) {
+    if let Some(obs) = observer() {
+        obs.after_switch(curr);
+    }
+}
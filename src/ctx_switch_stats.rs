@@ -0,0 +1,105 @@
+//! Context switch timing statistics.
+//!
+//! The time of each [`TaskContext::switch_to`] is measured on the CPU that
+//! performs it, from the beginning of the low-level context switch to the
+//! point where the next task resumes (in cycles of [`timer::cycles`]). Switches
+//! into newly created tasks, which do not resume from a previous switch, are
+//! not counted.
+//!
+//! [`TaskContext::switch_to`]: crate::TaskContext::switch_to
+//! [`timer::cycles`]: crate::timer::cycles
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Context switch statistics of a CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SwitchStats {
+    /// The number of measured context switches.
+    pub switches: u64,
+    /// The total cycles of the measured context switches.
+    pub total_cycles: u64,
+    /// The maximum cycles of a single context switch.
+    pub max_cycles: u64,
+}
+
+impl SwitchStats {
+    /// Returns the average cycles of a context switch, or 0 if none is
+    /// measured.
+    pub fn avg_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.switches).unwrap_or(0)
+    }
+}
+
+#[percpu::def_percpu]
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+#[percpu::def_percpu]
+static TOTAL_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+#[percpu::def_percpu]
+static MAX_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// The start time of the ongoing context switch on the CPU, or 0 if none.
+#[percpu::def_percpu]
+static SWITCH_START: AtomicU64 = AtomicU64::new(0);
+
+/// Records the start of a context switch on the current CPU.
+pub(crate) fn switch_begin() {
+    let now = crate::timer::cycles();
+    SWITCH_START.with_current(|start| start.store(now, Ordering::Relaxed));
+}
+
+/// Records the end of a context switch on the current CPU, after the next task
+/// has resumed.
+pub(crate) fn switch_end() {
+    let now = crate::timer::cycles();
+    let start = SWITCH_START.with_current(|start| start.swap(0, Ordering::Relaxed));
+    if start == 0 {
+        return;
+    }
+    let cycles = now.saturating_sub(start);
+    SWITCHES.with_current(|s| s.fetch_add(1, Ordering::Relaxed));
+    TOTAL_CYCLES.with_current(|t| t.fetch_add(cycles, Ordering::Relaxed));
+    MAX_CYCLES.with_current(|m| m.fetch_max(cycles, Ordering::Relaxed));
+}
+
+fn get(cpu_id: usize) -> SwitchStats {
+    // SAFETY: the per-CPU data of other CPUs are only accessed atomically.
+    unsafe {
+        SwitchStats {
+            switches: SWITCHES.remote_ref_raw(cpu_id).load(Ordering::Relaxed),
+            total_cycles: TOTAL_CYCLES.remote_ref_raw(cpu_id).load(Ordering::Relaxed),
+            max_cycles: MAX_CYCLES.remote_ref_raw(cpu_id).load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the context switch statistics of the current CPU.
+pub fn get_current_cpu() -> SwitchStats {
+    SwitchStats {
+        switches: SWITCHES.with_current(|s| s.load(Ordering::Relaxed)),
+        total_cycles: TOTAL_CYCLES.with_current(|t| t.load(Ordering::Relaxed)),
+        max_cycles: MAX_CYCLES.with_current(|m| m.load(Ordering::Relaxed)),
+    }
+}
+
+/// Returns the context switch statistics of all CPUs, with their CPU IDs.
+pub fn get_all() -> impl Iterator<Item = (usize, SwitchStats)> {
+    (0..percpu::percpu_area_num()).map(|cpu_id| (cpu_id, get(cpu_id)))
+}
+
+/// Resets the context switch statistics of all CPUs.
+pub fn reset() {
+    for cpu_id in 0..percpu::percpu_area_num() {
+        // SAFETY: the per-CPU data of other CPUs are only accessed atomically.
+        unsafe {
+            SWITCHES.remote_ref_raw(cpu_id).store(0, Ordering::Relaxed);
+            TOTAL_CYCLES
+                .remote_ref_raw(cpu_id)
+                .store(0, Ordering::Relaxed);
+            MAX_CYCLES
+                .remote_ref_raw(cpu_id)
+                .store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Interior mutability for the FP/SIMD states switched lazily.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// A cell holding the lazily switched FP/SIMD states of a task, when the
+/// "fp-lazy" feature is enabled.
+///
+/// The FP/SIMD trap handler saves the states of the previous FPU owner and
+/// restores those of the current task through their contexts, which are only
+/// known by the shared references given to `TaskContext::switch_to`. The
+/// states are thus kept in an [`UnsafeCell`], and the cell dereferences to the
+/// inner value for other accesses.
+///
+/// The trap handler only accesses the states of a task that is not running
+/// elsewhere, with the FPU ownership protocol preventing the context from being
+/// freed meanwhile.
+#[repr(transparent)]
+#[derive(Default)]
+pub struct FpCell<T>(UnsafeCell<T>);
+
+// SAFETY: the inner value is only mutated through a shared reference by the
+// FP/SIMD trap handler, while no other CPU accesses it.
+unsafe impl<T: Send + Sync> Sync for FpCell<T> {}
+
+impl<T> FpCell<T> {
+    /// Creates a new cell holding `value`.
+    pub const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    /// Returns a raw pointer to the inner value, which may be written by the
+    /// FP/SIMD trap handler.
+    pub(crate) const fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
+
+impl<T> Deref for FpCell<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: see the type-level comment.
+        unsafe { &*self.0.get() }
+    }
+}
+
+impl<T> DerefMut for FpCell<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for FpCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
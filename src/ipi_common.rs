@@ -0,0 +1,170 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use memory_addr::VirtAddr;
+
+/// The target CPUs of an inter-processor interrupt (IPI).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTarget {
+    /// The CPU with the given ID.
+    Cpu(usize),
+    /// All CPUs except the current one.
+    AllExcludingSelf,
+    /// All CPUs, including the current one.
+    All,
+}
+
+/// The request carried by an inter-processor interrupt (IPI).
+#[derive(Debug, Clone, Copy)]
+pub enum IpiKind {
+    /// Invalidates the TLB entry of the given virtual address, or the entire
+    /// TLB if the address is 0.
+    TlbShootdown(VirtAddr),
+    /// Asks the target CPU to reschedule.
+    Reschedule,
+    /// Calls the function on the target CPU.
+    FunctionCall(fn()),
+}
+
+const PENDING_RESCHEDULE: u32 = 1 << 0;
+const PENDING_TLB_SHOOTDOWN: u32 = 1 << 1;
+const PENDING_FUNCTION_CALL: u32 = 1 << 2;
+
+/// The value of [`IPI_TLB_ADDR`] to invalidate the entire TLB.
+const TLB_FLUSH_ALL: usize = usize::MAX;
+
+/// Bitmask of the pending IPI kinds of the CPU. Multiple IPIs of the same kind
+/// are coalesced into one before the CPU handles them.
+#[percpu::def_percpu]
+static IPI_PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// The virtual address of the pending TLB shootdown, 0 if none, or
+/// [`TLB_FLUSH_ALL`] if multiple addresses are coalesced.
+#[percpu::def_percpu]
+static IPI_TLB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+/// The pending function to call, or 0 if none.
+#[percpu::def_percpu]
+static IPI_FUNC: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the ID of the current CPU, derived from its per-CPU data area.
+fn this_cpu_id() -> usize {
+    (percpu::read_percpu_reg() - percpu::percpu_area_base(0)) / percpu::percpu_area_size()
+}
+
+/// Posts the request to the mailbox of the given CPU.
+fn post(cpu_id: usize, kind: IpiKind) {
+    // SAFETY: the per-CPU data of other CPUs are only accessed atomically.
+    let (pending, tlb_addr, func) = unsafe {
+        (
+            IPI_PENDING.remote_ref_raw(cpu_id),
+            IPI_TLB_ADDR.remote_ref_raw(cpu_id),
+            IPI_FUNC.remote_ref_raw(cpu_id),
+        )
+    };
+    let bit = match kind {
+        IpiKind::TlbShootdown(vaddr) => {
+            let addr = if vaddr.as_usize() == 0 {
+                TLB_FLUSH_ALL
+            } else {
+                vaddr.as_usize()
+            };
+            if let Err(old) =
+                tlb_addr.compare_exchange(0, addr, Ordering::AcqRel, Ordering::Acquire)
+            {
+                if old != addr {
+                    // different addresses are coalesced into a full flush
+                    tlb_addr.store(TLB_FLUSH_ALL, Ordering::Release);
+                }
+            }
+            PENDING_TLB_SHOOTDOWN
+        }
+        IpiKind::Reschedule => PENDING_RESCHEDULE,
+        IpiKind::FunctionCall(f) => {
+            // wait for the previous function call to be taken by the target
+            while func
+                .compare_exchange_weak(
+                    0,
+                    f as *const () as usize,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            PENDING_FUNCTION_CALL
+        }
+    };
+    pending.fetch_or(bit, Ordering::AcqRel);
+}
+
+/// Sends an inter-processor interrupt (IPI) to the target CPUs.
+///
+/// The request is posted to the per-CPU mailboxes of the target CPUs before
+/// the interrupt is raised, and handled by [`handle_ipi`] on them.
+///
+/// If the current CPU is a target and its IRQs are disabled, the request is
+/// handled in place by [`handle_ipi`] before returning, instead of raising an
+/// interrupt that would not be taken until the IRQs are enabled.
+pub fn send(target: IpiTarget, kind: IpiKind) {
+    let this_cpu = this_cpu_id();
+    let in_place = !crate::asm::irqs_enabled();
+    match target {
+        IpiTarget::Cpu(cpu_id) if cpu_id == this_cpu && in_place => {
+            post(this_cpu, kind);
+            handle_ipi();
+        }
+        IpiTarget::Cpu(cpu_id) => {
+            post(cpu_id, kind);
+            crate::ipi::send_raw(target);
+        }
+        IpiTarget::AllExcludingSelf | IpiTarget::All => {
+            (0..percpu::percpu_area_num())
+                .filter(|&cpu_id| cpu_id != this_cpu)
+                .for_each(|cpu_id| post(cpu_id, kind));
+            if target == IpiTarget::AllExcludingSelf {
+                crate::ipi::send_raw(target);
+            } else if in_place {
+                crate::ipi::send_raw(IpiTarget::AllExcludingSelf);
+                post(this_cpu, kind);
+                handle_ipi();
+            } else {
+                post(this_cpu, kind);
+                crate::ipi::send_raw(target);
+            }
+        }
+    }
+}
+
+/// Handles the pending IPIs of the current CPU.
+///
+/// It should be called by the interrupt handler of the IPI vector. Each kind
+/// of the pending requests is passed to the [`IPI_HANDLER`] slice. TLB
+/// shootdowns and function calls that are not handled by any handler are
+/// performed directly.
+///
+/// [`IPI_HANDLER`]: crate::trap::IPI_HANDLER
+pub fn handle_ipi() {
+    let pending = IPI_PENDING.with_current(|p| p.swap(0, Ordering::AcqRel));
+    let dispatch = |kind| crate::trap::IPI_HANDLER.iter().any(|handler| handler(kind));
+    if pending & PENDING_TLB_SHOOTDOWN != 0 {
+        let addr = IPI_TLB_ADDR.with_current(|a| a.swap(0, Ordering::AcqRel));
+        let vaddr = if addr == TLB_FLUSH_ALL { 0 } else { addr };
+        if addr != 0 && !dispatch(IpiKind::TlbShootdown(va!(vaddr))) {
+            crate::asm::flush_tlb((vaddr != 0).then_some(va!(vaddr)));
+        }
+    }
+    if pending & PENDING_FUNCTION_CALL != 0 {
+        let func = IPI_FUNC.with_current(|f| f.swap(0, Ordering::AcqRel));
+        if func != 0 {
+            // SAFETY: the value is stored from a `fn()` by `post`.
+            let func = unsafe { core::mem::transmute::<usize, fn()>(func) };
+            if !dispatch(IpiKind::FunctionCall(func)) {
+                func();
+            }
+        }
+    }
+    if pending & PENDING_RESCHEDULE != 0 && !dispatch(IpiKind::Reschedule) {
+        debug!("Unhandled reschedule IPI");
+    }
+}
@@ -11,23 +11,68 @@ extern crate log;
 #[macro_use]
 extern crate memory_addr;
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[macro_use]
 pub mod trap;
 
 #[cfg(feature = "uspace")]
 mod uspace_common;
 
+#[cfg(feature = "uspace")]
+pub mod uaccess;
+
+#[cfg(feature = "ctx-observer")]
+mod context_observer;
+
+#[cfg(feature = "ctx-observer")]
+pub use self::context_observer::{set_context_switch_observer, ContextSwitchObserver};
+
+#[cfg(feature = "stack-paint")]
+mod stack_paint;
+
+#[cfg(feature = "stack-paint")]
+pub use self::stack_paint::STACK_PAINT_PATTERN;
+
+#[cfg(all(
+    feature = "fp-lazy",
+    any(axcpu_arch = "x86_64", axcpu_arch = "aarch64")
+))]
+mod fp_cell;
+
+#[cfg(all(
+    feature = "fp-lazy",
+    any(axcpu_arch = "x86_64", axcpu_arch = "aarch64")
+))]
+pub use self::fp_cell::FpCell;
+
+#[cfg(all(feature = "ipi", any(axcpu_arch = "x86_64", axcpu_arch = "aarch64")))]
+mod ipi_common;
+
+#[cfg(all(
+    feature = "ctx-stats",
+    any(axcpu_arch = "x86_64", axcpu_arch = "aarch64")
+))]
+pub mod ctx_switch_stats;
+
+#[cfg(all(
+    feature = "softirq",
+    any(axcpu_arch = "x86_64", axcpu_arch = "aarch64")
+))]
+pub mod softirq;
+
 cfg_if::cfg_if! {
-    if #[cfg(target_arch = "x86_64")] {
+    if #[cfg(axcpu_arch = "x86_64")] {
         mod x86_64;
         pub use self::x86_64::*;
-    } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
+    } else if #[cfg(any(axcpu_arch = "riscv32", axcpu_arch = "riscv64"))] {
         mod riscv;
         pub use self::riscv::*;
-    } else if #[cfg(target_arch = "aarch64")]{
+    } else if #[cfg(axcpu_arch = "aarch64")]{
         mod aarch64;
         pub use self::aarch64::*;
-    } else if #[cfg(any(target_arch = "loongarch64"))] {
+    } else if #[cfg(axcpu_arch = "loongarch64")] {
         mod loongarch64;
         pub use self::loongarch64::*;
     }
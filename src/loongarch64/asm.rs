@@ -38,6 +38,48 @@ pub fn halt() {
     unsafe { loongArch64::asm::idle() }
 }
 
+/// Waits for an interrupt.
+///
+/// LoongArch64 does not have a dedicated `WFI` instruction, so this operation
+/// is the same as [`wait_for_irqs`] (`IDLE`).
+#[inline]
+pub fn wfi() {
+    wait_for_irqs()
+}
+
+/// Waits for an event.
+///
+/// LoongArch64 does not have the `WFE` instruction, so this operation is a
+/// no-op.
+#[inline]
+pub fn wfe() {}
+
+/// Sends an event to all CPUs in the system.
+///
+/// LoongArch64 does not have the `SEV` instruction, so this operation is a
+/// no-op.
+#[inline]
+pub fn sev() {}
+
+/// Sends an event to the current CPU only.
+///
+/// LoongArch64 does not have the `SEVL` instruction, so this operation is a
+/// no-op.
+#[inline]
+pub fn sevl() {}
+
+/// Executes a no-operation instruction (`NOP`).
+#[inline]
+pub fn nop() {
+    unsafe { asm!("nop", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Hints the CPU that the current thread is performing a spin-wait loop.
+#[inline]
+pub fn yield_cpu() {
+    core::hint::spin_loop()
+}
+
 /// Reads the current page table root register for user space (`PGDL`).
 ///
 /// Returns the physical address of the page table root.
@@ -202,3 +244,38 @@ unsafe extern "C" {
     /// while a value > 0 indicates failure.
     pub fn user_copy(dst: *mut u8, src: *const u8, size: usize) -> usize;
 }
+
+/// Reads a byte from `src`, where the address may be in user space.
+///
+/// The load is annotated in the exception table, so a page fault on it is
+/// recovered by the trap handler, and `Err(())` is returned instead of
+/// panicking.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads from a raw pointer. `src` must
+/// not point to memory-mapped I/O with side effects on reads.
+#[cfg(feature = "uspace")]
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_read_u8(src: *const u8) -> Result<u8, ()> {
+    let val: u32;
+    let err: u32;
+    unsafe {
+        asm!(
+            "li.w {err}, 1",
+            "2: ld.bu {val}, {src}, 0",
+            "move {err}, $zero",
+            "3:",
+            crate::ex_table_entry!("2b", "3b"),
+            src = in(reg) src,
+            val = out(reg) val,
+            err = out(reg) err,
+            options(nostack, readonly, preserves_flags),
+        )
+    }
+    if err == 0 {
+        Ok(val as u8)
+    } else {
+        Err(())
+    }
+}
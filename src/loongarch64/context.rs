@@ -3,6 +3,8 @@ use core::arch::naked_asm;
 use core::mem::offset_of;
 use memory_addr::VirtAddr;
 
+use crate::trap::PrivilegeLevel;
+
 /// General registers of Loongarch64.
 #[allow(missing_docs)]
 #[repr(C)]
@@ -81,6 +83,8 @@ pub struct TrapFrame {
     pub era: usize,
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<TrapFrame>(), 34 * 8);
+
 impl TrapFrame {
     /// Gets the 0th syscall argument.
     pub const fn arg0(&self) -> usize {
@@ -197,10 +201,120 @@ impl TrapFrame {
         self.regs.tp = tls_area;
     }
 
+    /// Whether the trap is from the user space, i.e., the previous privilege
+    /// level (`PRMD.PPLV`) is PLV3.
+    pub const fn is_from_user(&self) -> bool {
+        self.prmd & 0b11 == 3
+    }
+
+    /// Returns the privilege level that the trap is taken from.
+    ///
+    /// It is determined by the state saved on trap entry, so it does not
+    /// change during the trap handling.
+    pub const fn privilege_level(&self) -> PrivilegeLevel {
+        if self.is_from_user() {
+            PrivilegeLevel::User
+        } else {
+            PrivilegeLevel::Kernel
+        }
+    }
+
+    /// Whether the trap is from the kernel, i.e., the negation of
+    /// [`is_from_user`](Self::is_from_user).
+    pub const fn is_kernel(&self) -> bool {
+        !self.is_from_user()
+    }
+
     /// Unwind the stack and get the backtrace.
     pub fn backtrace(&self) -> axbacktrace::Backtrace {
         axbacktrace::Backtrace::capture_trap(self.regs.fp as _, self.era as _, self.regs.ra as _)
     }
+
+    /// Writes all registers to `w` in a compact columnar format, e.g.,
+    /// `ra=0x0000000000000000  tp=0x...`, four registers per line.
+    pub fn dump<W: core::fmt::Write>(&self, w: &mut W) -> core::fmt::Result {
+        let r = &self.regs;
+        crate::trap::dump_regs(
+            w,
+            &[
+                ("ra", r.ra),
+                ("tp", r.tp),
+                ("sp", r.sp),
+                ("a0", r.a0),
+                ("a1", r.a1),
+                ("a2", r.a2),
+                ("a3", r.a3),
+                ("a4", r.a4),
+                ("a5", r.a5),
+                ("a6", r.a6),
+                ("a7", r.a7),
+                ("t0", r.t0),
+                ("t1", r.t1),
+                ("t2", r.t2),
+                ("t3", r.t3),
+                ("t4", r.t4),
+                ("t5", r.t5),
+                ("t6", r.t6),
+                ("t7", r.t7),
+                ("t8", r.t8),
+                ("u0", r.u0),
+                ("fp", r.fp),
+                ("s0", r.s0),
+                ("s1", r.s1),
+                ("s2", r.s2),
+                ("s3", r.s3),
+                ("s4", r.s4),
+                ("s5", r.s5),
+                ("s6", r.s6),
+                ("s7", r.s7),
+                ("s8", r.s8),
+                ("era", self.era),
+                ("prmd", self.prmd),
+            ],
+        )
+    }
+
+    /// Dumps all registers to a newly allocated string, in the same format as
+    /// [`TrapFrame::dump`].
+    #[cfg(feature = "alloc")]
+    pub fn dump_to_string(&self) -> alloc::string::String {
+        let mut s = alloc::string::String::new();
+        self.dump(&mut s).unwrap();
+        s
+    }
+}
+
+/// The callee-saved registers in a [`TrapFrame`], i.e., the registers that
+/// must be preserved across a function call in the LoongArch calling
+/// convention.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CalleeSavedRegs {
+    /// Registers s0..s8.
+    pub s: [usize; 9],
+    /// The frame pointer.
+    pub fp: usize,
+    /// The stack pointer.
+    pub sp: usize,
+}
+
+impl TrapFrame {
+    /// Copies out the callee-saved registers (`s0`-`s8`, `fp` and `sp`).
+    pub const fn callee_saved_copy(&self) -> CalleeSavedRegs {
+        let r = &self.regs;
+        CalleeSavedRegs {
+            s: [r.s0, r.s1, r.s2, r.s3, r.s4, r.s5, r.s6, r.s7, r.s8],
+            fp: r.fp,
+            sp: r.sp,
+        }
+    }
+
+    /// Checks whether the callee-saved registers of two trap frames are
+    /// equal.
+    ///
+    /// `era`, `prmd` and the scratch registers are not compared.
+    pub fn callee_saved_eq(&self, other: &TrapFrame) -> bool {
+        self.callee_saved_copy() == other.callee_saved_copy()
+    }
 }
 
 /// Saved hardware states of a task.
@@ -226,26 +340,81 @@ pub struct TaskContext {
     pub s: [usize; 10],
     /// Thread Pointer
     pub tp: usize,
+    /// The CPU affinity mask of the task, where bit N means the task is
+    /// allowed to run on logical CPU N.
+    pub cpu_mask: u64,
     #[cfg(feature = "uspace")]
     /// user page table root
     pub pgdl: usize,
     #[cfg(feature = "fp-simd")]
     /// Floating Point Unit states
     pub fpu: FpuState,
+    /// The bottom (lowest address) of the kernel stack, where the canary is
+    /// placed, or 0 if the canary is not set.
+    #[cfg(feature = "stack-canary")]
+    pub kstack_bottom: VirtAddr,
+    /// The expected value of the canary at [`kstack_bottom`].
+    ///
+    /// [`kstack_bottom`]: TaskContext::kstack_bottom
+    #[cfg(feature = "stack-canary")]
+    pub stack_canary: u64,
+    /// The kernel stack top of the task, set by [`init`].
+    ///
+    /// [`init`]: TaskContext::init
+    #[cfg(feature = "stack-paint")]
+    pub kstack_top: VirtAddr,
+    /// The size of the kernel stack in bytes, which is painted by [`init`] if
+    /// not 0.
+    ///
+    /// [`init`]: TaskContext::init
+    #[cfg(feature = "stack-paint")]
+    pub kstack_size: usize,
 }
 
+static_assertions::const_assert_eq!(core::mem::offset_of!(TaskContext, s), 2 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(TaskContext, tp), 12 * 8);
+
 impl TaskContext {
     /// Creates a new default context for a new task.
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            cpu_mask: u64::MAX,
+            ..Default::default()
+        }
     }
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// With the "stack-paint" feature, the kernel stack is also painted by
+    /// [`paint_stack`] if [`kstack_size`] is set.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
         self.sp = kstack_top.as_usize();
         self.ra = entry;
         self.tp = tls_area.as_usize();
+        #[cfg(feature = "stack-paint")]
+        {
+            self.kstack_top = kstack_top;
+            self.paint_stack();
+        }
+    }
+
+    /// Resets the context for a new program of the task (e.g., `execve`), with
+    /// the given entry point, kernel stack and TLS area.
+    ///
+    /// Unlike [`init`], all other states in the context are also reset to
+    /// those of [`new`], so that nothing of the previous program remains:
+    /// the callee-saved registers and the FP states are cleared, and the user
+    /// page table root is reset.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`new`]: TaskContext::new
+    pub fn reset(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
+        *self = Self::new();
+        self.init(entry, kstack_top, tls_area);
     }
 
     /// Changes the page table root in this context.
@@ -257,11 +426,139 @@ impl TaskContext {
         self.pgdl = pgdl.as_usize();
     }
 
+    /// Unwind the kernel stack of the task and get the backtrace.
+    ///
+    /// It starts from the frame pointer (`$fp`) and the return address (`$ra`)
+    /// saved by [`switch_to`](Self::switch_to), so it is only meaningful when
+    /// the task is not running.
+    pub fn backtrace(&self) -> axbacktrace::Backtrace {
+        axbacktrace::Backtrace::capture_trap(self.s[9], self.ra, 0)
+    }
+
+    /// Places a canary at the bottom of the kernel stack, which is checked on
+    /// every [`switch_to`] from the task to detect kernel stack overflows.
+    ///
+    /// It must be called after [`init`] and before the task runs, with the
+    /// bottom (lowest address) of the kernel stack.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    #[cfg(feature = "stack-canary")]
+    pub fn init_stack_canary(&mut self, kstack_bottom: VirtAddr) {
+        const CANARY_MAGIC: u64 = 0xdead_beef_cafe_babe;
+        self.kstack_bottom = kstack_bottom;
+        // `sp` is still the kernel stack top before the task runs
+        self.stack_canary = CANARY_MAGIC ^ self.sp as u64;
+        unsafe { (kstack_bottom.as_mut_ptr() as *mut u64).write_volatile(self.stack_canary) };
+    }
+
+    /// Checks whether the canary at the bottom of the kernel stack is intact.
+    ///
+    /// Always returns `true` if the canary is not set by
+    /// [`init_stack_canary`](TaskContext::init_stack_canary).
+    #[cfg(feature = "stack-canary")]
+    pub fn check_stack_canary(&self) -> bool {
+        if self.kstack_bottom.as_usize() == 0 {
+            return true;
+        }
+        let value = unsafe { (self.kstack_bottom.as_ptr() as *const u64).read_volatile() };
+        value == self.stack_canary
+    }
+
+    /// Fills the unused part of the kernel stack with [`STACK_PAINT_PATTERN`],
+    /// to find out the maximum stack usage later.
+    ///
+    /// It is called by [`init`] before the task runs, and does nothing if
+    /// [`kstack_size`] is 0. The paint is only reliable if the task is started
+    /// from [`init`], as a running task may have already used the stack.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn paint_stack(&self) {
+        let top = self.kstack_top.as_usize();
+        unsafe { crate::stack_paint::paint(top - self.kstack_size, top) };
+    }
+
+    /// Returns the lowest address of the kernel stack that has been written
+    /// since it was painted by [`paint_stack`], i.e., the deepest point the
+    /// stack has ever reached.
+    ///
+    /// Returns [`kstack_top`] if the stack is not painted.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_top`]: TaskContext::kstack_top
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_high_water(&self) -> VirtAddr {
+        self.first_unpainted(self.kstack_size)
+    }
+
+    /// Returns the worst-case kernel stack usage in bytes, by scanning the
+    /// painted kernel stack of `stack_size` bytes upwards from its bottom until
+    /// the first word that differs from [`STACK_PAINT_PATTERN`].
+    ///
+    /// The result is only meaningful if the stack is painted by
+    /// [`paint_stack`]. `stack_size` is limited to [`kstack_size`].
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_usage(&self, stack_size: usize) -> usize {
+        self.kstack_top.as_usize() - self.first_unpainted(stack_size).as_usize()
+    }
+
+    #[cfg(feature = "stack-paint")]
+    fn first_unpainted(&self, stack_size: usize) -> VirtAddr {
+        let top = self.kstack_top.as_usize();
+        let start = top - stack_size.min(self.kstack_size);
+        // Skip the canary at the bottom of the stack.
+        #[cfg(feature = "stack-canary")]
+        let start = if self.kstack_bottom.as_usize() == start {
+            start + 8
+        } else {
+            start
+        };
+        VirtAddr::from(unsafe { crate::stack_paint::first_unpainted(start, top) })
+    }
+
+    /// Returns the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N. It is not
+    /// enforced by [`switch_to`], but by the scheduler.
+    ///
+    /// [`switch_to`]: TaskContext::switch_to
+    pub const fn affinity(&self) -> u64 {
+        self.cpu_mask
+    }
+
+    /// Sets the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N.
+    pub const fn set_affinity(&mut self, mask: u64) {
+        self.cpu_mask = mask;
+    }
+
+    /// Checks whether the task is allowed to run on the given logical CPU.
+    ///
+    /// Always returns `false` for CPUs beyond the range of the mask.
+    pub const fn is_allowed_on(&self, cpu: usize) -> bool {
+        cpu < u64::BITS as usize && self.cpu_mask & (1 << cpu) != 0
+    }
+
     /// Switches to another task.
     ///
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
     pub fn switch_to(&mut self, next_ctx: &Self) {
+        #[cfg(feature = "stack-canary")]
+        if !self.check_stack_canary() {
+            panic!(
+                "Kernel stack overflow detected: kstack_bottom={:#x}",
+                self.kstack_bottom
+            );
+        }
         #[cfg(feature = "tls")]
         {
             self.tp = crate::asm::read_thread_pointer();
@@ -279,7 +576,11 @@ impl TaskContext {
             self.fpu.save();
             next_ctx.fpu.restore();
         }
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch(self, next_ctx);
         unsafe { context_switch(self, next_ctx) }
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch_back(self);
     }
 }
 
@@ -7,9 +7,10 @@ mod unaligned;
 
 pub mod asm;
 pub mod init;
+pub mod timer;
 
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{FpuState, GeneralRegisters, TaskContext, TrapFrame};
+pub use self::context::{CalleeSavedRegs, FpuState, GeneralRegisters, TaskContext, TrapFrame};
 pub use self::unaligned::UnalignedError;
@@ -0,0 +1,10 @@
+//! Cycle counter of the current CPU.
+
+/// Reads the cycle counter of the current CPU, i.e., the stable counter
+/// (`RDTIME.D`) on LoongArch64.
+#[inline]
+pub fn cycles() -> u64 {
+    let cycles: u64;
+    unsafe { core::arch::asm!("rdtime.d {}, $zero", out(reg) cycles, options(nomem, nostack)) };
+    cycles
+}
@@ -28,11 +28,11 @@ fn handle_page_fault(tf: &mut TrapFrame, access_flags: PageFaultFlags) {
     }
     core::hint::cold_path();
     panic!(
-        "Unhandled PLV0 Page Fault @ {:#x}, fault_vaddr={:#x} ({:?}):\n{:#x?}\n{}",
+        "Unhandled PLV0 Page Fault @ {:#x}, fault_vaddr={:#x} ({:?}):\n{}\n{}",
         tf.era,
         vaddr,
         access_flags,
-        tf,
+        crate::trap::TrapFrameDump(tf),
         tf.backtrace()
     );
 }
@@ -64,10 +64,10 @@ fn loongarch64_trap_handler(tf: &mut TrapFrame) {
         }
         trap => {
             panic!(
-                "Unhandled trap {:?} @ {:#x}:\n{:#x?}\n{}",
+                "Unhandled trap {:?} @ {:#x}:\n{}\n{}",
                 trap,
                 tf.era,
-                tf,
+                crate::trap::TrapFrameDump(tf),
                 tf.backtrace()
             );
         }
@@ -37,6 +37,45 @@ pub fn halt() {
     riscv::asm::wfi() // should never return
 }
 
+/// Waits for an interrupt (`WFI`).
+#[inline]
+pub fn wfi() {
+    riscv::asm::wfi()
+}
+
+/// Waits for an event.
+///
+/// RISC-V does not have the `WFE` instruction, so this operation is a no-op.
+#[inline]
+pub fn wfe() {}
+
+/// Sends an event to all CPUs in the system.
+///
+/// RISC-V does not have the `SEV` instruction, so this operation is a no-op.
+#[inline]
+pub fn sev() {}
+
+/// Sends an event to the current CPU only.
+///
+/// RISC-V does not have the `SEVL` instruction, so this operation is a no-op.
+#[inline]
+pub fn sevl() {}
+
+/// Executes a no-operation instruction (`NOP`).
+#[inline]
+pub fn nop() {
+    riscv::asm::nop()
+}
+
+/// Hints the CPU that the current thread is performing a spin-wait loop.
+///
+/// It emits the `PAUSE` hint if the `Zihintpause` extension is available, or
+/// behaves as a `NOP` otherwise.
+#[inline]
+pub fn yield_cpu() {
+    core::hint::spin_loop()
+}
+
 /// Reads the current page table root register for user space (`satp`).
 ///
 /// RISC-V does not have a separate page table root register for user and
@@ -118,6 +157,38 @@ pub unsafe fn write_trap_vector_base(stvec: usize) {
     unsafe { stvec::write(reg) }
 }
 
+/// Reads the Supervisor Scratch register (`sscratch`).
+///
+/// The trap entry uses `sscratch` to distinguish the origin of a trap:
+///
+/// - When running in user mode, `sscratch` holds the address of the
+///   [`UserContext`] being run, whose trap frame is filled on entry and is
+///   followed by the saved kernel context.
+/// - When running in supervisor mode, `sscratch` holds 0, and the trap frame
+///   is pushed onto the current kernel stack.
+///
+/// [`UserContext`]: crate::uspace::UserContext
+#[inline]
+pub fn read_sscratch() -> usize {
+    let val;
+    unsafe { core::arch::asm!("csrr {}, sscratch", out(reg) val) };
+    val
+}
+
+/// Writes the Supervisor Scratch register (`sscratch`).
+///
+/// See [`read_sscratch`] for the convention used by the trap entry.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the trap handling behavior of the
+/// current CPU. Writing a non-zero value while running in supervisor mode
+/// makes the next trap be treated as coming from user mode.
+#[inline]
+pub unsafe fn write_sscratch(val: usize) {
+    unsafe { core::arch::asm!("csrw sscratch, {}", in(reg) val) }
+}
+
 /// Reads the thread pointer of the current CPU (`tp`).
 ///
 /// It is used to implement TLS (Thread Local Storage).
@@ -156,3 +227,38 @@ unsafe extern "C" {
     /// while a value > 0 indicates failure.
     pub fn user_copy(dst: *mut u8, src: *const u8, size: usize) -> usize;
 }
+
+/// Reads a byte from `src`, where the address may be in user space.
+///
+/// The load is annotated in the exception table, so a page fault on it is
+/// recovered by the trap handler, and `Err(())` is returned instead of
+/// panicking.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads from a raw pointer. `src` must
+/// not point to memory-mapped I/O with side effects on reads.
+#[cfg(feature = "uspace")]
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_read_u8(src: *const u8) -> Result<u8, ()> {
+    let val: u32;
+    let err: u32;
+    unsafe {
+        core::arch::asm!(
+            "li {err}, 1",
+            "2: lbu {val}, 0({src})",
+            "li {err}, 0",
+            "3:",
+            crate::ex_table_entry!("2b", "3b"),
+            src = in(reg) src,
+            val = out(reg) val,
+            err = out(reg) err,
+            options(nostack, readonly, preserves_flags),
+        )
+    }
+    if err == 0 {
+        Ok(val as u8)
+    } else {
+        Err(())
+    }
+}
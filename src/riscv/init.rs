@@ -2,7 +2,9 @@
 
 /// Initializes trap handling on the current CPU.
 ///
-/// In detail, it initializes the trap vector on RISC-V platforms.
+/// In detail, it initializes the trap vector on RISC-V platforms. When the
+/// "rv-v" feature is enabled, the vector unit is also initialized, which
+/// requires the `V` extension.
 pub fn init_trap() {
     #[cfg(feature = "uspace")]
     crate::uspace_common::init_exception_table();
@@ -14,4 +16,6 @@ pub fn init_trap() {
         riscv::register::sstatus::set_sum();
         crate::asm::write_trap_vector_base(trap_vector_base as usize);
     }
+    #[cfg(feature = "rv-v")]
+    super::vector::init();
 }
@@ -6,8 +6,15 @@ mod trap;
 
 pub mod asm;
 pub mod init;
+pub mod plic;
+pub mod sbi;
+pub mod timer;
 
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{FpState, GeneralRegisters, TaskContext, TrapFrame};
+#[cfg(feature = "rv-v")]
+pub mod vector;
+
+pub use self::context::{CalleeSavedRegs, FpState, GeneralRegisters, TaskContext, TrapFrame};
+pub use self::trap::emulate_atomic;
@@ -0,0 +1,124 @@
+//! Wrappers of the RISC-V Platform-Level Interrupt Controller (PLIC).
+//!
+//! See the [RISC-V PLIC specification](https://github.com/riscv/riscv-plic-spec)
+//! for details.
+//!
+//! A PLIC context is a privilege mode of a hart. As in QEMU `virt`, each hart
+//! is assumed to have two contexts, so the context `context` of the hart
+//! `hart` has the index `hart * 2 + context`, where the context 0 is M-mode
+//! and 1 is S-mode. This does not hold on all platforms, e.g., on the SiFive
+//! FU540 and FU740, the hart 0 (the monitor core) only has an M-mode
+//! context, so the context indices of the other harts are shifted by one.
+
+/// The offset of the interrupt source priorities.
+const PRIORITY_OFFSET: usize = 0x0;
+/// The offset of the interrupt enable bits of the context 0.
+const ENABLE_OFFSET: usize = 0x2000;
+/// The stride of the interrupt enable bits of each context.
+const ENABLE_STRIDE: usize = 0x80;
+/// The offset of the priority threshold of the context 0.
+const CONTEXT_OFFSET: usize = 0x20_0000;
+/// The stride of the priority threshold and claim/complete registers of each
+/// context.
+const CONTEXT_STRIDE: usize = 0x1000;
+/// The offset of the claim/complete register in the context registers.
+const CLAIM_COMPLETE_OFFSET: usize = 0x4;
+
+/// The number of PLIC contexts of each hart.
+const CONTEXTS_PER_HART: usize = 2;
+/// The number of interrupt sources, where the source 0 is reserved.
+const MAX_SOURCES: u32 = 1024;
+
+/// A PLIC accessed via MMIO.
+#[derive(Debug)]
+pub struct Plic {
+    base: *mut u32,
+}
+
+unsafe impl Send for Plic {}
+unsafe impl Sync for Plic {}
+
+impl Plic {
+    /// Creates a PLIC with the MMIO base address.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as all the methods access the registers at
+    /// `base`. The caller must ensure that `base` is the base address of the
+    /// PLIC registers, mapped as device memory in the kernel address space
+    /// for the lifetime of the returned value.
+    pub const unsafe fn new(base: *mut u32) -> Self {
+        Self { base }
+    }
+
+    /// Returns the register at the given byte offset.
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { self.base.byte_add(offset) }
+    }
+
+    /// Returns the byte offset of the registers of a context.
+    fn context_offset(hart: usize, context: usize) -> usize {
+        CONTEXT_OFFSET + (hart * CONTEXTS_PER_HART + context) * CONTEXT_STRIDE
+    }
+
+    /// Returns the enable register and the bit of `irq` in a context.
+    fn enable_bit(&self, hart: usize, context: usize, irq: u32) -> (*mut u32, u32) {
+        assert!(irq != 0 && irq < MAX_SOURCES, "invalid PLIC source {irq}");
+        let offset = ENABLE_OFFSET
+            + (hart * CONTEXTS_PER_HART + context) * ENABLE_STRIDE
+            + (irq as usize / 32) * 4;
+        (self.reg(offset), 1 << (irq % 32))
+    }
+
+    /// Sets the priority of the interrupt source `irq`, where 0 means never
+    /// interrupt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `irq` is 0 or not less than 1024.
+    pub fn set_priority(&self, irq: u32, priority: u8) {
+        assert!(irq != 0 && irq < MAX_SOURCES, "invalid PLIC source {irq}");
+        let reg = self.reg(PRIORITY_OFFSET + irq as usize * 4);
+        unsafe { reg.write_volatile(priority as u32) };
+    }
+
+    /// Enables the interrupt source `irq` for a context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `irq` is 0 or not less than 1024.
+    pub fn enable(&self, hart: usize, context: usize, irq: u32) {
+        let (reg, bit) = self.enable_bit(hart, context, irq);
+        unsafe { reg.write_volatile(reg.read_volatile() | bit) };
+    }
+
+    /// Disables the interrupt source `irq` for a context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `irq` is 0 or not less than 1024.
+    pub fn disable(&self, hart: usize, context: usize, irq: u32) {
+        let (reg, bit) = self.enable_bit(hart, context, irq);
+        unsafe { reg.write_volatile(reg.read_volatile() & !bit) };
+    }
+
+    /// Sets the priority threshold of a context. Only the interrupts with a
+    /// priority greater than the threshold are delivered to the context.
+    pub fn set_threshold(&self, hart: usize, context: usize, threshold: u8) {
+        let reg = self.reg(Self::context_offset(hart, context));
+        unsafe { reg.write_volatile(threshold as u32) };
+    }
+
+    /// Claims the highest-priority pending interrupt of a context, returns its
+    /// ID, or 0 if there is no pending interrupt.
+    pub fn claim(&self, hart: usize, context: usize) -> u32 {
+        let reg = self.reg(Self::context_offset(hart, context) + CLAIM_COMPLETE_OFFSET);
+        unsafe { reg.read_volatile() }
+    }
+
+    /// Completes the interrupt `irq` claimed by [`Plic::claim`].
+    pub fn complete(&self, hart: usize, context: usize, irq: u32) {
+        let reg = self.reg(Self::context_offset(hart, context) + CLAIM_COMPLETE_OFFSET);
+        unsafe { reg.write_volatile(irq) };
+    }
+}
@@ -0,0 +1,103 @@
+//! Wrappers of the RISC-V Supervisor Binary Interface (SBI) calls.
+//!
+//! See the [RISC-V SBI specification](https://github.com/riscv-non-isa/riscv-sbi-doc)
+//! for details.
+
+/// Timer extension (`TIME`).
+const EID_TIME: usize = 0x5449_4D45;
+/// IPI extension (`sPI`).
+const EID_IPI: usize = 0x73_5049;
+/// RFENCE extension (`RFNC`).
+const EID_RFENCE: usize = 0x5246_4E43;
+/// Hart state management extension (`HSM`).
+const EID_HSM: usize = 0x48_534D;
+/// Legacy console putchar extension.
+const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+
+/// The return value of an SBI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SbiRet {
+    /// The error code, where 0 means success and negative values are errors
+    /// (e.g., -1 for `SBI_ERR_FAILED`, -2 for `SBI_ERR_NOT_SUPPORTED`).
+    pub error: i64,
+    /// The return value on success.
+    pub value: i64,
+}
+
+impl SbiRet {
+    /// Returns whether the call succeeded (`SBI_SUCCESS`).
+    pub const fn is_ok(&self) -> bool {
+        self.error == 0
+    }
+}
+
+/// Makes an SBI call with the extension ID `eid`, the function ID `fid` and
+/// three arguments, via `ECALL`.
+///
+/// # Safety
+///
+/// This function is unsafe as an SBI call may have arbitrary side effects,
+/// e.g., starting a hart at an arbitrary address, or letting the firmware
+/// access the memory at the physical addresses passed as arguments. The
+/// caller must ensure that the call with the given arguments is sound.
+#[inline]
+pub unsafe fn sbi_call(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize) -> SbiRet {
+    let (error, value): (isize, isize);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") a0 => error,
+            inlateout("a1") a1 => value,
+            in("a2") a2,
+            in("a6") fid,
+            in("a7") eid,
+            options(nostack),
+        )
+    };
+    SbiRet {
+        error: error as i64,
+        value: value as i64,
+    }
+}
+
+/// Programs the clock for the next timer event at `stime` (in ticks of the
+/// `time` counter), and clears the pending timer interrupt.
+pub fn set_timer(stime: u64) -> SbiRet {
+    #[cfg(target_arch = "riscv64")]
+    {
+        unsafe { sbi_call(EID_TIME, 0, stime as usize, 0, 0) }
+    }
+    #[cfg(target_arch = "riscv32")]
+    {
+        unsafe { sbi_call(EID_TIME, 0, stime as usize, (stime >> 32) as usize, 0) }
+    }
+}
+
+/// Sends an IPI to the harts in the hart mask, where bit N of `hmask` means
+/// the hart `hbase + N`.
+pub fn send_ipi(hmask: u64, hbase: u64) -> SbiRet {
+    unsafe { sbi_call(EID_IPI, 0, hmask as usize, hbase as usize, 0) }
+}
+
+/// Instructs the harts in the hart mask (see [`send_ipi`]) to execute
+/// `FENCE.I`.
+pub fn remote_fence_i(hmask: u64, hbase: u64) -> SbiRet {
+    unsafe { sbi_call(EID_RFENCE, 0, hmask as usize, hbase as usize, 0) }
+}
+
+/// Starts the hart `hartid` in supervisor mode at the physical address
+/// `start`, with `a0` = `hartid` and `a1` = `opaque`.
+///
+/// # Safety
+///
+/// This function is unsafe as the hart runs the code at `start` with the MMU
+/// off. The caller must ensure that `start` is the physical address of a valid
+/// entry point, and that `opaque` is what that entry point expects.
+pub unsafe fn hart_start(hartid: usize, start: usize, opaque: usize) -> SbiRet {
+    unsafe { sbi_call(EID_HSM, 0, hartid, start, opaque) }
+}
+
+/// Writes a byte to the debug console (legacy extension).
+pub fn console_putchar(c: u8) {
+    unsafe { sbi_call(EID_LEGACY_CONSOLE_PUTCHAR, 0, c as usize, 0, 0) };
+}
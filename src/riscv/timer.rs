@@ -0,0 +1,38 @@
+//! Cycle counter of the current CPU.
+
+/// Reads the cycle counter of the current CPU (`cycle`), which must be
+/// accessible in supervisor mode (i.e., `mcounteren.CY` is set by the
+/// firmware).
+#[cfg(target_arch = "riscv64")]
+#[inline]
+pub fn cycles() -> u64 {
+    let cycles: u64;
+    unsafe { core::arch::asm!("rdcycle {}", out(reg) cycles, options(nomem, nostack)) };
+    cycles
+}
+
+/// Reads the cycle counter of the current CPU (`cycle` and `cycleh`), which
+/// must be accessible in supervisor mode (i.e., `mcounteren.CY` is set by the
+/// firmware).
+#[cfg(target_arch = "riscv32")]
+#[inline]
+pub fn cycles() -> u64 {
+    loop {
+        let (hi, lo, hi2): (u32, u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "rdcycleh {hi}",
+                "rdcycle {lo}",
+                "rdcycleh {hi2}",
+                hi = out(reg) hi,
+                lo = out(reg) lo,
+                hi2 = out(reg) hi2,
+                options(nomem, nostack),
+            )
+        };
+        // retry if the low word overflows between the reads
+        if hi == hi2 {
+            break ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
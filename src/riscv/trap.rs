@@ -1,6 +1,8 @@
+use core::cmp;
+
 use riscv::interrupt::supervisor::{Exception as E, Interrupt as I};
 use riscv::interrupt::Trap;
-#[cfg(feature = "fp-simd")]
+#[cfg(any(feature = "fp-simd", feature = "rv-v"))]
 use riscv::register::sstatus;
 use riscv::register::{scause, stval};
 
@@ -18,6 +20,123 @@ fn handle_breakpoint(sepc: &mut usize) {
     *sepc += 2
 }
 
+/// The major opcode of the `AMO` instructions (including `LR`/`SC`).
+const OPCODE_AMO: u32 = 0b010_1111;
+
+/// The reservation set by the emulated `LR` instruction: `(address, value)`.
+static mut AMO_RESERVATION: Option<(usize, usize)> = None;
+
+/// Emulates the atomic instruction (`LR`/`SC`/`AMO*`) that caused an illegal
+/// instruction exception, for the cores that lack the A extension.
+///
+/// Returns `true` if the instruction at `sepc` has been emulated, in which
+/// case the destination register is updated and `sepc` is advanced to the
+/// next instruction. Returns `false` if the trap is not an illegal instruction
+/// exception or the instruction is not a supported atomic instruction.
+///
+/// The emulation is performed with interrupts disabled (as they are during
+/// trap handling), so it is only atomic on uniprocessor systems. `SC` succeeds
+/// if the address matches that of the last `LR` and the memory still holds the
+/// value loaded by it.
+///
+/// Both `sepc` and the target address must be accessible from the current
+/// privilege level, e.g., `SSTATUS.SUM` must be set to emulate instructions
+/// from user space.
+pub fn emulate_atomic(tf: &mut TrapFrame) -> bool {
+    if !matches!(
+        scause::read().cause().try_into::<I, E>(),
+        Ok(Trap::Exception(E::IllegalInstruction))
+    ) {
+        return false;
+    }
+
+    // The instruction may be only 2-byte aligned if the C extension is present.
+    let insn = unsafe {
+        let ptr = tf.sepc as *const u16;
+        ptr.read() as u32 | (ptr.add(1).read() as u32) << 16
+    };
+    if insn & 0x7f != OPCODE_AMO {
+        return false;
+    }
+    let rd = ((insn >> 7) & 0x1f) as usize;
+    let rs1 = ((insn >> 15) & 0x1f) as usize;
+    let rs2 = ((insn >> 20) & 0x1f) as usize;
+    let funct5 = insn >> 27;
+
+    let size = match (insn >> 12) & 0x7 {
+        0b010 => 4,
+        #[cfg(target_arch = "riscv64")]
+        0b011 => 8,
+        _ => return false,
+    };
+    let addr = tf.regs.get(rs1);
+    if !addr.is_multiple_of(size) {
+        return false;
+    }
+    let src = tf.regs.get(rs2);
+
+    let load = || unsafe {
+        match size {
+            4 => (addr as *const i32).read_volatile() as isize as usize,
+            _ => (addr as *const usize).read_volatile(),
+        }
+    };
+    let store = |val: usize| unsafe {
+        match size {
+            4 => (addr as *mut u32).write_volatile(val as u32),
+            _ => (addr as *mut usize).write_volatile(val),
+        }
+    };
+    // Keys to compare values of the operand size as signed/unsigned integers.
+    let signed = |val: &usize| match size {
+        4 => *val as i32 as isize,
+        _ => *val as isize,
+    };
+    let unsigned = |val: &usize| match size {
+        4 => *val as u32 as usize,
+        _ => *val,
+    };
+
+    let reservation = unsafe { &mut *core::ptr::addr_of_mut!(AMO_RESERVATION) };
+    let result = match funct5 {
+        // LR
+        0b00010 if rs2 == 0 => {
+            let val = load();
+            *reservation = Some((addr, val));
+            val
+        }
+        // SC
+        0b00011 => match reservation.take() {
+            Some((raddr, rval)) if raddr == addr && load() == rval => {
+                store(src);
+                0
+            }
+            _ => 1,
+        },
+        _ => {
+            let old = load();
+            let new = match funct5 {
+                0b00001 => src,                                 // AMOSWAP
+                0b00000 => old.wrapping_add(src),               // AMOADD
+                0b00100 => old ^ src,                           // AMOXOR
+                0b01100 => old & src,                           // AMOAND
+                0b01000 => old | src,                           // AMOOR
+                0b10000 => cmp::min_by_key(old, src, signed),   // AMOMIN
+                0b10100 => cmp::max_by_key(old, src, signed),   // AMOMAX
+                0b11000 => cmp::min_by_key(old, src, unsigned), // AMOMINU
+                0b11100 => cmp::max_by_key(old, src, unsigned), // AMOMAXU
+                _ => return false,
+            };
+            store(new);
+            old
+        }
+    };
+
+    tf.regs.set(rd, result);
+    tf.sepc += 4;
+    true
+}
+
 fn handle_page_fault(tf: &mut TrapFrame, access_flags: PageFaultFlags) {
     let vaddr = va!(stval::read());
     if handle_trap!(PAGE_FAULT, vaddr, access_flags) {
@@ -29,11 +148,11 @@ fn handle_page_fault(tf: &mut TrapFrame, access_flags: PageFaultFlags) {
     }
     core::hint::cold_path();
     panic!(
-        "Unhandled Supervisor Page Fault @ {:#x}, fault_vaddr={:#x} ({:?}):\n{:#x?}\n{}",
+        "Unhandled Supervisor Page Fault @ {:#x}, fault_vaddr={:#x} ({:?}):\n{}\n{}",
         tf.sepc,
         vaddr,
         access_flags,
-        tf,
+        crate::trap::TrapFrameDump(tf),
         tf.backtrace()
     );
 }
@@ -49,26 +168,27 @@ fn riscv_trap_handler(tf: &mut TrapFrame) {
                 handle_page_fault(tf, PageFaultFlags::EXECUTE)
             }
             Trap::Exception(E::Breakpoint) => handle_breakpoint(&mut tf.sepc),
+            Trap::Exception(E::IllegalInstruction) if emulate_atomic(tf) => {}
             Trap::Interrupt(_) => {
                 handle_trap!(IRQ, scause.bits());
             }
             _ => {
                 panic!(
-                    "Unhandled trap {:?} @ {:#x}, stval={:#x}:\n{:#x?}\n{}",
+                    "Unhandled trap {:?} @ {:#x}, stval={:#x}:\n{}\n{}",
                     cause,
                     tf.sepc,
                     stval::read(),
-                    tf,
+                    crate::trap::TrapFrameDump(tf),
                     tf.backtrace()
                 );
             }
         }
     } else {
         panic!(
-            "Unknown trap {:#x?} @ {:#x}:\n{:#x?}\n{}",
+            "Unknown trap {:#x?} @ {:#x}:\n{}\n{}",
             scause.cause(),
             tf.sepc,
-            tf,
+            crate::trap::TrapFrameDump(tf),
             tf.backtrace()
         );
     }
@@ -77,4 +197,9 @@ fn riscv_trap_handler(tf: &mut TrapFrame) {
     // This replaces the assembly-level FS handling workaround
     #[cfg(feature = "fp-simd")]
     tf.sstatus.set_fs(sstatus::read().fs());
+    #[cfg(feature = "rv-v")]
+    {
+        tf.sstatus =
+            sstatus::Sstatus::from_bits(super::vector::with_current_status(tf.sstatus.bits()));
+    }
 }
@@ -16,13 +16,18 @@ use riscv::{
 
 use crate::{trap::PageFaultFlags, GeneralRegisters, TrapFrame};
 
-pub use crate::uspace_common::{ExceptionKind, ReturnReason};
+pub use crate::uspace_common::{probe_read, probe_write, ExceptionKind, ReturnReason, SigStack};
 
 /// Context to enter user space.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct UserContext(TrapFrame);
 
+static_assertions::const_assert_eq!(
+    core::mem::size_of::<UserContext>(),
+    core::mem::size_of::<TrapFrame>()
+);
+
 impl UserContext {
     /// Creates a new context with the given entry point, user stack pointer,
     /// and the argument.
@@ -32,6 +37,12 @@ impl UserContext {
         sstatus.set_sum(true); // enable user memory access in supervisor mode
         #[cfg(feature = "fp-simd")]
         sstatus.set_fs(FS::Initial); // set the FPU to initial state
+        #[cfg(feature = "rv-v")]
+        {
+            // set the vector unit to initial state
+            let bits = sstatus.bits() | (super::vector::VecStatus::Initial as usize) << 9;
+            sstatus = Sstatus::from_bits(bits);
+        }
 
         Self(TrapFrame {
             regs: GeneralRegisters {
@@ -106,6 +117,91 @@ impl DerefMut for UserContext {
     }
 }
 
+/// Machine context in the layout of Linux's `struct sigcontext`, i.e., the
+/// `uc_mcontext` of [`UContext`].
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct MContext {
+    /// The program counter (`sc_regs[0]`) and general registers `x1`..`x31`.
+    pub sc_regs: [usize; 32],
+    /// Floating-point registers, in the layout of `union __riscv_fp_state`.
+    pub sc_fpregs: [u64; 66],
+}
+
+/// User context in the layout of Linux's `struct ucontext`, which is saved in
+/// the signal frame and restored by `rt_sigreturn`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UContext {
+    /// The flags of the context.
+    pub uc_flags: usize,
+    /// The user address of the context to resume, or 0 if none.
+    pub uc_link: usize,
+    /// The signal stack used by the context.
+    pub uc_stack: SigStack,
+    /// The signal mask to restore.
+    pub uc_sigmask: u64,
+    __unused: [u8; 128 - 8],
+    /// The saved registers.
+    pub uc_mcontext: MContext,
+}
+
+#[cfg(target_arch = "riscv64")]
+static_assertions::const_assert_eq!(core::mem::size_of::<MContext>(), 784);
+#[cfg(target_arch = "riscv64")]
+static_assertions::const_assert_eq!(core::mem::offset_of!(UContext, uc_mcontext), 176);
+
+impl TrapFrame {
+    /// Converts the trap frame to [`UContext`].
+    ///
+    /// The fields that are not in the trap frame (e.g., the floating-point
+    /// registers, the signal stack and mask) are set to 0, which can be filled
+    /// by the caller.
+    pub fn to_ucontext(&self) -> UContext {
+        // SAFETY: `GeneralRegisters` consists of 32 `usize` registers.
+        let mut sc_regs =
+            unsafe { core::mem::transmute::<GeneralRegisters, [usize; 32]>(self.regs) };
+        sc_regs[0] = self.sepc;
+        UContext {
+            uc_flags: 0,
+            uc_link: 0,
+            uc_stack: SigStack::default(),
+            uc_sigmask: 0,
+            __unused: [0; 128 - 8],
+            uc_mcontext: MContext {
+                sc_regs,
+                sc_fpregs: [0; 66],
+            },
+        }
+    }
+
+    /// Creates a trap frame from the `uc_mcontext` of [`UContext`].
+    ///
+    /// `sstatus` is not saved in [`UContext`], so it is set to 0. Use
+    /// [`UserContext::set_ucontext`] to keep the current `sstatus`.
+    pub fn from_ucontext(uc: &UContext) -> Self {
+        let mut sc_regs = uc.uc_mcontext.sc_regs;
+        let sepc = core::mem::replace(&mut sc_regs[0], 0);
+        Self {
+            // SAFETY: `GeneralRegisters` consists of 32 `usize` registers.
+            regs: unsafe { core::mem::transmute::<[usize; 32], GeneralRegisters>(sc_regs) },
+            sepc,
+            sstatus: Sstatus::from_bits(0),
+        }
+    }
+}
+
+impl UserContext {
+    /// Updates the user context from the `uc_mcontext` of [`UContext`],
+    /// keeping the current `sstatus`.
+    pub fn set_ucontext(&mut self, uc: &UContext) {
+        self.0 = TrapFrame {
+            sstatus: self.0.sstatus,
+            ..TrapFrame::from_ucontext(uc)
+        };
+    }
+}
+
 /// Information about an exception that occurred in user space.
 #[derive(Debug, Clone, Copy)]
 pub struct ExceptionInfo {
@@ -0,0 +1,264 @@
+//! Vector extension (`V`) support.
+//!
+//! Like the FP states, the vector states are switched with the help of the
+//! `sstatus.VS` field, which the hardware sets to `Dirty` once a vector
+//! register is modified. The states are only saved when they are `Dirty`, and
+//! are not restored if the next task has never used the vector unit (`Off`
+//! or `Initial`).
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The maximum length of a vector register in bytes supported by
+/// [`VecState`] (`VLEN` of 512 bits).
+pub const VLENB_MAX: usize = 64;
+
+/// The `sstatus.VS` field.
+const SSTATUS_VS_MASK: usize = 0b11 << 9;
+const SSTATUS_VS_SHIFT: usize = 9;
+
+/// The length of a vector register in bytes (`vlenb`), set by [`init`].
+static VLENB: AtomicUsize = AtomicUsize::new(0);
+
+/// The status of the vector unit (`sstatus.VS`).
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VecStatus {
+    /// The vector unit is disabled, and vector instructions trap.
+    #[default]
+    Off = 0,
+    /// The vector states are in the initial state.
+    Initial = 1,
+    /// The vector states have not been modified since they were restored.
+    Clean = 2,
+    /// The vector states have been modified.
+    Dirty = 3,
+}
+
+impl VecStatus {
+    const fn from_bits(bits: usize) -> Self {
+        match bits & 0b11 {
+            0 => Self::Off,
+            1 => Self::Initial,
+            2 => Self::Clean,
+            _ => Self::Dirty,
+        }
+    }
+}
+
+/// Reads the status of the vector unit (`sstatus.VS`) of the current CPU.
+#[inline]
+pub fn read_status() -> VecStatus {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus, options(nomem, nostack)) };
+    VecStatus::from_bits(sstatus >> SSTATUS_VS_SHIFT)
+}
+
+/// Sets the status of the vector unit (`sstatus.VS`) of the current CPU.
+///
+/// # Safety
+///
+/// This function is unsafe as setting it to [`VecStatus::Off`] makes the
+/// vector instructions trap.
+#[inline]
+pub unsafe fn set_status(vs: VecStatus) {
+    unsafe {
+        asm!(
+            "csrc sstatus, {mask}",
+            "csrs sstatus, {bits}",
+            mask = in(reg) SSTATUS_VS_MASK,
+            bits = in(reg) (vs as usize) << SSTATUS_VS_SHIFT,
+            options(nomem, nostack)
+        )
+    }
+}
+
+/// Replaces the `VS` field of the raw `sstatus` value with the current one.
+#[inline]
+pub(super) fn with_current_status(sstatus: usize) -> usize {
+    (sstatus & !SSTATUS_VS_MASK) | (read_status() as usize) << SSTATUS_VS_SHIFT
+}
+
+/// Initializes the vector unit on the current CPU.
+///
+/// It records the length of the vector registers (`vlenb`), which must not
+/// exceed [`VLENB_MAX`].
+///
+/// It is called by [`init_trap`](crate::init::init_trap) on each CPU, before
+/// any task is created.
+pub fn init() {
+    unsafe { set_status(VecStatus::Initial) };
+    let vlenb: usize;
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +v",
+            "csrr {}, vlenb",
+            ".option pop",
+            out(reg) vlenb,
+            options(nomem, nostack)
+        )
+    };
+    assert!(vlenb <= VLENB_MAX, "unsupported VLEN: {} bits", vlenb * 8);
+    VLENB.store(vlenb, Ordering::Relaxed);
+    unsafe { set_status(VecStatus::Off) };
+}
+
+/// Returns the length of the vector registers in bytes (`vlenb`), or 0 if
+/// [`init`] has not been called.
+pub fn vlenb() -> usize {
+    VLENB.load(Ordering::Relaxed)
+}
+
+/// Vector registers (`v0..v31`) and vector CSRs of a task.
+///
+/// Each register is stored with the length of [`vlenb`], so only the first
+/// part of the buffer is used if it is shorter than [`VLENB_MAX`].
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct VecState {
+    /// Vector registers (`v0..v31`), each of `vlenb` bytes.
+    pub vregs: [u8; 32 * VLENB_MAX],
+    /// Vector length (`vl`).
+    pub vl: u64,
+    /// Vector data type (`vtype`).
+    pub vtype: u64,
+    /// Vector start index (`vstart`).
+    pub vstart: u64,
+    /// Vector control and status register (`vcsr`).
+    pub vcsr: u64,
+    /// The status of the vector states (`sstatus.VS`) of the task.
+    pub vs: VecStatus,
+}
+
+impl Default for VecState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VecState {
+    /// Creates the vector states of a task which has not used the vector
+    /// unit.
+    pub const fn new() -> Self {
+        Self {
+            vregs: [0; 32 * VLENB_MAX],
+            vl: 0,
+            vtype: 0,
+            vstart: 0,
+            vcsr: 0,
+            vs: VecStatus::Off,
+        }
+    }
+
+    /// Saves the vector states from CPU to this structure.
+    ///
+    /// The vector unit must be enabled (`sstatus.VS` is not `Off`).
+    pub fn save(&mut self) {
+        debug_assert!(vlenb() != 0, "the vector unit is not initialized");
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "csrr {vstart}, vstart",
+                "csrr {vcsr}, vcsr",
+                "csrr {vl}, vl",
+                "csrr {vtype}, vtype",
+                "vs8r.v v0, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vs8r.v v8, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vs8r.v v16, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vs8r.v v24, ({ptr})",
+                ".option pop",
+                ptr = inout(reg) self.vregs.as_mut_ptr() => _,
+                stride = in(reg) 8 * vlenb(),
+                vstart = out(reg) self.vstart,
+                vcsr = out(reg) self.vcsr,
+                vl = out(reg) self.vl,
+                vtype = out(reg) self.vtype,
+                options(nostack)
+            )
+        }
+    }
+
+    /// Restores the vector states from this structure to CPU.
+    ///
+    /// The vector unit must be enabled (`sstatus.VS` is not `Off`).
+    pub fn restore(&self) {
+        debug_assert!(vlenb() != 0, "the vector unit is not initialized");
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "vl8re8.v v0, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vl8re8.v v8, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vl8re8.v v16, ({ptr})",
+                "add {ptr}, {ptr}, {stride}",
+                "vl8re8.v v24, ({ptr})",
+                "vsetvl x0, {vl}, {vtype}",
+                "csrw vstart, {vstart}",
+                "csrw vcsr, {vcsr}",
+                ".option pop",
+                ptr = inout(reg) self.vregs.as_ptr() => _,
+                stride = in(reg) 8 * vlenb(),
+                vl = in(reg) self.vl,
+                vtype = in(reg) self.vtype,
+                vstart = in(reg) self.vstart,
+                vcsr = in(reg) self.vcsr,
+                options(nostack, readonly)
+            )
+        }
+    }
+
+    /// Clears all vector registers and CSRs to zero, so that no states of
+    /// other tasks are leaked.
+    ///
+    /// The vector unit must be enabled (`sstatus.VS` is not `Off`).
+    pub fn clear() {
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "vsetvli {tmp}, x0, e8, m8, ta, ma",
+                "vmv.v.i v0, 0",
+                "vmv.v.i v8, 0",
+                "vmv.v.i v16, 0",
+                "vmv.v.i v24, 0",
+                "csrw vstart, x0",
+                "csrw vcsr, x0",
+                ".option pop",
+                tmp = out(reg) _,
+                options(nomem, nostack)
+            )
+        }
+    }
+
+    /// Handles the vector state switching, in the same way as
+    /// [`FpState::switch_to`](super::FpState::switch_to).
+    ///
+    /// Saves the current task's vector states (if `Dirty`) and restores the
+    /// next task's vector states.
+    pub fn switch_to(&mut self, next: &VecState) {
+        if read_status() == VecStatus::Dirty {
+            self.save();
+            self.vs = VecStatus::Clean;
+        }
+        match next.vs {
+            VecStatus::Clean => {
+                unsafe { set_status(VecStatus::Clean) };
+                next.restore();
+            }
+            VecStatus::Initial => {
+                unsafe { set_status(VecStatus::Initial) };
+                Self::clear();
+            }
+            VecStatus::Off => {}
+            VecStatus::Dirty => unreachable!("vector state of the next task should not be dirty"),
+        }
+        unsafe { set_status(next.vs) };
+    }
+}
@@ -0,0 +1,123 @@
+//! Software interrupts (softirqs), i.e., deferred work of IRQ handlers.
+//!
+//! An IRQ handler can defer the work that does not have to be done in the hard
+//! IRQ context by [`schedule`]-ing a softirq handler registered in
+//! [`SOFTIRQ_HANDLERS`]. The pending softirqs of a CPU are run by
+//! [`run_pending`] at the end of each IRQ on that CPU, before returning to the
+//! interrupted code (e.g., the user space). It is called after the IRQ is
+//! acknowledged (EOI), and runs the handlers with IRQs enabled, so that a
+//! long softirq does not block other interrupts.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use axcpu::softirq::{self, def_softirq_handler, SOFTIRQ_HANDLERS};
+//!
+//! fn net_rx() -> bool {
+//!     // ...
+//!     true
+//! }
+//!
+//! #[def_softirq_handler(SOFTIRQ_HANDLERS)]
+//! static NET_RX: fn() -> bool = net_rx;
+//!
+//! // in the IRQ handler
+//! softirq::schedule(softirq::index_of(net_rx).unwrap());
+//! ```
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub use linkme::distributed_slice as def_softirq_handler;
+
+/// The maximum number of softirq handlers.
+pub const MAX_SOFTIRQS: usize = 64;
+
+/// A slice of softirq handlers, indexed by their positions in the slice.
+///
+/// A handler returns `true` if its work is done, or `false` to be run again on
+/// the next [`run_pending`]. Only the first [`MAX_SOFTIRQS`] handlers can be
+/// scheduled.
+#[def_softirq_handler]
+pub static SOFTIRQ_HANDLERS: [fn() -> bool];
+
+/// The bitmask of pending softirqs of the current CPU, where bit N means the
+/// handler at index N of [`SOFTIRQ_HANDLERS`] is pending.
+#[percpu::def_percpu]
+static SOFTIRQ_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Whether [`run_pending`] is running on the current CPU, so that it is not
+/// reentered from the IRQs taken while running the handlers.
+#[percpu::def_percpu]
+static SOFTIRQ_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The maximum number of rounds of [`run_pending`], to pick up the softirqs
+/// scheduled by the nested IRQs without starving the interrupted code.
+const MAX_ROUNDS: usize = 10;
+
+/// Returns the index of the given handler in [`SOFTIRQ_HANDLERS`], or `None`
+/// if it is not registered.
+///
+/// The order of the handlers is fixed at link time, so the index can be
+/// cached.
+pub fn index_of(handler: fn() -> bool) -> Option<usize> {
+    SOFTIRQ_HANDLERS
+        .iter()
+        .position(|&h| core::ptr::eq(h as *const (), handler as *const ()))
+}
+
+/// Marks the softirq at the given index of [`SOFTIRQ_HANDLERS`] as pending on
+/// the current CPU.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`MAX_SOFTIRQS`].
+pub fn schedule(index: usize) {
+    assert!(index < MAX_SOFTIRQS, "invalid softirq index {index}");
+    SOFTIRQ_PENDING.with_current(|pending| pending.fetch_or(1 << index, Ordering::Relaxed));
+}
+
+/// Returns the bitmask of pending softirqs of the current CPU.
+pub fn pending() -> u64 {
+    SOFTIRQ_PENDING.with_current(|pending| pending.load(Ordering::Relaxed))
+}
+
+/// Runs the pending softirqs of the current CPU in ascending order of their
+/// indices, and clears them.
+///
+/// It must be called at the end of an IRQ after the EOI is sent, with IRQs
+/// disabled. The handlers are run with IRQs enabled, and IRQs are disabled
+/// again on return. If it is already running on the current CPU (i.e., called
+/// from an IRQ nested in a softirq handler), it returns immediately and the
+/// softirqs scheduled by that IRQ are run by the outer call.
+///
+/// Softirqs whose handlers return `false`, and those still scheduled after a
+/// few rounds, are left pending for the next call. Pending indices without a
+/// registered handler are dropped.
+pub fn run_pending() {
+    if SOFTIRQ_RUNNING.with_current(|running| running.swap(true, Ordering::Relaxed)) {
+        return;
+    }
+    crate::asm::enable_irqs();
+    let mut rerun = 0;
+    for _ in 0..MAX_ROUNDS {
+        let mut pending =
+            SOFTIRQ_PENDING.with_current(|pending| pending.swap(0, Ordering::Relaxed));
+        if pending == 0 {
+            break;
+        }
+        while pending != 0 {
+            let index = pending.trailing_zeros() as usize;
+            pending &= pending - 1;
+            if let Some(handler) = SOFTIRQ_HANDLERS.get(index) {
+                if !handler() {
+                    rerun |= 1 << index;
+                }
+            }
+        }
+    }
+    crate::asm::disable_irqs();
+    if rerun != 0 {
+        SOFTIRQ_PENDING.with_current(|pending| pending.fetch_or(rerun, Ordering::Relaxed));
+    }
+    SOFTIRQ_RUNNING.with_current(|running| running.store(false, Ordering::Relaxed));
+}
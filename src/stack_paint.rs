@@ -0,0 +1,55 @@
+//! Kernel stack painting, to measure the worst-case stack usage of tasks.
+
+/// The pattern filled into the unused kernel stack by `paint_stack` of
+/// [`TaskContext`](crate::TaskContext).
+pub const STACK_PAINT_PATTERN: u64 = 0xcafe_babe_cafe_babe;
+
+/// Fills the 8-byte words in `[start, end)` with [`STACK_PAINT_PATTERN`].
+///
+/// # Safety
+///
+/// The range must be writable memory that is not in use.
+pub(crate) unsafe fn paint(start: usize, end: usize) {
+    for addr in (start.next_multiple_of(8)..end & !7).step_by(8) {
+        unsafe { (addr as *mut u64).write_volatile(STACK_PAINT_PATTERN) };
+    }
+}
+
+/// Returns the address of the lowest 8-byte word in `[start, end)` that
+/// differs from [`STACK_PAINT_PATTERN`], or `end` if all of them are intact.
+///
+/// # Safety
+///
+/// The range must be readable memory.
+pub(crate) unsafe fn first_unpainted(start: usize, end: usize) -> usize {
+    (start.next_multiple_of(8)..end & !7)
+        .step_by(8)
+        .find(|&addr| unsafe { (addr as *const u64).read_volatile() } != STACK_PAINT_PATTERN)
+        .unwrap_or(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paint_and_measure() {
+        let mut stack = [0u64; 64];
+        let base = stack.as_mut_ptr();
+        let start = base as usize;
+        let end = start + size_of_val(&stack);
+        unsafe { paint(start, end) };
+        assert!(stack.iter().all(|&w| w == STACK_PAINT_PATTERN));
+        assert_eq!(unsafe { first_unpainted(start, end) }, end);
+
+        // the stack grows downwards from `end`
+        unsafe { base.add(40).write_volatile(0) };
+        assert_eq!(unsafe { first_unpainted(start, end) }, start + 40 * 8);
+        unsafe { base.add(3).write_volatile(0) };
+        assert_eq!(unsafe { first_unpainted(start, end) }, start + 3 * 8);
+        assert_eq!(
+            unsafe { first_unpainted(start + 4 * 8, end) },
+            start + 40 * 8
+        );
+    }
+}
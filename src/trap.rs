@@ -1,32 +1,436 @@
 //! Trap handling.
 
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
 use memory_addr::VirtAddr;
 
+pub use crate::asm::{disable_irqs, enable_irqs, irqs_enabled};
 pub use crate::TrapFrame;
 pub use linkme::distributed_slice as def_trap_handler;
 pub use linkme::distributed_slice as register_trap_handler;
 pub use page_table_entry::MappingFlags as PageFaultFlags;
 
-/// A slice of IRQ handler functions.
+/// Architecture-specific [`PageFaultFlags`] that are not mapping flags.
+///
+/// Since [`PageFaultFlags`] is defined by the `page_table_entry` crate, these
+/// flags are kept out of its known bits (i.e., they are removed by
+/// `from_bits_truncate` and `complement`).
+pub trait PageFaultFlagsExt {
+    /// The fault is a protection key violation (x86_64 `PK`), i.e., the access
+    /// is denied by the protection key rights of the page (`PKRU`), rather
+    /// than by its mapping flags.
+    const PROTECTION_KEY: Self;
+}
+
+impl PageFaultFlagsExt for PageFaultFlags {
+    const PROTECTION_KEY: Self = Self::from_bits_retain(1 << 8);
+}
+
+/// The privilege level that a trap is taken from, see
+/// [`TrapFrame::privilege_level`].
+///
+/// It is determined from the state saved on trap entry, so it does not change
+/// during the trap handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeLevel {
+    /// The kernel (x86_64 ring 0, AArch64 EL1, RISC-V S-mode, LoongArch PLV0).
+    Kernel,
+    /// The user space (x86_64 ring 3, AArch64 EL0, RISC-V U-mode, LoongArch
+    /// PLV3).
+    User,
+}
+
+/// The result of an IRQ handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    /// The IRQ is handled, and the handler has sent the EOI.
+    Handled,
+    /// The IRQ is handled, and the dispatcher must send the EOI to the local
+    /// APIC by `irq::send_eoi`.
+    ///
+    /// It is only available on x86_64. On other architectures, the IRQ number
+    /// passed to the handlers is not the interrupt ID acknowledged from the
+    /// interrupt controller, so the handlers must send the EOI by themselves
+    /// (e.g., by `irq::send_eoi` on AArch64) and return
+    /// [`Handled`](Self::Handled).
+    #[cfg(target_arch = "x86_64")]
+    HandledNeedsEoi,
+    /// The IRQ is not handled.
+    Unhandled,
+}
+
+/// The result of a trap handler, see [`TrapHandlerEntry`].
+pub trait TrapResult: Copy {
+    /// The result if the trap is not handled by any handler.
+    const UNHANDLED: Self;
+
+    /// Returns whether the trap is handled.
+    fn is_handled(self) -> bool;
+}
+
+impl TrapResult for bool {
+    const UNHANDLED: Self = false;
+
+    fn is_handled(self) -> bool {
+        self
+    }
+}
+
+impl TrapResult for IrqResult {
+    const UNHANDLED: Self = Self::Unhandled;
+
+    fn is_handled(self) -> bool {
+        self != Self::Unhandled
+    }
+}
+
+/// A trap handler with a priority.
+///
+/// Multiple handlers can be registered for the same trap. They are called in
+/// descending order of [`priority`] (handlers with the same priority are
+/// called in an unspecified but fixed order), until one of them returns a
+/// handled result (e.g., `true`).
+///
+/// # Example
+///
+/// ```ignore
+/// use axcpu::trap::{register_trap_handler, IrqResult, TrapHandlerEntry, IRQ};
+///
+/// fn handle_irq(irq: usize) -> IrqResult {
+///     // ...
+///     IrqResult::HandledNeedsEoi
+/// }
+///
+/// #[register_trap_handler(IRQ)]
+/// static IRQ_HANDLER: TrapHandlerEntry<usize, IrqResult> =
+///     TrapHandlerEntry::new(0, handle_irq);
+/// ```
+///
+/// [`priority`]: TrapHandlerEntry::priority
+#[derive(Debug)]
+pub struct TrapHandlerEntry<A, R = bool> {
+    /// The priority of the handler. Handlers with higher priorities are called
+    /// first.
+    pub priority: u8,
+    /// The handler function. Returns whether the trap is handled.
+    pub handler: fn(A) -> R,
+}
+
+impl<A, R> TrapHandlerEntry<A, R> {
+    /// Creates a new trap handler entry with the given priority.
+    pub const fn new(priority: u8, handler: fn(A) -> R) -> Self {
+        Self { priority, handler }
+    }
+}
+
+/// A slice of IRQ handlers.
+///
+/// On x86_64, the EOI is sent by the dispatcher if a handler returns
+/// `IrqResult::HandledNeedsEoi`.
+#[def_trap_handler]
+pub static IRQ: [TrapHandlerEntry<usize, IrqResult>];
+
+/// A slice of page fault handlers.
+#[def_trap_handler]
+pub static PAGE_FAULT: [TrapHandlerEntry<(VirtAddr, PageFaultFlags)>];
+
+/// A slice of non-maskable interrupt (NMI) handler functions.
+///
+/// The handlers are called in order until one of them returns `true`. They
+/// run in the NMI context, so they must not take locks that may be held by the
+/// interrupted code.
+#[def_trap_handler]
+pub static NMI_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// A slice of single-step exception handler functions.
+///
+/// The handlers are called in order until one of them returns `true`, after a
+/// single-step exception enabled by [`TrapFrame::enable_single_step`], from
+/// either the kernel or the user space (in `UserContext::run`).
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[def_trap_handler]
+pub static STEP_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// A slice of divide error (`#DE`) handler functions.
+///
+/// The handlers are called in order until one of them returns `true`, for the
+/// divide errors (division by zero or quotient overflow) in the kernel.
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static DIVIDE_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// A slice of overflow exception (`#OF`, raised by `INTO`) handler functions.
+///
+/// The handlers are called in order until one of them returns `true`.
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static OVERFLOW_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// A slice of general protection fault (`#GP`) handler functions.
+///
+/// The handlers are called in order with the trap frame and the decoded error
+/// code until one of them returns `true`.
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static GP_HANDLER: [fn(&mut TrapFrame, crate::exception::GpErrorCode) -> bool];
+
+/// A slice of alignment check exception (`#AC`) handler functions.
+///
+/// The handlers are called in order until one of them returns `true`, for
+/// the exceptions from both the kernel and the user space (in
+/// `UserContext::run`).
+#[cfg(target_arch = "x86_64")]
+#[def_trap_handler]
+pub static ALIGNMENT_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// A slice of SIMD floating-point exception handler functions (x86_64 `#XF`,
+/// AArch64 trapped floating-point exceptions).
+///
+/// The handlers are called in order with the trap frame and the floating-point
+/// status (`MXCSR` on x86_64, `FPSR` on AArch64) until one of them returns
+/// `true`. On x86_64, the exceptions from both the kernel and the user space
+/// (in `UserContext::run`) are routed to the handlers; on AArch64, only those
+/// from the user space are.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[def_trap_handler]
+pub static SIMD_FP_HANDLER: [fn(&mut TrapFrame, u32) -> bool];
+
+/// A slice of SError (System Error) handler functions.
+///
+/// The handlers are called in order with the trap frame and the value of
+/// `ESR_EL1` until one of them returns `true`. SErrors are asynchronous
+/// aborts (e.g., uncorrectable ECC errors), so the trap frame may not point
+/// to the instruction that caused the error.
+#[cfg(target_arch = "aarch64")]
+#[def_trap_handler]
+pub static SERROR_HANDLER: [fn(&TrapFrame, u64) -> bool];
+
+/// A slice of EL2 trap handler functions, for synchronous exceptions taken
+/// from lower ELs to EL2.
+///
+/// The handlers are called in order with the trap frame and the syndrome
+/// (`ESR_EL2`) until one of them returns `true`.
+#[cfg(all(feature = "arm-el2", target_arch = "aarch64"))]
 #[def_trap_handler]
-pub static IRQ: [fn(usize) -> bool];
+pub static EL2_TRAP_HANDLER: [fn(&mut TrapFrame, u64) -> bool];
 
-/// A slice of page fault handler functions.
+/// A slice of inter-processor interrupt (IPI) handler functions.
+///
+/// The handlers are called in order for each pending request until one of
+/// them returns `true`.
+#[cfg(all(feature = "ipi", any(target_arch = "x86_64", target_arch = "aarch64")))]
 #[def_trap_handler]
-pub static PAGE_FAULT: [fn(VirtAddr, PageFaultFlags) -> bool];
+pub static IPI_HANDLER: [fn(crate::ipi::IpiKind) -> bool];
+
+/// The maximum number of handlers registered for a trap.
+const MAX_TRAP_HANDLERS: usize = 64;
+
+/// The cached calling order of the handlers of a trap, built on the first
+/// dispatch.
+///
+/// The handler slices are static and assembled by the linker, so they cannot
+/// be sorted in place. Instead, the indices of the handlers in descending
+/// order of priority are computed once and cached here.
+#[doc(hidden)]
+pub struct HandlerOrder {
+    /// The number of handlers, or [`HandlerOrder::UNINIT`] if not built yet.
+    len: AtomicUsize,
+    order: [AtomicU8; MAX_TRAP_HANDLERS],
+}
+
+impl HandlerOrder {
+    const UNINIT: usize = usize::MAX;
+
+    /// Creates an empty order, which is built on the first [`dispatch_trap`].
+    pub const fn new() -> Self {
+        Self {
+            len: AtomicUsize::new(Self::UNINIT),
+            order: [const { AtomicU8::new(0) }; MAX_TRAP_HANDLERS],
+        }
+    }
+
+    /// Returns the indices of `handlers` in calling order, building it on the
+    /// first call.
+    ///
+    /// Concurrent builders (e.g., on different CPUs) compute the same order,
+    /// so no lock is needed.
+    fn get<A, R>(&self, handlers: &[TrapHandlerEntry<A, R>]) -> ([u8; MAX_TRAP_HANDLERS], usize) {
+        let mut order = [0; MAX_TRAP_HANDLERS];
+        let len = self.len.load(Ordering::Acquire);
+        if len != Self::UNINIT {
+            for (dst, src) in order.iter_mut().zip(&self.order[..len]) {
+                *dst = src.load(Ordering::Relaxed);
+            }
+            return (order, len);
+        }
+
+        let len = handlers.len();
+        assert!(
+            len <= MAX_TRAP_HANDLERS,
+            "too many trap handlers: {len} > {MAX_TRAP_HANDLERS}"
+        );
+        for (idx, dst) in order[..len].iter_mut().enumerate() {
+            *dst = idx as u8;
+        }
+        // higher priority first, then lower index
+        order[..len].sort_unstable_by_key(|&idx| (u8::MAX - handlers[idx as usize].priority, idx));
+        for (dst, &src) in self.order.iter().zip(&order[..len]) {
+            dst.store(src, Ordering::Relaxed);
+        }
+        self.len.store(len, Ordering::Release);
+        (order, len)
+    }
+}
+
+/// Calls the handlers in descending order of priority until one of them
+/// returns a handled result.
+///
+/// Returns `None` if no handler is registered, otherwise returns the result of
+/// the handler that handles the trap, or [`TrapResult::UNHANDLED`].
+///
+/// `order` caches the calling order of `handlers`, so it must always be used
+/// with the same handler slice.
+///
+/// # Panics
+///
+/// Panics if more than 64 handlers are registered.
+#[doc(hidden)]
+pub fn dispatch_trap<A: Copy, R: TrapResult>(
+    handlers: &[TrapHandlerEntry<A, R>],
+    order: &HandlerOrder,
+    arg: A,
+) -> Option<R> {
+    if handlers.is_empty() {
+        return None;
+    }
+    let (order, len) = order.get(handlers);
+    for &idx in &order[..len] {
+        let result = (handlers[idx as usize].handler)(arg);
+        if result.is_handled() {
+            return Some(result);
+        }
+    }
+    Some(R::UNHANDLED)
+}
 
 #[allow(unused_macros)]
 macro_rules! handle_trap {
-    ($trap:ident, $($args:tt)*) => {{
-        let mut iter = $crate::trap::$trap.iter();
-        if let Some(func) = iter.next() {
-            if iter.next().is_some() {
-                warn!("Multiple handlers for trap {} are not currently supported", stringify!($trap));
-            }
-            func($($args)*)
+    (@dispatch $trap:ident, $arg:expr) => {{
+        static ORDER: $crate::trap::HandlerOrder = $crate::trap::HandlerOrder::new();
+        if let Some(result) = $crate::trap::dispatch_trap(&$crate::trap::$trap, &ORDER, $arg) {
+            result
         } else {
             warn!("No registered handler for trap {}", stringify!($trap));
-            false
+            $crate::trap::TrapResult::UNHANDLED
+        }
+    }};
+    (IRQ, $irq:expr) => {{
+        #[allow(unused_variables)]
+        let irq: usize = $irq;
+        let result: $crate::trap::IrqResult = handle_trap!(@dispatch IRQ, irq);
+        #[cfg(target_arch = "x86_64")]
+        if result == $crate::trap::IrqResult::HandledNeedsEoi {
+            $crate::irq::send_eoi(irq as _);
+        }
+        $crate::trap::TrapResult::is_handled(result)
+    }};
+    ($trap:ident, $arg:expr) => {
+        $crate::trap::TrapResult::is_handled(handle_trap!(@dispatch $trap, $arg))
+    };
+    ($trap:ident, $($args:expr),+) => {
+        $crate::trap::TrapResult::is_handled(handle_trap!(@dispatch $trap, ($($args),+)))
+    };
+}
+
+/// Returns whether `addr` is in the kernel text section.
+///
+/// The bounds are given by the `_stext` and `_etext` symbols, which must be
+/// defined by the linker script of the kernel to the start and end of its
+/// text section (see the "Linker Symbols" section of the README).
+#[allow(dead_code)]
+pub(crate) fn is_kernel_text(addr: usize) -> bool {
+    unsafe extern "C" {
+        static _stext: u8;
+        static _etext: u8;
+    }
+    let (start, end) = (&raw const _stext as usize, &raw const _etext as usize);
+    (start..end).contains(&addr)
+}
+
+/// Writes the named register values to `w`, four registers per line.
+///
+/// Used by the architecture-specific `TrapFrame::dump`.
+pub(crate) fn dump_regs<W: core::fmt::Write>(
+    w: &mut W,
+    regs: &[(&str, usize)],
+) -> core::fmt::Result {
+    const COLUMNS: usize = 4;
+    const VALUE_WIDTH: usize = 2 + 2 * core::mem::size_of::<usize>();
+    let name_width = regs.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (i, (name, value)) in regs.iter().enumerate() {
+        if i % COLUMNS != 0 {
+            w.write_str("  ")?;
+        } else if i != 0 {
+            w.write_char('\n')?;
         }
-    }}
+        write!(w, "{name:>name_width$}={value:#0VALUE_WIDTH$x}")?;
+    }
+    Ok(())
+}
+
+/// Displays a [`TrapFrame`] with [`TrapFrame::dump`], used in panic messages.
+pub(crate) struct TrapFrameDump<'a>(pub &'a TrapFrame);
+
+impl core::fmt::Display for TrapFrameDump<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.dump(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The handlers called by a dispatch, where the handler `target` handles
+    /// the trap.
+    #[derive(Default)]
+    struct Calls {
+        called: [usize; 3],
+        len: usize,
+        target: usize,
+    }
+
+    fn handler<const ID: usize>(calls: *mut Calls) -> bool {
+        let calls = unsafe { &mut *calls };
+        calls.called[calls.len] = ID;
+        calls.len += 1;
+        calls.target == ID
+    }
+
+    #[test]
+    fn dispatch_by_priority() {
+        static HANDLERS: [TrapHandlerEntry<*mut Calls>; 3] = [
+            TrapHandlerEntry::new(1, handler::<1>),
+            TrapHandlerEntry::new(5, handler::<2>),
+            TrapHandlerEntry::new(1, handler::<3>),
+        ];
+        let order = HandlerOrder::new();
+        let mut calls = Calls::default();
+        assert_eq!(
+            dispatch_trap(&HANDLERS, &order, &raw mut calls),
+            Some(false)
+        );
+        // higher priority first, then in declaration order
+        assert_eq!(calls.called[..calls.len], [2, 1, 3]);
+        // the cached order is used, and the dispatch stops at the handler
+        let mut calls = Calls {
+            target: 1,
+            ..Default::default()
+        };
+        assert_eq!(dispatch_trap(&HANDLERS, &order, &raw mut calls), Some(true));
+        assert_eq!(calls.called[..calls.len], [2, 1]);
+
+        let empty: [TrapHandlerEntry<usize, IrqResult>; 0] = [];
+        assert_eq!(dispatch_trap(&empty, &HandlerOrder::new(), 0), None);
+    }
 }
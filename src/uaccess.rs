@@ -0,0 +1,77 @@
+//! Copying data between the kernel and the user space.
+//!
+//! The memory accesses of the copies are annotated in the exception table, so
+//! a page fault that cannot be handled in the middle of a copy stops it and
+//! returns the number of bytes not copied, instead of panicking. Since the
+//! exception table is prepared by [`init_trap`], these functions must not be
+//! called before it.
+//!
+//! [`init_trap`]: crate::init::init_trap
+
+use crate::asm::user_copy;
+
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), usize> {
+    debug_assert!(
+        crate::uspace_common::exception_table_ready(),
+        "user access before `init_trap`"
+    );
+    match unsafe { user_copy(dst, src, len) } {
+        0 => Ok(()),
+        remaining => Err(remaining),
+    }
+}
+
+/// Copies `len` bytes from the user address `src` to the kernel address `dst`.
+///
+/// Returns `Err(remaining)` with the number of bytes not copied if a page
+/// fault on `src` cannot be handled.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `len` bytes. `src` is not checked to be
+/// a user address, which is the responsibility of the caller.
+pub unsafe fn copy_from_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), usize> {
+    copy(dst, src, len)
+}
+
+/// Copies `len` bytes from the kernel address `src` to the user address `dst`.
+///
+/// Returns `Err(remaining)` with the number of bytes not copied if a page
+/// fault on `dst` cannot be handled.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `len` bytes. `dst` is not checked to be a
+/// user address, which is the responsibility of the caller.
+pub unsafe fn copy_to_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), usize> {
+    copy(dst, src, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_from_user, copy_to_user};
+
+    #[test]
+    fn copy_lengths() {
+        crate::uspace_common::assume_exception_table_ready();
+        let src: [u8; 4097] = core::array::from_fn(|i| i as u8);
+        for len in [0, 1, 7, 4096, 4097] {
+            let mut dst = [0xff_u8; 4098];
+            assert_eq!(
+                unsafe { copy_from_user(dst.as_mut_ptr(), src.as_ptr(), len) },
+                Ok(())
+            );
+            assert_eq!(dst[..len], src[..len]);
+            // no byte after `len` is written
+            assert!(dst[len..].iter().all(|&b| b == 0xff));
+
+            let mut dst = [0xff_u8; 4098];
+            assert_eq!(
+                unsafe { copy_to_user(dst.as_mut_ptr(), src.as_ptr(), len) },
+                Ok(())
+            );
+            assert_eq!(dst[..len], src[..len]);
+            assert!(dst[len..].iter().all(|&b| b == 0xff));
+        }
+    }
+}
@@ -1,6 +1,13 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use memory_addr::VirtAddr;
 
-use crate::{trap::PageFaultFlags, uspace::ExceptionInfo, TrapFrame};
+use crate::{
+    trap::PageFaultFlags,
+    uspace::{ExceptionInfo, UserContext},
+    TrapFrame,
+};
 
 /// A reason as to why the control of the CPU is returned from
 /// the user space to the kernel.
@@ -18,6 +25,18 @@ pub enum ReturnReason {
     Unknown,
 }
 
+impl fmt::Display for ReturnReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interrupt => write!(f, "Interrupt"),
+            Self::Syscall => write!(f, "Syscall"),
+            Self::PageFault(vaddr, flags) => write!(f, "Page fault @ {vaddr:#x} ({flags:?})"),
+            Self::Exception(info) => write!(f, "Exception: {}", info.kind()),
+            Self::Unknown => write!(f, "Unknown exit"),
+        }
+    }
+}
+
 /// A generalized kind for [`ExceptionInfo`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExceptionKind {
@@ -27,10 +46,263 @@ pub enum ExceptionKind {
     IllegalInstruction,
     /// A misaligned access exception.
     Misaligned,
+    /// A branch target exception, i.e., an indirect branch to an instruction
+    /// that is not a valid branch target (e.g., AArch64 BTI).
+    BranchTargetFault,
+    /// An integer division by zero (or division overflow).
+    DivisionByZero,
+    /// An arithmetic overflow detected by an overflow check instruction
+    /// (e.g., x86 `INTO`).
+    ArithmeticOverflow,
+    /// An out-of-bounds index detected by a bound check instruction (e.g., x86
+    /// `BOUND`).
+    BoundRangeExceeded,
+    /// A floating-point or SIMD floating-point exception.
+    FloatingPoint,
+    /// A fault on the stack (e.g., x86 stack-segment fault).
+    StackOverflow,
+    /// A system error, i.e., an asynchronous abort (e.g., AArch64 SError).
+    SystemError,
+    /// A protection fault, with the architecture-specific error code.
+    ProtectionFault {
+        /// The error code of the fault.
+        error_code: u64,
+    },
     /// Other kinds of exceptions.
     Other,
 }
 
+impl ExceptionKind {
+    /// Returns the POSIX signal number that is conventionally delivered to
+    /// the user program for this kind of exception.
+    pub const fn to_posix_signal(&self) -> u32 {
+        const SIGILL: u32 = 4;
+        const SIGTRAP: u32 = 5;
+        const SIGBUS: u32 = 7;
+        const SIGFPE: u32 = 8;
+        const SIGSEGV: u32 = 11;
+        match self {
+            Self::Breakpoint => SIGTRAP,
+            Self::IllegalInstruction | Self::BranchTargetFault => SIGILL,
+            Self::Misaligned | Self::StackOverflow | Self::SystemError => SIGBUS,
+            Self::DivisionByZero | Self::ArithmeticOverflow | Self::FloatingPoint => SIGFPE,
+            Self::BoundRangeExceeded | Self::ProtectionFault { .. } | Self::Other => SIGSEGV,
+        }
+    }
+}
+
+impl fmt::Display for ExceptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Breakpoint => write!(f, "breakpoint"),
+            Self::IllegalInstruction => write!(f, "illegal instruction"),
+            Self::Misaligned => write!(f, "misaligned access"),
+            Self::BranchTargetFault => write!(f, "branch target fault"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            Self::BoundRangeExceeded => write!(f, "bound range exceeded"),
+            Self::FloatingPoint => write!(f, "floating-point exception"),
+            Self::StackOverflow => write!(f, "stack fault"),
+            Self::SystemError => write!(f, "system error"),
+            Self::ProtectionFault { error_code } => {
+                write!(f, "protection fault (error code {error_code:#x})")
+            }
+            Self::Other => write!(f, "other exception"),
+        }
+    }
+}
+
+/// An error when pushing or popping a signal frame on the user stack.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalFrameError {
+    /// The user stack pointer is misaligned, out of the user address space, or
+    /// the stack is not accessible (i.e., page faults).
+    BadStack,
+    /// The saved frame is invalid, e.g., it would return to a privileged mode.
+    BadFrame,
+}
+
+/// A signal stack, in the layout of Linux's `stack_t`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SigStack {
+    /// The base address of the stack.
+    pub ss_sp: usize,
+    /// The flags of the stack (e.g., `SS_ONSTACK`, `SS_DISABLE`).
+    pub ss_flags: i32,
+    /// The size of the stack in bytes.
+    pub ss_size: usize,
+}
+
+impl UserContext {
+    /// Sets the return value of a successful syscall.
+    pub fn set_syscall_ok(&mut self, value: usize) {
+        self.set_retval(value);
+    }
+
+    /// Sets the return value of a failed syscall.
+    ///
+    /// Following the Linux convention on all supported architectures, the
+    /// return value register is set to the negated `errno`, so that the user
+    /// space can distinguish errors by checking if the value is in the range of
+    /// `-4095..0`.
+    pub fn set_syscall_err(&mut self, errno: i32) {
+        self.set_retval(-(errno as isize) as usize);
+    }
+}
+
+/// Generates the assembly that emits an exception table entry.
+///
+/// The result is a string literal which can be used as (part of) the template
+/// of [`asm!`] or [`global_asm!`]. `$from` is the address of the instruction
+/// that may fault, and `$to` is the address to jump to when it faults. Both are
+/// string literals of assembly symbols or local labels (e.g., `"1b"`).
+///
+/// [`asm!`]: core::arch::asm
+/// [`global_asm!`]: core::arch::global_asm
+#[cfg(target_pointer_width = "64")]
+#[macro_export]
+macro_rules! ex_table_entry {
+    ($from:literal, $to:literal) => {
+        concat!(
+            ".pushsection __ex_table, \"a\"\n",
+            ".balign 8\n",
+            ".quad ",
+            $from,
+            "\n",
+            ".quad ",
+            $to,
+            "\n",
+            ".popsection\n",
+        )
+    };
+}
+
+/// Generates the assembly that emits an exception table entry.
+///
+/// The result is a string literal which can be used as (part of) the template
+/// of [`asm!`] or [`global_asm!`]. `$from` is the address of the instruction
+/// that may fault, and `$to` is the address to jump to when it faults. Both are
+/// string literals of assembly symbols or local labels (e.g., `"1b"`).
+///
+/// [`asm!`]: core::arch::asm
+/// [`global_asm!`]: core::arch::global_asm
+#[cfg(target_pointer_width = "32")]
+#[macro_export]
+macro_rules! ex_table_entry {
+    ($from:literal, $to:literal) => {
+        concat!(
+            ".pushsection __ex_table, \"a\"\n",
+            ".balign 4\n",
+            ".word ",
+            $from,
+            "\n",
+            ".word ",
+            $to,
+            "\n",
+            ".popsection\n",
+        )
+    };
+}
+
+/// Registers an exception table entry for global assembly symbols.
+///
+/// When the instruction at symbol `$from` faults in the kernel (e.g., a page
+/// fault on a user address), the trap handler resumes the execution at symbol
+/// `$to` instead of panicking.
+///
+/// The entries are sorted by [`init_trap`] and searched on faults, so they take
+/// effect only after [`init_trap`] is called.
+///
+/// For local labels in inline assembly, use [`ex_table_entry!`] in the `asm!`
+/// template instead.
+///
+/// [`init_trap`]: crate::init::init_trap
+///
+/// # Example
+///
+/// ```ignore
+/// axcpu::register_fixup!("my_faultable_load", "my_fixup");
+/// ```
+#[macro_export]
+macro_rules! register_fixup {
+    ($from:literal, $to:literal) => {
+        core::arch::global_asm!($crate::ex_table_entry!($from, $to));
+    };
+}
+
+/// Reads a value from `src`, where the address may be in user space.
+///
+/// Returns `Err(())` if a page fault on `src` cannot be handled, instead of
+/// panicking.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads from a raw pointer. `src` must
+/// not point to memory-mapped I/O with side effects on reads, and any bit
+/// pattern must be a valid value of `T`.
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_read<T: Copy>(src: *const T) -> Result<T, ()> {
+    let mut val = core::mem::MaybeUninit::<T>::uninit();
+    let size = core::mem::size_of::<T>();
+    match unsafe { crate::uaccess::copy_from_user(val.as_mut_ptr().cast(), src.cast(), size) } {
+        Ok(()) => Ok(unsafe { val.assume_init() }),
+        Err(_) => Err(()),
+    }
+}
+
+/// Writes a value to `dst`, where the address may be in user space.
+///
+/// Returns `Err(())` if a page fault on `dst` cannot be handled, instead of
+/// panicking. The value may be partially written on failure.
+///
+/// # Safety
+///
+/// This function is unsafe because it writes to a raw pointer. `dst` must not
+/// point to memory owned by the kernel.
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_write<T: Copy>(dst: *mut T, val: T) -> Result<(), ()> {
+    let size = core::mem::size_of::<T>();
+    unsafe { crate::uaccess::copy_to_user(dst.cast(), (&raw const val).cast(), size) }
+        .map_err(|_| ())
+}
+
+/// Accesses memory that may be in user space, and evaluates `$on_fault`
+/// instead of panicking if the access page faults.
+///
+/// It supports a load (`*ptr`), which evaluates to the loaded value, and a
+/// store (`*ptr = value`). `ptr` must be an identifier or a parenthesized
+/// expression, and `$on_fault` can diverge (e.g., `return Err(EFAULT)`).
+///
+/// It must be used in an `unsafe` context, as the requirements of
+/// [`probe_read`] and [`probe_write`] apply.
+///
+/// [`probe_read`]: crate::uspace::probe_read
+/// [`probe_write`]: crate::uspace::probe_write
+///
+/// # Example
+///
+/// ```ignore
+/// let val: u32 = unsafe { axcpu::with_user_access!(*uptr, return Err(EFAULT)) };
+/// unsafe { axcpu::with_user_access!(*uptr = val + 1, return Err(EFAULT)) };
+/// ```
+#[macro_export]
+macro_rules! with_user_access {
+    (*$ptr:tt = $val:expr, $on_fault:expr) => {
+        match $crate::uspace::probe_write($ptr, $val) {
+            Ok(()) => (),
+            Err(()) => $on_fault,
+        }
+    };
+    (*$ptr:tt, $on_fault:expr) => {
+        match $crate::uspace::probe_read($ptr) {
+            Ok(val) => val,
+            Err(()) => $on_fault,
+        }
+    };
+}
+
 #[repr(C)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 struct ExceptionTableEntry {
@@ -53,17 +325,45 @@ impl TrapFrame {
                     .offset_from_unsigned(_ex_table_start.as_ptr()),
             )
         };
-        match entries.binary_search_by(|e| e.from.cmp(&self.ip())) {
-            Ok(entry) => {
-                self.set_ip(entries[entry].to);
+        match ExceptionTableEntry::search(entries, self.ip()) {
+            Some(to) => {
+                self.set_ip(to);
                 true
             }
-            Err(_) => false,
+            None => false,
         }
     }
 }
 
+impl ExceptionTableEntry {
+    /// Returns the fixup address of the faulting instruction at `ip`, in the
+    /// exception table `entries` sorted by [`init_exception_table`].
+    fn search(entries: &[Self], ip: usize) -> Option<usize> {
+        entries
+            .binary_search_by(|e| e.from.cmp(&ip))
+            .ok()
+            .map(|idx| entries[idx].to)
+    }
+}
+
+/// Whether the exception table is sorted by [`init_exception_table`].
+static EX_TABLE_READY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn exception_table_ready() -> bool {
+    EX_TABLE_READY.load(Ordering::Acquire)
+}
+
+/// Marks the exception table as ready without sorting it, as the host test
+/// binary has no `_ex_table_start` and `_ex_table_end` symbols.
+#[cfg(test)]
+pub(crate) fn assume_exception_table_ready() {
+    EX_TABLE_READY.store(true, Ordering::Release);
+}
+
 pub(crate) fn init_exception_table() {
+    if exception_table_ready() {
+        return;
+    }
     // Sort exception table
     let ex_table = unsafe {
         core::slice::from_raw_parts_mut(
@@ -74,4 +374,34 @@ pub(crate) fn init_exception_table() {
         )
     };
     ex_table.sort_unstable();
+    EX_TABLE_READY.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExceptionTableEntry;
+
+    #[test]
+    fn exception_table_search() {
+        let entry = |from, to| ExceptionTableEntry { from, to };
+        let mut table = [
+            entry(0x3000, 0x3010),
+            entry(0x1000, 0x1008),
+            entry(0x2000, 0x2004),
+        ];
+        table.sort_unstable();
+        assert_eq!(ExceptionTableEntry::search(&table, 0x1000), Some(0x1008));
+        assert_eq!(ExceptionTableEntry::search(&table, 0x3000), Some(0x3010));
+        // only the exact faulting instruction is fixed up
+        assert_eq!(ExceptionTableEntry::search(&table, 0x2001), None);
+        assert_eq!(ExceptionTableEntry::search(&[], 0x2000), None);
+    }
+
+    #[test]
+    fn probe_read_mapped() {
+        // a fault cannot be recovered on the host, as it is delivered as a
+        // signal instead of to the kernel trap handler
+        let byte = 0x5a_u8;
+        assert_eq!(unsafe { crate::asm::probe_read_u8(&byte) }, Ok(0x5a));
+    }
 }
@@ -0,0 +1,102 @@
+//! The local APIC in the xAPIC mode, accessed via MMIO.
+
+/// Local APIC ID register offset.
+const ID: usize = 0x20;
+/// End-Of-Interrupt (EOI) register offset.
+const EOI: usize = 0xb0;
+/// Spurious Interrupt Vector Register (SVR) offset.
+const SVR: usize = 0xf0;
+/// Interrupt Command Register (ICR) offsets.
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+/// SVR: APIC software enable.
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+/// ICR: delivery status (send pending).
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// ICR: level assert.
+pub(super) const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// The vector of the spurious interrupts, set by [`Apic::init`].
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The delivery mode of an IPI.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Delivers the interrupt of the given vector.
+    Fixed = 0b000,
+    /// Delivers an NMI, the vector is ignored.
+    Nmi = 0b100,
+    /// Delivers an INIT request, the vector is ignored.
+    Init = 0b101,
+    /// Delivers a start-up request (SIPI), the vector is the page number of
+    /// the start-up code.
+    Startup = 0b110,
+}
+
+/// A local APIC in the xAPIC mode.
+#[derive(Debug)]
+pub struct Apic {
+    base: *mut u32,
+}
+
+unsafe impl Send for Apic {}
+unsafe impl Sync for Apic {}
+
+impl Apic {
+    /// Creates a local APIC with the MMIO base address.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as all the methods access the registers at
+    /// `base`. The caller must ensure that `base` is the base address of the
+    /// local APIC registers of the current CPU, mapped as uncached memory in
+    /// the kernel address space for the lifetime of the returned value.
+    pub const unsafe fn new(base: *mut u32) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.byte_add(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.byte_add(offset).write_volatile(value) }
+    }
+
+    /// Enables the local APIC by setting the software enable bit in the SVR,
+    /// with the spurious interrupt vector [`SPURIOUS_VECTOR`].
+    pub fn init(&self) {
+        self.write(SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    /// Sends the End-Of-Interrupt (EOI) of the current interrupt.
+    pub fn send_eoi(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Returns the local APIC ID.
+    pub fn read_id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Sends an IPI to the CPU with the APIC ID `dest`.
+    pub fn send_ipi(&self, dest: u8, vector: u8, delivery: DeliveryMode) {
+        self.write_icr(
+            dest,
+            vector as u32 | (delivery as u32) << 8 | ICR_LEVEL_ASSERT,
+        );
+    }
+
+    /// Writes the ICR with the destination APIC ID and the low word, after the
+    /// previous IPI is sent.
+    pub(super) fn write_icr(&self, dest: u8, low: u32) {
+        while self.read(ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        // the IPI is sent on writing the low word
+        self.write(ICR_HIGH, (dest as u32) << 24);
+        self.write(ICR_LOW, low);
+    }
+}
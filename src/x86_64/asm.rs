@@ -1,10 +1,10 @@
 //! Wrapper functions for assembly instructions.
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+use memory_addr::{PhysAddr, VirtAddr};
 use x86::{controlregs, msr, tlb};
-use x86_64::instructions::interrupts;
 
 /// Allows the current CPU to respond to interrupts.
 #[inline]
@@ -14,7 +14,7 @@ pub fn enable_irqs() {
         warn!("enable_irqs: not implemented");
     }
     #[cfg(target_os = "none")]
-    interrupts::enable()
+    super::flags::enable_irq()
 }
 
 /// Makes the current CPU to ignore interrupts.
@@ -25,13 +25,13 @@ pub fn disable_irqs() {
         warn!("disable_irqs: not implemented");
     }
     #[cfg(target_os = "none")]
-    interrupts::disable()
+    super::flags::disable_irq()
 }
 
 /// Returns whether the current CPU is allowed to respond to interrupts.
 #[inline]
 pub fn irqs_enabled() -> bool {
-    interrupts::are_enabled()
+    super::flags::read() & super::flags::IF != 0
 }
 
 /// Relaxes the current CPU and waits for interrupts.
@@ -53,6 +53,46 @@ pub fn halt() {
     wait_for_irqs(); // should never return
 }
 
+/// Waits for an interrupt.
+///
+/// x86_64 does not have a dedicated `WFI` instruction, so this operation is the
+/// same as [`wait_for_irqs`] (`HLT`).
+#[inline]
+pub fn wfi() {
+    wait_for_irqs()
+}
+
+/// Waits for an event.
+///
+/// x86_64 does not have the `WFE` instruction, so this operation is a no-op.
+#[inline]
+pub fn wfe() {}
+
+/// Sends an event to all CPUs in the system.
+///
+/// x86_64 does not have the `SEV` instruction, so this operation is a no-op.
+#[inline]
+pub fn sev() {}
+
+/// Sends an event to the current CPU only.
+///
+/// x86_64 does not have the `SEVL` instruction, so this operation is a no-op.
+#[inline]
+pub fn sevl() {}
+
+/// Executes a no-operation instruction (`NOP`).
+#[inline]
+pub fn nop() {
+    unsafe { asm!("nop", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Hints the CPU that the current thread is performing a spin-wait loop
+/// (`PAUSE`).
+#[inline]
+pub fn yield_cpu() {
+    core::hint::spin_loop()
+}
+
 /// Reads the current page table root register for user space (`CR3`).
 ///
 /// x86_64 does not have a separate page table root register for user and
@@ -61,7 +101,18 @@ pub fn halt() {
 /// Returns the physical address of the page table root.
 #[inline]
 pub fn read_user_page_table() -> PhysAddr {
-    pa!(unsafe { controlregs::cr3() } as usize).align_down_4k()
+    cr3_page_table_root(read_cr3().as_usize())
+}
+
+/// Extracts the physical address of the page table root from a `CR3` value.
+///
+/// Bits 11:0 are the PCID (or the PWT/PCD flags), and bits 63:52 are reserved
+/// or used by other features (e.g., LAM). Only bits 51:12 are the physical
+/// address of the page table root.
+#[inline]
+const fn cr3_page_table_root(cr3: usize) -> PhysAddr {
+    const CR3_ADDR_MASK: usize = 0x000f_ffff_ffff_f000;
+    PhysAddr::from_usize(cr3 & CR3_ADDR_MASK)
 }
 
 /// Reads the current page table root register for kernel space (`CR3`).
@@ -69,6 +120,9 @@ pub fn read_user_page_table() -> PhysAddr {
 /// x86_64 does not have a separate page table root register for user and
 /// kernel space, so this operation is the same as [`read_user_page_table`].
 ///
+/// The PCID and other non-address bits of `CR3` are masked out, so the
+/// returned address is always 4K-aligned.
+///
 /// Returns the physical address of the page table root.
 #[inline]
 pub fn read_kernel_page_table() -> PhysAddr {
@@ -120,12 +174,270 @@ pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     }
 }
 
+/// Reads the control register `CR0`.
+#[inline]
+pub fn read_cr0() -> u64 {
+    unsafe { controlregs::cr0() }.bits() as u64
+}
+
+/// Writes the control register `CR0`.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the operating mode of the current
+/// CPU (e.g., paging, write protection, and FPU behaviors).
+#[inline]
+pub unsafe fn write_cr0(val: u64) {
+    unsafe { controlregs::cr0_write(controlregs::Cr0::from_bits_truncate(val as usize)) }
+}
+
+/// Sets the task-switched flag (`CR0.TS`).
+///
+/// After that, the next FP/SIMD instruction raises a device-not-available
+/// exception (`#NM`).
+#[inline]
+pub fn cr0_set_ts() {
+    unsafe { controlregs::cr0_write(controlregs::cr0() | controlregs::Cr0::CR0_TASK_SWITCHED) }
+}
+
+/// Clears the task-switched flag (`CR0.TS`) with the `CLTS` instruction.
+#[inline]
+pub fn cr0_clear_ts() {
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Reads the page fault linear address register (`CR2`).
+///
+/// Returns the virtual address that caused the last page fault.
+#[inline]
+pub fn read_cr2() -> VirtAddr {
+    va!(unsafe { controlregs::cr2() })
+}
+
+/// Reads the raw value of the page table base register (`CR3`).
+///
+/// Note that the returned value may contain the PCID or other flags in the
+/// non-address bits. Use [`read_kernel_page_table`] to get the physical
+/// address of the page table root.
+#[inline]
+pub fn read_cr3() -> PhysAddr {
+    pa!(unsafe { controlregs::cr3() } as usize)
+}
+
+/// Reads the control register `CR4`.
+#[inline]
+pub fn read_cr4() -> u64 {
+    unsafe { controlregs::cr4() }.bits() as u64
+}
+
+/// Writes the control register `CR4`.
+///
+/// # Safety
+///
+/// This function is unsafe as it enables or disables architectural features
+/// of the current CPU.
+#[inline]
+pub unsafe fn write_cr4(val: u64) {
+    unsafe { controlregs::cr4_write(controlregs::Cr4::from_bits_truncate(val as usize)) }
+}
+
+/// Reads the debug status register (`DR6`).
+#[inline]
+pub fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes the debug status register (`DR6`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the debug states of the current CPU.
+#[inline]
+pub unsafe fn write_dr6(value: u64) {
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Reads the SSE control and status register (`MXCSR`).
+#[inline]
+pub fn read_mxcsr() -> u32 {
+    let mut value = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack, preserves_flags)) };
+    value
+}
+
+/// Whether the `RDFSBASE`/`WRFSBASE`/`RDGSBASE`/`WRGSBASE` instructions are
+/// enabled by [`enable_fsgsbase`].
+static FSGSBASE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the `RDFSBASE`/`WRFSBASE`/`RDGSBASE`/`WRGSBASE` instructions
+/// (`CR4.FSGSBASE`) on the current CPU.
+///
+/// Once enabled, they are used instead of the slower `RDMSR`/`WRMSR` to access
+/// the `FS` base (e.g., by [`read_thread_pointer`]). Note that they are also
+/// available to the user space, which can then set the GS base to any value
+/// (including a kernel address). The traps that may interrupt the kernel
+/// before `swapgs` (NMI, `#DF` and `#DB`) do not trust the GS base, as their
+/// entries load the kernel GS base saved by [`init_trap`].
+///
+/// Returns `false` if the instructions are not supported.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes `CR4` of the current CPU. It should
+/// be called on all CPUs before any of them accesses the `FS` base, and after
+/// [`init_trap`] on the current CPU.
+///
+/// [`init_trap`]: crate::init::init_trap
+pub unsafe fn enable_fsgsbase() -> bool {
+    use super::cpu_features::CpuFeatures;
+    if !CpuFeatures::current().contains(CpuFeatures::FSGSBASE) {
+        return false;
+    }
+    // the IST entries of the paranoid traps must have been set up
+    debug_assert!(
+        unsafe { super::gdt::current_tss_ist(super::gdt::NMI_IST_INDEX as usize) } != 0,
+        "`init_trap` must be called before enabling FSGSBASE"
+    );
+    unsafe { controlregs::cr4_write(controlregs::cr4() | controlregs::Cr4::CR4_ENABLE_FSGSBASE) };
+    FSGSBASE_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Returns whether the `RDFSBASE`/`WRFSBASE`/`RDGSBASE`/`WRGSBASE`
+/// instructions are enabled by [`enable_fsgsbase`].
+#[inline]
+pub fn fsgsbase_enabled() -> bool {
+    FSGSBASE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads the `FS` base of the current CPU (`RDFSBASE`).
+///
+/// # Safety
+///
+/// The instruction must be enabled by [`enable_fsgsbase`].
+#[inline]
+pub unsafe fn rdfsbase() -> usize {
+    let value: usize;
+    unsafe { asm!("rdfsbase {}", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes the `FS` base of the current CPU (`WRFSBASE`).
+///
+/// # Safety
+///
+/// The instruction must be enabled by [`enable_fsgsbase`]. It changes the CPU
+/// states.
+#[inline]
+pub unsafe fn wrfsbase(value: usize) {
+    unsafe { asm!("wrfsbase {}", in(reg) value, options(nostack, preserves_flags)) };
+}
+
+/// Reads the `GS` base of the current CPU (`RDGSBASE`).
+///
+/// # Safety
+///
+/// The instruction must be enabled by [`enable_fsgsbase`].
+#[inline]
+pub unsafe fn rdgsbase() -> usize {
+    let value: usize;
+    unsafe { asm!("rdgsbase {}", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes the `GS` base of the current CPU (`WRGSBASE`).
+///
+/// # Safety
+///
+/// The instruction must be enabled by [`enable_fsgsbase`]. It changes the CPU
+/// states, including the per-CPU data area in the kernel.
+#[inline]
+pub unsafe fn wrgsbase(value: usize) {
+    unsafe { asm!("wrgsbase {}", in(reg) value, options(nostack, preserves_flags)) };
+}
+
+/// Whether protection keys for user pages are enabled by [`enable_pku`].
+static PKU_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables protection keys for user pages (`CR4.PKE`) on the current CPU, and
+/// the `RDPKRU`/`WRPKRU` instructions.
+///
+/// Returns `false` if protection keys are not supported.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes `CR4` of the current CPU. It should
+/// be called on all CPUs before any task uses protection keys.
+pub unsafe fn enable_pku() -> bool {
+    use super::cpu_features::CpuFeatures;
+    if !CpuFeatures::current().contains(CpuFeatures::PKU) {
+        return false;
+    }
+    unsafe {
+        controlregs::cr4_write(controlregs::cr4() | controlregs::Cr4::CR4_ENABLE_PROTECTION_KEY)
+    };
+    PKU_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Returns whether protection keys for user pages are enabled by
+/// [`enable_pku`].
+#[inline]
+pub fn pku_enabled() -> bool {
+    PKU_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads the protection key rights register (`RDPKRU`).
+///
+/// # Safety
+///
+/// Protection keys must be enabled by [`enable_pku`].
+#[inline]
+pub unsafe fn rdpkru() -> u32 {
+    let value: u32;
+    unsafe {
+        asm!(
+            "rdpkru",
+            in("ecx") 0,
+            out("eax") value,
+            out("edx") _,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    value
+}
+
+/// Writes the protection key rights register (`WRPKRU`).
+///
+/// # Safety
+///
+/// Protection keys must be enabled by [`enable_pku`]. It changes the access
+/// rights of user pages.
+#[inline]
+pub unsafe fn wrpkru(value: u32) {
+    unsafe {
+        asm!(
+            "wrpkru",
+            in("eax") value,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
 /// Reads the thread pointer of the current CPU (`FS_BASE`).
 ///
 /// It is used to implement TLS (Thread Local Storage).
 #[inline]
 pub fn read_thread_pointer() -> usize {
-    unsafe { msr::rdmsr(msr::IA32_FS_BASE) as usize }
+    if fsgsbase_enabled() {
+        unsafe { rdfsbase() }
+    } else {
+        unsafe { msr::rdmsr(msr::IA32_FS_BASE) as usize }
+    }
 }
 
 /// Writes the thread pointer of the current CPU (`FS_BASE`).
@@ -137,7 +449,11 @@ pub fn read_thread_pointer() -> usize {
 /// This function is unsafe as it changes the CPU states.
 #[inline]
 pub unsafe fn write_thread_pointer(fs_base: usize) {
-    unsafe { msr::wrmsr(msr::IA32_FS_BASE, fs_base as u64) }
+    if fsgsbase_enabled() {
+        unsafe { wrfsbase(fs_base) }
+    } else {
+        unsafe { msr::wrmsr(msr::IA32_FS_BASE, fs_base as u64) }
+    }
 }
 
 #[cfg(feature = "uspace")]
@@ -156,3 +472,68 @@ unsafe extern "C" {
     /// while a value > 0 indicates failure.
     pub fn user_copy(dst: *mut u8, src: *const u8, size: usize) -> usize;
 }
+
+/// Reads a byte from `src`, where the address may be in user space.
+///
+/// The load is annotated in the exception table, so a page fault on it is
+/// recovered by the trap handler, and `Err(())` is returned instead of
+/// panicking.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads from a raw pointer. `src` must
+/// not point to memory-mapped I/O with side effects on reads.
+#[cfg(feature = "uspace")]
+#[allow(clippy::result_unit_err)]
+pub unsafe fn probe_read_u8(src: *const u8) -> Result<u8, ()> {
+    let val: u32;
+    let err: u32;
+    unsafe {
+        asm!(
+            "mov {err:e}, 1",
+            "2: movzx {val:e}, byte ptr [{src}]",
+            "xor {err:e}, {err:e}",
+            "3:",
+            crate::ex_table_entry!("2b", "3b"),
+            src = in(reg) src,
+            val = out(reg) val,
+            err = out(reg) err,
+            options(nostack, readonly),
+        )
+    }
+    if err == 0 {
+        Ok(val as u8)
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cr3_page_table_root;
+    use memory_addr::MemoryAddr;
+
+    #[test]
+    fn cr3_root_mask() {
+        // PCID 0x123
+        assert_eq!(cr3_page_table_root(0x1234_5123).as_usize(), 0x1234_5000);
+        // the no-flush bit (63) and LAM bits (62:61) are not address bits
+        let cr3 = (0b111 << 61) | 0x000f_ffff_ffff_f000 | 0xfff;
+        let root = cr3_page_table_root(cr3);
+        assert_eq!(root.as_usize(), 0x000f_ffff_ffff_f000);
+        assert!(root.is_aligned_4k());
+    }
+
+    #[test]
+    fn irqs_enabled_reads_rflags() {
+        use crate::x86_64::flags;
+
+        // bit 1 of `RFLAGS` is reserved and always set, and `IF` is always
+        // set in user mode
+        let rflags = flags::read();
+        assert_ne!(rflags & 0b10, 0);
+        assert_ne!(rflags & flags::IF, 0);
+        assert!(super::irqs_enabled());
+        assert!(crate::trap::irqs_enabled());
+    }
+}
@@ -0,0 +1,223 @@
+//! Intel CET (Control-flow Enforcement Technology) shadow stack support.
+
+use core::arch::asm;
+
+use x86::msr::{rdmsr, wrmsr};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+use super::{cpu_features::CpuFeatures, TrapFrame};
+use crate::trap::def_trap_handler;
+
+/// MSR of the user mode CET configuration.
+const IA32_U_CET: u32 = 0x6a0;
+/// MSR of the supervisor mode CET configuration.
+const IA32_S_CET: u32 = 0x6a2;
+/// MSR of the shadow stack pointer for privilege level 0.
+const IA32_PL0_SSP: u32 = 0x6a4;
+/// MSR of the shadow stack pointer for privilege level 3.
+const IA32_PL3_SSP: u32 = 0x6a7;
+
+/// `SH_STK_EN` bit of `IA32_U_CET` and `IA32_S_CET`.
+const CET_SH_STK_EN: u64 = 1 << 0;
+/// `WR_SHSTK_EN` bit of `IA32_U_CET` and `IA32_S_CET`, which enables `WRSSQ`.
+const CET_WR_SHSTK_EN: u64 = 1 << 1;
+
+/// Shadow stack states of a task.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CetState {
+    /// The shadow stack pointer (`SSP`) of the task when it is switched out,
+    /// or 0 if the task has no shadow stack.
+    ///
+    /// A restore token of the shadow stack is at `ssp - 8`.
+    pub ssp: u64,
+    /// The top of the kernel shadow stack of the task, or 0 if the task has no
+    /// shadow stack.
+    pub sstack_top: u64,
+}
+
+/// Returns whether the CPU supports CET shadow stacks
+/// (`CPUID.(EAX=07H,ECX=0):ECX.CET_SS`).
+pub fn shadow_stack_supported() -> bool {
+    CpuFeatures::current().contains(CpuFeatures::CET_SS)
+}
+
+/// Enables CET (`CR4.CET`) and user mode shadow stacks
+/// (`IA32_U_CET.SH_STK_EN`) on the current CPU.
+///
+/// Returns `false` if CET shadow stacks are not supported. The shadow stacks
+/// of user tasks are set by [`UserContext::set_shadow_stack`].
+///
+/// [`UserContext::set_shadow_stack`]: crate::uspace::UserContext::set_shadow_stack
+///
+/// # Safety
+///
+/// This function is unsafe as it changes `CR4` and the CET configuration of
+/// the current CPU. `CR0.WP` must be set.
+pub unsafe fn enable_user_shadow_stack() -> bool {
+    if !shadow_stack_supported() {
+        return false;
+    }
+    unsafe {
+        Cr4::update(|flags| flags.insert(Cr4Flags::CONTROL_FLOW_ENFORCEMENT));
+        wrmsr(IA32_U_CET, rdmsr(IA32_U_CET) | CET_SH_STK_EN);
+    }
+    true
+}
+
+/// Enables supervisor mode shadow stacks (`IA32_S_CET.SH_STK_EN` and
+/// `IA32_S_CET.WR_SHSTK_EN`) on the current CPU, switching to the kernel
+/// shadow stack `ssp`.
+///
+/// `ssp` is also set as the kernel shadow stack pointer (see
+/// [`set_kernel_shadow_stack`]), and made the current `SSP` by `SETSSBSY`.
+///
+/// Each task then runs on its own shadow stack, given by
+/// [`TaskContext::set_shadow_stack`] and switched in
+/// [`TaskContext::switch_to`]. When a task enters user space, a supervisor
+/// shadow stack token is placed right below its current `SSP` and written to
+/// `IA32_PL0_SSP`, so that the entry from user space (interrupts, or
+/// `SETSSBSY` after `SYSCALL` and `SYSENTER`) continues on the shadow stack of
+/// the task. The traps from user space delivered on IST stacks release the
+/// token of the IST shadow stack and switch to the task's one in the same way.
+///
+/// [`TaskContext::set_shadow_stack`]: crate::TaskContext::set_shadow_stack
+/// [`TaskContext::switch_to`]: crate::TaskContext::switch_to
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the shadow stack of the current CPU.
+/// It must be called after [`enable_user_shadow_stack`] returns `true`, and
+/// `ssp` must point to a free supervisor shadow stack token at the top of a
+/// valid shadow stack. Task contexts saved before this call have no shadow
+/// stacks. The traps delivered on IST stacks also need their shadow stacks in
+/// `IA32_INTERRUPT_SSP_TABLE_ADDR`, which must be set by the caller.
+pub unsafe fn enable_kernel_shadow_stack(ssp: u64) {
+    unsafe {
+        set_kernel_shadow_stack(ssp);
+        wrmsr(
+            IA32_S_CET,
+            rdmsr(IA32_S_CET) | CET_SH_STK_EN | CET_WR_SHSTK_EN,
+        );
+        asm!("setssbsy", options(nostack));
+    }
+}
+
+/// Returns whether supervisor mode shadow stacks are enabled on the current
+/// CPU by [`enable_kernel_shadow_stack`].
+#[inline]
+pub fn kernel_shadow_stack_enabled() -> bool {
+    KERNEL_SHADOW_STACK.read_current() != 0
+}
+
+/// Prepares the kernel shadow stack of a new task whose top is `sstack_top`,
+/// so that the task starts at `entry` when it is switched to.
+///
+/// The return address `entry` is pushed, followed by a restore token for
+/// `RSTORSSP`. Returns the shadow stack pointer to be saved in
+/// [`CetState::ssp`].
+///
+/// # Safety
+///
+/// This function is unsafe as it writes to a raw address with `WRSSQ`. Kernel
+/// shadow stacks must be enabled, and `sstack_top` must be the 8-byte aligned
+/// top of a free supervisor shadow stack mapping.
+pub(super) unsafe fn init_task_shadow_stack(sstack_top: u64, entry: u64) -> u64 {
+    let ssp = sstack_top - 8;
+    unsafe {
+        asm!(
+            "wrssq [{ssp}], {entry}",
+            "wrssq [{ssp} - 8], {token}",
+            ssp = in(reg) ssp,
+            entry = in(reg) entry,
+            token = in(reg) ssp | 1, // 64-bit mode restore token
+            options(nostack, preserves_flags)
+        )
+    };
+    ssp
+}
+
+/// Reads the shadow stack pointer of user space (`IA32_PL3_SSP`).
+#[inline]
+pub fn read_user_ssp() -> u64 {
+    unsafe { rdmsr(IA32_PL3_SSP) }
+}
+
+/// Writes the shadow stack pointer of user space (`IA32_PL3_SSP`), which is
+/// loaded into `SSP` on the next return to user space.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the user shadow stack. `ssp` must be
+/// in a user shadow stack mapping, or 0.
+#[inline]
+pub unsafe fn write_user_ssp(ssp: u64) {
+    unsafe { wrmsr(IA32_PL3_SSP, ssp) }
+}
+
+/// Writes a quadword to a user shadow stack (`WRUSSQ`), e.g., to place a
+/// restore token.
+///
+/// # Safety
+///
+/// This function is unsafe as it writes to a raw address. `addr` must be in a
+/// user shadow stack mapping.
+#[inline]
+pub unsafe fn write_user_shadow_stack(addr: u64, value: u64) {
+    unsafe {
+        asm!(
+            "wrussq [{addr}], {value}",
+            addr = in(reg) addr,
+            value = in(reg) value,
+            options(nostack, preserves_flags)
+        )
+    }
+}
+
+/// A slice of control protection exception (`#CP`) handler functions.
+///
+/// The handlers are called in order until one of them returns `true`, on
+/// shadow stack violations (e.g., mismatched return addresses) in the kernel.
+/// The error code is in [`TrapFrame::error_code`].
+#[def_trap_handler]
+pub static CP_HANDLER: [fn(&mut TrapFrame) -> bool];
+
+/// The shadow stack pointer of the current CPU set by
+/// [`enable_kernel_shadow_stack`], or 0 if kernel shadow stacks are disabled.
+///
+/// It is checked by `syscall_entry` before `SETSSBSY`.
+#[percpu::def_percpu]
+#[unsafe(no_mangle)]
+static KERNEL_SHADOW_STACK: u64 = 0;
+
+/// Sets the kernel shadow stack pointer of the current CPU.
+///
+/// It is saved in a per-CPU variable and written to `IA32_PL0_SSP`, so that
+/// the CPU loads it into `SSP` on entry to the kernel from user space. Note
+/// that `IA32_PL0_SSP` is replaced by the token of the current task on every
+/// entry to user space.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the shadow stack used by the CPU.
+/// `ssp` must point to a valid shadow stack.
+pub unsafe fn set_kernel_shadow_stack(ssp: u64) {
+    KERNEL_SHADOW_STACK.write_current(ssp);
+    unsafe { wrmsr(IA32_PL0_SSP, ssp) };
+}
+
+/// Returns the kernel shadow stack pointer of the current CPU set by
+/// [`set_kernel_shadow_stack`].
+pub fn kernel_shadow_stack() -> u64 {
+    KERNEL_SHADOW_STACK.read_current()
+}
+
+/// Reads the current shadow stack pointer (`RDSSPQ`).
+///
+/// Returns 0 if shadow stacks are not enabled, as `RDSSPQ` is a no-op in that
+/// case.
+#[inline]
+pub fn read_ssp() -> u64 {
+    let mut ssp: u64 = 0;
+    unsafe { asm!("rdsspq {}", inout(reg) ssp, options(nomem, nostack, preserves_flags)) };
+    ssp
+}
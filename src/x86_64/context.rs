@@ -1,7 +1,11 @@
-use core::{arch::naked_asm, fmt};
+use core::{arch::naked_asm, fmt, marker::PhantomData};
 
 use memory_addr::VirtAddr;
 
+use crate::trap::PrivilegeLevel;
+#[cfg(feature = "fp-lazy")]
+use crate::FpCell;
+
 /// Saved registers when a trap (interrupt or exception) occurs.
 #[allow(missing_docs)]
 #[repr(C)]
@@ -35,6 +39,17 @@ pub struct TrapFrame {
     pub ss: u64,
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<TrapFrame>(), 22 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(TrapFrame, vector), 15 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(TrapFrame, rip), 17 * 8);
+
+/// The trap flag (`TF`) in `RFLAGS`.
+const RFLAGS_TF: u64 = 1 << 8;
+/// The direction flag (`DF`) in `RFLAGS`.
+const RFLAGS_DF: u64 = 1 << 10;
+/// The alignment check flag (`AC`) in `RFLAGS`.
+const RFLAGS_AC: u64 = 1 << 18;
+
 impl TrapFrame {
     /// Gets the 0th syscall argument.
     pub const fn arg0(&self) -> usize {
@@ -136,10 +151,242 @@ impl TrapFrame {
         self.rax = rax as _;
     }
 
+    /// Enables single-stepping by setting the trap flag (`TF`) in `RFLAGS`.
+    ///
+    /// A debug exception (`#DB`) with `DR6.BS` set is raised after the next
+    /// instruction is executed after returning to this trap frame.
+    pub const fn enable_single_step(&mut self) {
+        self.rflags |= RFLAGS_TF;
+    }
+
+    /// Disables single-stepping by clearing the trap flag (`TF`) in `RFLAGS`.
+    pub const fn disable_single_step(&mut self) {
+        self.rflags &= !RFLAGS_TF;
+    }
+
+    /// Returns whether single-stepping is enabled in this trap frame.
+    pub const fn is_single_step(&self) -> bool {
+        self.rflags & RFLAGS_TF != 0
+    }
+
+    /// Returns whether the instruction pointer (`rip`) points into the
+    /// kernel text section, i.e., the trap interrupted kernel code.
+    ///
+    /// The kernel text bounds are given by the `_stext` and `_etext` linker
+    /// symbols. The kernel must define them in its linker script when this
+    /// method is used, e.g.:
+    ///
+    /// ```ld
+    /// .text : {
+    ///     _stext = .;
+    ///     *(.text .text.*)
+    ///     _etext = .;
+    /// }
+    /// ```
+    pub fn rip_in_kernel(&self) -> bool {
+        crate::trap::is_kernel_text(self.rip as _)
+    }
+
+    /// Gets the register by its DWARF register number (see [`dwarf_reg`]).
+    ///
+    /// Returns `None` if the register is not saved in the trap frame.
+    ///
+    /// [`dwarf_reg`]: super::dwarf_reg
+    pub const fn get_reg(&self, dwarf_num: u16) -> Option<u64> {
+        use super::dwarf_reg::*;
+        Some(match dwarf_num {
+            RAX => self.rax,
+            RDX => self.rdx,
+            RCX => self.rcx,
+            RBX => self.rbx,
+            RSI => self.rsi,
+            RDI => self.rdi,
+            RBP => self.rbp,
+            RSP => self.rsp,
+            R8 => self.r8,
+            R9 => self.r9,
+            R10 => self.r10,
+            R11 => self.r11,
+            R12 => self.r12,
+            R13 => self.r13,
+            R14 => self.r14,
+            R15 => self.r15,
+            RIP => self.rip,
+            RFLAGS => self.rflags,
+            _ => return None,
+        })
+    }
+
+    /// Sets the register by its DWARF register number (see [`dwarf_reg`]).
+    ///
+    /// Returns `false` if the register is not saved in the trap frame.
+    ///
+    /// [`dwarf_reg`]: super::dwarf_reg
+    pub const fn set_reg(&mut self, dwarf_num: u16, val: u64) -> bool {
+        use super::dwarf_reg::*;
+        let reg = match dwarf_num {
+            RAX => &mut self.rax,
+            RDX => &mut self.rdx,
+            RCX => &mut self.rcx,
+            RBX => &mut self.rbx,
+            RSI => &mut self.rsi,
+            RDI => &mut self.rdi,
+            RBP => &mut self.rbp,
+            RSP => &mut self.rsp,
+            R8 => &mut self.r8,
+            R9 => &mut self.r9,
+            R10 => &mut self.r10,
+            R11 => &mut self.r11,
+            R12 => &mut self.r12,
+            R13 => &mut self.r13,
+            R14 => &mut self.r14,
+            R15 => &mut self.r15,
+            RIP => &mut self.rip,
+            RFLAGS => &mut self.rflags,
+            _ => return false,
+        };
+        *reg = val;
+        true
+    }
+
+    /// Zeros `rbx`, `rbp`, `rcx`, `rdx` and `r10`-`r15`, and clears `DF`, `TF`
+    /// and `AC` in `RFLAGS`, so that no stale values are leaked to the user
+    /// space.
+    ///
+    /// `rax`, `rdi` and `rsi` are kept, e.g., for the return value and the
+    /// first two arguments. Note that `rdx` and `r10` are also the third and
+    /// fourth syscall arguments, which are cleared here.
+    ///
+    /// It discards the user states in these registers, so it must only be used
+    /// for a trap frame that does not resume interrupted user code, e.g., the
+    /// initial frame of a new program.
+    pub const fn scrub_for_user_return(&mut self) {
+        self.rbx = 0;
+        self.rbp = 0;
+        self.rcx = 0;
+        self.rdx = 0;
+        self.r10 = 0;
+        self.r11 = 0;
+        self.r12 = 0;
+        self.r13 = 0;
+        self.r14 = 0;
+        self.r15 = 0;
+        self.rflags &= !(RFLAGS_DF | RFLAGS_TF | RFLAGS_AC);
+    }
+
+    /// Whether the trap is from the user space, i.e., the requested privilege
+    /// level of the saved `CS` is ring 3.
+    pub const fn is_from_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+
+    /// Returns the current privilege level (ring 0 or 3) that the trap is
+    /// taken from, i.e., the requested privilege level of the saved `CS`.
+    pub const fn cpl(&self) -> u8 {
+        (self.cs & 0b11) as u8
+    }
+
+    /// Returns the privilege level that the trap is taken from.
+    ///
+    /// It is determined by the state saved on trap entry, so it does not
+    /// change during the trap handling.
+    pub const fn privilege_level(&self) -> PrivilegeLevel {
+        if self.is_from_user() {
+            PrivilegeLevel::User
+        } else {
+            PrivilegeLevel::Kernel
+        }
+    }
+
+    /// Whether the trap is from the kernel, i.e., the negation of
+    /// [`is_from_user`](Self::is_from_user).
+    pub const fn is_kernel(&self) -> bool {
+        !self.is_from_user()
+    }
+
     /// Unwind the stack and get the backtrace.
     pub fn backtrace(&self) -> axbacktrace::Backtrace {
         axbacktrace::Backtrace::capture_trap(self.rbp as _, self.rip as _, 0)
     }
+
+    /// Writes all registers to `w` in a compact columnar format, e.g.,
+    /// `rax=0x0000000000000000  rcx=0x...`, four registers per line.
+    pub fn dump<W: core::fmt::Write>(&self, w: &mut W) -> core::fmt::Result {
+        crate::trap::dump_regs(
+            w,
+            &[
+                ("rax", self.rax as _),
+                ("rbx", self.rbx as _),
+                ("rcx", self.rcx as _),
+                ("rdx", self.rdx as _),
+                ("rsi", self.rsi as _),
+                ("rdi", self.rdi as _),
+                ("rbp", self.rbp as _),
+                ("rsp", self.rsp as _),
+                ("r8", self.r8 as _),
+                ("r9", self.r9 as _),
+                ("r10", self.r10 as _),
+                ("r11", self.r11 as _),
+                ("r12", self.r12 as _),
+                ("r13", self.r13 as _),
+                ("r14", self.r14 as _),
+                ("r15", self.r15 as _),
+                ("rip", self.rip as _),
+                ("rflags", self.rflags as _),
+                ("cs", self.cs as _),
+                ("ss", self.ss as _),
+                ("vector", self.vector as _),
+                ("error_code", self.error_code as _),
+            ],
+        )
+    }
+
+    /// Dumps all registers to a newly allocated string, in the same format as
+    /// [`TrapFrame::dump`].
+    #[cfg(feature = "alloc")]
+    pub fn dump_to_string(&self) -> alloc::string::String {
+        let mut s = alloc::string::String::new();
+        self.dump(&mut s).unwrap();
+        s
+    }
+}
+
+/// The callee-saved registers in a [`TrapFrame`], i.e., the registers that
+/// must be preserved across a function call in the System V ABI.
+#[allow(missing_docs)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CalleeSavedRegs {
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rsp: u64,
+}
+
+impl TrapFrame {
+    /// Copies out the callee-saved registers (`rbx`, `rbp`, `r12`-`r15` and
+    /// `rsp`).
+    pub const fn callee_saved_copy(&self) -> CalleeSavedRegs {
+        CalleeSavedRegs {
+            rbx: self.rbx,
+            rbp: self.rbp,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rsp: self.rsp,
+        }
+    }
+
+    /// Checks whether the callee-saved registers of two trap frames are
+    /// equal.
+    ///
+    /// `rip`, `rflags` and the scratch registers are not compared.
+    pub fn callee_saved_eq(&self, other: &TrapFrame) -> bool {
+        self.callee_saved_copy() == other.callee_saved_copy()
+    }
 }
 
 #[repr(C)]
@@ -154,6 +401,35 @@ struct ContextSwitchFrame {
     rip: u64,
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<ContextSwitchFrame>(), 7 * 8);
+
+impl ContextSwitchFrame {
+    /// Writes the initial frame for entering `entry` below `stack_top`, and
+    /// returns the stack pointer to be restored by `context_switch`.
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be the 16-byte aligned top of a valid and writable
+    /// stack.
+    unsafe fn write_initial(stack_top: VirtAddr, entry: usize) -> u64 {
+        // x86_64 calling convention: the stack must be 16-byte aligned before
+        // calling a function. That means when entering a new task (`ret` in `context_switch`
+        // is executed), (stack pointer + 8) should be 16-byte aligned.
+        unsafe {
+            let frame_ptr = (stack_top.as_mut_ptr() as *mut u64).sub(1);
+            let frame_ptr = (frame_ptr as *mut ContextSwitchFrame).sub(1);
+            core::ptr::write(
+                frame_ptr,
+                ContextSwitchFrame {
+                    rip: entry as _,
+                    ..Default::default()
+                },
+            );
+            frame_ptr as u64
+        }
+    }
+}
+
 /// A 512-byte memory region for the FXSAVE/FXRSTOR instruction to save and
 /// restore the x87 FPU, MMX, XMM, and MXCSR registers.
 ///
@@ -176,34 +452,80 @@ pub struct FxsaveArea {
 }
 
 static_assertions::const_assert_eq!(core::mem::size_of::<FxsaveArea>(), 512);
+static_assertions::const_assert_eq!(core::mem::align_of::<FxsaveArea>(), 16);
+
+/// The size of the XSAVE area in [`ExtendedState`], which is large enough for
+/// the x87, SSE, AVX, MPX, AVX-512 and PKRU state components in the standard
+/// format.
+///
+/// State components requiring a larger area (e.g., AMX tiles) can not be
+/// enabled by the [`xsave`](super::xsave) module.
+pub const XSAVE_AREA_SIZE: usize = 2752;
+
+/// The offset of the XSAVE header in the XSAVE area, right after the legacy
+/// region (Intel SDM Vol. 1, 13.4).
+pub(super) const XSAVE_HEADER_OFFSET: usize = 512;
 
 /// Extended state of a task, such as FP/SIMD states.
+///
+/// It is laid out as an XSAVE area in the standard format, whose first 512
+/// bytes are compatible with the FXSAVE area. `XSAVE`/`XRSTOR` is used when
+/// enabled by the `xsave` module (requires the "fp-simd" feature), otherwise
+/// `FXSAVE`/`FXRSTOR` is used.
+#[repr(C, align(64))]
 pub struct ExtendedState {
-    /// Memory region for the FXSAVE/FXRSTOR instruction.
+    /// Memory region for the FXSAVE/FXRSTOR instruction, i.e., the legacy
+    /// region of the XSAVE area.
     pub fxsave_area: FxsaveArea,
+    /// The XSAVE header.
+    pub xsave_header: [u64; 8],
+    /// The extended region of the XSAVE area.
+    xsave_ext: [u8; XSAVE_AREA_SIZE - 576],
 }
 
+static_assertions::const_assert_eq!(core::mem::size_of::<ExtendedState>(), XSAVE_AREA_SIZE);
+static_assertions::const_assert_eq!(core::mem::align_of::<ExtendedState>(), 64);
+static_assertions::const_assert_eq!(
+    core::mem::offset_of!(ExtendedState, xsave_header),
+    XSAVE_HEADER_OFFSET
+);
+
 #[cfg(feature = "fp-simd")]
 impl ExtendedState {
     /// Saves the current extended states from CPU to this structure.
+    ///
+    /// `XSAVEOPT` is used if supported, which skips the state components that
+    /// are not modified since they were restored from this structure.
     #[inline]
     pub fn save(&mut self) {
-        unsafe { core::arch::x86_64::_fxsave64(&mut self.fxsave_area as *mut _ as *mut u8) }
+        let ptr = self as *mut _ as *mut u8;
+        if super::xsave::xsaveopt_enabled() {
+            unsafe { core::arch::x86_64::_xsaveopt64(ptr, super::xsave::xsave_mask()) }
+        } else if super::xsave::xsave_enabled() {
+            unsafe { core::arch::x86_64::_xsave64(ptr, super::xsave::xsave_mask()) }
+        } else {
+            unsafe { core::arch::x86_64::_fxsave64(ptr) }
+        }
     }
 
     /// Restores the extended states from this structure to CPU.
     #[inline]
     pub fn restore(&self) {
-        unsafe { core::arch::x86_64::_fxrstor64(&self.fxsave_area as *const _ as *const u8) }
+        let ptr = self as *const _ as *const u8;
+        if super::xsave::xsave_enabled() {
+            unsafe { core::arch::x86_64::_xrstor64(ptr, super::xsave::xsave_mask()) }
+        } else {
+            unsafe { core::arch::x86_64::_fxrstor64(ptr) }
+        }
     }
 
     /// Returns the extended state with initialized values.
     pub const fn default() -> Self {
-        let mut area: FxsaveArea = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
-        area.fcw = 0x37f;
-        area.ftw = 0xffff;
-        area.mxcsr = 0x1f80;
-        Self { fxsave_area: area }
+        let mut state: Self = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+        state.fxsave_area.fcw = 0x37f;
+        state.fxsave_area.ftw = 0xffff;
+        state.fxsave_area.mxcsr = 0x1f80;
+        state
     }
 }
 
@@ -211,7 +533,27 @@ impl fmt::Debug for ExtendedState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("ExtendedState")
             .field("fxsave_area", &self.fxsave_area)
-            .finish()
+            .field("xsave_header", &self.xsave_header)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The page table root of an address space, i.e., the value of `CR3`.
+#[cfg(feature = "uspace")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageTableRoot {
+    /// The physical address of the root page table.
+    pub phys: memory_addr::PhysAddr,
+    /// The process-context identifier (PCID) tagging the TLB entries of the
+    /// address space, or 0 if the address space has no PCID.
+    pub pcid: u16,
+}
+
+#[cfg(feature = "uspace")]
+impl PageTableRoot {
+    /// Creates a page table root without a PCID.
+    pub const fn new(phys: memory_addr::PhysAddr) -> Self {
+        Self { phys, pcid: 0 }
     }
 }
 
@@ -240,14 +582,61 @@ pub struct TaskContext {
     pub kstack_top: VirtAddr,
     /// `RSP` after all callee-saved registers are pushed.
     pub rsp: u64,
+    /// The maximum kernel stack usage in bytes observed on context switches.
+    pub max_kstack_used: u64,
     /// Thread pointer (FS segment base address)
     pub fs_base: usize,
+    /// The CPU affinity mask of the task, where bit N means the task is
+    /// allowed to run on logical CPU N.
+    pub cpu_mask: u64,
     /// Extended states, i.e., FP/SIMD states.
-    #[cfg(feature = "fp-simd")]
+    #[cfg(all(feature = "fp-simd", not(feature = "fp-lazy")))]
     pub ext_state: ExtendedState,
+    /// Extended states, i.e., FP/SIMD states, which are switched lazily by the
+    /// `#NM` handler.
+    #[cfg(feature = "fp-lazy")]
+    pub ext_state: FpCell<ExtendedState>,
+    /// Whether the FPU of the current CPU holds the latest FP/SIMD states of
+    /// the task, which have not been saved to [`ext_state`].
+    ///
+    /// [`ext_state`]: TaskContext::ext_state
+    #[cfg(feature = "fp-lazy")]
+    pub fpu_needs_save: FpCell<bool>,
+    /// The address of the FPU owner slot of the CPU whose FPU holds the states
+    /// of the task, valid when the task may still be the owner there.
+    #[cfg(feature = "fp-lazy")]
+    pub(super) fpu_owner_slot: FpCell<usize>,
     /// The `CR3` register value, i.e., the page table root.
     #[cfg(feature = "uspace")]
-    pub cr3: memory_addr::PhysAddr,
+    pub cr3: PageTableRoot,
+    /// The protection key rights register (`PKRU`) of the task.
+    #[cfg(feature = "pku")]
+    pub pkru: u32,
+    /// The shadow stack states when CET shadow stacks are enabled.
+    #[cfg(feature = "cet")]
+    pub cet_state: super::cet::CetState,
+    /// Debug registers (hardware breakpoints).
+    #[cfg(feature = "debug-regs")]
+    pub debug_state: super::debug::DebugState,
+    /// Whether the debug registers are used by the task, i.e., need to be
+    /// switched.
+    #[cfg(feature = "debug-regs")]
+    pub debug_active: bool,
+    /// The bottom (lowest address) of the kernel stack, where the canary is
+    /// placed, or 0 if the canary is not set.
+    #[cfg(feature = "stack-canary")]
+    pub kstack_bottom: VirtAddr,
+    /// The expected value of the canary at [`kstack_bottom`].
+    ///
+    /// [`kstack_bottom`]: TaskContext::kstack_bottom
+    #[cfg(feature = "stack-canary")]
+    pub stack_canary: u64,
+    /// The size of the kernel stack in bytes, which is painted by [`init`] if
+    /// not 0.
+    ///
+    /// [`init`]: TaskContext::init
+    #[cfg(feature = "stack-paint")]
+    pub kstack_size: usize,
 }
 
 impl TaskContext {
@@ -262,34 +651,101 @@ impl TaskContext {
         Self {
             kstack_top: va!(0),
             rsp: 0,
+            max_kstack_used: 0,
             fs_base: 0,
+            cpu_mask: u64::MAX,
             #[cfg(feature = "uspace")]
-            cr3: crate::asm::read_kernel_page_table(),
-            #[cfg(feature = "fp-simd")]
+            cr3: PageTableRoot::new(crate::asm::read_kernel_page_table()),
+            #[cfg(all(feature = "fp-simd", not(feature = "fp-lazy")))]
             ext_state: ExtendedState::default(),
+            #[cfg(feature = "fp-lazy")]
+            ext_state: FpCell::new(ExtendedState::default()),
+            #[cfg(feature = "fp-lazy")]
+            fpu_needs_save: FpCell::new(false),
+            #[cfg(feature = "fp-lazy")]
+            fpu_owner_slot: FpCell::new(0),
+            #[cfg(feature = "pku")]
+            pkru: 0,
+            #[cfg(feature = "cet")]
+            cet_state: super::cet::CetState {
+                ssp: 0,
+                sstack_top: 0,
+            },
+            #[cfg(feature = "debug-regs")]
+            debug_state: super::debug::DebugState::default(),
+            #[cfg(feature = "debug-regs")]
+            debug_active: false,
+            #[cfg(feature = "stack-canary")]
+            kstack_bottom: va!(0),
+            #[cfg(feature = "stack-canary")]
+            stack_canary: 0,
+            #[cfg(feature = "stack-paint")]
+            kstack_size: 0,
         }
     }
 
     /// Initializes the context for a new task, with the given entry point and
     /// kernel stack.
+    ///
+    /// With the "stack-paint" feature, the kernel stack is also painted by
+    /// [`paint_stack`] if [`kstack_size`] is set.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
     pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
-        unsafe {
-            // x86_64 calling convention: the stack must be 16-byte aligned before
-            // calling a function. That means when entering a new task (`ret` in `context_switch`
-            // is executed), (stack pointer + 8) should be 16-byte aligned.
-            let frame_ptr = (kstack_top.as_mut_ptr() as *mut u64).sub(1);
-            let frame_ptr = (frame_ptr as *mut ContextSwitchFrame).sub(1);
-            core::ptr::write(
-                frame_ptr,
-                ContextSwitchFrame {
-                    rip: entry as _,
-                    ..Default::default()
-                },
-            );
-            self.rsp = frame_ptr as u64;
-        }
+        self.rsp = unsafe { ContextSwitchFrame::write_initial(kstack_top, entry) };
         self.kstack_top = kstack_top;
         self.fs_base = tls_area.as_usize();
+        #[cfg(feature = "cet")]
+        if self.cet_state.sstack_top != 0 {
+            self.cet_state.ssp = unsafe {
+                super::cet::init_task_shadow_stack(self.cet_state.sstack_top, entry as u64)
+            };
+        }
+        #[cfg(feature = "stack-paint")]
+        self.paint_stack();
+    }
+
+    /// Resets the context for a new program of the task (e.g., `execve`), with
+    /// the given entry point, kernel stack and TLS area.
+    ///
+    /// Unlike [`init`], all other states in the context are also reset to
+    /// those of [`new`], so that nothing of the previous program remains:
+    /// the extended states are reset to their initial values (`FCW = 0x37f`,
+    /// `MXCSR = 0x1f80`), the debug registers are cleared, and the page table
+    /// root is reset to the kernel page table. The stack canary must be placed
+    /// again by `init_stack_canary` if needed, and the kernel stack is not
+    /// painted again as `kstack_size` is cleared. The CPU affinity mask and
+    /// the kernel shadow stack set by `set_shadow_stack` are kept, and the
+    /// latter is reinitialized.
+    ///
+    /// The task must not be running on the kernel stack at `kstack_top`, as the
+    /// initial context switch frame is written to its top.
+    ///
+    /// Only the saved states are reset. If it is called on the running task,
+    /// the FPU still holds the FP/SIMD states of the previous program: with
+    /// the "fp-lazy" feature, the ownership of the FPU is released and the
+    /// next FP/SIMD instruction loads the initial states; otherwise, the
+    /// caller must load them with `ext_state.restore()`.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`new`]: TaskContext::new
+    pub fn reset(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
+        let cpu_mask = self.cpu_mask;
+        #[cfg(feature = "cet")]
+        let sstack_top = self.cet_state.sstack_top;
+        *self = Self::new();
+        self.cpu_mask = cpu_mask;
+        #[cfg(feature = "cet")]
+        {
+            self.cet_state.sstack_top = sstack_top;
+        }
+        self.init(entry, kstack_top, tls_area);
+        // Dropping the old states has released the ownership of the FPU.
+        #[cfg(feature = "fp-lazy")]
+        if super::trap::CURRENT_CTX.read_current() == self as *mut Self as usize {
+            crate::asm::cr0_set_ts();
+        }
     }
 
     /// Changes the page table root in this context.
@@ -298,7 +754,305 @@ impl TaskContext {
     /// updated to the next task's after [`Self::switch_to`].
     #[cfg(feature = "uspace")]
     pub fn set_page_table_root(&mut self, cr3: memory_addr::PhysAddr) {
-        self.cr3 = cr3;
+        self.cr3.phys = cr3;
+    }
+
+    /// Changes the PCID of the address space in this context.
+    ///
+    /// The PCID is allocated by [`pcid::allocate`](super::pcid::allocate),
+    /// and takes effect after [`Self::switch_to`].
+    #[cfg(feature = "pcid")]
+    pub fn set_pcid(&mut self, pcid: u16) {
+        self.cr3.pcid = pcid;
+    }
+
+    /// Sets the protection key rights (`PKRU`) of the task.
+    ///
+    /// It takes effect when the task is switched in. If this is the context of
+    /// the current task, call [`wrpkru`](crate::asm::wrpkru) to apply it
+    /// immediately.
+    #[cfg(feature = "pku")]
+    pub const fn set_pkru(&mut self, val: u32) {
+        self.pkru = val;
+    }
+
+    /// Returns the protection key rights (`PKRU`) of the task, saved when it
+    /// is switched out.
+    #[cfg(feature = "pku")]
+    pub const fn get_pkru(&self) -> u32 {
+        self.pkru
+    }
+
+    /// Sets the kernel shadow stack of the task, whose top is `sstack_top`.
+    ///
+    /// It must be called before [`init`], which prepares the shadow stack to
+    /// start at the entry point of the task. The shadow stack is switched
+    /// together with the kernel stack in [`switch_to`].
+    ///
+    /// # Safety
+    ///
+    /// Kernel shadow stacks must be enabled by
+    /// [`enable_kernel_shadow_stack`], and `sstack_top` must be the 8-byte
+    /// aligned top of a supervisor shadow stack mapping owned by the task.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    /// [`enable_kernel_shadow_stack`]: super::cet::enable_kernel_shadow_stack
+    #[cfg(feature = "cet")]
+    pub unsafe fn set_shadow_stack(&mut self, sstack_top: VirtAddr) {
+        debug_assert!(super::cet::kernel_shadow_stack_enabled());
+        debug_assert!(sstack_top.as_usize().is_multiple_of(8));
+        self.cet_state.sstack_top = sstack_top.as_usize() as u64;
+    }
+
+    /// Returns the number of bytes of the kernel stack used by the task, i.e.,
+    /// the distance from [`kstack_top`] to the saved [`rsp`].
+    ///
+    /// It is only accurate when the task is not running, as [`rsp`] is updated
+    /// on context switches.
+    ///
+    /// [`kstack_top`]: TaskContext::kstack_top
+    /// [`rsp`]: TaskContext::rsp
+    pub fn kstack_used_bytes(&self) -> usize {
+        self.kstack_top.as_usize().saturating_sub(self.rsp as usize)
+    }
+
+    /// Returns the maximum [`kstack_used_bytes`] observed when the task is
+    /// switched out.
+    ///
+    /// [`kstack_used_bytes`]: TaskContext::kstack_used_bytes
+    pub fn kstack_max_depth_bytes(&self) -> usize {
+        self.max_kstack_used as usize
+    }
+
+    /// Places a canary at the bottom of the kernel stack, which is checked on
+    /// every [`switch_to`] from the task to detect kernel stack overflows.
+    ///
+    /// It must be called after [`init`], with the bottom (lowest address) of
+    /// the kernel stack.
+    ///
+    /// [`init`]: TaskContext::init
+    /// [`switch_to`]: TaskContext::switch_to
+    #[cfg(feature = "stack-canary")]
+    pub fn init_stack_canary(&mut self, kstack_bottom: VirtAddr) {
+        const CANARY_MAGIC: u64 = 0xdead_beef_cafe_babe;
+        self.kstack_bottom = kstack_bottom;
+        self.stack_canary = CANARY_MAGIC ^ self.kstack_top.as_usize() as u64;
+        unsafe { (kstack_bottom.as_mut_ptr() as *mut u64).write_volatile(self.stack_canary) };
+    }
+
+    /// Checks whether the canary at the bottom of the kernel stack is intact.
+    ///
+    /// Always returns `true` if the canary is not set by
+    /// [`init_stack_canary`](TaskContext::init_stack_canary).
+    #[cfg(feature = "stack-canary")]
+    pub fn check_stack_canary(&self) -> bool {
+        if self.kstack_bottom.as_usize() == 0 {
+            return true;
+        }
+        let value = unsafe { (self.kstack_bottom.as_ptr() as *const u64).read_volatile() };
+        value == self.stack_canary
+    }
+
+    /// Fills the unused part of the kernel stack (below the saved [`rsp`])
+    /// with [`STACK_PAINT_PATTERN`], to find out the maximum stack usage later.
+    ///
+    /// It is called by [`init`] before the task runs, and does nothing if
+    /// [`kstack_size`] is 0. The paint is only reliable if the task is started
+    /// from [`init`], as a running task may have already used the stack below
+    /// [`rsp`].
+    ///
+    /// [`rsp`]: TaskContext::rsp
+    /// [`init`]: TaskContext::init
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn paint_stack(&self) {
+        let bottom = self.kstack_top.as_usize() - self.kstack_size;
+        unsafe { crate::stack_paint::paint(bottom, self.rsp as usize) };
+    }
+
+    /// Returns the lowest address of the kernel stack that has been written
+    /// since it was painted by [`paint_stack`], i.e., the deepest point the
+    /// stack has ever reached.
+    ///
+    /// Returns [`kstack_top`] if the stack is not painted.
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_top`]: TaskContext::kstack_top
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_high_water(&self) -> VirtAddr {
+        self.first_unpainted(self.kstack_size)
+    }
+
+    /// Returns the worst-case kernel stack usage in bytes, by scanning the
+    /// painted kernel stack of `stack_size` bytes upwards from its bottom until
+    /// the first word that differs from [`STACK_PAINT_PATTERN`].
+    ///
+    /// The result is only meaningful if the stack is painted by
+    /// [`paint_stack`]. `stack_size` is limited to [`kstack_size`].
+    ///
+    /// [`paint_stack`]: TaskContext::paint_stack
+    /// [`kstack_size`]: TaskContext::kstack_size
+    /// [`STACK_PAINT_PATTERN`]: crate::STACK_PAINT_PATTERN
+    #[cfg(feature = "stack-paint")]
+    pub fn stack_usage(&self, stack_size: usize) -> usize {
+        self.kstack_top.as_usize() - self.first_unpainted(stack_size).as_usize()
+    }
+
+    #[cfg(feature = "stack-paint")]
+    fn first_unpainted(&self, stack_size: usize) -> VirtAddr {
+        let top = self.kstack_top.as_usize();
+        let start = top - stack_size.min(self.kstack_size);
+        // Skip the canary at the bottom of the stack.
+        #[cfg(feature = "stack-canary")]
+        let start = if self.kstack_bottom.as_usize() == start {
+            start + 8
+        } else {
+            start
+        };
+        VirtAddr::from(unsafe { crate::stack_paint::first_unpainted(start, top) })
+    }
+
+    /// Unwind the kernel stack of the task and get the backtrace.
+    ///
+    /// It starts from the `RBP` and the return address saved on the kernel
+    /// stack by [`switch_to`], so it is only meaningful when the task is not
+    /// running.
+    ///
+    /// [`switch_to`]: TaskContext::switch_to
+    pub fn backtrace(&self) -> axbacktrace::Backtrace {
+        if self.rsp == 0 {
+            return axbacktrace::Backtrace::capture_trap(0, 0, 0);
+        }
+        // SAFETY: `rsp` points to the `ContextSwitchFrame` pushed on the
+        // kernel stack when the task is switched out (or set up by `init`).
+        let frame = unsafe { &*(self.rsp as *const ContextSwitchFrame) };
+        axbacktrace::Backtrace::capture_trap(frame.rbp as _, frame.rip as _, 0)
+    }
+
+    /// Prepares the task for migration to another CPU.
+    ///
+    /// It must be called on the source CPU after the task has been switched
+    /// out, and before [`migrate_finish`] is called on the target CPU.
+    ///
+    /// Most CPU-local states of the task (`FS_BASE`, `CR3`, etc.) are saved
+    /// eagerly by [`switch_to`], and `TSS.RSP0` is set on each entry to user
+    /// space. When the "fp-lazy" feature is enabled, the FP/SIMD states still
+    /// held by the FPU of the source CPU are saved here.
+    ///
+    /// [`migrate_finish`]: TaskContext::migrate_finish
+    /// [`switch_to`]: TaskContext::switch_to
+    #[inline]
+    pub fn migrate_prepare(&mut self) {
+        #[cfg(feature = "fp-lazy")]
+        self.release_fpu(true);
+    }
+
+    /// Saves the FP/SIMD states of this task if they are still held by the FPU
+    /// of the current CPU (when `save` is true), and gives up the ownership of
+    /// the FPU.
+    ///
+    /// If the task owns the FPU of another CPU (e.g., it is dropped on a CPU
+    /// other than the one it last ran on), the owner slot of that CPU is
+    /// cleared without saving the states, so that it never points to a freed
+    /// context.
+    #[cfg(feature = "fp-lazy")]
+    fn release_fpu(&mut self, save: bool) {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        use super::trap::{FPU_OWNER, FPU_OWNER_BUSY};
+
+        if *self.fpu_owner_slot == 0 {
+            return;
+        }
+        let this = self as *mut Self as usize;
+        // SAFETY: the per-CPU data areas are never freed, and the slot is
+        // only accessed atomically.
+        let slot = unsafe { &*(*self.fpu_owner_slot as *const AtomicUsize) };
+        *self.fpu_owner_slot = 0;
+        if !core::ptr::eq(slot, unsafe { FPU_OWNER.current_ref_raw() }) {
+            // Wait until the #NM handler of that CPU has saved the states to
+            // this context, if it is doing so.
+            while let Err(owner) =
+                slot.compare_exchange(this, 0, Ordering::AcqRel, Ordering::Acquire)
+            {
+                if owner != FPU_OWNER_BUSY {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            *self.fpu_needs_save = false;
+            return;
+        }
+        if slot.load(Ordering::Acquire) != this {
+            return;
+        }
+        if save && *self.fpu_needs_save {
+            let ts_set = crate::asm::read_cr0()
+                & x86::controlregs::Cr0::CR0_TASK_SWITCHED.bits() as u64
+                != 0;
+            crate::asm::cr0_clear_ts();
+            self.ext_state.save();
+            if ts_set {
+                crate::asm::cr0_set_ts();
+            }
+        }
+        *self.fpu_needs_save = false;
+        slot.store(0, Ordering::Release);
+    }
+
+    /// Finishes the migration of the task to the CPU `new_cpu`.
+    ///
+    /// It must be called on the target CPU before the task is switched in. See
+    /// [`migrate_prepare`] for details.
+    ///
+    /// The task must not own the FPU of any CPU when it arrives, so that the
+    /// per-CPU FPU owner of the target CPU is bound to it by the `#NM` handler
+    /// on its first FP/SIMD instruction there (with the "fp-lazy" feature).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_cpu` is not allowed by the CPU affinity mask of the task,
+    /// or (with the "fp-lazy" feature) if the task still owns the FPU of a CPU,
+    /// i.e., [`migrate_prepare`] was not called.
+    ///
+    /// [`migrate_prepare`]: TaskContext::migrate_prepare
+    pub fn migrate_finish(&mut self, new_cpu: usize) {
+        assert!(
+            self.is_allowed_on(new_cpu),
+            "Task migrated to CPU {new_cpu} outside its affinity mask {:#x}",
+            self.cpu_mask
+        );
+        #[cfg(feature = "fp-lazy")]
+        assert!(
+            *self.fpu_owner_slot == 0,
+            "Task migrated to CPU {new_cpu} while owning the FPU of another CPU"
+        );
+    }
+
+    /// Returns the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N. It is not
+    /// enforced by [`switch_to`], but by the scheduler.
+    ///
+    /// [`switch_to`]: TaskContext::switch_to
+    pub const fn affinity(&self) -> u64 {
+        self.cpu_mask
+    }
+
+    /// Sets the CPU affinity mask of the task.
+    ///
+    /// Bit N means the task is allowed to run on logical CPU N.
+    pub const fn set_affinity(&mut self, mask: u64) {
+        self.cpu_mask = mask;
+    }
+
+    /// Checks whether the task is allowed to run on the given logical CPU.
+    ///
+    /// Always returns `false` for CPUs beyond the range of the mask.
+    pub const fn is_allowed_on(&self, cpu: usize) -> bool {
+        cpu < u64::BITS as usize && self.cpu_mask & (1 << cpu) != 0
     }
 
     /// Switches to another task.
@@ -306,11 +1060,31 @@ impl TaskContext {
     /// It first saves the current task's context from CPU to this place, and then
     /// restores the next task's context from `next_ctx` to CPU.
     pub fn switch_to(&mut self, next_ctx: &Self) {
-        #[cfg(feature = "fp-simd")]
+        #[cfg(feature = "stack-canary")]
+        if !self.check_stack_canary() {
+            panic!(
+                "Kernel stack overflow detected: kstack_top={:#x}, kstack_bottom={:#x}",
+                self.kstack_top, self.kstack_bottom
+            );
+        }
+        #[cfg(all(feature = "fp-simd", not(feature = "fp-lazy")))]
         {
             self.ext_state.save();
             next_ctx.ext_state.restore();
         }
+        #[cfg(feature = "fp-lazy")]
+        {
+            // The FP/SIMD states are switched on the first FP/SIMD instruction
+            // of the next task, unless the FPU still holds its states.
+            let next_ptr = next_ctx as *const Self as usize;
+            super::trap::CURRENT_CTX.write_current(next_ptr);
+            let owner = unsafe { super::trap::FPU_OWNER.current_ref_raw() };
+            if owner.load(core::sync::atomic::Ordering::Acquire) == next_ptr {
+                crate::asm::cr0_clear_ts();
+            } else {
+                crate::asm::cr0_set_ts();
+            }
+        }
         #[cfg(feature = "tls")]
         unsafe {
             self.fs_base = crate::asm::read_thread_pointer();
@@ -319,11 +1093,133 @@ impl TaskContext {
         #[cfg(feature = "uspace")]
         unsafe {
             if next_ctx.cr3 != self.cr3 {
-                crate::asm::write_user_page_table(next_ctx.cr3);
-                // writing to CR3 has flushed the TLB
+                #[cfg(feature = "pcid")]
+                super::pcid::write_page_table_root(next_ctx.cr3);
+                #[cfg(not(feature = "pcid"))]
+                crate::asm::write_user_page_table(next_ctx.cr3.phys);
+                // writing to CR3 has flushed the TLB, unless the TLB entries
+                // are tagged by the PCID of the next task
+            }
+        }
+        #[cfg(feature = "pku")]
+        if crate::asm::pku_enabled() {
+            unsafe {
+                self.pkru = crate::asm::rdpkru();
+                if next_ctx.pkru != self.pkru {
+                    crate::asm::wrpkru(next_ctx.pkru);
+                }
             }
         }
-        unsafe { context_switch(&mut self.rsp, &next_ctx.rsp) }
+        #[cfg(feature = "debug-regs")]
+        {
+            if self.debug_active {
+                self.debug_state.save();
+            }
+            if next_ctx.debug_active {
+                next_ctx.debug_state.restore();
+            } else if self.debug_active {
+                super::debug::DebugState::disable_all();
+            }
+        }
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch(self, next_ctx);
+        #[cfg(feature = "ctx-stats")]
+        crate::ctx_switch_stats::switch_begin();
+        // The saved `rsp` points to a `ContextSwitchFrame`. It is 16-byte
+        // aligned for a new task (`rip` is the entry of a function), or 8 mod
+        // 16 for a task switched out in `context_switch` (`rip` is a return
+        // address), so only the 8-byte alignment can be checked here.
+        debug_assert!(
+            next_ctx.rsp.is_multiple_of(8),
+            "misaligned kernel stack pointer {:#x} of the next task",
+            next_ctx.rsp
+        );
+        #[cfg(not(feature = "cet"))]
+        unsafe {
+            context_switch(&mut self.rsp, &next_ctx.rsp)
+        }
+        #[cfg(feature = "cet")]
+        unsafe {
+            context_switch_ssp(
+                &mut self.rsp,
+                &next_ctx.rsp,
+                &mut self.cet_state.ssp,
+                &next_ctx.cet_state.ssp,
+            )
+        }
+        #[cfg(feature = "ctx-stats")]
+        crate::ctx_switch_stats::switch_end();
+        #[cfg(feature = "ctx-observer")]
+        crate::context_observer::notify_switch_back(self);
+        // Switched back: `rsp` now holds the value saved when switching out.
+        let used = self.kstack_used_bytes() as u64;
+        self.max_kstack_used = self.max_kstack_used.max(used);
+    }
+}
+
+#[cfg(feature = "fp-lazy")]
+impl Drop for TaskContext {
+    fn drop(&mut self) {
+        self.release_fpu(false);
+    }
+}
+
+/// A lightweight context for cooperative switching (e.g., fibers or
+/// coroutines) within the same kernel task.
+///
+/// Only the callee-saved registers are saved (on the stack of the fiber, as in
+/// [`TaskContext`]). Unlike [`TaskContext::switch_to`], the FP/SIMD states,
+/// the TLS, the page table and other per-task states are not switched, so all
+/// fibers share them with the task that runs them.
+///
+/// Fibers have no shadow stacks either, so they cannot be used while the
+/// kernel shadow stack is enabled (see
+/// [`cet::enable_kernel_shadow_stack`](super::cet::enable_kernel_shadow_stack)):
+/// the `RET` that resumes a fiber would pop a return address pushed by
+/// another fiber from the shadow stack, raising a `#CP`.
+#[derive(Debug, Default)]
+pub struct FiberContext {
+    /// The saved stack pointer of the fiber, which points to the saved
+    /// callee-saved registers.
+    pub rsp: u64,
+    /// Makes it `!Send` and `!Sync`, as a fiber must be resumed in the task
+    /// that runs it.
+    _phantom: PhantomData<*mut ()>,
+}
+
+impl FiberContext {
+    /// Creates a new fiber context that starts at `entry` on the stack whose
+    /// top is `stack_top`.
+    ///
+    /// The `entry` function must never return, as there is no return address
+    /// on the stack, and it should switch to another fiber instead. The stack
+    /// must be 16-byte aligned and outlive the fiber.
+    pub fn new(entry: usize, stack_top: VirtAddr) -> Self {
+        Self {
+            rsp: unsafe { ContextSwitchFrame::write_initial(stack_top, entry) },
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Switches to another fiber.
+    ///
+    /// It saves the callee-saved registers of the current fiber to `self`, and
+    /// restores those of `next`. It returns when another fiber switches back to
+    /// `self`.
+    ///
+    /// It must not be called while the kernel shadow stack is enabled.
+    pub fn switch_to(&mut self, next: &FiberContext) {
+        #[cfg(feature = "cet")]
+        debug_assert!(
+            !super::cet::kernel_shadow_stack_enabled(),
+            "fibers do not support kernel shadow stacks"
+        );
+        debug_assert!(
+            next.rsp.is_multiple_of(8),
+            "misaligned stack pointer {:#x} of the next fiber",
+            next.rsp
+        );
+        unsafe { context_switch(&mut self.rsp, &next.rsp) }
     }
 }
 
@@ -350,3 +1246,128 @@ unsafe extern "C" fn context_switch(_current_stack: &mut u64, _next_stack: &u64)
         ret",
     )
 }
+
+/// Same as [`context_switch`], and also switches the shadow stack if the next
+/// task has one, i.e., `next_ssp` is not 0.
+///
+/// The shadow stack must be switched here rather than by the caller, as the
+/// final `ret` pops the return address from the shadow stack of the next task.
+/// The current `SSP` is saved to `current_ssp` with a restore token pushed by
+/// `SAVEPREVSSP`.
+#[cfg(feature = "cet")]
+#[unsafe(naked)]
+unsafe extern "C" fn context_switch_ssp(
+    _current_stack: &mut u64,
+    _next_stack: &u64,
+    _current_ssp: &mut u64,
+    _next_ssp: &u64,
+) {
+    naked_asm!(
+        "
+        .code64
+        push    rbp
+        push    rbx
+        push    r12
+        push    r13
+        push    r14
+        push    r15
+        mov     [rdi], rsp
+
+        xor     eax, eax
+        rdsspq  rax                     # no-op if shadow stacks are disabled
+        mov     r8, [rcx]
+        test    rax, rax
+        jz      1f
+        test    r8, r8
+        jz      1f
+        mov     [rdx], rax
+        rstorssp [r8 - 8]
+        saveprevssp
+    1:
+        mov     rsp, [rsi]
+        pop     r15
+        pop     r14
+        pop     r13
+        pop     r12
+        pop     rbx
+        pop     rbp
+        ret",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrapFrame;
+    use crate::gdt::{KCODE64, UCODE32, UCODE64};
+
+    #[test]
+    fn trap_from_user() {
+        let tf = |cs: u16| TrapFrame {
+            cs: cs as u64,
+            ..Default::default()
+        };
+        assert!(!tf(KCODE64.0).is_from_user());
+        assert!(tf(UCODE64.0).is_from_user());
+        assert!(tf(UCODE32.0).is_from_user());
+        // only the requested privilege level is checked
+        assert!(tf(0x3).is_from_user());
+        assert!(!tf(0x1).is_from_user());
+        assert_eq!(tf(UCODE64.0).cpl(), 3);
+        assert_eq!(tf(KCODE64.0).cpl(), 0);
+    }
+
+    // `FiberContext::switch_to` reads a per-CPU variable when CET is enabled,
+    // which is not available on the host
+    #[cfg(not(feature = "cet"))]
+    mod fiber {
+        use crate::FiberContext;
+        use core::marker::PhantomData;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        const STACK_SIZE: usize = 0x4000;
+
+        #[repr(C, align(16))]
+        struct FiberStack([u8; STACK_SIZE]);
+
+        static mut STACK: FiberStack = FiberStack([0; STACK_SIZE]);
+        static mut MAIN: FiberContext = FiberContext {
+            rsp: 0,
+            _phantom: PhantomData,
+        };
+        static mut FIBER: FiberContext = FiberContext {
+            rsp: 0,
+            _phantom: PhantomData,
+        };
+        static RESUMED: AtomicUsize = AtomicUsize::new(0);
+
+        extern "C" fn fiber_entry() -> ! {
+            let (main, fiber) = (&raw const MAIN, &raw mut FIBER);
+            loop {
+                RESUMED.fetch_add(1, Ordering::Relaxed);
+                unsafe { (*fiber).switch_to(&*main) };
+            }
+        }
+
+        #[test]
+        fn fiber_switch() {
+            let (main, fiber) = (&raw mut MAIN, &raw mut FIBER);
+            let stack_top = (&raw mut STACK as usize + STACK_SIZE).into();
+            unsafe { *fiber = FiberContext::new(fiber_entry as usize, stack_top) };
+            for i in 1..=3 {
+                unsafe { (*main).switch_to(&*fiber) };
+                assert_eq!(RESUMED.load(Ordering::Relaxed), i);
+            }
+        }
+
+        #[test]
+        #[cfg(debug_assertions)]
+        #[should_panic(expected = "misaligned stack pointer 0x3 of the next fiber")]
+        fn fiber_misaligned_stack() {
+            let next = FiberContext {
+                rsp: 3,
+                ..Default::default()
+            };
+            FiberContext::default().switch_to(&next);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! CPU feature detection via CPUID.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::cpuid::{native_cpuid::cpuid_count, CpuIdResult};
+
+bitflags::bitflags! {
+    /// CPU features relevant to this crate, detected via CPUID.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        /// `XSAVE`/`XRSTOR` and `XCR0` (`CPUID.01H:ECX[26]`).
+        const XSAVE = 1 << 0;
+        /// `XSAVEOPT` (`CPUID.(EAX=0DH,ECX=1):EAX[0]`).
+        const XSAVEOPT = 1 << 1;
+        /// Advanced Vector Extensions (`CPUID.01H:ECX[28]`).
+        const AVX = 1 << 2;
+        /// AVX-512 Foundation (`CPUID.(EAX=07H,ECX=0):EBX[16]`).
+        const AVX512F = 1 << 3;
+        /// `RDRAND` instruction (`CPUID.01H:ECX[30]`).
+        const RDRAND = 1 << 4;
+        /// `RDFSBASE`/`WRFSBASE`/`RDGSBASE`/`WRGSBASE` instructions
+        /// (`CPUID.(EAX=07H,ECX=0):EBX[0]`).
+        const FSGSBASE = 1 << 5;
+        /// Process-context identifiers (`CPUID.01H:ECX[17]`).
+        const PCID = 1 << 6;
+        /// `INVPCID` instruction (`CPUID.(EAX=07H,ECX=0):EBX[10]`).
+        const INVPCID = 1 << 7;
+        /// Supervisor-mode execution prevention (`CPUID.(EAX=07H,ECX=0):EBX[7]`).
+        const SMEP = 1 << 8;
+        /// Supervisor-mode access prevention (`CPUID.(EAX=07H,ECX=0):EBX[20]`).
+        const SMAP = 1 << 9;
+        /// CET shadow stacks (`CPUID.(EAX=07H,ECX=0):ECX[7]`).
+        const CET_SS = 1 << 10;
+        /// 5-level paging (`CPUID.(EAX=07H,ECX=0):ECX[16]`).
+        const LA57 = 1 << 11;
+        /// Protection keys for user pages (`CPUID.(EAX=07H,ECX=0):ECX[3]`).
+        const PKU = 1 << 12;
+        /// `MONITOR`/`MWAIT` instructions (`CPUID.01H:ECX[3]`).
+        const MONITOR = 1 << 13;
+    }
+}
+
+/// Set in [`FEATURES_CACHE`] once the features are detected.
+const DETECTED: u64 = 1 << 63;
+
+/// The cached features detected by [`CpuFeatures::detect`].
+static FEATURES_CACHE: AtomicU64 = AtomicU64::new(0);
+
+impl CpuFeatures {
+    /// Detects the features of the current CPU via CPUID, and caches the
+    /// result for [`CpuFeatures::current`].
+    pub fn detect() -> Self {
+        let features = Self::decode(cpuid_count);
+        FEATURES_CACHE.store(features.bits() | DETECTED, Ordering::Relaxed);
+        features
+    }
+
+    /// Decodes the features from the results of `cpuid(leaf, subleaf)`.
+    fn decode(cpuid: impl Fn(u32, u32) -> CpuIdResult) -> Self {
+        let mut features = Self::empty();
+        let leaf1 = cpuid(0x1, 0);
+        features.set(Self::XSAVE, leaf1.ecx & (1 << 26) != 0);
+        features.set(Self::AVX, leaf1.ecx & (1 << 28) != 0);
+        features.set(Self::RDRAND, leaf1.ecx & (1 << 30) != 0);
+        features.set(Self::PCID, leaf1.ecx & (1 << 17) != 0);
+        features.set(Self::MONITOR, leaf1.ecx & (1 << 3) != 0);
+
+        let max_leaf = cpuid(0x0, 0).eax;
+        if max_leaf >= 0x7 {
+            let leaf7 = cpuid(0x7, 0);
+            features.set(Self::FSGSBASE, leaf7.ebx & (1 << 0) != 0);
+            features.set(Self::SMEP, leaf7.ebx & (1 << 7) != 0);
+            features.set(Self::INVPCID, leaf7.ebx & (1 << 10) != 0);
+            features.set(Self::AVX512F, leaf7.ebx & (1 << 16) != 0);
+            features.set(Self::SMAP, leaf7.ebx & (1 << 20) != 0);
+            features.set(Self::CET_SS, leaf7.ecx & (1 << 7) != 0);
+            features.set(Self::LA57, leaf7.ecx & (1 << 16) != 0);
+            features.set(Self::PKU, leaf7.ecx & (1 << 3) != 0);
+        }
+        if max_leaf >= 0xd && features.contains(Self::XSAVE) {
+            features.set(Self::XSAVEOPT, cpuid(0xd, 1).eax & 1 != 0);
+        }
+        features
+    }
+
+    /// Returns the cached features, which are detected on the first call.
+    pub fn current() -> Self {
+        let cached = FEATURES_CACHE.load(Ordering::Relaxed);
+        if cached & DETECTED != 0 {
+            Self::from_bits_truncate(cached)
+        } else {
+            Self::detect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuFeatures;
+    use x86::cpuid::CpuIdResult;
+
+    fn result(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdResult {
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn decode_bits() {
+        let features = CpuFeatures::decode(|leaf, subleaf| match (leaf, subleaf) {
+            (0x0, _) => result(0xd, 0, 0, 0),
+            // XSAVE, AVX, PCID
+            (0x1, _) => result(0, 0, (1 << 26) | (1 << 28) | (1 << 17), 0),
+            // FSGSBASE, SMAP; CET_SS, LA57
+            (0x7, 0) => result(0, (1 << 0) | (1 << 20), (1 << 7) | (1 << 16), 0),
+            (0xd, 1) => result(1, 0, 0, 0),
+            _ => result(0, 0, 0, 0),
+        });
+        assert_eq!(
+            features,
+            CpuFeatures::XSAVE
+                | CpuFeatures::XSAVEOPT
+                | CpuFeatures::AVX
+                | CpuFeatures::PCID
+                | CpuFeatures::FSGSBASE
+                | CpuFeatures::SMAP
+                | CpuFeatures::CET_SS
+                | CpuFeatures::LA57
+        );
+    }
+
+    #[test]
+    fn decode_max_leaf() {
+        // leaf 07H and 0DH are not queried beyond the maximum leaf
+        let features = CpuFeatures::decode(|leaf, _| match leaf {
+            0x0 => result(0x1, 0, 0, 0),
+            0x1 => result(0, 0, (1 << 26) | (1 << 30) | (1 << 3), 0),
+            _ => result(u32::MAX, u32::MAX, u32::MAX, u32::MAX),
+        });
+        assert_eq!(
+            features,
+            CpuFeatures::XSAVE | CpuFeatures::RDRAND | CpuFeatures::MONITOR
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Hardware breakpoints and watchpoints with the debug registers
+//! (`DR0`–`DR7`).
+
+use memory_addr::{MemoryAddr, VirtAddr};
+use x86::debugregs::{self, Dr6, Dr7};
+
+use super::{
+    asm::{read_dr6, write_dr6},
+    TrapFrame,
+};
+use crate::trap::def_trap_handler;
+
+/// The number of hardware breakpoints (`DR0`–`DR3`).
+pub const NUM_BREAKPOINTS: usize = 4;
+
+/// The condition to trigger a hardware breakpoint.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Break on instruction execution.
+    Execute = 0b00,
+    /// Break on data writes.
+    Write = 0b01,
+    /// Break on data reads or writes.
+    ReadWrite = 0b11,
+}
+
+/// The length of the memory region watched by a hardware breakpoint.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointLen {
+    /// 1 byte.
+    Len1 = 0b00,
+    /// 2 bytes.
+    Len2 = 0b01,
+    /// 4 bytes.
+    Len4 = 0b11,
+    /// 8 bytes.
+    Len8 = 0b10,
+}
+
+impl BreakpointLen {
+    /// Returns the length in bytes.
+    pub const fn bytes(self) -> usize {
+        match self {
+            Self::Len1 => 1,
+            Self::Len2 => 2,
+            Self::Len4 => 4,
+            Self::Len8 => 8,
+        }
+    }
+}
+
+/// Errors of setting a hardware breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugError {
+    /// The breakpoint index is not less than [`NUM_BREAKPOINTS`].
+    InvalidIndex,
+    /// The address is not aligned to the length.
+    MisalignedAddress,
+    /// Instruction breakpoints must have a length of 1 byte.
+    InvalidLength,
+}
+
+/// Debug registers of a task.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DebugState {
+    /// Breakpoint address registers (`DR0`–`DR3`).
+    pub dr: [u64; NUM_BREAKPOINTS],
+    /// Debug status register.
+    pub dr6: u64,
+    /// Debug control register.
+    pub dr7: u64,
+}
+
+impl DebugState {
+    /// Sets and enables the hardware breakpoint `index` (locally, for the task).
+    pub fn set_breakpoint(
+        &mut self,
+        index: usize,
+        addr: VirtAddr,
+        kind: BreakpointKind,
+        len: BreakpointLen,
+    ) -> Result<(), DebugError> {
+        if index >= NUM_BREAKPOINTS {
+            return Err(DebugError::InvalidIndex);
+        }
+        if kind == BreakpointKind::Execute && len != BreakpointLen::Len1 {
+            return Err(DebugError::InvalidLength);
+        }
+        if !addr.is_aligned(len.bytes()) {
+            return Err(DebugError::MisalignedAddress);
+        }
+        let ctrl_shift = 16 + index * 4;
+        self.dr[index] = addr.as_usize() as u64;
+        self.dr7 &= !(0b1111 << ctrl_shift);
+        self.dr7 |= ((kind as u64) | (len as u64) << 2) << ctrl_shift;
+        self.dr7 |= 1 << (index * 2); // local enable
+        Ok(())
+    }
+
+    /// Disables the hardware breakpoint `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than [`NUM_BREAKPOINTS`].
+    pub fn clear_breakpoint(&mut self, index: usize) {
+        assert!(index < NUM_BREAKPOINTS);
+        self.dr[index] = 0;
+        self.dr7 &= !(0b11 << (index * 2));
+        self.dr7 &= !(0b1111 << (16 + index * 4));
+    }
+
+    /// Returns whether any breakpoint is enabled.
+    pub const fn is_active(&self) -> bool {
+        self.dr7 & 0xff != 0
+    }
+
+    /// Saves the debug registers from CPU to this structure.
+    pub fn save(&mut self) {
+        unsafe {
+            self.dr = [
+                debugregs::dr0() as u64,
+                debugregs::dr1() as u64,
+                debugregs::dr2() as u64,
+                debugregs::dr3() as u64,
+            ];
+            self.dr6 = read_dr6();
+            self.dr7 = debugregs::dr7().0 as u64;
+        }
+    }
+
+    /// Restores the debug registers from this structure to CPU.
+    pub fn restore(&self) {
+        unsafe {
+            debugregs::dr0_write(self.dr[0] as usize);
+            debugregs::dr1_write(self.dr[1] as usize);
+            debugregs::dr2_write(self.dr[2] as usize);
+            debugregs::dr3_write(self.dr[3] as usize);
+            write_dr6(self.dr6);
+            debugregs::dr7_write(Dr7(self.dr7 as usize));
+        }
+    }
+
+    /// Disables all hardware breakpoints on the current CPU.
+    pub fn disable_all() {
+        unsafe { debugregs::dr7_write(Dr7(Dr7::EMPTY)) };
+    }
+}
+
+/// Information about a debug exception (`#DB`), decoded from `DR6`.
+#[derive(Debug, Clone, Copy)]
+pub struct DebugInfo {
+    /// The raw value of `DR6`.
+    pub dr6: u64,
+}
+
+impl DebugInfo {
+    /// Returns whether the hardware breakpoint `index` is triggered.
+    pub const fn breakpoint_hit(&self, index: usize) -> bool {
+        index < NUM_BREAKPOINTS && self.dr6 & (1 << index) != 0
+    }
+
+    /// Returns whether the exception is caused by single-stepping (`DR6.BS`).
+    pub const fn single_step(&self) -> bool {
+        self.dr6 & Dr6::BS.bits() as u64 != 0
+    }
+}
+
+/// A slice of debug exception (`#DB`) handler functions.
+#[def_trap_handler]
+pub static DR_HANDLER: [fn(&mut TrapFrame, DebugInfo) -> bool];
+
+/// Handles the debug exception (`#DB`) with the given `DR6` value.
+///
+/// It calls the registered [`DR_HANDLER`]s until one of them returns `true`.
+/// Returns whether the exception is handled.
+pub(super) fn handle_debug(tf: &mut TrapFrame, dr6: u64) -> bool {
+    let info = DebugInfo { dr6 };
+    DR_HANDLER.iter().any(|handler| handler(tf, info))
+}
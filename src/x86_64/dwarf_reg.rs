@@ -0,0 +1,29 @@
+//! DWARF register numbers of x86_64, used by [`TrapFrame::get_reg`] and
+//! [`TrapFrame::set_reg`].
+//!
+//! See the System V AMD64 ABI, "DWARF Register Number Mapping".
+//!
+//! [`TrapFrame::get_reg`]: crate::TrapFrame::get_reg
+//! [`TrapFrame::set_reg`]: crate::TrapFrame::set_reg
+
+#![allow(missing_docs)]
+
+pub const RAX: u16 = 0;
+pub const RDX: u16 = 1;
+pub const RCX: u16 = 2;
+pub const RBX: u16 = 3;
+pub const RSI: u16 = 4;
+pub const RDI: u16 = 5;
+pub const RBP: u16 = 6;
+pub const RSP: u16 = 7;
+pub const R8: u16 = 8;
+pub const R9: u16 = 9;
+pub const R10: u16 = 10;
+pub const R11: u16 = 11;
+pub const R12: u16 = 12;
+pub const R13: u16 = 13;
+pub const R14: u16 = 14;
+pub const R15: u16 = 15;
+/// The return address, i.e., `RIP`.
+pub const RIP: u16 = 16;
+pub const RFLAGS: u16 = 49;
@@ -0,0 +1,152 @@
+//! Descriptions of the x86 exception vectors 0–31 (Intel SDM Vol. 3A, 6.15).
+
+/// The description of an exception vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionDescriptor {
+    /// The vector number.
+    pub vector: u8,
+    /// The mnemonic, e.g., `#PF`, or an empty string if there is none.
+    pub mnemonic: &'static str,
+    /// The name of the exception.
+    pub name: &'static str,
+    /// Whether the CPU pushes an error code for the exception.
+    pub has_error_code: bool,
+    /// Whether the exception is contributory, i.e., a contributory exception
+    /// raised during the delivery of another contributory exception (or a
+    /// page fault) causes a double fault.
+    pub contributory: bool,
+}
+
+impl ExceptionDescriptor {
+    const fn new(
+        vector: u8,
+        mnemonic: &'static str,
+        name: &'static str,
+        has_error_code: bool,
+        contributory: bool,
+    ) -> Self {
+        Self {
+            vector,
+            mnemonic,
+            name,
+            has_error_code,
+            contributory,
+        }
+    }
+
+    /// Returns the descriptor of the exception vector, or `None` if `vector`
+    /// is not an exception vector (i.e., not less than 32).
+    pub fn for_vector(vector: u8) -> Option<&'static ExceptionDescriptor> {
+        EXCEPTION_DESCRIPTORS.get(vector as usize)
+    }
+}
+
+/// The descriptors of the exception vectors 0–31, indexed by the vector
+/// number.
+pub const EXCEPTION_DESCRIPTORS: [ExceptionDescriptor; 32] = [
+    ExceptionDescriptor::new(0, "#DE", "Divide Error", false, true),
+    ExceptionDescriptor::new(1, "#DB", "Debug", false, false),
+    ExceptionDescriptor::new(2, "NMI", "Non-Maskable Interrupt", false, false),
+    ExceptionDescriptor::new(3, "#BP", "Breakpoint", false, false),
+    ExceptionDescriptor::new(4, "#OF", "Overflow", false, false),
+    ExceptionDescriptor::new(5, "#BR", "BOUND Range Exceeded", false, false),
+    ExceptionDescriptor::new(6, "#UD", "Invalid Opcode", false, false),
+    ExceptionDescriptor::new(7, "#NM", "Device Not Available", false, false),
+    ExceptionDescriptor::new(8, "#DF", "Double Fault", true, false),
+    ExceptionDescriptor::new(9, "", "Coprocessor Segment Overrun", false, false),
+    ExceptionDescriptor::new(10, "#TS", "Invalid TSS", true, true),
+    ExceptionDescriptor::new(11, "#NP", "Segment Not Present", true, true),
+    ExceptionDescriptor::new(12, "#SS", "Stack-Segment Fault", true, true),
+    ExceptionDescriptor::new(13, "#GP", "General Protection", true, true),
+    ExceptionDescriptor::new(14, "#PF", "Page Fault", true, false),
+    ExceptionDescriptor::new(15, "", "Reserved", false, false),
+    ExceptionDescriptor::new(16, "#MF", "x87 FPU Floating-Point Error", false, false),
+    ExceptionDescriptor::new(17, "#AC", "Alignment Check", true, false),
+    ExceptionDescriptor::new(18, "#MC", "Machine Check", false, false),
+    ExceptionDescriptor::new(19, "#XM", "SIMD Floating-Point Exception", false, false),
+    ExceptionDescriptor::new(20, "#VE", "Virtualization Exception", false, false),
+    ExceptionDescriptor::new(21, "#CP", "Control Protection Exception", true, true),
+    ExceptionDescriptor::new(22, "", "Reserved", false, false),
+    ExceptionDescriptor::new(23, "", "Reserved", false, false),
+    ExceptionDescriptor::new(24, "", "Reserved", false, false),
+    ExceptionDescriptor::new(25, "", "Reserved", false, false),
+    ExceptionDescriptor::new(26, "", "Reserved", false, false),
+    ExceptionDescriptor::new(27, "", "Reserved", false, false),
+    ExceptionDescriptor::new(28, "#HV", "Hypervisor Injection Exception", false, false),
+    ExceptionDescriptor::new(29, "#VC", "VMM Communication Exception", true, false),
+    ExceptionDescriptor::new(30, "#SX", "Security Exception", true, false),
+    ExceptionDescriptor::new(31, "", "Reserved", false, false),
+];
+
+/// The descriptor table referenced by a selector error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentTable {
+    /// The global descriptor table.
+    Gdt,
+    /// The local descriptor table.
+    Ldt,
+    /// The interrupt descriptor table.
+    Idt,
+}
+
+/// The error code of a general protection fault (`#GP`), in the selector
+/// error code format (Intel SDM Vol. 3A, 6.13).
+///
+/// It is 0 if the fault is not related to a segment (e.g., a privileged
+/// instruction in user mode, or a non-canonical address).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpErrorCode {
+    /// The raw error code.
+    pub raw: u64,
+}
+
+impl GpErrorCode {
+    /// Creates the error code from the raw value.
+    pub const fn new(raw: u64) -> Self {
+        Self { raw }
+    }
+
+    /// Whether the fault is related to a segment selector or IDT vector.
+    pub const fn is_segment_related(&self) -> bool {
+        self.raw != 0
+    }
+
+    /// Whether the fault occurred during the delivery of an event external to
+    /// the program, e.g., an interrupt (`EXT`, bit 0).
+    pub const fn is_external(&self) -> bool {
+        self.raw & 0b1 != 0
+    }
+
+    /// The descriptor table referenced by the selector index (`IDT` and `TI`,
+    /// bits 2:1).
+    pub const fn table(&self) -> SegmentTable {
+        if self.raw & 0b10 != 0 {
+            SegmentTable::Idt
+        } else if self.raw & 0b100 != 0 {
+            SegmentTable::Ldt
+        } else {
+            SegmentTable::Gdt
+        }
+    }
+
+    /// The index of the segment descriptor or IDT gate (bits 15:3).
+    pub const fn selector_index(&self) -> u16 {
+        ((self.raw >> 3) & 0x1fff) as u16
+    }
+}
+
+impl core::fmt::Display for GpErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if !self.is_segment_related() {
+            return write!(f, "{:#x}", self.raw);
+        }
+        write!(
+            f,
+            "{:#x} ({:?}[{}]{})",
+            self.raw,
+            self.table(),
+            self.selector_index(),
+            if self.is_external() { ", external" } else { "" }
+        )
+    }
+}
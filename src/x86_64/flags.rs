@@ -0,0 +1,112 @@
+//! Manipulation of the `RFLAGS` register of the current CPU.
+
+use core::arch::asm;
+
+/// The trap flag (`TF`), which enables single-step debugging.
+pub const TF: u64 = 1 << 8;
+/// The interrupt enable flag (`IF`).
+pub const IF: u64 = 1 << 9;
+/// The direction flag (`DF`), which makes string instructions decrement the
+/// addresses.
+pub const DF: u64 = 1 << 10;
+/// The alignment check flag (`AC`), which also allows supervisor accesses to
+/// user pages when SMAP is enabled.
+pub const AC: u64 = 1 << 18;
+
+/// Reads the `RFLAGS` register.
+#[inline]
+pub fn read() -> u64 {
+    let value: u64;
+    unsafe { asm!("pushfq; pop {}", out(reg) value, options(nomem, preserves_flags)) };
+    value
+}
+
+/// Writes the `RFLAGS` register.
+#[inline]
+fn write(value: u64) {
+    unsafe { asm!("push {}; popfq", in(reg) value) };
+}
+
+/// Enables interrupts by setting `IF` (`STI`).
+#[inline]
+pub fn enable_irq() {
+    unsafe { asm!("sti", options(nostack)) };
+}
+
+/// Disables interrupts by clearing `IF` (`CLI`).
+#[inline]
+pub fn disable_irq() {
+    unsafe { asm!("cli", options(nostack)) };
+}
+
+/// Sets `TF`, so that a debug exception (`#DB`) is raised after the next
+/// instruction.
+#[inline]
+pub fn set_tf() {
+    write(read() | TF);
+}
+
+/// Clears `TF`.
+#[inline]
+pub fn clear_tf() {
+    write(read() & !TF);
+}
+
+/// Sets `DF` (`STD`).
+///
+/// The System V ABI requires `DF` to be clear on function calls and returns,
+/// so it must be cleared before calling any Rust code.
+#[inline]
+pub fn set_df() {
+    unsafe { asm!("std", options(nomem, nostack)) };
+}
+
+/// Clears `DF` (`CLD`).
+#[inline]
+pub fn clear_df() {
+    unsafe { asm!("cld", options(nomem, nostack)) };
+}
+
+/// Sets `AC`.
+///
+/// If SMAP is enabled, it allows the kernel to access user pages (the same as
+/// `STAC`). Otherwise, it enables the alignment check for user mode if
+/// `CR0.AM` is set.
+#[inline]
+pub fn set_ac() {
+    write(read() | AC);
+}
+
+/// Clears `AC`.
+#[inline]
+pub fn clear_ac() {
+    write(read() & !AC);
+}
+
+/// A guard that disables interrupts while it is alive, created by
+/// [`IrqGuard::new`].
+///
+/// On drop, interrupts are enabled only if they were enabled before the
+/// guard was created, so guards can be nested.
+#[must_use = "interrupts are restored once the guard is dropped"]
+pub struct IrqGuard {
+    irqs_enabled: bool,
+}
+
+impl IrqGuard {
+    /// Disables interrupts and saves whether they were enabled.
+    #[inline]
+    pub fn new() -> Self {
+        let irqs_enabled = read() & IF != 0;
+        disable_irq();
+        Self { irqs_enabled }
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        if self.irqs_enabled {
+            enable_irq();
+        }
+    }
+}
@@ -1,19 +1,67 @@
+//! Global Descriptor Table (GDT) and Task State Segment (TSS).
+
+use memory_addr::VirtAddr;
 use x86_64::{
-    instructions::tables::load_tss,
+    instructions::tables::{lgdt, load_tss},
     registers::segmentation::{Segment, SegmentSelector, CS},
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, DescriptorFlags},
         tss::TaskStateSegment,
+        DescriptorTablePointer,
     },
     PrivilegeLevel,
 };
 
 #[percpu::def_percpu]
 #[unsafe(no_mangle)]
-static TSS: TaskStateSegment = TaskStateSegment::new();
+static TSS: TssStruct = TssStruct::new();
+
+#[percpu::def_percpu]
+static GDT: GdtStruct = GdtStruct::new();
+
+/// The size of the default IST stacks of each CPU.
+const IST_STACK_SIZE: usize = 4096;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+impl IstStack {
+    fn top(&self) -> VirtAddr {
+        va!(self.0.as_ptr_range().end as usize)
+    }
+}
+
+/// The default NMI stack of each CPU, used until [`setup_nmi_stack`] is called.
+#[percpu::def_percpu]
+static NMI_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
+/// The default double fault stack of each CPU, used until [`setup_df_stack`]
+/// is called.
+#[percpu::def_percpu]
+static DF_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
 
+/// The debug exception (`#DB`) stack of each CPU.
 #[percpu::def_percpu]
-static GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+static DB_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
+/// The index in the Interrupt Stack Table (IST) of the TSS for the NMI stack
+/// (i.e., `IST1`).
+pub const NMI_IST_INDEX: u16 = 0;
+
+/// The index in the Interrupt Stack Table (IST) of the TSS for the double
+/// fault stack (i.e., `IST2`).
+pub const DF_IST_INDEX: u16 = 1;
+
+/// The index in the Interrupt Stack Table (IST) of the TSS for the debug
+/// exception stack (i.e., `IST3`).
+pub const DB_IST_INDEX: u16 = 2;
+
+// the traps on IST stacks can nest (e.g., a double fault or `#DB` in the NMI
+// handler), so each of them must have its own entry among `IST1`-`IST7`
+static_assertions::const_assert!(
+    NMI_IST_INDEX != DF_IST_INDEX && NMI_IST_INDEX != DB_IST_INDEX && DF_IST_INDEX != DB_IST_INDEX
+);
+static_assertions::const_assert!(NMI_IST_INDEX < 7 && DF_IST_INDEX < 7 && DB_IST_INDEX < 7);
 
 /// Kernel code segment for 64-bit mode.
 pub const KCODE64: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
@@ -23,19 +71,460 @@ pub const KDATA: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0
 pub const UDATA: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
 /// User code segment for 64-bit mode.
 pub const UCODE64: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+/// User code segment for 32-bit compatibility mode.
+pub const UCODE32: SegmentSelector = SegmentSelector::new(12, PrivilegeLevel::Ring3);
+/// User data segment for 32-bit compatibility mode, which is loaded by
+/// `SYSEXIT`.
+pub const UDATA32: SegmentSelector = SegmentSelector::new(13, PrivilegeLevel::Ring3);
+
+/// Kernel code segment loaded by `SYSENTER` (`IA32_SYSENTER_CS`).
+///
+/// `SYSENTER` and `SYSEXIT` derive the other selectors from it, so it must be
+/// followed by a kernel data segment, [`UCODE32`] and [`UDATA32`].
+pub(super) const SYSENTER_KCODE: SegmentSelector = SegmentSelector::new(10, PrivilegeLevel::Ring0);
+
+/// The number of entries in the Interrupt Stack Table (IST) of the TSS.
+const IST_ENTRIES: usize = 7;
+
+/// The error returned by [`TssStruct::set_ist`] when the IST index is out of
+/// range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IstOutOfRange;
+
+/// A 64-bit Task State Segment (TSS).
+///
+/// In 64-bit mode, the TSS holds the stack pointers loaded on privilege
+/// changes (`RSP0`) and for the IST entries of the IDT, and the offset of the
+/// I/O permission bitmap.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy)]
+pub struct TssStruct(TaskStateSegment);
+
+impl TssStruct {
+    /// Creates a zeroed TSS, without the I/O permission bitmap.
+    pub const fn new() -> Self {
+        Self(TaskStateSegment::new())
+    }
+
+    /// Sets the stack pointer loaded on traps from user space (`RSP0`).
+    ///
+    /// Note that it is also set on each entry to user space by
+    /// [`UserContext::run`](crate::uspace::UserContext::run).
+    pub fn set_rsp0(&mut self, rsp: VirtAddr) {
+        self.0.privilege_stack_table[0] = x86_64::VirtAddr::new_truncate(rsp.as_usize() as u64);
+    }
+
+    /// Sets the `index`-th stack (0-based, i.e., `0` for `IST1`) of the
+    /// Interrupt Stack Table (IST).
+    ///
+    /// The CPU reads the IST on each interrupt, so the new stack takes effect
+    /// immediately without reloading the task register.
+    pub fn set_ist(&mut self, index: usize, stack_top: VirtAddr) -> Result<(), IstOutOfRange> {
+        if index >= IST_ENTRIES {
+            return Err(IstOutOfRange);
+        }
+        self.0.interrupt_stack_table[index] =
+            x86_64::VirtAddr::new_truncate(stack_top.as_usize() as u64);
+        Ok(())
+    }
+
+    /// Sets the offset of the I/O permission bitmap from the TSS base.
+    ///
+    /// An offset beyond the TSS limit means there is no bitmap, i.e., all I/O
+    /// ports are inaccessible from user space.
+    pub fn set_iopb_offset(&mut self, offset: u16) {
+        self.0.iomap_base = offset;
+    }
+}
+
+/// The number of entries in the GDT.
+const GDT_ENTRIES: usize = 16;
+
+/// The Global Descriptor Table (GDT) with the fixed layout used by this crate.
+///
+/// | Index | Descriptor                            |
+/// |-------|---------------------------------------|
+/// | 0     | Null                                  |
+/// | 1     | Kernel code segment ([`KCODE64`])     |
+/// | 2     | Kernel data segment ([`KDATA`])       |
+/// | 3     | User data segment ([`UDATA`])         |
+/// | 4     | User code segment ([`UCODE64`])       |
+/// | 5, 6  | TSS (a 16-byte system descriptor)     |
+/// | 7..=9 | User TLS segments                     |
+/// | 10    | Kernel code segment for `SYSENTER`    |
+/// | 11    | Kernel data segment for `SYSENTER`    |
+/// | 12    | User code segment ([`UCODE32`])       |
+/// | 13    | User data segment ([`UDATA32`])       |
+#[repr(C, align(16))]
+#[derive(Debug)]
+pub struct GdtStruct {
+    table: [u64; GDT_ENTRIES],
+}
+
+impl GdtStruct {
+    /// Selector of the TSS descriptor.
+    pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+    /// Selectors of the user TLS segments, analogous to Linux's
+    /// `GDT_ENTRY_TLS_MIN`..=`GDT_ENTRY_TLS_MAX`.
+    ///
+    /// These segments are only meaningful for 32-bit compatibility mode tasks.
+    /// 64-bit tasks should set the `FS`/`GS` base directly.
+    pub const TLS_SELECTOR: [SegmentSelector; 3] = [
+        SegmentSelector::new(7, PrivilegeLevel::Ring3),
+        SegmentSelector::new(8, PrivilegeLevel::Ring3),
+        SegmentSelector::new(9, PrivilegeLevel::Ring3),
+    ];
+
+    /// Creates an empty GDT, with all descriptors set to null.
+    pub const fn new() -> Self {
+        Self {
+            table: [0; GDT_ENTRIES],
+        }
+    }
+
+    /// Fills the kernel, user and TSS descriptors of the GDT.
+    fn init(&mut self, tss: &'static TssStruct) {
+        self.set_descriptor(KCODE64.index() as _, Descriptor::kernel_code_segment());
+        self.set_descriptor(KDATA.index() as _, Descriptor::kernel_data_segment());
+        self.set_descriptor(UDATA.index() as _, Descriptor::user_data_segment());
+        self.set_descriptor(UCODE64.index() as _, Descriptor::user_code_segment());
+        self.set_descriptor(
+            SYSENTER_KCODE.index() as _,
+            Descriptor::kernel_code_segment(),
+        );
+        self.set_descriptor(
+            SYSENTER_KCODE.index() as usize + 1,
+            Descriptor::kernel_data_segment(),
+        );
+        self.set_descriptor(
+            UCODE32.index() as _,
+            Descriptor::UserSegment(DescriptorFlags::USER_CODE32.bits()),
+        );
+        self.set_descriptor(UDATA32.index() as _, Descriptor::user_data_segment());
+        self.set_tss(tss);
+    }
+
+    /// Writes the TSS descriptor with the base address of `tss`.
+    ///
+    /// The CPU caches the TSS base when the task register is loaded, so
+    /// [`reload_tr`] must be called after the base address is changed.
+    pub fn set_tss(&mut self, tss: &'static TssStruct) {
+        self.set_descriptor(
+            Self::TSS_SELECTOR.index() as _,
+            Descriptor::tss_segment(&tss.0),
+        );
+    }
+
+    /// Returns the TSS referred to by the TSS descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The TSS descriptor must be set (e.g., by [`set_tss`]), and the caller
+    /// must ensure that the TSS is not accessed concurrently, e.g., by
+    /// disabling preemption.
+    ///
+    /// [`set_tss`]: GdtStruct::set_tss
+    pub unsafe fn get_tss(&mut self) -> &mut TssStruct {
+        let index = Self::TSS_SELECTOR.index() as usize;
+        let (low, high) = (self.table[index], self.table[index + 1]);
+        let base = (low >> 16) & 0xff_ffff | ((low >> 56) & 0xff) << 24 | high << 32;
+        unsafe { &mut *(base as *mut TssStruct) }
+    }
+
+    /// Writes a descriptor to the given index of the GDT.
+    fn set_descriptor(&mut self, index: usize, desc: Descriptor) {
+        match desc {
+            Descriptor::UserSegment(value) => self.table[index] = value,
+            Descriptor::SystemSegment(low, high) => {
+                self.table[index] = low;
+                self.table[index + 1] = high;
+            }
+        }
+    }
+
+    /// Writes a 32-bit user data descriptor to the `index`-th TLS slot, and
+    /// returns the selector of that slot.
+    ///
+    /// If `limit` does not fit in 20 bits, the limit is scaled by 4 KiB (i.e.,
+    /// the granularity flag is set).
+    ///
+    /// Note that the descriptor is cached by the CPU when the selector is
+    /// loaded into a segment register, so the segment register needs to be
+    /// reloaded after this operation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than the number of TLS slots.
+    pub fn set_tls_entry(&mut self, index: usize, base: u32, limit: u32) -> SegmentSelector {
+        let selector = Self::TLS_SELECTOR[index];
+        let (limit, granularity) = if limit > 0xf_ffff {
+            (limit >> 12, DescriptorFlags::GRANULARITY)
+        } else {
+            (limit, DescriptorFlags::empty())
+        };
+        let flags = DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::WRITABLE
+            | DescriptorFlags::ACCESSED
+            | DescriptorFlags::DPL_RING_3
+            | DescriptorFlags::DEFAULT_SIZE
+            | granularity;
+        let (base, limit) = (base as u64, limit as u64);
+        self.table[selector.index() as usize] = flags.bits()
+            | (limit & 0xffff)
+            | ((limit >> 16) & 0xf) << 48
+            | (base & 0xff_ffff) << 16
+            | (base >> 24) << 56;
+        selector
+    }
+
+    /// Loads the GDT into the current CPU (`LGDT`).
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as it changes the segmentation configuration
+    /// of the current CPU.
+    pub unsafe fn load(&'static self) {
+        let ptr = DescriptorTablePointer {
+            base: x86_64::VirtAddr::from_ptr(self.table.as_ptr()),
+            limit: (core::mem::size_of_val(&self.table) - 1) as u16,
+        };
+        unsafe { lgdt(&ptr) };
+    }
+}
+
+/// The error returned by [`GdtBuilder`] when the GDT has no free entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GdtFull;
+
+/// A raw 8-byte segment descriptor, to be added to the GDT by
+/// [`GdtBuilder::add_segment`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentDescriptor(pub u64);
+
+impl SegmentDescriptor {
+    /// Creates a flat 64-bit code segment descriptor with the given descriptor
+    /// privilege level (0..=3).
+    pub const fn code64(dpl: u8) -> Self {
+        Self(DescriptorFlags::KERNEL_CODE64.bits() | Self::dpl_bits(dpl))
+    }
+
+    /// Creates a flat writable data segment descriptor with the given
+    /// descriptor privilege level (0..=3).
+    pub const fn data(dpl: u8) -> Self {
+        Self(DescriptorFlags::KERNEL_DATA.bits() | Self::dpl_bits(dpl))
+    }
+
+    /// Returns the descriptor privilege level (`DPL`) of the descriptor.
+    pub const fn dpl(&self) -> u8 {
+        ((self.0 >> 45) & 0b11) as u8
+    }
+
+    const fn dpl_bits(dpl: u8) -> u64 {
+        assert!(dpl <= 3, "invalid descriptor privilege level");
+        (dpl as u64) << 45
+    }
+}
+
+/// A builder to compose a GDT with a custom layout, e.g., with LDT or
+/// call-gate descriptors.
+///
+/// The descriptors are placed in the order they are added, starting from
+/// index 1 (index 0 is the null descriptor).
+///
+/// Note that the user space support of this crate (`SYSCALL`/`SYSRET` and the
+/// trap entries) relies on the fixed selectors of [`GdtStruct`] (e.g.,
+/// [`KCODE64`] and [`UCODE64`]), so the custom GDT must keep them at the same
+/// indices if user space is used.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut builder = GdtBuilder::new();
+/// let kcode = builder.add_segment(SegmentDescriptor::code64(0))?;
+/// let kdata = builder.add_segment(SegmentDescriptor::data(0))?;
+/// let tss = builder.add_tss(tss_base, tss_limit)?;
+/// let gdt = builder.build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct GdtBuilder {
+    entries: [u64; GDT_ENTRIES],
+    /// The index of the TSS descriptor, if added.
+    tss_index: Option<usize>,
+    count: usize,
+}
+
+impl GdtBuilder {
+    /// Creates a builder with only the null descriptor.
+    pub const fn new() -> Self {
+        Self {
+            entries: [0; GDT_ENTRIES],
+            tss_index: None,
+            count: 1,
+        }
+    }
+
+    /// Adds a segment descriptor, and returns the selector of it, whose
+    /// requested privilege level (`RPL`) is the `DPL` of the descriptor.
+    pub fn add_segment(&mut self, desc: SegmentDescriptor) -> Result<u16, GdtFull> {
+        if self.count >= GDT_ENTRIES {
+            return Err(GdtFull);
+        }
+        let index = self.count;
+        self.entries[index] = desc.0;
+        self.count += 1;
+        Ok(((index as u16) << 3) | desc.dpl() as u16)
+    }
+
+    /// Adds a 16-byte descriptor of an available 64-bit TSS at `base` with
+    /// the given `limit`, and returns the selector of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a TSS descriptor has already been added.
+    pub fn add_tss(&mut self, base: u64, limit: u32) -> Result<u16, GdtFull> {
+        assert!(self.tss_index.is_none(), "TSS descriptor already added");
+        if self.count + 2 > GDT_ENTRIES {
+            return Err(GdtFull);
+        }
+        let (limit, base_low) = (limit as u64, base & 0xffff_ffff);
+        let low = DescriptorFlags::PRESENT.bits()
+            | (0b1001 << 40) // available 64-bit TSS
+            | (limit & 0xffff)
+            | ((limit >> 16) & 0xf) << 48
+            | (base_low & 0xff_ffff) << 16
+            | (base_low >> 24) << 56;
+        let index = self.count;
+        self.entries[index] = low;
+        self.entries[index + 1] = base >> 32;
+        self.tss_index = Some(index);
+        self.count += 2;
+        Ok((index as u16) << 3)
+    }
+
+    /// Builds the GDT with the added descriptors.
+    pub fn build(self) -> GdtStruct {
+        GdtStruct {
+            table: self.entries,
+        }
+    }
+}
+
+/// Returns a mutable reference to the GDT of the current CPU.
+///
+/// # Safety
+///
+/// The caller must ensure that the GDT is not accessed concurrently, e.g., by
+/// disabling preemption.
+pub unsafe fn current_gdt_mut() -> &'static mut GdtStruct {
+    unsafe { GDT.current_ref_mut_raw() }
+}
+
+/// Sets the stack used to handle NMIs on the current CPU, by writing the
+/// `IST1` entry of the current TSS.
+///
+/// NMIs may arrive at any point (e.g., when the kernel stack is being
+/// switched), so they are always handled on a dedicated stack. A default
+/// 4 KiB stack is used if this function is not called.
+///
+/// The CPU reads the IST from the TSS on each NMI, so the new stack takes
+/// effect immediately without reloading the task register.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the stack used by NMIs. `stack_top`
+/// must be the top of a valid stack that is only used by NMIs of the current
+/// CPU. The top 16 bytes of the stack are reserved to save the kernel GS base,
+/// so it must be called with the GS base of the per-CPU area of this CPU.
+pub unsafe fn setup_nmi_stack(stack_top: VirtAddr) {
+    unsafe { set_paranoid_ist(NMI_IST_INDEX as usize, stack_top) };
+}
+
+/// Sets the stack used to handle double faults (`#DF`) on the current CPU, by
+/// writing the `IST2` entry of the current TSS.
+///
+/// A double fault is usually caused by a kernel stack overflow, so it must be
+/// handled on a dedicated stack. A default 4 KiB stack is used if this
+/// function is not called.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the stack used by double faults.
+/// `stack_top` must be the top of a valid stack that is only used by double
+/// faults of the current CPU. The top 16 bytes of the stack are reserved to
+/// save the kernel GS base, so it must be called with the GS base of the
+/// per-CPU area of this CPU.
+pub unsafe fn setup_df_stack(stack_top: VirtAddr) {
+    unsafe { set_paranoid_ist(DF_IST_INDEX as usize, stack_top) };
+}
+
+/// Returns the `index`-th IST entry of the current TSS, or 0 if it is not set.
+///
+/// # Safety
+///
+/// The per-CPU GS base of the current CPU must be loaded.
+pub(super) unsafe fn current_tss_ist(index: usize) -> u64 {
+    unsafe { TSS.current_ref_raw() }.0.interrupt_stack_table[index].as_u64()
+}
+
+/// Sets the IST entry of the current TSS for a trap with the paranoid entry
+/// (see `trap.S`), which may interrupt the kernel with the user GS base.
+///
+/// The top 16 bytes of the stack are reserved, and the current (kernel) GS
+/// base is saved at the IST stack pointer, where the paranoid entry loads it
+/// from.
+///
+/// # Safety
+///
+/// It must be called in the kernel with the per-CPU GS base of the current
+/// CPU, and `stack_top` must be the 16-byte aligned top of a valid stack.
+unsafe fn set_paranoid_ist(index: usize, stack_top: VirtAddr) {
+    const IA32_GS_BASE: u32 = 0xc000_0101;
+    let ist = stack_top - 16;
+    unsafe {
+        (ist.as_mut_ptr() as *mut u64).write(x86::msr::rdmsr(IA32_GS_BASE));
+        TSS.current_ref_mut_raw().set_ist(index, ist).unwrap();
+    }
+}
+
+/// Reloads the task register (`LTR`) of the current CPU with the TSS selector
+/// of the current GDT.
+///
+/// The CPU caches the base address of the TSS when the task register is
+/// loaded, so it must be called after the base address in the TSS descriptor
+/// is changed (e.g., by [`GdtStruct::set_tss`]). The busy flag of the
+/// descriptor set by the previous `LTR` is cleared first, as `LTR` faults on
+/// a busy TSS.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the TSS of the current CPU. The TSS
+/// descriptor of the current GDT must refer to a valid TSS.
+pub unsafe fn reload_tr() {
+    const TSS_BUSY: u64 = 1 << 41;
+    let gdt = unsafe { current_gdt_mut() };
+    gdt.table[GdtStruct::TSS_SELECTOR.index() as usize] &= !TSS_BUSY;
+    unsafe { load_tss(GdtStruct::TSS_SELECTOR) };
+}
 
 /// Initializes the per-CPU TSS and GDT structures and loads them into the
 /// current CPU.
 pub(super) fn init() {
+    unsafe {
+        setup_nmi_stack(NMI_STACK.current_ref_raw().top());
+        setup_df_stack(DF_STACK.current_ref_raw().top());
+        // `#DB` from the kernel may occur before `swapgs` on the user entry
+        // (e.g., on the first instruction of `sysenter_entry`), so it is also
+        // handled by the paranoid entry
+        set_paranoid_ist(DB_IST_INDEX as usize, DB_STACK.current_ref_raw().top());
+    }
+
     let gdt = unsafe { GDT.current_ref_mut_raw() };
-    assert_eq!(gdt.append(Descriptor::kernel_code_segment()), KCODE64);
-    assert_eq!(gdt.append(Descriptor::kernel_data_segment()), KDATA);
-    assert_eq!(gdt.append(Descriptor::user_data_segment()), UDATA);
-    assert_eq!(gdt.append(Descriptor::user_code_segment()), UCODE64);
-    let tss = gdt.append(Descriptor::tss_segment(unsafe { TSS.current_ref_raw() }));
-    gdt.load();
+    gdt.init(unsafe { TSS.current_ref_raw() });
     unsafe {
+        gdt.load();
         CS::set_reg(KCODE64);
-        load_tss(tss);
+        load_tss(GdtStruct::TSS_SELECTOR);
     }
 }
@@ -0,0 +1,178 @@
+//! Idle helpers for entering low-power states (C-states).
+//!
+//! [`hlt`] is always available and enters C1. With the "mwait" feature, deeper
+//! C-states can be requested by [`enter_c_state`] via `MONITOR`/`MWAIT`, if
+//! [`mwait_available`] returns `true`.
+
+use core::arch::asm;
+
+/// Halts the CPU until the next interrupt (`HLT`).
+///
+/// It must be called with interrupts enabled, otherwise it will never return.
+#[inline]
+pub fn hlt() {
+    unsafe { asm!("hlt") }
+}
+
+/// Checks whether the `MONITOR`/`MWAIT` instructions are supported
+/// (`CPUID.01H:ECX[3]`).
+pub fn mwait_available() -> bool {
+    super::cpu_features::CpuFeatures::current().contains(super::cpu_features::CpuFeatures::MONITOR)
+}
+
+/// `MWAIT` extension: treat interrupts as break events even if they are
+/// masked (`ECX[0]`).
+///
+/// It is only supported if [`MwaitInfo::interrupt_break`] is `true`.
+#[cfg(feature = "mwait")]
+pub const MWAIT_ECX_INTERRUPT_BREAK: u32 = 1 << 0;
+
+/// The `MWAIT` capabilities enumerated by `CPUID.05H`.
+#[cfg(feature = "mwait")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MwaitInfo {
+    /// Whether interrupts can be break events even if they are masked
+    /// (`ECX[1]`, with the extensions enumerated by `ECX[0]`).
+    pub interrupt_break: bool,
+    /// The number of sub C-states of each `MWAIT` C-state `C0..=C7`
+    /// (`EDX[4n+3:4n]`).
+    pub sub_states: [u8; 8],
+}
+
+#[cfg(feature = "mwait")]
+impl MwaitInfo {
+    /// Decodes the `ECX` and `EDX` registers of `CPUID.05H`.
+    pub const fn from_cpuid(ecx: u32, edx: u32) -> Self {
+        let mut sub_states = [0; 8];
+        let mut i = 0;
+        while i < 8 {
+            sub_states[i] = ((edx >> (4 * i)) & 0xf) as u8;
+            i += 1;
+        }
+        Self {
+            interrupt_break: ecx & 0b11 == 0b11,
+            sub_states,
+        }
+    }
+
+    /// Reads the capabilities of the current CPU, or returns `None` if
+    /// `MONITOR`/`MWAIT` or `CPUID.05H` is not supported.
+    pub fn current() -> Option<Self> {
+        use x86::cpuid::native_cpuid::cpuid_count;
+
+        if !mwait_available() || cpuid_count(0, 0).eax < 5 {
+            return None;
+        }
+        let leaf5 = cpuid_count(5, 0);
+        Some(Self::from_cpuid(leaf5.ecx, leaf5.edx))
+    }
+
+    /// Returns the `MWAIT` hints (`EAX`) of the deepest supported C-state not
+    /// deeper than `C<level>`, with its deepest sub C-state, or `None` if no
+    /// C-state in `C1..=C<level>` is supported.
+    ///
+    /// The hints are encoded as the C-state minus one in bits 7:4, and the
+    /// sub C-state in bits 3:0.
+    pub fn hints(&self, level: u8) -> Option<u32> {
+        (1..=level.min(7))
+            .rev()
+            .find(|&n| self.sub_states[n as usize] != 0)
+            .map(|n| ((n as u32 - 1) << 4) | (self.sub_states[n as usize] as u32 - 1))
+    }
+}
+
+/// Sets up the address range to be monitored for the next [`mwait`]
+/// (`MONITOR`).
+///
+/// # Safety
+///
+/// `MONITOR`/`MWAIT` must be supported (see [`mwait_available`]), and `addr`
+/// must be a valid address in the kernel address space.
+#[cfg(feature = "mwait")]
+#[inline]
+pub unsafe fn monitor(addr: *const u32, extension: u32, hint: u32) {
+    unsafe {
+        asm!("monitor", in("rax") addr, in("ecx") extension, in("edx") hint, options(nostack))
+    }
+}
+
+/// Enters an implementation-dependent optimized state until a store to the
+/// range set by [`monitor`] or a break event (e.g., an interrupt) occurs
+/// (`MWAIT`).
+///
+/// `hints` selects the target C-state: bits 7:4 are the C-state minus one,
+/// and bits 3:0 are the sub C-state.
+///
+/// # Safety
+///
+/// `MONITOR`/`MWAIT` must be supported (see [`mwait_available`]).
+#[cfg(feature = "mwait")]
+#[inline]
+pub unsafe fn mwait(extension: u32, hints: u32) {
+    unsafe { asm!("mwait", in("eax") hints, in("ecx") extension, options(nostack)) }
+}
+
+/// Enters the `MWAIT` C-state `level` (1 to 7, as enumerated by
+/// `CPUID.05H:EDX`), and returns when an interrupt occurs.
+///
+/// The deepest supported C-state not deeper than `level` is entered (see
+/// [`MwaitInfo::hints`]). It can be called with interrupts disabled, to avoid
+/// the race between checking for pending work and entering the idle state:
+/// if the CPU supports interrupts as break events while masked, they are
+/// left disabled; otherwise (including the fallback to [`hlt`] when `MWAIT`
+/// is not supported), interrupts are enabled in the shadow of `STI` right
+/// before waiting, and disabled again on return, so the interrupt is handled
+/// before this function returns.
+///
+/// # Panics
+///
+/// Panics if `level` is not in `1..=7`.
+#[cfg(feature = "mwait")]
+pub fn enter_c_state(level: u8) {
+    /// The monitored dummy location, which is never written.
+    static MONITOR_TARGET: u32 = 0;
+
+    assert!((1..=7).contains(&level), "invalid C-state C{level}");
+    let irqs_enabled = super::asm::irqs_enabled();
+    let Some((info, hints)) =
+        MwaitInfo::current().and_then(|info| Some((info, info.hints(level)?)))
+    else {
+        if irqs_enabled {
+            hlt();
+        } else {
+            unsafe { asm!("sti; hlt; cli") };
+        }
+        return;
+    };
+    unsafe {
+        monitor(&MONITOR_TARGET, 0, 0);
+        if irqs_enabled {
+            mwait(0, hints);
+        } else if info.interrupt_break {
+            mwait(MWAIT_ECX_INTERRUPT_BREAK, hints);
+        } else {
+            asm!("sti; mwait; cli", in("eax") hints, in("ecx") 0, options(nostack));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mwait"))]
+mod tests {
+    use super::MwaitInfo;
+
+    #[test]
+    fn mwait_hints() {
+        // C0: 0, C1: 2, C2: 1, C3: 0, C4: 4 sub C-states, interrupt break
+        let info = MwaitInfo::from_cpuid(0b11, 0x4_0120);
+        assert!(info.interrupt_break);
+        assert_eq!(info.sub_states, [0, 2, 1, 0, 4, 0, 0, 0]);
+        assert_eq!(info.hints(1), Some(0x01));
+        assert_eq!(info.hints(2), Some(0x10));
+        // C3 is not supported, falls back to C2
+        assert_eq!(info.hints(3), Some(0x10));
+        assert_eq!(info.hints(6), Some(0x33));
+        // the extensions must be enumerated for the interrupt break
+        assert!(!MwaitInfo::from_cpuid(0b10, 0).interrupt_break);
+        assert_eq!(MwaitInfo::from_cpuid(0, 0).hints(7), None);
+    }
+}
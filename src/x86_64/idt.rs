@@ -1,35 +1,174 @@
+//! Interrupt Descriptor Table (IDT).
+
 use lazyinit::LazyInit;
+use x86::irq::{DEBUG_VECTOR, DOUBLE_FAULT_VECTOR, NONMASKABLE_INTERRUPT_VECTOR};
 use x86_64::{
-    addr::VirtAddr,
-    structures::idt::{Entry, InterruptDescriptorTable},
+    instructions::tables::{lidt, sidt},
+    structures::DescriptorTablePointer,
 };
 
+use super::gdt::KCODE64;
+
 const NUM_INT: usize = 256;
 
-static IDT: LazyInit<InterruptDescriptorTable> = LazyInit::new();
+/// The type of a 64-bit interrupt gate, which clears `IF` on entry.
+const GATE_TYPE_INTERRUPT: u64 = 0xe;
+
+static IDT: LazyInit<IdtStruct> = LazyInit::new();
+
+/// The configuration of an IDT entry (an interrupt gate).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdtEntry {
+    /// The interrupt vector.
+    pub vector: u8,
+    /// The address of the handler.
+    pub handler_addr: usize,
+    /// The index of the stack in the Interrupt Stack Table (IST) of the TSS
+    /// plus one (i.e., `1` for `IST1`), or `0` to use the current stack (or
+    /// `TSS.RSP0` on privilege changes).
+    pub ist: u8,
+    /// The descriptor privilege level, i.e., the lowest privilege level that
+    /// can raise the interrupt by `INT n` (`3` for user space).
+    pub dpl: u8,
+}
+
+impl IdtEntry {
+    /// Encodes the entry into a 16-byte interrupt gate descriptor, with the
+    /// kernel code segment ([`KCODE64`]) as the target segment.
+    pub const fn encode(&self) -> [u64; 2] {
+        assert!(self.ist <= 7, "invalid IST index");
+        assert!(self.dpl <= 3, "invalid descriptor privilege level");
+        let addr = self.handler_addr as u64;
+        let low = (addr & 0xffff)
+            | (KCODE64.0 as u64) << 16
+            | (self.ist as u64) << 32
+            | GATE_TYPE_INTERRUPT << 40
+            | (self.dpl as u64) << 45
+            | 1 << 47 // present
+            | ((addr >> 16) & 0xffff) << 48;
+        [low, addr >> 32]
+    }
+}
+
+static_assertions::const_assert_eq!(
+    IdtEntry {
+        vector: 0,
+        handler_addr: 0x1234_5678_9abc_def0,
+        ist: 1,
+        dpl: 3,
+    }
+    .encode()[0],
+    0x9abc_ee01_0008_def0
+);
+static_assertions::const_assert_eq!(
+    IdtEntry {
+        vector: 0,
+        handler_addr: 0x1234_5678_9abc_def0,
+        ist: 1,
+        dpl: 3,
+    }
+    .encode()[1],
+    0x1234_5678
+);
+
+/// An Interrupt Descriptor Table (IDT) with all 256 entries.
+#[repr(C, align(16))]
+#[derive(Debug, Clone)]
+pub struct IdtStruct {
+    table: [[u64; 2]; NUM_INT],
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<IdtStruct>(), 4096);
+
+impl IdtStruct {
+    /// Creates an IDT with all entries not present.
+    pub const fn new() -> Self {
+        Self {
+            table: [[0; 2]; NUM_INT],
+        }
+    }
+
+    /// Writes the interrupt gate of `entry.vector`.
+    ///
+    /// If the IDT is installed, the new entry takes effect on the next
+    /// interrupt of the vector.
+    pub fn set_entry(&mut self, entry: IdtEntry) -> &mut Self {
+        self.table[entry.vector as usize] = entry.encode();
+        self
+    }
+
+    /// Loads the IDT into the current CPU (`LIDT`).
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as it changes the interrupt handlers of the
+    /// current CPU. All present entries must point to valid handlers.
+    pub unsafe fn install(&'static self) {
+        let ptr = DescriptorTablePointer {
+            base: x86_64::VirtAddr::from_ptr(self.table.as_ptr()),
+            limit: (core::mem::size_of_val(&self.table) - 1) as u16,
+        };
+        unsafe { lidt(&ptr) };
+    }
+
+    /// Returns the IDT loaded in the current CPU, read by `SIDT`.
+    ///
+    /// # Safety
+    ///
+    /// The loaded IDT must be an [`IdtStruct`], e.g., the one loaded by
+    /// [`init_trap`](crate::init::init_trap).
+    pub unsafe fn current() -> &'static IdtStruct {
+        unsafe { &*sidt().base.as_ptr() }
+    }
+
+    /// Returns a mutable reference to the IDT loaded in the current CPU, to
+    /// install additional handlers at runtime.
+    ///
+    /// # Safety
+    ///
+    /// The loaded IDT must be an [`IdtStruct`], and the caller must ensure
+    /// that it is not accessed concurrently. Note that the IDT loaded by
+    /// [`init_trap`](crate::init::init_trap) is shared by all CPUs.
+    pub unsafe fn current_mut() -> &'static mut IdtStruct {
+        unsafe { &mut *sidt().base.as_mut_ptr() }
+    }
+}
 
 /// Initializes the global IDT and loads it into the current CPU.
 pub(super) fn init() {
     IDT.call_once(|| {
         unsafe extern "C" {
             #[link_name = "trap_handler_table"]
-            static ENTRIES: [VirtAddr; NUM_INT];
+            static ENTRIES: [usize; NUM_INT];
         }
-        let mut table = InterruptDescriptorTable::new();
-        let entries = unsafe {
-            core::mem::transmute::<&mut InterruptDescriptorTable, &mut [Entry<()>; NUM_INT]>(
-                &mut table,
-            )
-        };
-        for i in 0..NUM_INT {
-            let opt = unsafe { entries[i].set_handler_addr(ENTRIES[i]) };
-            if i == 0x3 || i == 0x80 {
-                // enable user space breakpoints and legacy int 0x80 syscall
-                opt.set_privilege_level(x86_64::PrivilegeLevel::Ring3);
-            }
+        let mut idt = IdtStruct::new();
+        for (i, &handler_addr) in unsafe { ENTRIES.iter() }.enumerate() {
+            let vector = i as u8;
+            // enable user space breakpoints and legacy int 0x80 syscall
+            let dpl = if vector == 0x3 || vector == super::trap::LEGACY_SYSCALL_VECTOR {
+                3
+            } else {
+                0
+            };
+            // handle NMIs, double faults and debug exceptions on dedicated
+            // stacks
+            let ist = if vector == NONMASKABLE_INTERRUPT_VECTOR {
+                super::gdt::NMI_IST_INDEX as u8 + 1
+            } else if vector == DOUBLE_FAULT_VECTOR {
+                super::gdt::DF_IST_INDEX as u8 + 1
+            } else if vector == DEBUG_VECTOR {
+                super::gdt::DB_IST_INDEX as u8 + 1
+            } else {
+                0
+            };
+            idt.set_entry(IdtEntry {
+                vector,
+                handler_addr,
+                ist,
+                dpl,
+            });
         }
-
-        table
+        idt
     });
-    IDT.load();
+    unsafe { IDT.install() };
 }
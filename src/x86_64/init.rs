@@ -31,3 +31,27 @@ pub fn init_trap() {
     #[cfg(feature = "uspace")]
     super::uspace::init_syscall();
 }
+
+/// Initializes a secondary CPU for SMP bringup, and returns the context of the
+/// boot task running on it.
+///
+/// It sets the per-CPU data register to `cpu_id`, initializes trap handling
+/// (loads the GDT with the TSS of this CPU and the IDT), and detects the CPU
+/// topology. The per-CPU data areas must have been initialized by the bootstrap
+/// CPU (see [`init_percpu`]).
+///
+/// `stack_top` is the top of the kernel stack that the CPU is running on,
+/// which is used as [`TaskContext::kstack_top`] of the boot task.
+///
+/// Other optional CPU features (e.g., XSAVE by `xsave::enable`) must be
+/// enabled in the same way as on the bootstrap CPU.
+///
+/// [`TaskContext::kstack_top`]: crate::TaskContext::kstack_top
+pub fn init_secondary(cpu_id: usize, stack_top: memory_addr::VirtAddr) -> crate::TaskContext {
+    percpu::init_percpu_reg(cpu_id);
+    init_trap();
+    super::topology::init();
+    let mut ctx = crate::TaskContext::new();
+    ctx.kstack_top = stack_top;
+    ctx
+}
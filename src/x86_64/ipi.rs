@@ -0,0 +1,53 @@
+//! Inter-processor interrupts (IPIs) via the local APIC.
+//!
+//! The IPIs are sent to the [`IPI_VECTOR`], and the kernel should call
+//! [`handle_ipi`] (and send the EOI) in the handler of this vector.
+//!
+//! The CPU IDs of [`IpiTarget::Cpu`] are the indices of the per-CPU data
+//! areas, which are translated to the APIC IDs cached by
+//! [`topology::init`](super::topology::init), so it must have been called on
+//! the target CPUs.
+
+pub use crate::ipi_common::{handle_ipi, send, IpiKind, IpiTarget};
+
+use super::apic::{Apic, ICR_LEVEL_ASSERT};
+
+/// The interrupt vector of IPIs.
+pub const IPI_VECTOR: u8 = 0xf3;
+
+/// x2APIC Interrupt Command Register (ICR) MSR.
+const IA32_X2APIC_ICR: u32 = 0x830;
+
+/// ICR: destination shorthand of all CPUs including self.
+const ICR_DEST_ALL: u32 = 0b10 << 18;
+/// ICR: destination shorthand of all CPUs excluding self.
+const ICR_DEST_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Registers the MMIO base address of the local APIC (xAPIC mode), which must
+/// be mapped in the kernel address space.
+///
+/// If it is called with a null pointer, the local APIC is accessed in the
+/// x2APIC mode via MSRs.
+///
+/// It is the same as [`irq::init_lapic`](super::irq::init_lapic).
+pub fn init(lapic_base: *mut u8) {
+    super::irq::init_lapic(lapic_base);
+}
+
+/// Raises the IPI on the target CPUs by writing the ICR of the local APIC.
+pub(crate) fn send_raw(target: IpiTarget) {
+    let (apic_id, shorthand) = match target {
+        IpiTarget::Cpu(cpu_id) => (super::topology::apic_id_of(cpu_id) as u32, 0),
+        IpiTarget::AllExcludingSelf => (0, ICR_DEST_ALL_EXCLUDING_SELF),
+        IpiTarget::All => (0, ICR_DEST_ALL),
+    };
+    let low = IPI_VECTOR as u32 | ICR_LEVEL_ASSERT | shorthand;
+    let base = super::irq::lapic_base();
+    if base == 0 {
+        unsafe { x86::msr::wrmsr(IA32_X2APIC_ICR, ((apic_id as u64) << 32) | low as u64) };
+        return;
+    }
+    // SAFETY: `base` is registered by `init_lapic`, whose caller ensures it is
+    // mapped
+    unsafe { Apic::new(base as _) }.write_icr(apic_id as u8, low);
+}
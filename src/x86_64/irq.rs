@@ -0,0 +1,349 @@
+//! IRQ dispatching, EOI and per-vector IRQ statistics.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The MMIO base address of the local APIC, 0 if the x2APIC mode is used, or
+/// [`LAPIC_UNINIT`] if [`init_lapic`] has not been called.
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(LAPIC_UNINIT);
+
+/// The value of [`LAPIC_BASE`] before [`init_lapic`] is called.
+const LAPIC_UNINIT: usize = usize::MAX;
+
+/// x2APIC End-Of-Interrupt (EOI) register MSR.
+const IA32_X2APIC_EOI: u32 = 0x80b;
+
+/// The number of IRQs received on each vector.
+pub static IRQ_COUNT: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// The number of spurious IRQs on each vector, i.e., IRQs that no handler
+/// claimed.
+pub static SPURIOUS_IRQ_COUNT: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Returns the number of IRQs received on the given vector.
+pub fn count(vector: u8) -> u64 {
+    IRQ_COUNT[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns the number of spurious IRQs on the given vector.
+pub fn spurious_count(vector: u8) -> u64 {
+    SPURIOUS_IRQ_COUNT[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns the total number of spurious IRQs on all vectors.
+pub fn total_spurious_count() -> u64 {
+    SPURIOUS_IRQ_COUNT
+        .iter()
+        .map(|count| count.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// The statistics of an IRQ vector.
+#[cfg(feature = "irq-stats")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    /// The number of IRQs received on the vector.
+    pub count: u64,
+    /// The TSC value when the last IRQ was received, or 0 if none.
+    pub last_cycle: u64,
+}
+
+/// The TSC value when the last IRQ was received on each vector, or 0 if none.
+#[cfg(feature = "irq-stats")]
+static IRQ_LAST_CYCLE: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Returns the statistics of the given vector.
+///
+/// The count is that of [`IRQ_COUNT`]. The two fields are read separately, so
+/// they may be slightly inconsistent when racing with an IRQ on another CPU.
+#[cfg(feature = "irq-stats")]
+pub fn stats(vector: u8) -> IrqStats {
+    IrqStats {
+        count: count(vector),
+        last_cycle: IRQ_LAST_CYCLE[vector as usize].load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the statistics of all vectors, in the order of the vector number.
+///
+/// The statistics are read when the iterator is advanced, as they are kept in
+/// atomics updated by the IRQ handlers on all CPUs rather than in a slice of
+/// [`IrqStats`].
+#[cfg(feature = "irq-stats")]
+pub fn stats_all() -> impl Iterator<Item = IrqStats> {
+    (0..=u8::MAX).map(stats)
+}
+
+/// Registers the MMIO base address of the local APIC (xAPIC mode), which must
+/// be mapped in the kernel address space.
+///
+/// If it is called with a null pointer, the local APIC is accessed in the
+/// x2APIC mode via MSRs. It must be called before any EOI or IPI is sent.
+pub fn init_lapic(lapic_base: *mut u8) {
+    LAPIC_BASE.store(lapic_base as usize, Ordering::Release);
+}
+
+/// Returns the MMIO base address of the local APIC, or 0 if the x2APIC mode
+/// is used.
+///
+/// # Panics
+///
+/// Panics if [`init_lapic`] has not been called, as accessing the x2APIC
+/// MSRs in the xAPIC mode causes a `#GP`.
+pub(super) fn lapic_base() -> usize {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    assert!(base != LAPIC_UNINIT, "the local APIC is not initialized");
+    base
+}
+
+/// Sends the End-Of-Interrupt (EOI) to the local APIC.
+///
+/// The local APIC always completes the highest-priority in-service interrupt,
+/// so `vector` is only used for tracing.
+pub fn send_eoi(vector: u8) {
+    trace!("EOI for vector {vector:#x}");
+    let base = lapic_base();
+    if base == 0 {
+        unsafe { x86::msr::wrmsr(IA32_X2APIC_EOI, 0) };
+    } else {
+        // SAFETY: `base` is registered by `init_lapic`, whose caller ensures it
+        // is mapped
+        unsafe { super::apic::Apic::new(base as _) }.send_eoi();
+    }
+}
+
+/// Dispatches an IRQ to the registered handler and updates the statistics.
+///
+/// The EOI is sent if the handler returns
+/// [`IrqResult::HandledNeedsEoi`](crate::trap::IrqResult::HandledNeedsEoi).
+///
+/// The pending softirqs are run afterwards if the "softirq" feature is enabled.
+pub(super) fn handle_irq(vector: u8) {
+    IRQ_COUNT[vector as usize].fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "irq-stats")]
+    IRQ_LAST_CYCLE[vector as usize].store(super::tsc::read(), Ordering::Relaxed);
+    if !handle_trap!(IRQ, vector as _) {
+        SPURIOUS_IRQ_COUNT[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    #[cfg(feature = "softirq")]
+    crate::softirq::run_pending();
+}
+
+/// The last vector reserved for the legacy PIC and platform IRQs, which start
+/// at `0x20`.
+const LEGACY_VECTOR_END: u8 = 0x2f;
+
+/// An error when allocating an IRQ vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqAllocError {
+    /// All vectors are allocated or reserved.
+    NoFreeVector,
+}
+
+/// An allocator of IRQ vectors, e.g., for the MSI/MSI-X interrupts of PCIe
+/// devices.
+///
+/// The exception vectors (`0x00..=0x1f`), the legacy PIC and platform IRQ
+/// vectors (`0x20..=0x2f`), and the vectors used by this crate (the legacy
+/// syscall vector `0x80`, the spurious vector `0xff` and the IPI vector) are
+/// reserved on creation and are never allocated.
+pub struct IrqAllocator {
+    /// One bit per vector, set if the vector is allocated or reserved.
+    bitmap: [AtomicU64; 4],
+}
+
+/// The bitmap of the reserved vectors.
+const RESERVED_VECTORS: [u64; 4] = {
+    let mut bitmap = [0u64; 4];
+    let mut vector = 0;
+    while vector <= LEGACY_VECTOR_END as usize {
+        bitmap[vector / 64] |= 1 << (vector % 64);
+        vector += 1;
+    }
+    let syscall = super::trap::LEGACY_SYSCALL_VECTOR as usize;
+    bitmap[syscall / 64] |= 1 << (syscall % 64);
+    let spurious = super::apic::SPURIOUS_VECTOR as usize;
+    bitmap[spurious / 64] |= 1 << (spurious % 64);
+    #[cfg(feature = "ipi")]
+    {
+        let ipi = super::ipi::IPI_VECTOR as usize;
+        bitmap[ipi / 64] |= 1 << (ipi % 64);
+    }
+    bitmap
+};
+
+impl IrqAllocator {
+    /// Creates a new allocator with only the reserved vectors allocated.
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [
+                AtomicU64::new(RESERVED_VECTORS[0]),
+                AtomicU64::new(RESERVED_VECTORS[1]),
+                AtomicU64::new(RESERVED_VECTORS[2]),
+                AtomicU64::new(RESERVED_VECTORS[3]),
+            ],
+        }
+    }
+
+    /// Tries to allocate the given vector, returns whether it was free.
+    fn try_allocate(&self, vector: u8) -> bool {
+        let bit = 1 << (vector % 64);
+        self.bitmap[vector as usize / 64].fetch_or(bit, Ordering::AcqRel) & bit == 0
+    }
+
+    /// Allocates a vector.
+    ///
+    /// The `preferred` vector is allocated if it is free, otherwise the lowest
+    /// free vector is allocated.
+    pub fn allocate(&self, preferred: Option<u8>) -> Result<u8, IrqAllocError> {
+        if let Some(vector) = preferred {
+            if self.try_allocate(vector) {
+                return Ok(vector);
+            }
+        }
+        for (i, word) in self.bitmap.iter().enumerate() {
+            let mut value = word.load(Ordering::Acquire);
+            while value != u64::MAX {
+                let vector = (i * 64) as u8 + value.trailing_ones() as u8;
+                if self.try_allocate(vector) {
+                    return Ok(vector);
+                }
+                value = word.load(Ordering::Acquire);
+            }
+        }
+        Err(IrqAllocError::NoFreeVector)
+    }
+
+    /// Releases a vector allocated by [`IrqAllocator::allocate`].
+    ///
+    /// The reserved vectors can not be released.
+    pub fn release(&self, vector: u8) {
+        if RESERVED_VECTORS[vector as usize / 64] & (1 << (vector % 64)) != 0 {
+            warn!("Releasing a reserved IRQ vector {vector:#x}");
+            return;
+        }
+        let bit = 1 << (vector % 64);
+        self.bitmap[vector as usize / 64].fetch_and(!bit, Ordering::AcqRel);
+    }
+
+    /// Returns whether the vector is allocated (or reserved).
+    pub fn is_allocated(&self, vector: u8) -> bool {
+        self.bitmap[vector as usize / 64].load(Ordering::Acquire) & (1 << (vector % 64)) != 0
+    }
+}
+
+/// The global IRQ vector allocator.
+static IRQ_ALLOCATOR: IrqAllocator = IrqAllocator::new();
+
+/// Allocates an IRQ vector from the global allocator, see
+/// [`IrqAllocator::allocate`].
+pub fn allocate(preferred: Option<u8>) -> Result<u8, IrqAllocError> {
+    IRQ_ALLOCATOR.allocate(preferred)
+}
+
+/// Releases an IRQ vector to the global allocator, see
+/// [`IrqAllocator::release`].
+pub fn release(vector: u8) {
+    IRQ_ALLOCATOR.release(vector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::{register_trap_handler, IrqResult, TrapHandlerEntry, IRQ};
+
+    /// The vector handled by [`test_irq_handler`].
+    const HANDLED_VECTOR: u8 = 0xf1;
+
+    #[register_trap_handler(IRQ)]
+    static TEST_IRQ_HANDLER: TrapHandlerEntry<usize, IrqResult> =
+        TrapHandlerEntry::new(0, test_irq_handler);
+
+    fn test_irq_handler(vector: usize) -> IrqResult {
+        if vector == HANDLED_VECTOR as usize {
+            IrqResult::Handled
+        } else {
+            IrqResult::Unhandled
+        }
+    }
+
+    // `softirq::run_pending` called by `handle_irq` accesses per-CPU data,
+    // which is not set up on the host.
+    #[cfg(not(feature = "softirq"))]
+    #[test]
+    fn handle_irq_counts() {
+        const UNHANDLED_VECTOR: u8 = 0xf2;
+
+        let total_spurious = total_spurious_count();
+        handle_irq(HANDLED_VECTOR);
+        handle_irq(HANDLED_VECTOR);
+        handle_irq(UNHANDLED_VECTOR);
+        assert_eq!(count(HANDLED_VECTOR), 2);
+        assert_eq!(spurious_count(HANDLED_VECTOR), 0);
+        assert_eq!(count(UNHANDLED_VECTOR), 1);
+        assert_eq!(spurious_count(UNHANDLED_VECTOR), 1);
+        assert_eq!(total_spurious_count(), total_spurious + 1);
+
+        #[cfg(feature = "irq-stats")]
+        {
+            let first = stats(HANDLED_VECTOR);
+            assert_eq!(first.count, 2);
+            assert_ne!(first.last_cycle, 0);
+            handle_irq(HANDLED_VECTOR);
+            let second = stats(HANDLED_VECTOR);
+            assert_eq!(second.count, 3);
+            assert!(second.last_cycle >= first.last_cycle);
+            assert_eq!(stats(0xf3), IrqStats::default());
+            let all: Vec<IrqStats> = stats_all().collect();
+            assert_eq!(all.len(), 256);
+            assert_eq!(all[HANDLED_VECTOR as usize], second);
+            assert_eq!(all[UNHANDLED_VECTOR as usize].count, 1);
+        }
+    }
+
+    #[test]
+    fn allocator_skips_reserved() {
+        let allocator = IrqAllocator::new();
+        let mut allocated = [false; 256];
+        while let Ok(vector) = allocator.allocate(None) {
+            assert!(!allocated[vector as usize], "{vector:#x} allocated twice");
+            allocated[vector as usize] = true;
+        }
+        for vector in 0..=u8::MAX {
+            let reserved = vector <= LEGACY_VECTOR_END
+                || vector == crate::x86_64::trap::LEGACY_SYSCALL_VECTOR
+                || vector == crate::x86_64::apic::SPURIOUS_VECTOR;
+            #[cfg(feature = "ipi")]
+            let reserved = reserved || vector == crate::x86_64::ipi::IPI_VECTOR;
+            assert_eq!(allocated[vector as usize], !reserved, "vector {vector:#x}");
+            assert!(allocator.is_allocated(vector));
+        }
+        // a reserved vector is not allocated even if preferred
+        assert_eq!(
+            allocator.allocate(Some(0x20)),
+            Err(IrqAllocError::NoFreeVector)
+        );
+    }
+
+    #[test]
+    fn allocator_preferred() {
+        let allocator = IrqAllocator::new();
+        assert_eq!(allocator.allocate(Some(0x40)), Ok(0x40));
+        // the lowest free vector is allocated if the preferred one is taken
+        assert_eq!(allocator.allocate(Some(0x40)), Ok(0x30));
+        assert_eq!(allocator.allocate(None), Ok(0x31));
+        assert_eq!(allocator.allocate(Some(0x20)), Ok(0x32));
+        allocator.release(0x40);
+        assert!(!allocator.is_allocated(0x40));
+        assert_eq!(allocator.allocate(Some(0x40)), Ok(0x40));
+    }
+
+    #[test]
+    fn allocator_release_reserved() {
+        let allocator = IrqAllocator::new();
+        allocator.release(0x20);
+        allocator.release(crate::x86_64::apic::SPURIOUS_VECTOR);
+        assert!(allocator.is_allocated(0x20));
+        assert!(allocator.is_allocated(crate::x86_64::apic::SPURIOUS_VECTOR));
+        assert_eq!(allocator.allocate(None), Ok(0x30));
+    }
+}
@@ -0,0 +1,83 @@
+//! Instruction patching helpers for kernel probes (kprobes).
+
+use core::arch::asm;
+
+use memory_addr::VirtAddr;
+
+/// The opcode of the one-byte `INT3` instruction.
+pub const INT3_OPCODE: u8 = 0xcc;
+
+/// Writes a byte to the kernel text with write protection temporarily
+/// disabled, and returns the original byte.
+///
+/// Interrupts are disabled and `CR0.WP` is cleared during the write, so that
+/// read-only kernel pages can be patched without changing the page table.
+/// `CR4.CET` is also cleared meanwhile, as clearing `CR0.WP` with CET enabled
+/// causes a `#GP`. This is done in a single `asm!` block, so that no call or
+/// return crosses the change of `CR4.CET`.
+unsafe fn patch_text_byte(addr: VirtAddr, byte: u8) -> u8 {
+    const CR0_WP_BIT: u32 = 16;
+    const CR4_CET_BIT: u32 = 23;
+    let irqs_enabled = super::asm::irqs_enabled();
+    super::asm::disable_irqs();
+    let original: u8;
+    unsafe {
+        asm!(
+            "mov {cr4}, cr4",
+            "mov {tmp}, {cr4}",
+            "btr {tmp}, {cet}",
+            "mov cr4, {tmp}",
+            "mov {cr0}, cr0",
+            "mov {tmp}, {cr0}",
+            "btr {tmp}, {wp}",
+            "mov cr0, {tmp}",
+            "mov {original}, byte ptr [{ptr}]",
+            "mov byte ptr [{ptr}], {byte}",
+            "mov cr0, {cr0}",
+            "mov cr4, {cr4}",
+            ptr = in(reg) addr.as_usize(),
+            byte = in(reg_byte) byte,
+            original = out(reg_byte) original,
+            cr0 = out(reg) _,
+            cr4 = out(reg) _,
+            tmp = out(reg) _,
+            wp = const CR0_WP_BIT,
+            cet = const CR4_CET_BIT,
+            options(nostack),
+        );
+    }
+    // Execute a serializing instruction so that the modified code is visible
+    // to the instruction fetch on the current CPU.
+    x86::cpuid::native_cpuid::cpuid_count(0, 0);
+    if irqs_enabled {
+        super::asm::enable_irqs();
+    }
+    original
+}
+
+/// Replaces the first byte of the instruction at `addr` with `INT3`, and
+/// returns the original byte.
+///
+/// The original byte should be saved by the caller and written back with
+/// [`restore_original`] when the probe is removed.
+///
+/// # Safety
+///
+/// This function is unsafe as it modifies the kernel text. The caller must
+/// ensure that `addr` is mapped and points to the first byte of an
+/// instruction, and that no other CPU is executing the patched instruction.
+pub unsafe fn patch_with_int3(addr: VirtAddr) -> u8 {
+    unsafe { patch_text_byte(addr, INT3_OPCODE) }
+}
+
+/// Restores the original byte of the instruction at `addr` that was replaced
+/// by [`patch_with_int3`].
+///
+/// # Safety
+///
+/// This function is unsafe as it modifies the kernel text. The caller must
+/// ensure that `original` is the value returned by the corresponding
+/// [`patch_with_int3`].
+pub unsafe fn restore_original(addr: VirtAddr, original: u8) {
+    unsafe { patch_text_byte(addr, original) };
+}
@@ -1,13 +1,52 @@
 mod context;
-mod gdt;
-mod idt;
+pub mod idt;
 
+pub mod apic;
 pub mod asm;
+pub mod cpu_features;
+pub mod dwarf_reg;
+pub mod exception;
+pub mod flags;
+pub mod gdt;
+pub mod idle;
 pub mod init;
+pub mod irq;
+pub mod timer;
+pub mod topology;
+pub mod tsc;
 
 mod trap;
 
+#[cfg(feature = "cet")]
+pub mod cet;
+
+#[cfg(feature = "debug-regs")]
+pub mod debug;
+
+#[cfg(feature = "ipi")]
+pub mod ipi;
+
+#[cfg(feature = "kprobe")]
+pub mod kprobe;
+
+#[cfg(feature = "pcid")]
+pub mod pcid;
+
+#[cfg(feature = "sysenter")]
+mod sysenter;
+
 #[cfg(feature = "uspace")]
 pub mod uspace;
 
-pub use self::context::{ExtendedState, FxsaveArea, TaskContext, TrapFrame};
+#[cfg(feature = "virt")]
+pub mod virt;
+
+#[cfg(feature = "fp-simd")]
+pub mod xsave;
+
+pub use self::context::{
+    CalleeSavedRegs, ExtendedState, FiberContext, FxsaveArea, TaskContext, TrapFrame,
+};
+
+#[cfg(feature = "uspace")]
+pub use self::context::PageTableRoot;
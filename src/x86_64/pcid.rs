@@ -0,0 +1,151 @@
+//! Process-context identifiers (PCIDs), to tag the TLB entries of different
+//! address spaces and avoid TLB flushes on address space switches.
+//!
+//! Each address space is tagged by a PCID from [`allocate`], which is stored
+//! in the [`PageTableRoot`] of the task context. Since the TLB entries tagged
+//! by a PCID survive `CR3` writes, the kernel is responsible for flushing them
+//! (e.g., by [`flush`]) after the page table of the address space is modified,
+//! on every CPU that the address space has run on.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86::controlregs::{cr3_write, cr4, cr4_write, Cr4};
+use x86_64::instructions::tlb::{flush_pcid, InvPcidCommand, Pcid};
+
+use super::cpu_features::CpuFeatures;
+use super::PageTableRoot;
+
+/// The number of PCIDs (12 bits in `CR3`).
+pub const PCID_COUNT: usize = 4096;
+
+/// The no-flush bit of `CR3`. If set when `CR4.PCIDE = 1`, the TLB entries
+/// tagged by the new PCID are not invalidated by the `CR3` write.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/// Whether PCIDs are enabled (`CR4.PCIDE`) and used for context switches.
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Bitmap of the allocated PCIDs. PCID 0 is reserved for the kernel (and
+/// address spaces without a PCID), so it is never allocated.
+static PCID_BITMAP: [AtomicU64; PCID_COUNT / 64] = [const { AtomicU64::new(0) }; PCID_COUNT / 64];
+
+/// Returns whether the CPU supports PCIDs (`CPUID.01H:ECX.PCID`).
+pub fn pcid_supported() -> bool {
+    CpuFeatures::current().contains(CpuFeatures::PCID)
+}
+
+/// Returns whether the CPU supports the `INVPCID` instruction
+/// (`CPUID.(EAX=07H,ECX=0):EBX.INVPCID`).
+pub fn invpcid_supported() -> bool {
+    CpuFeatures::current().contains(CpuFeatures::INVPCID)
+}
+
+/// Returns whether PCIDs are enabled and used for context switches.
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables PCIDs (`CR4.PCIDE`) on the current CPU.
+///
+/// Returns `false` if PCIDs are not supported.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes `CR4` of the current CPU. It should
+/// be called on all CPUs before any PCID is used, while the PCID of the
+/// current `CR3` is 0.
+pub unsafe fn enable() -> bool {
+    if !pcid_supported() {
+        return false;
+    }
+    unsafe { cr4_write(cr4() | Cr4::CR4_ENABLE_PCID) };
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Allocates a free PCID for an address space.
+///
+/// Returns 0 (i.e., no PCID, the TLB is flushed on every switch to the address
+/// space) if all PCIDs are in use.
+pub fn allocate() -> u16 {
+    for (i, word) in PCID_BITMAP.iter().enumerate() {
+        let mut bits = word.load(Ordering::Relaxed);
+        loop {
+            // skip PCID 0
+            let free = !bits & if i == 0 { !1 } else { !0 };
+            if free == 0 {
+                break;
+            }
+            let bit = free.trailing_zeros() as usize;
+            match word.compare_exchange_weak(
+                bits,
+                bits | (1 << bit),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return (i * 64 + bit) as u16,
+                Err(old) => bits = old,
+            }
+        }
+    }
+    0
+}
+
+/// Releases a PCID allocated by [`allocate`].
+///
+/// The TLB entries tagged by the PCID are flushed on the current CPU. Other
+/// CPUs that have run the address space must be flushed by the caller (e.g.,
+/// by [`flush`] in IPIs) before the PCID is reused.
+pub fn release(pcid: u16) {
+    if pcid == 0 || pcid as usize >= PCID_COUNT {
+        return;
+    }
+    flush(pcid);
+    let (i, bit) = (pcid as usize / 64, pcid as usize % 64);
+    PCID_BITMAP[i].fetch_and(!(1 << bit), Ordering::Release);
+}
+
+/// Invalidates the TLB entries tagged by the given PCID on the current CPU.
+///
+/// If `INVPCID` is not supported, all TLB entries (including global ones) are
+/// invalidated instead.
+pub fn flush(pcid: u16) {
+    if !pcid_enabled() {
+        return;
+    }
+    if invpcid_supported() {
+        let pcid = Pcid::new(pcid).expect("invalid PCID");
+        unsafe { flush_pcid(InvPcidCommand::Single(pcid)) };
+    } else {
+        // toggling `CR4.PGE` invalidates all TLB entries for all PCIDs
+        unsafe {
+            let cr4 = cr4();
+            if cr4.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES) {
+                cr4_write(cr4 - Cr4::CR4_ENABLE_GLOBAL_PAGES);
+                cr4_write(cr4);
+            } else {
+                cr4_write(cr4 | Cr4::CR4_ENABLE_GLOBAL_PAGES);
+                cr4_write(cr4);
+            }
+        }
+    }
+}
+
+/// Writes the page table root with its PCID to `CR3`.
+///
+/// If PCIDs are enabled and the PCID is not 0, the TLB entries tagged by the
+/// PCID are kept. Otherwise, the non-global TLB entries are flushed as a
+/// regular `CR3` write.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address space.
+pub unsafe fn write_page_table_root(root: PageTableRoot) {
+    let phys = root.phys.as_usize() as u64;
+    let value = if pcid_enabled() && root.pcid != 0 {
+        phys | root.pcid as u64 | CR3_NOFLUSH
+    } else {
+        phys
+    };
+    unsafe { cr3_write(value) }
+}
@@ -0,0 +1,48 @@
+//! `SYSENTER`/`SYSEXIT` fast system calls for 32-bit compatibility mode.
+//!
+//! The user space saves its stack pointer in `ECX` and the return address in
+//! `EDX` before `SYSENTER`. The syscall returns from [`UserContext::run`] with
+//! [`TrapFrame::vector`] set to [`SYSENTER_VECTOR`], and is returned with
+//! `SYSEXIT` if the context is unchanged in these registers.
+//!
+//! Note that `SYSENTER` in compatibility mode is only supported by Intel CPUs.
+//! AMD CPUs raise `#UD` on it, so the user space should fall back to
+//! `INT 0x80`.
+//!
+//! [`UserContext::run`]: crate::uspace::UserContext::run
+//! [`TrapFrame::vector`]: crate::TrapFrame::vector
+//! [`SYSENTER_VECTOR`]: crate::uspace::SYSENTER_VECTOR
+
+use x86::msr::{wrmsr, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP};
+
+use super::gdt::SYSENTER_KCODE;
+
+/// The size of the stack loaded by `SYSENTER`.
+const SYSENTER_STACK_SIZE: usize = 4096;
+
+#[repr(C, align(16))]
+struct SysenterStack([u8; SYSENTER_STACK_SIZE]);
+
+/// The stack loaded by `SYSENTER` of each CPU (`IA32_SYSENTER_ESP`).
+///
+/// The entry switches to the kernel stack of the user context immediately, so
+/// this stack is only used if a debug exception (e.g., by `RFLAGS.TF` that is
+/// not cleared by `SYSENTER`) occurs on the first instruction of the entry.
+#[percpu::def_percpu]
+static SYSENTER_STACK: SysenterStack = SysenterStack([0; SYSENTER_STACK_SIZE]);
+
+/// Initializes the `SYSENTER` MSRs of the current CPU.
+pub(super) fn init() {
+    unsafe extern "C" {
+        fn sysenter_entry();
+    }
+    let stack_top = unsafe { SYSENTER_STACK.current_ref_raw() }
+        .0
+        .as_ptr_range()
+        .end as u64;
+    unsafe {
+        wrmsr(IA32_SYSENTER_CS, SYSENTER_KCODE.0 as u64);
+        wrmsr(IA32_SYSENTER_ESP, stack_top);
+        wrmsr(IA32_SYSENTER_EIP, sysenter_entry as *const () as u64);
+    }
+}
@@ -0,0 +1,11 @@
+//! Cycle counter of the current CPU.
+
+/// Reads the cycle counter of the current CPU, i.e., the TSC on x86_64.
+///
+/// Use [`tsc::cycles_to_ns`] to convert the cycles to nanoseconds.
+///
+/// [`tsc::cycles_to_ns`]: super::tsc::cycles_to_ns
+#[inline]
+pub fn cycles() -> u64 {
+    super::tsc::read()
+}
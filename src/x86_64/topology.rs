@@ -0,0 +1,186 @@
+//! CPU topology (APIC ID, core and socket) of the current CPU.
+//!
+//! The topology is detected via CPUID and cached in per-CPU data by [`init`],
+//! so that the query functions do not execute CPUID (which may cause a VM exit)
+//! every time.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86::cpuid::{native_cpuid::cpuid_count, CpuIdResult};
+
+/// The topology of a CPU, decoded from its APIC ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// The (x2)APIC ID of the logical processor.
+    pub cpu_id: usize,
+    /// The ID of the core in the package.
+    pub core_id: usize,
+    /// The ID of the package (socket).
+    pub socket_id: usize,
+}
+
+/// The value of [`CPU_ID`] before [`init`] is called.
+const CPU_ID_UNKNOWN: usize = usize::MAX;
+
+/// The APIC ID of each CPU, which is also read by other CPUs to send IPIs.
+#[percpu::def_percpu]
+static CPU_ID: AtomicUsize = AtomicUsize::new(CPU_ID_UNKNOWN);
+
+#[percpu::def_percpu]
+static CORE_ID: usize = 0;
+
+#[percpu::def_percpu]
+static SOCKET_ID: usize = 0;
+
+impl CpuTopology {
+    /// Detects the topology of the current CPU via CPUID.
+    ///
+    /// The x2APIC ID and the bit widths of the SMT and core levels are taken
+    /// from leaf `0BH` if available. Otherwise, the 8-bit APIC ID in
+    /// `CPUID.01H:EBX[31:24]` is used, and each logical processor is treated as
+    /// a core.
+    pub fn detect() -> Self {
+        Self::decode(cpuid_count)
+    }
+
+    /// Decodes the topology from the results of `cpuid(leaf, subleaf)`.
+    fn decode(cpuid: impl Fn(u32, u32) -> CpuIdResult) -> Self {
+        let max_leaf = cpuid(0x0, 0).eax;
+        let (apic_id, smt_shift, core_shift) = if max_leaf >= 0xb && cpuid(0xb, 0).ebx & 0xffff != 0
+        {
+            let (mut smt_shift, mut core_shift) = (0, None);
+            for level in 0..8 {
+                let res = cpuid(0xb, level);
+                // ECX[15:8]: level type, EAX[4:0]: bits to shift to the
+                // next level
+                match (res.ecx >> 8) & 0xff {
+                    0 => break,
+                    1 => smt_shift = res.eax & 0x1f,
+                    2 => core_shift = Some(res.eax & 0x1f),
+                    _ => {}
+                }
+            }
+            let apic_id = cpuid(0xb, 0).edx;
+            (apic_id, smt_shift, core_shift.unwrap_or(smt_shift))
+        } else {
+            let leaf1 = cpuid(0x1, 0);
+            // EBX[23:16]: maximum number of addressable IDs of logical
+            // processors in the package, valid if `HTT` (EDX[28]) is set
+            let logical = if leaf1.edx & (1 << 28) != 0 {
+                (leaf1.ebx >> 16) & 0xff
+            } else {
+                1
+            };
+            let core_shift = logical.max(1).next_power_of_two().trailing_zeros();
+            (leaf1.ebx >> 24, 0, core_shift)
+        };
+        let core_mask = 1u32.checked_shl(core_shift).map_or(u32::MAX, |v| v - 1);
+        Self {
+            cpu_id: apic_id as usize,
+            core_id: ((apic_id & core_mask) >> smt_shift) as usize,
+            socket_id: apic_id.checked_shr(core_shift).unwrap_or(0) as usize,
+        }
+    }
+}
+
+/// Detects the topology of the current CPU, and caches it in per-CPU data.
+///
+/// It should be called on each CPU after the per-CPU data is initialized (see
+/// [`init_percpu`]), and before the query functions of this module are used.
+///
+/// [`init_percpu`]: crate::init::init_percpu
+pub fn init() {
+    let topo = CpuTopology::detect();
+    CPU_ID.with_current(|id| id.store(topo.cpu_id, Ordering::Release));
+    CORE_ID.write_current(topo.core_id);
+    SOCKET_ID.write_current(topo.socket_id);
+}
+
+/// Returns the (x2)APIC ID of the current CPU, cached by [`init`].
+pub fn cpu_id() -> usize {
+    CPU_ID.with_current(|id| id.load(Ordering::Relaxed))
+}
+
+/// Returns the (x2)APIC ID of the CPU with the given index (i.e., the index of
+/// its per-CPU data area), cached by [`init`] on that CPU.
+///
+/// # Panics
+///
+/// Panics if [`init`] has not been called on that CPU.
+pub fn apic_id_of(cpu_index: usize) -> usize {
+    // SAFETY: the per-CPU data of other CPUs are only accessed atomically.
+    let apic_id = unsafe { CPU_ID.remote_ref_raw(cpu_index) }.load(Ordering::Acquire);
+    assert_ne!(
+        apic_id, CPU_ID_UNKNOWN,
+        "topology of CPU {cpu_index} is not initialized"
+    );
+    apic_id
+}
+
+/// Returns the ID of the core of the current CPU in its package, cached by
+/// [`init`].
+pub fn core_id() -> usize {
+    CORE_ID.read_current()
+}
+
+/// Returns the ID of the package (socket) of the current CPU, cached by
+/// [`init`].
+pub fn socket_id() -> usize {
+    SOCKET_ID.read_current()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuTopology;
+    use x86::cpuid::CpuIdResult;
+
+    fn result(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdResult {
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn decode_leaf_0b() {
+        // 2 threads per core (SMT shift 1), 8 cores per package (core shift
+        // 4), x2APIC ID 0x2b: package 2, core 5, thread 1
+        let topo = CpuTopology::decode(|leaf, subleaf| match (leaf, subleaf) {
+            (0x0, _) => result(0xb, 0, 0, 0),
+            (0xb, 0) => result(1, 2, 0x100, 0x2b),
+            (0xb, 1) => result(4, 16, 0x201, 0x2b),
+            (0xb, _) => result(0, 0, subleaf, 0x2b),
+            _ => result(0, 0, 0, 0),
+        });
+        assert_eq!(
+            topo,
+            CpuTopology {
+                cpu_id: 0x2b,
+                core_id: 5,
+                socket_id: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn decode_leaf_01() {
+        // no leaf 0BH, HTT with 6 addressable IDs (core shift 3), APIC ID 0x0d
+        let topo = CpuTopology::decode(|leaf, _| match leaf {
+            0x0 => result(0x4, 0, 0, 0),
+            0x1 => result(0, (0x0d << 24) | (6 << 16), 0, 1 << 28),
+            _ => result(0, 0, 0, 0),
+        });
+        assert_eq!(
+            topo,
+            CpuTopology {
+                cpu_id: 0x0d,
+                core_id: 5,
+                socket_id: 1,
+            }
+        );
+        // no HTT: a single logical processor per package
+        let topo = CpuTopology::decode(|leaf, _| match leaf {
+            0x0 => result(0x1, 0, 0, 0),
+            0x1 => result(0, 3 << 24, 0, 0),
+            _ => result(0, 0, 0, 0),
+        });
+        assert_eq!((topo.cpu_id, topo.core_id, topo.socket_id), (3, 0, 3));
+    }
+}
@@ -1,25 +1,48 @@
-use x86::{controlregs::cr2, irq::*};
+#[cfg(feature = "fp-lazy")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86::irq::*;
 use x86_64::structures::idt::PageFaultErrorCode;
 
-use super::{gdt, TrapFrame};
-use crate::trap::PageFaultFlags;
+use super::{
+    exception::{ExceptionDescriptor, GpErrorCode},
+    gdt, TrapFrame,
+};
+use crate::trap::{PageFaultFlags, PageFaultFlagsExt};
 
 core::arch::global_asm!(
     include_str!("trap.S"),
     trapframe_size = const core::mem::size_of::<TrapFrame>(),
     UDATA = const gdt::UDATA.0,
     UCODE64 = const gdt::UCODE64.0,
-    SYSCALL_VECTOR = const LEGACY_SYSCALL_VECTOR,
+    UDATA32 = const gdt::UDATA32.0,
+    UCODE32 = const gdt::UCODE32.0,
+    SYSCALL_VECTOR = const SYSCALL_VECTOR_FAST,
+    SYSENTER = const cfg!(feature = "sysenter") as u8,
+    SYSENTER_VECTOR = const SYSENTER_VECTOR,
+    CET = const cfg!(feature = "cet") as u8,
 );
 
 pub(super) const LEGACY_SYSCALL_VECTOR: u8 = 0x80;
+
+/// The pseudo vector number saved in [`TrapFrame::vector`] when the user space
+/// enters the kernel with the `SYSCALL` instruction.
+///
+/// It is out of the range of the IDT vectors, so that it can be distinguished
+/// from the legacy `INT 0x80` syscalls and other traps.
+pub const SYSCALL_VECTOR_FAST: u64 = 0x100;
+
+/// The pseudo vector number saved in [`TrapFrame::vector`] when the user space
+/// (in 32-bit compatibility mode) enters the kernel with the `SYSENTER`
+/// instruction.
+pub const SYSENTER_VECTOR: u64 = 0x101;
 pub(super) const IRQ_VECTOR_START: u8 = 0x20;
 pub(super) const IRQ_VECTOR_END: u8 = 0xff;
 
 fn handle_page_fault(tf: &mut TrapFrame) {
     let access_flags = err_code_to_flags(tf.error_code)
         .unwrap_or_else(|e| panic!("Invalid #PF error code: {:#x}", e));
-    let vaddr = va!(unsafe { cr2() });
+    let vaddr = super::asm::read_cr2();
     if handle_trap!(PAGE_FAULT, vaddr, access_flags) {
         return;
     }
@@ -29,61 +52,275 @@ fn handle_page_fault(tf: &mut TrapFrame) {
     }
     core::hint::cold_path();
     panic!(
-        "Unhandled #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x} ({:?}):\n{:#x?}\n{}",
+        "Unhandled #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x} ({:?}):\n{}\n{}",
         tf.rip,
         vaddr,
         tf.error_code,
         access_flags,
-        tf,
+        crate::trap::TrapFrameDump(tf),
         tf.backtrace()
     );
 }
 
+/// Whether the current CPU is handling an NMI.
+#[percpu::def_percpu]
+static IN_NMI: bool = false;
+
+fn handle_nmi(tf: &mut TrapFrame) {
+    // NMIs are blocked by the CPU until `IRET`, but the guard prevents
+    // re-entry if a handler unblocks NMIs (e.g., by an exception).
+    if IN_NMI.read_current() {
+        return;
+    }
+    IN_NMI.write_current(true);
+    if !crate::trap::NMI_HANDLER.iter().any(|handler| handler(tf)) {
+        warn!("Unhandled NMI @ {:#x}", tf.rip);
+    }
+    IN_NMI.write_current(false);
+}
+
+/// Handles the double fault (`#DF`), which is not recoverable.
+///
+/// The system is in an undefined state (e.g., the kernel stack overflows), so
+/// it only prints the trap frame and halts the current CPU, without panicking.
+fn handle_double_fault(tf: &TrapFrame) -> ! {
+    error!(
+        "#DF @ {:#x}, error_code={:#x}:\n{}\n{}",
+        tf.rip,
+        tf.error_code,
+        crate::trap::TrapFrameDump(tf),
+        tf.backtrace()
+    );
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// `DR6.BS`: the debug exception is caused by single-stepping.
+const DR6_BS: u64 = 1 << 14;
+/// The initial value of `DR6`, with all status bits cleared.
+const DR6_RESET: u64 = 0xffff_0ff0;
+
+/// Handles the single-step debug exception (`#DB` with `DR6.BS`) from the user
+/// space with [`STEP_HANDLER`](crate::trap::STEP_HANDLER).
+///
+/// Returns `true` if it is handled, in which case `DR6` is reset.
+#[cfg(feature = "uspace")]
+pub(super) fn handle_user_step(tf: &mut TrapFrame) -> bool {
+    if super::asm::read_dr6() & DR6_BS == 0
+        || !crate::trap::STEP_HANDLER.iter().any(|handler| handler(tf))
+    {
+        return false;
+    }
+    unsafe { super::asm::write_dr6(DR6_RESET) };
+    true
+}
+
+fn handle_debug(tf: &mut TrapFrame) {
+    let dr6 = super::asm::read_dr6();
+    // the status bits of `DR6` are sticky, so reset them before handling
+    unsafe { super::asm::write_dr6(DR6_RESET) };
+
+    let mut handled = false;
+    if dr6 & DR6_BS != 0 {
+        handled |= crate::trap::STEP_HANDLER.iter().any(|handler| handler(tf));
+    }
+    #[cfg(feature = "debug-regs")]
+    {
+        handled |= super::debug::handle_debug(tf, dr6);
+    }
+    if !handled {
+        core::hint::cold_path();
+        panic!(
+            "Unhandled #DB @ {:#x}, DR6={:#x}:\n{}\n{}",
+            tf.rip,
+            dr6,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
+
+/// The context of the task whose FP/SIMD states are in the FPU of the current
+/// CPU, or 0 if there is none.
+///
+/// It may be cleared by another CPU when the owner is dropped there, and is
+/// [`FPU_OWNER_BUSY`] while the #NM handler saves the states to the owner.
+#[cfg(feature = "fp-lazy")]
+#[percpu::def_percpu]
+pub(super) static FPU_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// The value of [`FPU_OWNER`] while its context is being accessed by the
+/// CPU that owns the slot, which must not be freed until then.
+#[cfg(feature = "fp-lazy")]
+pub(super) const FPU_OWNER_BUSY: usize = 1;
+
+/// The context of the task running on the current CPU, set by
+/// [`TaskContext::switch_to`](super::TaskContext::switch_to).
+#[cfg(feature = "fp-lazy")]
+#[percpu::def_percpu]
+pub(super) static CURRENT_CTX: usize = 0;
+
+/// Handles the device-not-available exception (`#NM`) caused by the first
+/// FP/SIMD instruction of a task after it is switched in with `CR0.TS` set.
+///
+/// It saves the FP/SIMD states of the previous owner of the FPU, restores the
+/// states of the current task, and makes the current task the owner.
+#[cfg(feature = "fp-lazy")]
+pub(super) fn handle_nm_exception() {
+    use super::TaskContext;
+
+    super::asm::cr0_clear_ts();
+    let current = CURRENT_CTX.read_current() as *const TaskContext;
+    // SAFETY: IRQs are disabled in the trap handler.
+    let slot = unsafe { FPU_OWNER.current_ref_raw() };
+    if slot.load(Ordering::Acquire) == current as usize {
+        return;
+    }
+    // Mark the slot busy, so that the owner is not freed by another CPU until
+    // its states are saved.
+    let owner = slot.swap(FPU_OWNER_BUSY, Ordering::Acquire) as *const TaskContext;
+    // SAFETY: the task contexts are valid as long as the tasks exist, and the
+    // owner is reset when its context is dropped or migrated. The lazily
+    // switched states are in `FpCell`s, which may be written through the
+    // shared references.
+    unsafe {
+        if let Some(owner) = owner.as_ref() {
+            (*owner.ext_state.get()).save();
+            *owner.fpu_needs_save.get() = false;
+        }
+        if let Some(current) = current.as_ref() {
+            current.ext_state.restore();
+            *current.fpu_needs_save.get() = true;
+            *current.fpu_owner_slot.get() = slot as *const AtomicUsize as usize;
+        }
+    }
+    slot.store(current as usize, Ordering::Release);
+}
+
+/// The vector of the control protection exception (`#CP`).
+#[cfg(feature = "cet")]
+const CONTROL_PROTECTION_VECTOR: u8 = 21;
+
+#[cfg(feature = "cet")]
+fn handle_cp(tf: &mut TrapFrame) {
+    if !super::cet::CP_HANDLER.iter().any(|handler| handler(tf)) {
+        core::hint::cold_path();
+        panic!(
+            "Unhandled #CP @ {:#x}, error_code={:#x}:\n{}\n{}",
+            tf.rip,
+            tf.error_code,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
+
+/// Calls the handlers in order until one of them returns `true`, or panics if
+/// none of them handles the exception.
+fn handle_exception(tf: &mut TrapFrame, handlers: &[fn(&mut TrapFrame) -> bool], name: &str) {
+    if !handlers.iter().any(|handler| handler(tf)) {
+        core::hint::cold_path();
+        panic!(
+            "Unhandled {} @ {:#x}:\n{}\n{}",
+            name,
+            tf.rip,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
+
+fn handle_gp(tf: &mut TrapFrame) {
+    let error_code = GpErrorCode::new(tf.error_code);
+    if !crate::trap::GP_HANDLER
+        .iter()
+        .any(|handler| handler(tf, error_code))
+    {
+        core::hint::cold_path();
+        panic!(
+            "#GP @ {:#x}, error_code={}:\n{}\n{}",
+            tf.rip,
+            error_code,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
+
+fn handle_simd_fp(tf: &mut TrapFrame) {
+    // The faulting SSE instruction has just run, so the live `MXCSR` is the
+    // state of the current task.
+    let mxcsr = super::asm::read_mxcsr();
+    if !crate::trap::SIMD_FP_HANDLER
+        .iter()
+        .any(|handler| handler(tf, mxcsr))
+    {
+        core::hint::cold_path();
+        panic!(
+            "Unhandled #XF @ {:#x}, MXCSR={:#x}:\n{}\n{}",
+            tf.rip,
+            mxcsr,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
+
 #[unsafe(no_mangle)]
 fn x86_trap_handler(tf: &mut TrapFrame) {
     match tf.vector as u8 {
         PAGE_FAULT_VECTOR => handle_page_fault(tf),
+        NONMASKABLE_INTERRUPT_VECTOR => handle_nmi(tf),
         BREAKPOINT_VECTOR => debug!("#BP @ {:#x} ", tf.rip),
-        GENERAL_PROTECTION_FAULT_VECTOR => {
-            panic!(
-                "#GP @ {:#x}, error_code={:#x}:\n{:#x?}\n{}",
-                tf.rip,
-                tf.error_code,
-                tf,
-                tf.backtrace()
-            );
-        }
-        IRQ_VECTOR_START..=IRQ_VECTOR_END => {
-            handle_trap!(IRQ, tf.vector as _);
-        }
-        _ => {
-            panic!(
-                "Unhandled exception {} ({}, error_code={:#x}) @ {:#x}:\n{:#x?}\n{}",
-                tf.vector,
-                vec_to_str(tf.vector),
-                tf.error_code,
-                tf.rip,
-                tf,
-                tf.backtrace()
-            );
+        DOUBLE_FAULT_VECTOR => handle_double_fault(tf),
+        DEBUG_VECTOR => handle_debug(tf),
+        DIVIDE_ERROR_VECTOR => handle_exception(tf, &crate::trap::DIVIDE_HANDLER, "#DE"),
+        OVERFLOW_VECTOR => handle_exception(tf, &crate::trap::OVERFLOW_HANDLER, "#OF"),
+        ALIGNMENT_CHECK_VECTOR => handle_exception(tf, &crate::trap::ALIGNMENT_HANDLER, "#AC"),
+        SIMD_FLOATING_POINT_VECTOR => handle_simd_fp(tf),
+        #[cfg(feature = "virt")]
+        VIRTUALIZATION_VECTOR => super::virt::handle_ve(tf),
+        #[cfg(feature = "fp-lazy")]
+        DEVICE_NOT_AVAILABLE_VECTOR => handle_nm_exception(),
+        #[cfg(feature = "cet")]
+        CONTROL_PROTECTION_VECTOR => handle_cp(tf),
+        GENERAL_PROTECTION_FAULT_VECTOR => handle_gp(tf),
+        IRQ_VECTOR_START..=IRQ_VECTOR_END => super::irq::handle_irq(tf.vector as _),
+        vector => {
+            let desc = ExceptionDescriptor::for_vector(vector);
+            let name = desc.map_or("Unknown", |desc| desc.name);
+            if desc.is_some_and(|desc| desc.has_error_code) {
+                panic!(
+                    "Unhandled exception {} ({}, error_code={:#x}) @ {:#x}:\n{}\n{}",
+                    vector,
+                    name,
+                    tf.error_code,
+                    tf.rip,
+                    crate::trap::TrapFrameDump(tf),
+                    tf.backtrace()
+                );
+            } else {
+                panic!(
+                    "Unhandled exception {} ({}) @ {:#x}:\n{}\n{}",
+                    vector,
+                    name,
+                    tf.rip,
+                    crate::trap::TrapFrameDump(tf),
+                    tf.backtrace()
+                );
+            }
         }
     }
 }
 
-fn vec_to_str(vec: u64) -> &'static str {
-    if vec < 32 {
-        EXCEPTIONS[vec as usize].mnemonic
-    } else {
-        "Unknown"
-    }
-}
-
 pub(super) fn err_code_to_flags(err_code: u64) -> Result<PageFaultFlags, u64> {
     let code = PageFaultErrorCode::from_bits_truncate(err_code);
     let reserved_bits = (PageFaultErrorCode::CAUSED_BY_WRITE
         | PageFaultErrorCode::USER_MODE
         | PageFaultErrorCode::INSTRUCTION_FETCH
-        | PageFaultErrorCode::PROTECTION_VIOLATION)
+        | PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::PROTECTION_KEY)
         .complement();
     if code.intersects(reserved_bits) {
         Err(err_code)
@@ -100,6 +337,9 @@ pub(super) fn err_code_to_flags(err_code: u64) -> Result<PageFaultFlags, u64> {
         if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
             flags |= PageFaultFlags::EXECUTE;
         }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            flags |= PageFaultFlags::PROTECTION_KEY;
+        }
         Ok(flags)
     }
 }
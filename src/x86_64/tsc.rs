@@ -0,0 +1,131 @@
+//! Time Stamp Counter (TSC) and its frequency calibration.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::cpuid::native_cpuid::cpuid_count;
+use x86::io::{inb, outb};
+
+/// The frequency of the PIT input clock.
+const PIT_FREQ_HZ: u64 = 1_193_182;
+
+/// The calibration window against the PIT, in milliseconds.
+const PIT_CALIBRATE_MS: u64 = 1;
+
+/// The maximum number of polls of the PIT output during the calibration. It
+/// takes far longer than the calibration window, as a port I/O takes about
+/// 1µs.
+const PIT_MAX_POLLS: u32 = 1_000_000;
+
+/// An error when determining the TSC frequency by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscCalibrationError {
+    /// The frequency is not enumerated by CPUID, and there is no legacy PIT to
+    /// calibrate against, i.e., its output does not follow the programmed
+    /// count.
+    NoPit,
+    /// The PIT did not reach the terminal count in time.
+    Timeout,
+}
+
+/// The TSC frequency determined by [`init`], or 0 if not initialized.
+static TSC_FREQ_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the current value of the TSC (`RDTSC`).
+#[inline]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns the TSC frequency in Hz determined by [`init`], or 0 if not
+/// initialized.
+#[inline]
+pub fn frequency() -> u64 {
+    TSC_FREQ_HZ.load(Ordering::Relaxed)
+}
+
+/// Determines the TSC frequency, and returns it in Hz.
+///
+/// The frequency is first derived from the nominal core crystal clock
+/// frequency in CPUID leaf `15H` (or the base frequency in leaf `16H` if the
+/// crystal frequency is not enumerated). If neither is available, the TSC is
+/// calibrated against the legacy PIT over a 1ms window.
+///
+/// It only needs to be called once on the bootstrap processor, assuming the
+/// TSC is invariant and synchronized across CPUs.
+///
+/// Returns an error if the PIT is needed but missing or not responding, and
+/// [`frequency`] is left 0 then.
+pub fn init() -> Result<u64, TscCalibrationError> {
+    let freq = match freq_from_cpuid() {
+        Some(freq) => freq,
+        None => calibrate_with_pit()?,
+    };
+    TSC_FREQ_HZ.store(freq, Ordering::Relaxed);
+    Ok(freq)
+}
+
+/// Converts TSC cycles to nanoseconds. Returns 0 if [`init`] is not called.
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    match frequency() {
+        0 => 0,
+        freq => (cycles as u128 * 1_000_000_000 / freq as u128) as u64,
+    }
+}
+
+/// Converts nanoseconds to TSC cycles. Returns 0 if [`init`] is not called.
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    (ns as u128 * frequency() as u128 / 1_000_000_000) as u64
+}
+
+fn freq_from_cpuid() -> Option<u64> {
+    let max_leaf = cpuid_count(0x0, 0).eax;
+    if max_leaf < 0x15 {
+        return None;
+    }
+    // EAX: denominator, EBX: numerator of the TSC/crystal ratio, ECX: crystal
+    // frequency in Hz
+    let leaf15 = cpuid_count(0x15, 0);
+    if leaf15.eax == 0 || leaf15.ebx == 0 {
+        return None;
+    }
+    if leaf15.ecx != 0 {
+        return Some(leaf15.ecx as u64 * leaf15.ebx as u64 / leaf15.eax as u64);
+    }
+    // EAX[15:0]: processor base frequency in MHz
+    if max_leaf >= 0x16 {
+        let base_mhz = cpuid_count(0x16, 0).eax & 0xffff;
+        if base_mhz != 0 {
+            return Some(base_mhz as u64 * 1_000_000);
+        }
+    }
+    None
+}
+
+fn calibrate_with_pit() -> Result<u64, TscCalibrationError> {
+    let count = (PIT_FREQ_HZ * PIT_CALIBRATE_MS / 1000) as u16;
+    let (start, end) = unsafe {
+        // enable the gate of channel 2, and disable the speaker
+        outb(0x61, (inb(0x61) & !0x02) | 0x01);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        outb(0x43, 0xb0);
+        outb(0x42, count as u8);
+        outb(0x42, (count >> 8) as u8);
+        // the output is low until the terminal count in mode 0, while reads
+        // of a missing port return all ones
+        if inb(0x61) & 0x20 != 0 {
+            return Err(TscCalibrationError::NoPit);
+        }
+        let start = read();
+        // wait for the output of channel 2 to go high
+        let mut polls = 0;
+        while inb(0x61) & 0x20 == 0 {
+            polls += 1;
+            if polls == PIT_MAX_POLLS {
+                return Err(TscCalibrationError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        (start, read())
+    };
+    Ok((end - start) * 1000 / PIT_CALIBRATE_MS)
+}
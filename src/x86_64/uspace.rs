@@ -5,7 +5,6 @@ use core::ops::{Deref, DerefMut};
 use memory_addr::VirtAddr;
 use x86_64::{
     registers::{
-        control::Cr2,
         model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star},
         rflags::RFlags,
     },
@@ -18,8 +17,12 @@ use super::{
     trap::{err_code_to_flags, IRQ_VECTOR_END, IRQ_VECTOR_START, LEGACY_SYSCALL_VECTOR},
     TrapFrame,
 };
+use crate::uaccess::{copy_from_user, copy_to_user};
 
-pub use crate::uspace_common::{ExceptionKind, ReturnReason};
+pub use super::trap::{SYSCALL_VECTOR_FAST, SYSENTER_VECTOR};
+pub use crate::uspace_common::{
+    probe_read, probe_write, ExceptionKind, ReturnReason, SigStack, SignalFrameError,
+};
 
 /// Context to enter user space.
 #[derive(Debug, Clone, Copy)]
@@ -30,8 +33,19 @@ pub struct UserContext {
     pub fs_base: u64,
     /// GS Segment Base
     pub gs_base: u64,
+    /// The user shadow stack pointer (`SSP`), or 0 if the task has no shadow
+    /// stack.
+    #[cfg(feature = "cet")]
+    pub ssp: u64,
 }
 
+// `enter_user` stashes the kernel stack pointer right after the trap frame.
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserContext, tf), 0);
+static_assertions::const_assert_eq!(
+    core::mem::size_of::<UserContext>(),
+    core::mem::size_of::<TrapFrame>() + if cfg!(feature = "cet") { 3 * 8 } else { 2 * 8 }
+);
+
 impl UserContext {
     /// Creates a new context with the given entry point, user stack pointer,
     /// and the argument.
@@ -49,9 +63,24 @@ impl UserContext {
             },
             fs_base: 0,
             gs_base: 0,
+            #[cfg(feature = "cet")]
+            ssp: 0,
         }
     }
 
+    /// Sets the user shadow stack, which is used after user shadow stacks are
+    /// enabled by [`enable_user_shadow_stack`].
+    ///
+    /// The kernel is responsible for allocating the shadow stack with the
+    /// shadow stack page attributes. `ssp` is the top of it, which is loaded
+    /// into `SSP` on returns to user space.
+    ///
+    /// [`enable_user_shadow_stack`]: super::cet::enable_user_shadow_stack
+    #[cfg(feature = "cet")]
+    pub const fn set_shadow_stack(&mut self, ssp: u64) {
+        self.ssp = ssp;
+    }
+
     /// Gets the TLS area.
     pub const fn tls(&self) -> usize {
         self.fs_base as _
@@ -73,40 +102,82 @@ impl UserContext {
             fn enter_user(uctx: &mut UserContext);
         }
 
-        assert_eq!(self.cs, gdt::UCODE64.0 as _);
-        assert_eq!(self.ss, gdt::UDATA.0 as _);
+        assert!(self.cs == gdt::UCODE64.0 as _ || self.cs == gdt::UCODE32.0 as _);
+        assert!(self.ss == gdt::UDATA.0 as _ || self.ss == gdt::UDATA32.0 as _);
 
         crate::asm::disable_irqs();
 
-        let kernel_fs_base = read_thread_pointer();
-        unsafe { write_thread_pointer(self.fs_base as _) };
-        KernelGsBase::write(x86_64::VirtAddr::new_truncate(self.gs_base));
+        let ret = loop {
+            let kernel_fs_base = read_thread_pointer();
+            unsafe { write_thread_pointer(self.fs_base as _) };
+            KernelGsBase::write(x86_64::VirtAddr::new_truncate(self.gs_base));
 
-        unsafe { enter_user(self) };
+            #[cfg(feature = "cet")]
+            unsafe {
+                super::cet::write_user_ssp(self.ssp)
+            };
+            unsafe { enter_user(self) };
+            #[cfg(feature = "cet")]
+            {
+                self.ssp = super::cet::read_user_ssp();
+            }
 
-        self.gs_base = KernelGsBase::read().as_u64();
-        self.fs_base = read_thread_pointer() as _;
-        unsafe { write_thread_pointer(kernel_fs_base) };
+            self.gs_base = KernelGsBase::read().as_u64();
+            self.fs_base = read_thread_pointer() as _;
+            unsafe { write_thread_pointer(kernel_fs_base) };
 
-        let cr2 = Cr2::read().unwrap().as_u64() as usize;
-        let vector = self.vector as u8;
+            let cr2 = crate::asm::read_cr2().as_usize();
+            if self.vector == SYSCALL_VECTOR_FAST || self.vector == SYSENTER_VECTOR {
+                break ReturnReason::Syscall;
+            }
+            let vector = self.vector as u8;
 
-        const PAGE_FAULT_VECTOR: u8 = ExceptionVector::Page as u8;
+            const PAGE_FAULT_VECTOR: u8 = ExceptionVector::Page as u8;
+            const SIMD_FLOATING_POINT_VECTOR: u8 = ExceptionVector::SimdFloatingPoint as u8;
+            const ALIGNMENT_CHECK_VECTOR: u8 = ExceptionVector::AlignmentCheck as u8;
+            const DEBUG_VECTOR: u8 = ExceptionVector::Debug as u8;
+            #[cfg(feature = "fp-lazy")]
+            const DEVICE_NOT_AVAILABLE_VECTOR: u8 = ExceptionVector::DeviceNotAvailable as u8;
 
-        let ret = match vector {
-            PAGE_FAULT_VECTOR if let Ok(flags) = err_code_to_flags(self.error_code) => {
-                ReturnReason::PageFault(va!(cr2), flags)
-            }
-            LEGACY_SYSCALL_VECTOR => ReturnReason::Syscall,
-            IRQ_VECTOR_START..=IRQ_VECTOR_END => {
-                handle_trap!(IRQ, vector as _);
-                ReturnReason::Interrupt
-            }
-            _ => ReturnReason::Exception(ExceptionInfo {
-                vector,
-                error_code: self.error_code,
-                cr2,
-            }),
+            break match vector {
+                // switch the FP/SIMD states lazily, and return to user space
+                #[cfg(feature = "fp-lazy")]
+                DEVICE_NOT_AVAILABLE_VECTOR => {
+                    super::trap::handle_nm_exception();
+                    continue;
+                }
+                PAGE_FAULT_VECTOR if let Ok(flags) = err_code_to_flags(self.error_code) => {
+                    ReturnReason::PageFault(va!(cr2), flags)
+                }
+                // single steps handled by the kernel
+                DEBUG_VECTOR if super::trap::handle_user_step(self) => continue,
+                // SIMD floating-point and alignment check exceptions handled by
+                // the kernel
+                SIMD_FLOATING_POINT_VECTOR
+                    if crate::trap::SIMD_FP_HANDLER
+                        .iter()
+                        .any(|handler| handler(self, super::asm::read_mxcsr())) =>
+                {
+                    continue;
+                }
+                ALIGNMENT_CHECK_VECTOR
+                    if crate::trap::ALIGNMENT_HANDLER
+                        .iter()
+                        .any(|handler| handler(self)) =>
+                {
+                    continue;
+                }
+                LEGACY_SYSCALL_VECTOR => ReturnReason::Syscall,
+                IRQ_VECTOR_START..=IRQ_VECTOR_END => {
+                    super::irq::handle_irq(vector as _);
+                    ReturnReason::Interrupt
+                }
+                _ => ReturnReason::Exception(ExceptionInfo {
+                    vector,
+                    error_code: self.error_code,
+                    cr2,
+                }),
+            };
         };
 
         crate::asm::enable_irqs();
@@ -128,6 +199,259 @@ impl DerefMut for UserContext {
     }
 }
 
+/// General registers in the layout of Linux's `struct user_regs_struct`, used
+/// by `ptrace(PTRACE_GETREGS)`/`ptrace(PTRACE_SETREGS)`.
+#[allow(missing_docs)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserRegsStruct {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<UserRegsStruct>(), 27 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserRegsStruct, orig_rax), 15 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserRegsStruct, rip), 16 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserRegsStruct, rsp), 19 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserRegsStruct, fs_base), 21 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UserRegsStruct, gs), 26 * 8);
+
+impl TrapFrame {
+    /// Converts the trap frame to [`UserRegsStruct`].
+    ///
+    /// `orig_rax` is set to the syscall number if the trap is a syscall, and
+    /// `-1` otherwise. `FS`/`GS` bases and data segment selectors are not
+    /// saved in the trap frame, so they are set to 0. Use
+    /// [`UserContext::to_user_regs`] to get the segment bases.
+    pub fn to_user_regs(&self) -> UserRegsStruct {
+        let is_syscall = matches!(self.vector, SYSCALL_VECTOR_FAST | SYSENTER_VECTOR)
+            || self.vector == LEGACY_SYSCALL_VECTOR as u64;
+        UserRegsStruct {
+            r15: self.r15,
+            r14: self.r14,
+            r13: self.r13,
+            r12: self.r12,
+            rbp: self.rbp,
+            rbx: self.rbx,
+            r11: self.r11,
+            r10: self.r10,
+            r9: self.r9,
+            r8: self.r8,
+            rax: self.rax,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            orig_rax: if is_syscall { self.rax } else { u64::MAX },
+            rip: self.rip,
+            cs: self.cs,
+            rflags: self.rflags,
+            rsp: self.rsp,
+            ss: self.ss,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a trap frame from [`UserRegsStruct`].
+    ///
+    /// `orig_rax`, segment bases and data segment selectors are ignored. The
+    /// caller must validate the `cs`, `ss` and `rflags` values from the user
+    /// space before returning to it.
+    pub fn from_user_regs(regs: &UserRegsStruct) -> Self {
+        Self {
+            rax: regs.rax,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rbx: regs.rbx,
+            rbp: regs.rbp,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            vector: 0,
+            error_code: 0,
+            rip: regs.rip,
+            cs: regs.cs,
+            rflags: regs.rflags,
+            rsp: regs.rsp,
+            ss: regs.ss,
+        }
+    }
+}
+
+impl UserContext {
+    /// Converts the user context to [`UserRegsStruct`], including the `FS` and
+    /// `GS` segment bases.
+    pub fn to_user_regs(&self) -> UserRegsStruct {
+        UserRegsStruct {
+            fs_base: self.fs_base,
+            gs_base: self.gs_base,
+            ..self.tf.to_user_regs()
+        }
+    }
+}
+
+/// Machine context in the layout of Linux's `struct sigcontext`, i.e., the
+/// `uc_mcontext` of [`UContext`].
+#[allow(missing_docs)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MContext {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub eflags: u64,
+    pub cs: u16,
+    pub gs: u16,
+    pub fs: u16,
+    pub ss: u16,
+    pub err: u64,
+    pub trapno: u64,
+    pub oldmask: u64,
+    pub cr2: u64,
+    /// The user address of the saved FPU state, or 0 if none.
+    pub fpstate: u64,
+    pub reserved1: [u64; 8],
+}
+
+/// User context in the layout of Linux's `struct ucontext`, which is saved in
+/// the signal frame and restored by `rt_sigreturn`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UContext {
+    /// The flags of the context.
+    pub uc_flags: usize,
+    /// The user address of the context to resume, or 0 if none.
+    pub uc_link: usize,
+    /// The signal stack used by the context.
+    pub uc_stack: SigStack,
+    /// The saved registers.
+    pub uc_mcontext: MContext,
+    /// The signal mask to restore.
+    pub uc_sigmask: u64,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<MContext>(), 256);
+static_assertions::const_assert_eq!(core::mem::offset_of!(MContext, cs), 18 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(MContext, fpstate), 23 * 8);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UContext, uc_mcontext), 40);
+static_assertions::const_assert_eq!(core::mem::offset_of!(UContext, uc_sigmask), 296);
+
+impl TrapFrame {
+    /// Converts the trap frame to [`UContext`].
+    ///
+    /// The trap number and error code are saved in `trapno` and `err`. The
+    /// fields that are not in the trap frame (e.g., `cr2`, `fpstate`, the
+    /// signal stack and mask) are set to 0, which can be filled by the caller.
+    pub fn to_ucontext(&self) -> UContext {
+        UContext {
+            uc_mcontext: MContext {
+                r8: self.r8,
+                r9: self.r9,
+                r10: self.r10,
+                r11: self.r11,
+                r12: self.r12,
+                r13: self.r13,
+                r14: self.r14,
+                r15: self.r15,
+                rdi: self.rdi,
+                rsi: self.rsi,
+                rbp: self.rbp,
+                rbx: self.rbx,
+                rdx: self.rdx,
+                rax: self.rax,
+                rcx: self.rcx,
+                rsp: self.rsp,
+                rip: self.rip,
+                eflags: self.rflags,
+                cs: self.cs as u16,
+                ss: self.ss as u16,
+                err: self.error_code,
+                trapno: self.vector,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Creates a trap frame from the `uc_mcontext` of [`UContext`].
+    ///
+    /// The caller must validate the `cs`, `ss` and `rflags` values from the
+    /// user space before returning to it.
+    pub fn from_ucontext(uc: &UContext) -> Self {
+        let mc = &uc.uc_mcontext;
+        Self {
+            rax: mc.rax,
+            rcx: mc.rcx,
+            rdx: mc.rdx,
+            rbx: mc.rbx,
+            rbp: mc.rbp,
+            rsi: mc.rsi,
+            rdi: mc.rdi,
+            r8: mc.r8,
+            r9: mc.r9,
+            r10: mc.r10,
+            r11: mc.r11,
+            r12: mc.r12,
+            r13: mc.r13,
+            r14: mc.r14,
+            r15: mc.r15,
+            vector: mc.trapno,
+            error_code: mc.err,
+            rip: mc.rip,
+            cs: mc.cs as u64,
+            rflags: mc.eflags,
+            rsp: mc.rsp,
+            ss: mc.ss as u64,
+        }
+    }
+}
+
 /// Information about an exception that occurred in user space.
 #[derive(Debug, Clone, Copy)]
 pub struct ExceptionInfo {
@@ -145,11 +469,174 @@ impl ExceptionInfo {
         match ExceptionVector::try_from(self.vector) {
             Ok(ExceptionVector::Breakpoint) => ExceptionKind::Breakpoint,
             Ok(ExceptionVector::InvalidOpcode) => ExceptionKind::IllegalInstruction,
+            Ok(ExceptionVector::AlignmentCheck) => ExceptionKind::Misaligned,
+            Ok(ExceptionVector::Division) => ExceptionKind::DivisionByZero,
+            Ok(ExceptionVector::Overflow) => ExceptionKind::ArithmeticOverflow,
+            Ok(ExceptionVector::BoundRange) => ExceptionKind::BoundRangeExceeded,
+            Ok(ExceptionVector::X87FloatingPoint) | Ok(ExceptionVector::SimdFloatingPoint) => {
+                ExceptionKind::FloatingPoint
+            }
+            Ok(ExceptionVector::Stack) => ExceptionKind::StackOverflow,
+            Ok(ExceptionVector::GeneralProtection) => ExceptionKind::ProtectionFault {
+                error_code: self.error_code,
+            },
             _ => ExceptionKind::Other,
         }
     }
 }
 
+/// The end of the user address space (lower half of the canonical addresses).
+const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+/// The size of the red zone below the user stack pointer, which must not be
+/// clobbered by signal frames.
+const RED_ZONE_SIZE: usize = 128;
+
+/// The `RFLAGS` bits that can be restored from a signal frame: `CF`, `PF`,
+/// `AF`, `ZF`, `SF`, `TF`, `DF`, `OF`, `RF` and `AC`.
+const RFLAGS_USER_MASK: u64 = 0x50dd5;
+
+/// The end of the address space of compatibility mode (32-bit) tasks.
+const COMPAT_SPACE_END: usize = 0x1_0000_0000;
+
+/// The size of the block below a compatibility mode signal frame, holding the
+/// return address and the arguments of the handler (see
+/// [`UserContext::push_signal_frame`]).
+const COMPAT_ARGS_SIZE: usize = 20;
+
+impl UserContext {
+    /// Returns whether the context runs in compatibility mode (32-bit code),
+    /// i.e., the code segment is [`UCODE32`](gdt::UCODE32).
+    fn is_compat(&self) -> bool {
+        self.tf.cs == gdt::UCODE32.0 as u64
+    }
+
+    /// Pushes a signal frame onto the user stack, and sets up the context to
+    /// run the signal handler.
+    ///
+    /// The current trap frame is saved on the user stack (below the red zone),
+    /// with `restorer` pushed as the return address of `handler`. The handler
+    /// is called with `signum`, `siginfo` (if any, otherwise 0 in
+    /// compatibility mode) and the address of the saved frame as its
+    /// arguments, passed in `RDI`, `RSI` and `RDX` in 64-bit mode, or on the
+    /// stack (the i386 calling convention) in compatibility mode.
+    ///
+    /// In compatibility mode, the saved frame must be below 4 GiB, and the
+    /// handler and the restorer must be 32-bit addresses.
+    pub fn push_signal_frame(
+        &mut self,
+        signum: u32,
+        handler: usize,
+        restorer: usize,
+        siginfo: Option<usize>,
+    ) -> Result<(), SignalFrameError> {
+        const FRAME_SIZE: usize = core::mem::size_of::<TrapFrame>();
+        let compat = self.is_compat();
+        let (space_end, args_size) = if compat {
+            (COMPAT_SPACE_END, COMPAT_ARGS_SIZE)
+        } else {
+            (USER_SPACE_END, 8)
+        };
+        // a non-canonical `RIP` would fault on `SYSRET`/`IRET` in the kernel
+        if handler >= space_end || restorer >= space_end {
+            return Err(SignalFrameError::BadFrame);
+        }
+        let sp = (self.tf.rsp as usize)
+            .checked_sub(RED_ZONE_SIZE + FRAME_SIZE + args_size)
+            .ok_or(SignalFrameError::BadStack)?;
+        // `frame` is 16-byte aligned, so that the stack is aligned on entry of
+        // the handler (`RSP + 8` in 64-bit mode, `ESP + 4` in compatibility
+        // mode).
+        let frame = (sp + args_size) & !0xf;
+        let sp = frame - args_size;
+        if frame > space_end - FRAME_SIZE {
+            return Err(SignalFrameError::BadStack);
+        }
+        // the return address, followed by the arguments in compatibility mode
+        let args: [u32; 5] = [
+            restorer as _,
+            signum,
+            siginfo.unwrap_or(0) as _,
+            frame as _,
+            0,
+        ];
+        unsafe {
+            let tf = &self.tf as *const TrapFrame as *const u8;
+            let (ret, len) = if compat {
+                (args.as_ptr() as *const u8, COMPAT_ARGS_SIZE)
+            } else {
+                (&restorer as *const usize as *const u8, 8)
+            };
+            if copy_to_user(frame as *mut u8, tf, FRAME_SIZE).is_err()
+                || copy_to_user(sp as *mut u8, ret, len).is_err()
+            {
+                return Err(SignalFrameError::BadStack);
+            }
+        }
+        self.tf.rsp = sp as _;
+        self.tf.rip = handler as _;
+        if !compat {
+            self.tf.rdi = signum as _;
+            if let Some(siginfo) = siginfo {
+                self.tf.rsi = siginfo as _;
+            }
+            self.tf.rdx = frame as _;
+        }
+        // clear the flags as on function calls
+        self.tf.rflags &= !(RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG).bits();
+        Ok(())
+    }
+
+    /// Pops the signal frame pushed by [`push_signal_frame`] from the user
+    /// stack, i.e., `sigreturn`.
+    ///
+    /// It should be called on the syscall from `restorer`, right after the
+    /// handler returns to it: the user stack pointer points to the saved frame
+    /// in 64-bit mode, or to the arguments of the handler in compatibility
+    /// mode. The saved `CS` and `SS` must be the user selectors of either
+    /// mode ([`UCODE64`](gdt::UCODE64) and [`UDATA`](gdt::UDATA), or
+    /// [`UCODE32`](gdt::UCODE32) and [`UDATA32`](gdt::UDATA32)), the saved
+    /// `RIP` must be a user address of that mode, and only the
+    /// user-modifiable `RFLAGS` bits are restored.
+    ///
+    /// [`push_signal_frame`]: UserContext::push_signal_frame
+    pub fn pop_signal_frame(&mut self) -> Result<(), SignalFrameError> {
+        const FRAME_SIZE: usize = core::mem::size_of::<TrapFrame>();
+        let (frame, space_end) = if self.is_compat() {
+            // the return address has been popped by the handler
+            let frame = (self.tf.rsp as u32 as usize) + COMPAT_ARGS_SIZE - 4;
+            (frame, COMPAT_SPACE_END)
+        } else {
+            (self.tf.rsp as usize, USER_SPACE_END)
+        };
+        if !frame.is_multiple_of(16) || frame > space_end - FRAME_SIZE {
+            return Err(SignalFrameError::BadStack);
+        }
+        let mut tf = TrapFrame::default();
+        unsafe {
+            let dst = &mut tf as *mut TrapFrame as *mut u8;
+            if copy_from_user(dst, frame as *const u8, FRAME_SIZE).is_err() {
+                return Err(SignalFrameError::BadStack);
+            }
+        }
+        let rip_end = match (tf.cs, tf.ss) {
+            (cs, ss) if cs == gdt::UCODE64.0 as u64 && ss == gdt::UDATA.0 as u64 => USER_SPACE_END,
+            (cs, ss) if cs == gdt::UCODE32.0 as u64 && ss == gdt::UDATA32.0 as u64 => {
+                COMPAT_SPACE_END
+            }
+            _ => return Err(SignalFrameError::BadFrame),
+        };
+        if tf.rip as usize >= rip_end {
+            return Err(SignalFrameError::BadFrame);
+        }
+        tf.rflags = (tf.rflags & RFLAGS_USER_MASK) | RFlags::INTERRUPT_FLAG.bits();
+        tf.vector = self.tf.vector;
+        tf.error_code = self.tf.error_code;
+        self.tf = tf;
+        Ok(())
+    }
+}
+
 /// Initializes syscall support and setups the syscall handler.
 pub(super) fn init_syscall() {
     extern "C" {
@@ -170,4 +657,6 @@ pub(super) fn init_syscall() {
     unsafe {
         Efer::update(|efer| *efer |= EferFlags::SYSTEM_CALL_EXTENSIONS);
     }
+    #[cfg(feature = "sysenter")]
+    super::sysenter::init();
 }
@@ -0,0 +1,94 @@
+//! Virtualization exception (`#VE`) support for Intel TDX guests.
+//!
+//! In a TDX guest, some instructions (e.g., MMIO accesses, `CPUID` and some
+//! MSR accesses) cause a `#VE` instead of a VM exit, and the guest kernel must
+//! emulate them (usually with `TDG.VP.VMCALL`). The `#VE` information is
+//! retrieved from the TDX module with `TDG.VP.VEINFO.GET` and passed to the
+//! [`VE_HANDLER`]s.
+
+use core::arch::asm;
+
+use super::TrapFrame;
+use crate::trap::def_trap_handler;
+
+/// The TDCALL leaf of `TDG.VP.VEINFO.GET`.
+const TDG_VP_VEINFO_GET: u64 = 3;
+
+/// The information of a virtualization exception (`#VE`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VeInfo {
+    /// The exit reason, in the format of the VT-x basic exit reason.
+    pub exit_reason: u64,
+    /// The exit qualification.
+    pub exit_qual: u64,
+    /// The guest linear address.
+    pub guest_linear_addr: u64,
+    /// The guest physical address.
+    pub guest_phys_addr: u64,
+    /// The length of the instruction that caused the `#VE`, which should be
+    /// skipped after emulating it.
+    pub instr_len: u32,
+    /// The VM-exit instruction information.
+    pub instr_info: u32,
+}
+
+/// Retrieves the information of the current `#VE` from the TDX module
+/// (`TDG.VP.VEINFO.GET`).
+///
+/// Returns `None` if the TDCALL fails, e.g., there is no pending `#VE`
+/// information.
+///
+/// # Safety
+///
+/// It must be called in a TDX guest, as `TDCALL` is an undefined instruction
+/// otherwise.
+pub unsafe fn read_ve_info() -> Option<VeInfo> {
+    let (status, exit_reason, exit_qual, gla, gpa, instr): (u64, u64, u64, u64, u64, u64);
+    unsafe {
+        asm!(
+            ".byte 0x66, 0x0f, 0x01, 0xcc", // tdcall
+            inlateout("rax") TDG_VP_VEINFO_GET => status,
+            lateout("rcx") exit_reason,
+            lateout("rdx") exit_qual,
+            lateout("r8") gla,
+            lateout("r9") gpa,
+            lateout("r10") instr,
+            options(nostack),
+        )
+    };
+    (status == 0).then_some(VeInfo {
+        exit_reason,
+        exit_qual,
+        guest_linear_addr: gla,
+        guest_phys_addr: gpa,
+        instr_len: instr as u32,
+        instr_info: (instr >> 32) as u32,
+    })
+}
+
+/// A slice of virtualization exception (`#VE`) handler functions.
+///
+/// The handlers are called in order with the trap frame and the `#VE`
+/// information until one of them returns `true`. A handler that emulates the
+/// faulting instruction must advance [`TrapFrame::rip`] by
+/// [`VeInfo::instr_len`].
+#[def_trap_handler]
+pub static VE_HANDLER: [fn(&mut TrapFrame, &VeInfo) -> bool];
+
+/// Handles the `#VE` by retrieving its information and calling the registered
+/// [`VE_HANDLER`]s.
+pub(super) fn handle_ve(tf: &mut TrapFrame) {
+    let Some(info) = (unsafe { read_ve_info() }) else {
+        panic!("#VE @ {:#x}: failed to get the #VE information", tf.rip);
+    };
+    if !VE_HANDLER.iter().any(|handler| handler(tf, &info)) {
+        core::hint::cold_path();
+        panic!(
+            "Unhandled #VE @ {:#x}, {:#x?}:\n{}\n{}",
+            tf.rip,
+            info,
+            crate::trap::TrapFrameDump(tf),
+            tf.backtrace()
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! XSAVE feature set support, to save and restore extended processor states
+//! (e.g., AVX and AVX-512 registers) that `FXSAVE` cannot handle.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86::controlregs::{cr4, cr4_write, xcr0, xcr0_write, Cr4, Xcr0};
+use x86::cpuid::native_cpuid::cpuid_count;
+
+pub use super::context::XSAVE_AREA_SIZE;
+use super::cpu_features::CpuFeatures;
+
+/// An error when enabling XSAVE state components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XsaveError {
+    /// The CPU does not support the XSAVE feature set.
+    NotSupported,
+    /// The CPU does not support the state components in the mask (in the
+    /// format of `XCR0`).
+    UnsupportedComponents(u64),
+    /// The XSAVE area required by the state components in the mask (in the
+    /// format of `XCR0`, including those already enabled) is `size` bytes,
+    /// larger than [`XSAVE_AREA_SIZE`] that [`ExtendedState`] can hold.
+    ///
+    /// [`ExtendedState`]: super::ExtendedState
+    AreaTooLarge {
+        /// The state components enabled by the request.
+        components: u64,
+        /// The size of the XSAVE area they require.
+        size: usize,
+    },
+}
+
+/// Whether `XSAVE`/`XRSTOR` is used to save and restore extended states.
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `XSAVEOPT` is used instead of `XSAVE` to save extended states.
+static XSAVEOPT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Cached size of the XSAVE area for the features enabled in `XCR0`.
+static XSAVE_AREA_SIZE_CACHE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the CPU supports the XSAVE feature set (`CPUID.01H:ECX.XSAVE`).
+pub fn xsave_supported() -> bool {
+    CpuFeatures::current().contains(CpuFeatures::XSAVE)
+}
+
+/// Returns whether `XSAVE`/`XRSTOR` is enabled and used for context switches.
+pub fn xsave_enabled() -> bool {
+    XSAVE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns whether `XSAVEOPT` is used to save extended states.
+pub fn xsaveopt_enabled() -> bool {
+    XSAVEOPT_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the size in bytes of the XSAVE area required by the state
+/// components currently enabled in `XCR0` (`CPUID.(EAX=0DH,ECX=0):EBX`).
+///
+/// The result is cached, and updated when state components are enabled by
+/// this module. Returns 0 if XSAVE is not supported.
+pub fn xsave_area_size() -> usize {
+    let size = XSAVE_AREA_SIZE_CACHE.load(Ordering::Relaxed);
+    if size != 0 {
+        return size;
+    }
+    init_xsave_area_size()
+}
+
+/// Reads the size in bytes of the XSAVE area required by the state components
+/// currently enabled in `XCR0` from CPUID, and caches it.
+///
+/// It is called when state components are enabled by this module, and should
+/// be called again if `XCR0` is changed elsewhere. Returns 0 if XSAVE is not
+/// supported.
+pub fn init_xsave_area_size() -> usize {
+    if !xsave_supported() {
+        return 0;
+    }
+    let size = cpuid_count(0xd, 0).ebx as usize;
+    XSAVE_AREA_SIZE_CACHE.store(size, Ordering::Relaxed);
+    size
+}
+
+/// Returns the mask of the enabled state components (`XCR0`), which is passed
+/// to `XSAVE`/`XRSTOR`.
+#[inline]
+pub(super) fn xsave_mask() -> u64 {
+    unsafe { xcr0() }.bits()
+}
+
+/// Enables the given state components in `XCR0`, and switches context
+/// switches to `XSAVE`/`XRSTOR`.
+///
+/// Fails if the CPU does not support the components, or the XSAVE area
+/// required is larger than [`XSAVE_AREA_SIZE`], in which case `XCR0` is left
+/// unchanged.
+unsafe fn enable_components(components: Xcr0) -> Result<(), XsaveError> {
+    if !xsave_supported() {
+        return Err(XsaveError::NotSupported);
+    }
+    let supported = cpuid_count(0xd, 0).eax as u64;
+    let unsupported = components.bits() & !supported;
+    if unsupported != 0 {
+        return Err(XsaveError::UnsupportedComponents(unsupported));
+    }
+    unsafe {
+        cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
+        let old = xcr0();
+        let new = old | components;
+        xcr0_write(new);
+        let size = init_xsave_area_size();
+        if size > XSAVE_AREA_SIZE {
+            xcr0_write(old);
+            init_xsave_area_size();
+            return Err(XsaveError::AreaTooLarge {
+                components: new.bits(),
+                size,
+            });
+        }
+    }
+    let xsaveopt = CpuFeatures::current().contains(CpuFeatures::XSAVEOPT);
+    XSAVEOPT_ENABLED.store(xsaveopt, Ordering::Relaxed);
+    XSAVE_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Enables `XSAVE`/`XRSTOR` for the x87 and SSE states on the current CPU.
+///
+/// Fails if XSAVE is not supported, in which case `FXSAVE`/`FXRSTOR` is still
+/// used.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes `CR4` and `XCR0` of the current CPU.
+/// It should be called on all CPUs before any task is created, as the saved
+/// states in different formats are not interchangeable.
+pub unsafe fn enable() -> Result<(), XsaveError> {
+    unsafe { enable_components(Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE) }
+}
+
+/// Enables the AVX state (`YMM` registers) in `XCR0` on the current CPU.
+///
+/// Fails with [`XsaveError::UnsupportedComponents`] if AVX is not supported.
+///
+/// # Safety
+///
+/// See [`enable`].
+pub unsafe fn enable_avx() -> Result<(), XsaveError> {
+    if !CpuFeatures::current().contains(CpuFeatures::AVX) {
+        return Err(XsaveError::UnsupportedComponents(
+            Xcr0::XCR0_AVX_STATE.bits(),
+        ));
+    }
+    unsafe {
+        enable_components(Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE | Xcr0::XCR0_AVX_STATE)
+    }
+}
+
+/// Enables the AVX-512 states (opmask, `ZMM_Hi256` and `Hi16_ZMM`) in `XCR0`
+/// on the current CPU. AVX is also enabled as required by the architecture.
+///
+/// Fails with [`XsaveError::UnsupportedComponents`] if AVX-512F is not
+/// supported, or [`XsaveError::AreaTooLarge`] if the states do not fit in
+/// [`XSAVE_AREA_SIZE`] together with the components already enabled.
+///
+/// # Safety
+///
+/// See [`enable`].
+pub unsafe fn enable_avx512f() -> Result<(), XsaveError> {
+    let avx512 = Xcr0::XCR0_OPMASK_STATE | Xcr0::XCR0_ZMM_HI256_STATE | Xcr0::XCR0_HI16_ZMM_STATE;
+    if !CpuFeatures::current().contains(CpuFeatures::AVX512F) {
+        return Err(XsaveError::UnsupportedComponents(avx512.bits()));
+    }
+    unsafe {
+        enable_components(
+            Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE | Xcr0::XCR0_AVX_STATE | avx512,
+        )
+    }
+}